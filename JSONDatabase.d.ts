@@ -3,16 +3,59 @@ import { EventEmitter } from 'events';
 export interface IndexConfig {
   name: string;
   path: string;
-  field: string;
+  field?: string;
+  /** Fields of a compound index, most significant first; replaces `field`. */
+  fields?: string[];
+  /** Skip documents that have none of the indexed fields. */
+  sparse?: boolean;
+  /** Only index documents matching this query (a partial index). */
+  filter?: object;
   unique?: boolean;
 }
 
+export type StorageCodec = 'json' | 'json-compact' | 'msgpack' | 'cbor';
+
 export interface DatabaseOptions {
   encryptionKey?: string;
   saveDelay?: number;
   prettyPrint?: boolean;
   silent?: boolean;
   wal?: boolean;
+  persistIndexes?: boolean;
+  /** Collections with at least this many items are queried in parallel (default 10000). */
+  parallelThreshold?: number;
+  /** Threads for parallel queries; defaults to one per CPU. */
+  queryThreads?: number;
+  /** Split the data over this many shard files, each with its own WAL. */
+  shards?: number;
+  /** Dotted path of the object whose entries are sharded (default: top-level keys). */
+  shardKey?: string;
+  /** Save only what changed since the last save, into an append-structured segment file. */
+  incremental?: boolean;
+  /** Levels below the root at which incremental saves split the data (default 1). */
+  segmentDepth?: number;
+  /** Load top-level collections on first use and evict the least recently used ones. Implies `incremental`. */
+  lazy?: boolean;
+  /** Megabytes of collections a lazy database keeps in memory (default 256). */
+  memoryBudgetMb?: number;
+  /** Storage codec of the data files and the WAL (default `json`, or `json-compact` without pretty printing). */
+  codec?: StorageCodec;
+  /** Compress data files and WAL records before encryption (default `none`). */
+  compression?: 'none' | 'zstd' | 'gzip';
+  /** 1-22 for zstd (default 3), 0-9 for gzip (default 6). */
+  compressionLevel?: number;
+  /** Never create or modify files; writes throw `ReadOnlyError`. */
+  readOnly?: boolean;
+  /** With `readOnly`, pick up changes other processes make to the files. */
+  reloadOnChange?: boolean;
+  /** Read-only, and applies what the writing process appends to its WAL instead of reloading. */
+  follow?: boolean;
+  /** Writes each commit through to the WAL and publishes the LSN in `<name>.lsn`, so `follow` databases see writes before they are saved (default false). */
+  allowFollowers?: boolean;
+  /** Milliseconds to wait for another process to close the database (default: fail right away). */
+  lockTimeoutMs?: number;
+  /** WALs of earlier saves to keep for `changesSince` (default 0). */
+  retainWalSegments?: number;
   schema?: any;
   indices?: IndexConfig[];
 }
@@ -26,30 +69,174 @@ export interface MiddlewareContext {
 
 export type MiddlewareFn = (ctx: MiddlewareContext) => MiddlewareContext;
 
+/** A committed write, as `watch` callbacks receive it. */
+export interface ChangeEvent {
+  op: 'set' | 'delete';
+  path: string;
+  /** The value at `path` before the write, if there was one. */
+  oldValue?: any;
+  /** The value at `path` after a set. */
+  newValue?: any;
+  lsn: number;
+}
+
+/** A backup written by `backup`. */
+export interface BackupInfo {
+  id: string;
+  kind: 'full' | 'incremental';
+  /** The backup an incremental one builds on. */
+  parent?: string;
+  /** LSN of the last write the backup holds. */
+  lsn: number;
+  /** Milliseconds since the Unix epoch. */
+  createdAt: number;
+}
+
+export interface ReplicationOptions {
+  /** Writes wait until a replica acknowledges them (default false). */
+  synchronous?: boolean;
+  /** How long a synchronous write waits before it rejects with `UnacknowledgedWriteError` (default 5000). */
+  syncTimeoutMs?: number;
+  /**
+   * 32 bytes in hex that replicas must prove they hold. The stream is sealed
+   * with it (AES-256-GCM). Required over TCP.
+   */
+  secret?: string;
+}
+
+export interface ReplicaOptions {
+  /** The primary's `secret`. */
+  secret?: string;
+}
+
+export interface ReplicationStatus {
+  /** `primary` while serving, `replica` while following a primary, else `none`. */
+  role: 'primary' | 'replica' | 'none';
+  /** LSN of the last write applied. */
+  lsn: number;
+  /** The address served, or the primary followed. */
+  address?: string;
+  /** Whether a replica is connected to its primary. */
+  connected: boolean;
+  /** Why a replica's last connection to its primary was lost or refused. */
+  error?: string;
+  /** The replicas connected to a primary and the last LSN each acknowledged. */
+  replicas: { peer: string; ackedLsn: number }[];
+}
+
 export class DBError extends Error {}
 export class TransactionError extends DBError {}
+/** Thrown by writes to a database opened with `readOnly`. */
+export class ReadOnlyError extends DBError {}
 export class ValidationError extends DBError {
   issues?: any[];
   constructor(msg: string, issues?: any[]);
 }
+/**
+ * Rejects a synchronous write on a primary that no replica acknowledged in
+ * time. The write is committed and saved on the primary anyway, so it must
+ * not be retried.
+ */
+export class UnacknowledgedWriteError extends DBError {
+  /** LSN of the write. */
+  lsn: number;
+  constructor(msg: string, lsn: number);
+}
+
+export interface FollowerLag {
+  /** LSN of the last write applied. */
+  lsn: number;
+  /** LSN of the last write the writer committed. */
+  latestLsn: number;
+  behind: number;
+  msSinceSync: number;
+}
+
+export interface TextIndexDefinition {
+  name: string;
+  path: string;
+  fields: string[];
+  /** 'word' (default) or 'whitespace'. */
+  tokenizer?: string;
+  lowercase?: boolean;
+  /** Stemming language, 'english' by default; 'none' disables stemming. */
+  language?: string;
+  stopWords?: string[];
+}
+
+export interface GeoIndexDefinition {
+  name: string;
+  path: string;
+  /** Field holding a { lat, lng } point or an array of points. */
+  field: string;
+  /** Grid cell size in degrees, 0.1 by default. */
+  cellSize?: number;
+}
+
+export interface SearchOptions {
+  index?: string;
+  filter?: object;
+  limit?: number;
+  skip?: number;
+  select?: string[];
+}
+
+export interface AggregateSpec {
+  /** Field whose value keys the groups; all matches form one group when unset. */
+  groupBy?: string;
+  /** Output field → `{ $count: true }`, or `{ $sum | $avg | $min | $max: field }`. */
+  accumulators: { [name: string]: { $count: any } | { $sum: string } | { $avg: string } | { $min: string } | { $max: string } };
+}
+
+export interface QueryExplain {
+  plan: 'collectionScan' | 'shardScan' | 'indexScan' | 'indexOrderScan' | 'textSearch' | 'geoScan';
+  index?: string;
+  candidatesExamined: number;
+  returned: number;
+  sortedByIndex: boolean;
+  /** Whether the filter or sort ran in parallel. */
+  parallel: boolean;
+  filterMs: number;
+  sortMs: number;
+  projectionMs: number;
+}
 
 export class QueryCursor implements PromiseLike<any[]> {
   limit(n: number): this;
   skip(n: number): this;
   sort(criteria: any): this;
   select(fields: string[]): this;
+  /** Resumes after the last document of the page that returned `token`. */
+  after(token: string): this;
+  /** Keyset pagination: up to `limit` documents and the token of the next page. */
+  page<T = any>(): Promise<{ items: T[]; nextToken?: string }>;
+  /** Documents fetched per native call when iterating with `for await` (default 1000). */
+  batchSize(n: number): this;
+  [Symbol.asyncIterator](): AsyncGenerator<any>;
   exec(): Promise<any[]>;
+  explain(): Promise<QueryExplain>;
   then<TResult1 = any[], TResult2 = never>(
     onfulfilled?: ((value: any[]) => TResult1 | PromiseLike<TResult1>) | null,
     onrejected?: ((reason: any) => TResult2 | PromiseLike<TResult2>) | null
   ): PromiseLike<TResult1 | TResult2>;
 }
 
+/** Read-only lookups on a memory-mapped snapshot written by `exportSnapshot`. */
+export class MappedSnapshot {
+  constructor(filename: string);
+  get<T = any>(path?: string, defaultValue?: T): Promise<T>;
+  has(path: string): Promise<boolean>;
+  find<T = any>(path: string, query: object): Promise<T | undefined>;
+}
+
 export default class JSONDatabase extends EventEmitter {
   static DBError: typeof DBError;
   static TransactionError: typeof TransactionError;
+  static ReadOnlyError: typeof ReadOnlyError;
   static ValidationError: typeof ValidationError;
+  static UnacknowledgedWriteError: typeof UnacknowledgedWriteError;
   static QueryCursor: typeof QueryCursor;
+  static MappedSnapshot: typeof MappedSnapshot;
 
   constructor(filename: string, options?: DatabaseOptions);
   
@@ -66,8 +253,14 @@ export default class JSONDatabase extends EventEmitter {
   
   find<T = any>(path: string, predicate: ((item: T) => boolean) | object): Promise<T | undefined>;
   findByIndex<T = any>(indexName: string, value: any): Promise<T | undefined>;
+
+  createTextIndex(definition: TextIndexDefinition): Promise<void>;
+  createGeoIndex(definition: GeoIndexDefinition): Promise<void>;
+  search<T = any>(path: string, text: string, options?: SearchOptions): Promise<{ score: number; document: T }[]>;
   
   query(path: string, query?: any): QueryCursor;
+  /** Groups the documents matching `query`, one `{ _id, ...accumulators }` per group in key order. */
+  aggregate<T = any>(path: string, query: object, spec: AggregateSpec): Promise<T[]>;
   
   transaction<T = any>(fn: (data: any) => T | Promise<T>): Promise<boolean>;
   batch(ops: Array<{ type: 'set' | 'delete' | 'push'; path: string; value?: any; values?: any[] }>): Promise<boolean>;
@@ -80,6 +273,32 @@ export default class JSONDatabase extends EventEmitter {
   }>;
   
   createSnapshot(label?: string): Promise<string>;
+  /** Writes a point-in-time backup to the new directory `destination` without stopping writes. */
+  backup(destination: string): Promise<BackupInfo>;
+  /** Writes the writes logged since the backup `sinceBackupId` in `destDir` to a new incremental backup there. The WAL has to reach back to that backup; see `retainWalSegments`. */
+  incrementalBackup(destDir: string, sinceBackupId: string): Promise<BackupInfo>;
+  /** Replaces the data with the backup in `source` as of `lsn` (default: its last write), applying an incremental backup on top of the ones it builds on. Rejects, leaving the data as it is, if a file or record is corrupt or the backup does not decrypt with this database's key. The restore is saved as a write of its own at the next LSN: replicas are sent the restored data, and `changesSince` rejects positions from before it. Resolves to the backup restored, with `lsn` the position it was restored as of. */
+  restore(source: string, lsn?: number): Promise<BackupInfo>;
+  /** Writes a copy of the database to `destination` stored with `codec`, compressed and encrypted like this one. Rejects if `destination` exists. */
+  convert(destination: string, codec: StorageCodec): Promise<void>;
+  /** Writes the data to `destination` as a snapshot for `MappedSnapshot`. Not available for encrypted databases. */
+  exportSnapshot(destination: string): Promise<void>;
+  /** Calls `callback` for every committed write that can change a path matching `pattern`. Returns a function that stops watching. */
+  watch(pattern: string, callback: (event: ChangeEvent) => void): () => void;
+  /** The writes logged after `lsn`, oldest first, without `oldValue`. Rejects if `lsn` is older than the retained WALs. */
+  changesSince(lsn: number, limit?: number): Promise<ChangeEvent[]>;
+  /** Serves the WAL stream to replicas on `address` (`host:port`, `tcp://host:port`, a socket path or `unix:path`; TCP needs a `secret`). Resolves to the address bound. */
+  serveReplication(address: string, options?: ReplicationOptions): Promise<string>;
+  /** Follows the primary at `address`. Writes throw `ReadOnlyError` until `stopReplication`. */
+  replicateFrom(address: string, options?: ReplicaOptions): Promise<void>;
+  /** Stops serving and following; a replica becomes writable. */
+  stopReplication(): Promise<void>;
+  replicationStatus(): Promise<ReplicationStatus>;
+  /** With `follow`, applies the writer's new WAL records now rather than on the next read. Resolves to the number applied. */
+  catchUp(): Promise<number>;
+  /** With `follow`, how many writes the in-memory copy is behind the writer's files. */
+  lag(): Promise<FollowerLag>;
+  /** Waits for pending saves, flushes queued writes and releases the lock that keeps other processes from writing the database. */
   close(): Promise<void>;
 
   before(op: 'set' | 'delete' | 'push' | 'pull', pattern: string, cb: MiddlewareFn): void;
@@ -1,9 +1,8 @@
 const { EventEmitter } = require('events');
 const path = require('path');
-const lockfile = require('proper-lockfile');
 const _ = require('lodash');
 const fs = require('fs');
-const { DatabaseCore } = require('./index');
+const { DatabaseCore, MappedDatabase } = require('./index');
 
 // Custom Errors
 class DBError extends Error {
@@ -13,12 +12,25 @@ class DBError extends Error {
   }
 }
 class TransactionError extends DBError {}
+class ReadOnlyError extends DBError {}
 class ValidationError extends DBError {
   constructor(msg, issues) {
     super(msg);
     this.issues = issues;
   }
 }
+// A synchronous write that no replica acknowledged in time. It is committed
+// and saved on the primary, so it must not be retried.
+class UnacknowledgedWriteError extends DBError {
+  constructor(msg, lsn) {
+    super(msg);
+    this.lsn = lsn;
+  }
+  static from(e) {
+    const match = /^Write at LSN (\d+) is committed, but no replica acknowledged it/.exec(e && e.message);
+    return match ? new UnacknowledgedWriteError(e.message, Number(match[1])) : null;
+  }
+}
 
 class QueryCursor {
     constructor(core, path, query, dbInstance) {
@@ -27,6 +39,7 @@ class QueryCursor {
         this.query = query;
         this.dbInstance = dbInstance;
         this.options = {};
+        this._batchSize = 1000;
     }
 
     limit(n) {
@@ -49,8 +62,58 @@ class QueryCursor {
         return this;
     }
 
+    /** Resumes after the last document of the page that returned `token`. */
+    after(token) {
+        this.options.after = token;
+        return this;
+    }
+
+    /**
+     * Keyset pagination: returns up to `limit` documents and a `nextToken` to pass
+     * to `after()` for the next page. Unlike `skip`, pages stay consistent while data changes.
+     */
+    async page() {
+        if (typeof this.query === 'function') {
+            throw new DBError("page() is only available for object queries");
+        }
+        if (this.dbInstance) {
+            await this.dbInstance._ensureInitialized();
+            this.dbInstance['_flushOps']();
+        }
+        return this.core.findPage(this.path, this.query, this.options);
+    }
+
+    /** Number of documents fetched from the native cursor at a time when iterating. */
+    batchSize(n) {
+        this._batchSize = n;
+        return this;
+    }
+
+    async *[Symbol.asyncIterator]() {
+        if (typeof this.query === 'function') {
+            yield* await this.exec();
+            return;
+        }
+        if (this.dbInstance) {
+            await this.dbInstance._ensureInitialized();
+            this.dbInstance['_flushOps']();
+        }
+        // The native cursor reads a snapshot, so writes made while iterating are not seen.
+        const cursor = this.core.findCursor(this.path, this.query, this.options);
+        try {
+            while (true) {
+                const batch = cursor.nextBatch(this._batchSize);
+                if (batch.length === 0) return;
+                yield* batch;
+            }
+        } finally {
+            cursor.close();
+        }
+    }
+
     async exec() {
         if (this.dbInstance) {
+            await this.dbInstance._ensureInitialized();
             this.dbInstance._flushOps();
         }
         
@@ -73,20 +136,63 @@ class QueryCursor {
             }
             return result;
         } else {
-            return this.core.find(this.path, this.query, this.options);
+            return this.core.findAsync(this.path, this.query, this.options);
         }
     }
 
+    async explain() {
+        if (typeof this.query === 'function') {
+            throw new DBError("explain() is only available for object queries");
+        }
+        if (this.dbInstance) {
+            await this.dbInstance._ensureInitialized();
+            this.dbInstance._flushOps();
+        }
+        return this.core.explain(this.path, this.query, this.options);
+    }
+
     then(onfulfilled, onrejected) {
         return this.exec().then(onfulfilled, onrejected);
     }
 }
 
+/**
+ * Read-only lookups on a snapshot written by `exportSnapshot`. The file is
+ * memory-mapped, so opening it is instant and only the values that are
+ * returned are read into memory.
+ */
+class MappedSnapshot {
+    constructor(filename) {
+        const resolvedPath = path.resolve(filename);
+        if (!resolvedPath.startsWith(process.cwd())) {
+            throw new Error("Security Violation: Database path must be inside the project directory.");
+        }
+        this.core = new MappedDatabase(resolvedPath);
+    }
+
+    async get(path, defaultValue = null) {
+        const val = this.core.get(path || undefined);
+        return val === null || val === undefined ? defaultValue : val;
+    }
+
+    async has(path) {
+        return this.core.has(path);
+    }
+
+    async find(path, query) {
+        const res = this.core.findOne(path, query);
+        return res === null ? undefined : res;
+    }
+}
+
 class JSONDatabase extends EventEmitter {
   static DBError = DBError;
   static TransactionError = TransactionError;
+  static ReadOnlyError = ReadOnlyError;
   static ValidationError = ValidationError;
+  static UnacknowledgedWriteError = UnacknowledgedWriteError;
   static QueryCursor = QueryCursor;
+  static MappedSnapshot = MappedSnapshot;
 
   constructor(filename, options = {}) {
     super();
@@ -105,19 +211,59 @@ class JSONDatabase extends EventEmitter {
       prettyPrint: options.prettyPrint !== false,
       silent: options.silent || false,
       wal: options.wal !== false,
+      persistIndexes: options.persistIndexes || false,
+      parallelThreshold: options.parallelThreshold,
+      queryThreads: options.queryThreads,
+      shards: options.shards,
+      shardKey: options.shardKey,
+      incremental: options.incremental,
+      segmentDepth: options.segmentDepth,
+      lazy: options.lazy,
+      memoryBudgetMb: options.memoryBudgetMb,
+      codec: options.codec,
+      compression: options.compression,
+      compressionLevel: options.compressionLevel,
+      readOnly: options.readOnly || options.follow || false,
+      reloadOnChange: options.reloadOnChange,
+      follow: options.follow,
+      allowFollowers: options.allowFollowers,
+      lockTimeoutMs: options.lockTimeoutMs,
+      retainWalSegments: options.retainWalSegments,
     };
 
     this.core = new DatabaseCore(
       this.filename,
       this.config.encryptionKey || undefined,
       this.config.prettyPrint,
-      this.config.wal
+      this.config.wal,
+      {
+        persistIndexes: this.config.persistIndexes,
+        parallelThreshold: this.config.parallelThreshold,
+        queryThreads: this.config.queryThreads,
+        shards: this.config.shards,
+        shardKey: this.config.shardKey,
+        incremental: this.config.incremental,
+        segmentDepth: this.config.segmentDepth,
+        lazy: this.config.lazy,
+        memoryBudgetMb: this.config.memoryBudgetMb,
+        codec: this.config.codec,
+        compression: this.config.compression,
+        compressionLevel: this.config.compressionLevel,
+        readOnly: this.config.readOnly,
+        reloadOnChange: this.config.reloadOnChange,
+        follow: this.config.follow,
+        allowFollowers: this.config.allowFollowers,
+        lockTimeoutMs: this.config.lockTimeoutMs,
+        retainWalSegments: this.config.retainWalSegments,
+      }
     );
 
     this._saveTimer = null;
     this._savePromise = null;
+    this._replicaOf = null;
     this._saveResolve = null;
     this._saveReject = null;
+    this._unacknowledged = null;
     
     this._middleware = {
       before: { set: [], delete: [], push: [], pull: [] },
@@ -149,10 +295,14 @@ class JSONDatabase extends EventEmitter {
 
   async _initialize() {
       try {
-          if (fs.existsSync(this.filename)) {
-              this.core.load();
-          } else {
-              this.core.set("", {});
+          // Load once the code that constructed the database has run, so
+          // watches set up right after it see the writes replayed from the WAL.
+          await Promise.resolve();
+          // A missing file loads as an empty database; sharded data
+          // lives in a directory next to it.
+          await this.core.loadAsync();
+          for (const idx of this.config.indices) {
+              this.core.createIndex({ name: idx.name, path: idx.path, field: idx.field, fields: idx.fields, sparse: idx.sparse, filter: idx.filter });
           }
           this._loaded = true;
           this.emit('ready');
@@ -173,7 +323,17 @@ class JSONDatabase extends EventEmitter {
       
       try {
           this.core.batch(ops);
-          
+      } catch (e) {
+          const unacknowledged = UnacknowledgedWriteError.from(e);
+          if (!unacknowledged) {
+              this._log('error', "Flush failed:", e);
+              return;
+          }
+          // The writes are committed; the save they wait for rejects with this.
+          this._unacknowledged = unacknowledged;
+      }
+
+      try {
           const hasAfter = Object.values(this._middleware.after).some(arr => arr.length > 0);
           if (hasAfter) {
               for (const op of ops) {
@@ -197,18 +357,13 @@ class JSONDatabase extends EventEmitter {
              await fs.promises.mkdir(dir, { recursive: true });
          }
 
-         let release = () => {};
-         if (fs.existsSync(this.filename)) {
-             release = await lockfile.lock(this.filename, { retries: 3 });
-         }
-         
-         try {
-           this.core.save();
-           this.emit('write');
-           if (this._saveResolve) this._saveResolve(true);
-         } finally {
-           await release();
-         }
+         await this.core.saveAsync();
+         this.emit('write');
+         const unacknowledged = this._unacknowledged;
+         this._unacknowledged = null;
+         if (unacknowledged) {
+             if (this._saveReject) this._saveReject(unacknowledged);
+         } else if (this._saveResolve) this._saveResolve(true);
 
       } catch (e) {
          this._log('error', "Save Failed:", e);
@@ -221,6 +376,27 @@ class JSONDatabase extends EventEmitter {
       }
   }
 
+  // Commits a write that bypasses the queue, and waits for its save.
+  async _commitNow(write) {
+      try {
+          write();
+      } catch (e) {
+          const unacknowledged = UnacknowledgedWriteError.from(e);
+          if (!unacknowledged) throw e;
+          this._unacknowledged = unacknowledged;
+      }
+      return await this._scheduleSave();
+  }
+
+  _assertWritable() {
+    if (this.config.readOnly) {
+      throw new ReadOnlyError("Database is open read-only");
+    }
+    if (this._replicaOf) {
+      throw new ReadOnlyError(`Database is a replica of ${this._replicaOf}`);
+    }
+  }
+
   _scheduleSave() {
     // this._flushOps(); // REMOVED: Batching optimization. Flush only on read or before save.
 
@@ -243,6 +419,7 @@ class JSONDatabase extends EventEmitter {
 
   set(path, value) {
     try {
+        this._assertWritable();
         if (!this._loaded) {
             return this._initPromise.then(() => this._setSync(path, value));
         }
@@ -307,6 +484,7 @@ class JSONDatabase extends EventEmitter {
   }
 
   async delete(path) {
+    this._assertWritable();
     await this._ensureInitialized();
     let ctx = { path };
     ctx = this._runMiddleware("before", "delete", ctx);
@@ -321,6 +499,7 @@ class JSONDatabase extends EventEmitter {
   }
 
   async push(path, ...items) {
+    this._assertWritable();
     await this._ensureInitialized();
     this._flushOps(); 
 
@@ -346,6 +525,7 @@ class JSONDatabase extends EventEmitter {
   }
 
   async pull(path, ...items) {
+    this._assertWritable();
     await this._ensureInitialized();
     this._flushOps(); 
 
@@ -366,6 +546,7 @@ class JSONDatabase extends EventEmitter {
   }
 
   async add(path, amount) {
+    this._assertWritable();
     await this._ensureInitialized();
     this._flushOps(); 
 
@@ -406,11 +587,36 @@ class JSONDatabase extends EventEmitter {
       return res === null ? undefined : res;
   }
 
+  async createTextIndex(definition) {
+      await this._ensureInitialized();
+      this._flushOps();
+      this.core.createTextIndex(definition);
+  }
+
+  async createGeoIndex(definition) {
+      await this._ensureInitialized();
+      this._flushOps();
+      this.core.createGeoIndex(definition);
+  }
+
+  async search(path, text, options = {}) {
+      await this._ensureInitialized();
+      this._flushOps();
+      return this.core.search(path, text, options);
+  }
+
   query(path, query) {
       return new QueryCursor(this.core, path, query, this);
   }
 
+  async aggregate(path, query, spec) {
+      await this._ensureInitialized();
+      this._flushOps();
+      return this.core.aggregateAsync(path, query, spec);
+  }
+
   async transaction(fn) {
+      this._assertWritable();
       await this._ensureInitialized();
       this._flushOps(); 
       const data = this.core.get(undefined);
@@ -420,14 +626,14 @@ class JSONDatabase extends EventEmitter {
           
           if (result === undefined) throw new TransactionError("Atomic operation function returned undefined");
           
-          this.core.set("", mutableClone);
-          return await this._scheduleSave();
+          return await this._commitNow(() => this.core.set("", mutableClone));
       } catch (e) {
           throw e;
       }
   }
 
   async batch(ops) {
+      this._assertWritable();
       await this._ensureInitialized();
       this._flushOps();
 
@@ -447,15 +653,14 @@ class JSONDatabase extends EventEmitter {
           }
       }
 
-      this.core.batch(rustOps);
-      return await this._scheduleSave();
+      return await this._commitNow(() => this.core.batch(rustOps));
   }
 
   async clear() {
+      this._assertWritable();
       await this._ensureInitialized();
       this._writeQueue = []; 
-      this.core.set("", {});
-      return await this._scheduleSave();
+      return await this._commitNow(() => this.core.set("", {}));
   }
   
   async paginate(path, page = 1, limit = 10) {
@@ -476,16 +681,148 @@ class JSONDatabase extends EventEmitter {
   
   async createSnapshot(label = "backup") {
     await this._ensureInitialized();
-    await this._scheduleSave(); 
+    if (!this.config.readOnly) await this._scheduleSave();
     
     const backupName = `${this.filename.replace(".json", "")}.${label}-${Date.now()}.bak`;
     await fs.promises.copyFile(this.filename, backupName);
     return backupName;
   }
+
+  /**
+   * Writes a consistent backup of the data to the new directory
+   * `destination` while writes carry on.
+   */
+  async backup(destination) {
+    await this._ensureInitialized();
+    this._flushOps();
+    const target = path.resolve(destination);
+    if (!target.startsWith(process.cwd())) {
+      throw new Error("Security Violation: Database path must be inside the project directory.");
+    }
+    return this.core.backup(target);
+  }
+
+  /**
+   * Writes the writes logged since the backup `sinceBackupId` in `destDir`
+   * to a new incremental backup there.
+   */
+  async incrementalBackup(destDir, sinceBackupId) {
+    await this._ensureInitialized();
+    this._flushOps();
+    const target = path.resolve(destDir);
+    if (!target.startsWith(process.cwd())) {
+      throw new Error("Security Violation: Database path must be inside the project directory.");
+    }
+    return this.core.incrementalBackup(target, sinceBackupId);
+  }
+
+  /**
+   * Replaces the data with a backup as of `lsn`, by default its last write,
+   * once its checksums and key check out.
+   */
+  async restore(source, lsn) {
+    this._assertWritable();
+    await this._ensureInitialized();
+    this._flushOps();
+    const target = path.resolve(source);
+    if (!target.startsWith(process.cwd())) {
+      throw new Error("Security Violation: Database path must be inside the project directory.");
+    }
+    return this.core.restore(target, lsn);
+  }
   
+  async convert(destination, codec) {
+    await this._ensureInitialized();
+    this._flushOps();
+    const target = path.resolve(destination);
+    if (!target.startsWith(process.cwd())) {
+      throw new Error("Security Violation: Database path must be inside the project directory.");
+    }
+    this.core.convert(target, codec);
+  }
+
+  async exportSnapshot(destination) {
+    await this._ensureInitialized();
+    this._flushOps();
+    const target = path.resolve(destination);
+    if (!target.startsWith(process.cwd())) {
+      throw new Error("Security Violation: Database path must be inside the project directory.");
+    }
+    this.core.exportSnapshot(target);
+  }
+
+  /**
+   * Calls `callback` for every committed write that can change a path
+   * matching `pattern`, e.g. `users.*` or `**`. Returns a function that
+   * stops watching.
+   */
+  watch(pattern, callback) {
+    const id = this.core.watch(pattern, callback);
+    return () => {
+      this.core.unwatch(id);
+    };
+  }
+
+  /**
+   * The writes logged after `lsn`, oldest first, from the WAL and the ones
+   * kept by `retainWalSegments`. Pass the `lsn` of the last change to
+   * resume; a position that is no longer retained rejects.
+   */
+  async changesSince(lsn, limit) {
+    await this._ensureInitialized();
+    this._flushOps();
+    return this.core.changesSince(lsn, limit);
+  }
+
+  /**
+   * Streams every committed write to replicas that connect to `address`:
+   * `host:port` or `tcp://host:port` for TCP, a path or `unix:path` for a
+   * Unix socket. TCP needs a `secret`. Resolves to the address bound.
+   */
+  async serveReplication(address, options) {
+    await this._ensureInitialized();
+    this._flushOps();
+    return this.core.serveReplication(address, options);
+  }
+
+  /** Follows the primary serving at `address`; writes are rejected until `stopReplication`. */
+  async replicateFrom(address, options) {
+    this._assertWritable();
+    await this._ensureInitialized();
+    this._flushOps();
+    this.core.replicateFrom(address, options);
+    this._replicaOf = address;
+  }
+
+  /** Stops serving replicas and following a primary, which promotes a replica. */
+  async stopReplication() {
+    await this._ensureInitialized();
+    this.core.stopReplication();
+    this._replicaOf = null;
+  }
+
+  async replicationStatus() {
+    await this._ensureInitialized();
+    return this.core.replicationStatus();
+  }
+
+  /** Applies what the writer appended to its WAL since the last read. */
+  async catchUp() {
+    await this._ensureInitialized();
+    return this.core.catchUp();
+  }
+
+  /** How far a `follow` database is behind the writer. */
+  async lag() {
+    await this._ensureInitialized();
+    return this.core.lag();
+  }
+
   async close() {
       if (this._savePromise) await this._savePromise;
       this._flushOps(); 
+      // Lets other processes open the database for writing.
+      this.core.close();
       this.removeAllListeners();
   }
 
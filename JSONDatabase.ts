@@ -1,21 +1,39 @@
 import { EventEmitter } from 'events';
 import * as path from 'path';
 // @ts-ignore
-import * as lockfile from 'proper-lockfile';
-// @ts-ignore
 import * as _ from 'lodash';
 import * as fs from 'fs';
-import { DatabaseCore } from './index'; 
+import { DatabaseCore, MappedDatabase } from './index'; 
 
 // Types
+export type StorageCodec = 'json' | 'json-compact' | 'msgpack' | 'cbor';
+
 export interface DatabaseOptions {
   encryptionKey?: string;
   saveDelay?: number;
   prettyPrint?: boolean;
   schema?: any;
-  indices?: { name: string; path: string; field: string; unique?: boolean }[];
+  indices?: { name: string; path: string; field?: string; fields?: string[]; sparse?: boolean; filter?: object; unique?: boolean }[];
   silent?: boolean;
   wal?: boolean;
+  persistIndexes?: boolean;
+  parallelThreshold?: number;
+  queryThreads?: number;
+  shards?: number;
+  shardKey?: string;
+  incremental?: boolean;
+  segmentDepth?: number;
+  lazy?: boolean;
+  memoryBudgetMb?: number;
+  codec?: StorageCodec;
+  compression?: 'none' | 'zstd' | 'gzip';
+  compressionLevel?: number;
+  readOnly?: boolean;
+  reloadOnChange?: boolean;
+  follow?: boolean;
+  allowFollowers?: boolean;
+  lockTimeoutMs?: number;
+  retainWalSegments?: number;
 }
 
 export interface MiddlewareContext {
@@ -27,6 +45,36 @@ export interface MiddlewareContext {
 
 export type MiddlewareFn = (ctx: MiddlewareContext) => MiddlewareContext;
 
+export interface ChangeEvent {
+  op: 'set' | 'delete';
+  path: string;
+  oldValue?: any;
+  newValue?: any;
+  lsn: number;
+}
+
+export interface ReplicationStatus {
+  role: 'primary' | 'replica' | 'none';
+  lsn: number;
+  address?: string;
+  connected: boolean;
+  error?: string;
+  replicas: { peer: string; ackedLsn: number }[];
+}
+
+export interface BackupInfo {
+  id: string;
+  kind: 'full' | 'incremental';
+  parent?: string;
+  lsn: number;
+  createdAt: number;
+}
+
+export interface AggregateSpec {
+  groupBy?: string;
+  accumulators: { [name: string]: object };
+}
+
 interface MiddlewareStore {
   before: { [key: string]: { regex: RegExp; cb: MiddlewareFn }[] };
   after: { [key: string]: { regex: RegExp; cb: MiddlewareFn }[] };
@@ -40,6 +88,7 @@ class DBError extends Error {
   }
 }
 class TransactionError extends DBError {}
+class ReadOnlyError extends DBError {}
 class ValidationError extends DBError {
   issues?: any[];
   constructor(msg: string, issues?: any[]) {
@@ -47,6 +96,19 @@ class ValidationError extends DBError {
     this.issues = issues;
   }
 }
+// A synchronous write that no replica acknowledged in time. It is committed
+// and saved on the primary, so it must not be retried.
+class UnacknowledgedWriteError extends DBError {
+  lsn: number;
+  constructor(msg: string, lsn: number) {
+    super(msg);
+    this.lsn = lsn;
+  }
+  static from(e: any): UnacknowledgedWriteError | null {
+    const match = /^Write at LSN (\d+) is committed, but no replica acknowledged it/.exec(e && e.message);
+    return match ? new UnacknowledgedWriteError(e.message, Number(match[1])) : null;
+  }
+}
 
 class QueryCursor implements PromiseLike<any[]> {
     private core: DatabaseCore;
@@ -58,7 +120,9 @@ class QueryCursor implements PromiseLike<any[]> {
         skip?: number;
         sort?: any;
         select?: string[];
+        after?: string;
     } = {};
+    private _batchSize: number = 1000;
 
     constructor(core: DatabaseCore, path: string, query: any, dbInstance: JSONDatabase | null = null) {
         this.core = core;
@@ -87,8 +151,60 @@ class QueryCursor implements PromiseLike<any[]> {
         return this;
     }
 
+    /** Resumes after the last document of the page that returned `token`. */
+    public after(token: string): this {
+        this.options.after = token;
+        return this;
+    }
+
+    /**
+     * Keyset pagination: returns up to `limit` documents and a `nextToken` to pass
+     * to `after()` for the next page. Unlike `skip`, pages stay consistent while data changes.
+     */
+    public async page(): Promise<{ items: any[]; nextToken?: string }> {
+        if (typeof this.query === 'function') {
+            throw new DBError("page() is only available for object queries");
+        }
+        if (this.dbInstance) {
+            await this.dbInstance._ensureInitialized();
+            // @ts-ignore
+            this.dbInstance['_flushOps']();
+        }
+        return this.core.findPage(this.path, this.query, this.options);
+    }
+
+    /** Number of documents fetched from the native cursor at a time when iterating. */
+    public batchSize(n: number): this {
+        this._batchSize = n;
+        return this;
+    }
+
+    public async *[Symbol.asyncIterator](): AsyncGenerator<any> {
+        if (typeof this.query === 'function') {
+            yield* await this.exec();
+            return;
+        }
+        if (this.dbInstance) {
+            await this.dbInstance._ensureInitialized();
+            // @ts-ignore
+            this.dbInstance['_flushOps']();
+        }
+        // The native cursor reads a snapshot, so writes made while iterating are not seen.
+        const cursor = this.core.findCursor(this.path, this.query, this.options);
+        try {
+            while (true) {
+                const batch = cursor.nextBatch(this._batchSize);
+                if (batch.length === 0) return;
+                yield* batch;
+            }
+        } finally {
+            cursor.close();
+        }
+    }
+
     public async exec(): Promise<any[]> {
         if (this.dbInstance) {
+            await this.dbInstance._ensureInitialized();
             // @ts-ignore
             this.dbInstance['_flushOps']();
         }
@@ -112,8 +228,20 @@ class QueryCursor implements PromiseLike<any[]> {
             }
             return result;
         } else {
-            return this.core.find(this.path, this.query, this.options);
+            return this.core.findAsync(this.path, this.query, this.options);
+        }
+    }
+
+    public async explain(): Promise<any> {
+        if (typeof this.query === 'function') {
+            throw new DBError("explain() is only available for object queries");
         }
+        if (this.dbInstance) {
+            await this.dbInstance._ensureInitialized();
+            // @ts-ignore
+            this.dbInstance['_flushOps']();
+        }
+        return this.core.explain(this.path, this.query, this.options);
     }
 
     then<TResult1 = any[], TResult2 = never>(
@@ -124,11 +252,45 @@ class QueryCursor implements PromiseLike<any[]> {
     }
 }
 
+/**
+ * Read-only lookups on a snapshot written by `exportSnapshot`. The file is
+ * memory-mapped, so opening it is instant and only the values that are
+ * returned are read into memory.
+ */
+class MappedSnapshot {
+    private core: MappedDatabase;
+
+    constructor(filename: string) {
+        const resolvedPath = path.resolve(filename);
+        if (!resolvedPath.startsWith(process.cwd())) {
+            throw new Error("Security Violation: Database path must be inside the project directory.");
+        }
+        this.core = new MappedDatabase(resolvedPath);
+    }
+
+    public async get(path?: string, defaultValue: any = null): Promise<any> {
+        const val = this.core.get(path || undefined);
+        return val === null || val === undefined ? defaultValue : val;
+    }
+
+    public async has(path: string): Promise<boolean> {
+        return this.core.has(path);
+    }
+
+    public async find(path: string, query: object): Promise<any> {
+        const res = this.core.findOne(path, query);
+        return res === null ? undefined : res;
+    }
+}
+
 class JSONDatabase extends EventEmitter {
   public static DBError = DBError;
   public static TransactionError = TransactionError;
+  public static ReadOnlyError = ReadOnlyError;
   public static ValidationError = ValidationError;
+  public static UnacknowledgedWriteError = UnacknowledgedWriteError;
   public static QueryCursor = QueryCursor;
+  public static MappedSnapshot = MappedSnapshot;
 
   private core: DatabaseCore;
   private filename: string;
@@ -140,11 +302,31 @@ class JSONDatabase extends EventEmitter {
     prettyPrint: boolean;
     silent: boolean;
     wal: boolean;
+    persistIndexes: boolean;
+    parallelThreshold?: number;
+    queryThreads?: number;
+    shards?: number;
+    shardKey?: string;
+    incremental?: boolean;
+    segmentDepth?: number;
+    lazy?: boolean;
+    memoryBudgetMb?: number;
+    codec?: StorageCodec;
+    compression?: 'none' | 'zstd' | 'gzip';
+    compressionLevel?: number;
+    readOnly: boolean;
+    reloadOnChange?: boolean;
+    follow?: boolean;
+    allowFollowers?: boolean;
+    lockTimeoutMs?: number;
+    retainWalSegments?: number;
   };
   private _saveTimer: any = null;
   private _savePromise: Promise<boolean> | null = null;
+  private _replicaOf: string | null = null;
   private _saveResolve: ((value: boolean | PromiseLike<boolean>) => void) | null = null;
   private _saveReject: ((reason?: any) => void) | null = null;
+  private _unacknowledged: UnacknowledgedWriteError | null = null;
   
   private _middleware: MiddlewareStore = {
     before: { set: [], delete: [], push: [], pull: [] },
@@ -172,13 +354,51 @@ class JSONDatabase extends EventEmitter {
       prettyPrint: options.prettyPrint !== false,
       silent: options.silent || false,
       wal: options.wal !== false,
+      persistIndexes: options.persistIndexes || false,
+      parallelThreshold: options.parallelThreshold,
+      queryThreads: options.queryThreads,
+      shards: options.shards,
+      shardKey: options.shardKey,
+      incremental: options.incremental,
+      segmentDepth: options.segmentDepth,
+      lazy: options.lazy,
+      memoryBudgetMb: options.memoryBudgetMb,
+      codec: options.codec,
+      compression: options.compression,
+      compressionLevel: options.compressionLevel,
+      readOnly: options.readOnly || options.follow || false,
+      reloadOnChange: options.reloadOnChange,
+      follow: options.follow,
+      allowFollowers: options.allowFollowers,
+      lockTimeoutMs: options.lockTimeoutMs,
+      retainWalSegments: options.retainWalSegments,
     };
 
     this.core = new DatabaseCore(
       this.filename,
       this.config.encryptionKey || undefined,
       this.config.prettyPrint,
-      this.config.wal
+      this.config.wal,
+      {
+        persistIndexes: this.config.persistIndexes,
+        parallelThreshold: this.config.parallelThreshold,
+        queryThreads: this.config.queryThreads,
+        shards: this.config.shards,
+        shardKey: this.config.shardKey,
+        incremental: this.config.incremental,
+        segmentDepth: this.config.segmentDepth,
+        lazy: this.config.lazy,
+        memoryBudgetMb: this.config.memoryBudgetMb,
+        codec: this.config.codec,
+        compression: this.config.compression,
+        compressionLevel: this.config.compressionLevel,
+        readOnly: this.config.readOnly,
+        reloadOnChange: this.config.reloadOnChange,
+        follow: this.config.follow,
+        allowFollowers: this.config.allowFollowers,
+        lockTimeoutMs: this.config.lockTimeoutMs,
+        retainWalSegments: this.config.retainWalSegments,
+      }
     );
 
     this._initPromise = this._initialize();
@@ -202,10 +422,14 @@ class JSONDatabase extends EventEmitter {
 
   private async _initialize() {
       try {
-          if (fs.existsSync(this.filename)) {
-              this.core.load();
-          } else {
-              this.core.set("", {});
+          // Load once the code that constructed the database has run, so
+          // watches set up right after it see the writes replayed from the WAL.
+          await Promise.resolve();
+          // A missing file loads as an empty database; sharded data
+          // lives in a directory next to it.
+          await this.core.loadAsync();
+          for (const idx of this.config.indices) {
+              this.core.createIndex({ name: idx.name, path: idx.path, field: idx.field, fields: idx.fields, sparse: idx.sparse, filter: idx.filter });
           }
           this._loaded = true;
           this.emit('ready');
@@ -226,6 +450,17 @@ class JSONDatabase extends EventEmitter {
       
       try {
           this.core.batch(ops);
+      } catch (e) {
+          const unacknowledged = UnacknowledgedWriteError.from(e);
+          if (!unacknowledged) {
+              this._log('error', "Flush failed:", e);
+              return;
+          }
+          // The writes are committed; the save they wait for rejects with this.
+          this._unacknowledged = unacknowledged;
+      }
+
+      try {
           for (const op of ops) {
               if (this._middleware.after[op.type]) {
                   const { type, ...rest } = op;
@@ -237,6 +472,27 @@ class JSONDatabase extends EventEmitter {
       }
   }
 
+  // Commits a write that bypasses the queue, and waits for its save.
+  private async _commitNow(write: () => void): Promise<boolean> {
+      try {
+          write();
+      } catch (e) {
+          const unacknowledged = UnacknowledgedWriteError.from(e);
+          if (!unacknowledged) throw e;
+          this._unacknowledged = unacknowledged;
+      }
+      return await this._scheduleSave();
+  }
+
+  private _assertWritable() {
+    if (this.config.readOnly) {
+      throw new ReadOnlyError("Database is open read-only");
+    }
+    if (this._replicaOf) {
+      throw new ReadOnlyError(`Database is a replica of ${this._replicaOf}`);
+    }
+  }
+
   private async _scheduleSave(): Promise<boolean> {
     this._flushOps();
 
@@ -261,18 +517,13 @@ class JSONDatabase extends EventEmitter {
              await fs.promises.mkdir(dir, { recursive: true });
          }
 
-         let release = () => {};
-         if (fs.existsSync(this.filename)) {
-             release = await lockfile.lock(this.filename, { retries: 3 });
-         }
-         
-         try {
-           this.core.save();
-           this.emit('write');
-           if (this._saveResolve) this._saveResolve(true);
-         } finally {
-           await release();
-         }
+         await this.core.saveAsync();
+         this.emit('write');
+         const unacknowledged = this._unacknowledged;
+         this._unacknowledged = null;
+         if (unacknowledged) {
+             if (this._saveReject) this._saveReject(unacknowledged);
+         } else if (this._saveResolve) this._saveResolve(true);
 
       } catch (e: any) {
          this._log('error', "Save Failed:", e);
@@ -289,6 +540,7 @@ class JSONDatabase extends EventEmitter {
   }
 
   public async set(path: string, value: any): Promise<boolean> {
+    this._assertWritable();
     await this._ensureInitialized();
     
     let ctx = { path, value };
@@ -345,6 +597,7 @@ class JSONDatabase extends EventEmitter {
   }
 
   public async delete(path: string): Promise<boolean> {
+    this._assertWritable();
     await this._ensureInitialized();
     let ctx = { path };
     ctx = this._runMiddleware("before", "delete", ctx);
@@ -359,6 +612,7 @@ class JSONDatabase extends EventEmitter {
   }
 
   public async push(path: string, ...items: any[]): Promise<boolean | void> {
+    this._assertWritable();
     await this._ensureInitialized();
     this._flushOps(); 
 
@@ -384,6 +638,7 @@ class JSONDatabase extends EventEmitter {
   }
 
   public async pull(path: string, ...items: any[]): Promise<boolean | void> {
+    this._assertWritable();
     await this._ensureInitialized();
     this._flushOps(); 
 
@@ -404,6 +659,7 @@ class JSONDatabase extends EventEmitter {
   }
 
   public async add(path: string, amount: number): Promise<boolean> {
+    this._assertWritable();
     await this._ensureInitialized();
     this._flushOps(); 
 
@@ -444,11 +700,36 @@ class JSONDatabase extends EventEmitter {
       return res === null ? undefined : res;
   }
 
+  public async createTextIndex(definition: { name: string; path: string; fields: string[]; tokenizer?: string; lowercase?: boolean; language?: string; stopWords?: string[] }): Promise<void> {
+      await this._ensureInitialized();
+      this._flushOps();
+      this.core.createTextIndex(definition);
+  }
+
+  public async createGeoIndex(definition: { name: string; path: string; field: string; cellSize?: number }): Promise<void> {
+      await this._ensureInitialized();
+      this._flushOps();
+      this.core.createGeoIndex(definition);
+  }
+
+  public async search(path: string, text: string, options: any = {}): Promise<{ score: number; document: any }[]> {
+      await this._ensureInitialized();
+      this._flushOps();
+      return this.core.search(path, text, options);
+  }
+
   public query(path: string, query: any): QueryCursor {
       return new QueryCursor(this.core, path, query, this);
   }
 
+  public async aggregate(path: string, query: object, spec: AggregateSpec): Promise<any[]> {
+      await this._ensureInitialized();
+      this._flushOps();
+      return this.core.aggregateAsync(path, query, spec);
+  }
+
   public async transaction(fn: (data: any) => any): Promise<boolean> {
+      this._assertWritable();
       await this._ensureInitialized();
       this._flushOps(); 
       const data = this.core.get(undefined);
@@ -458,14 +739,14 @@ class JSONDatabase extends EventEmitter {
           
           if (result === undefined) throw new TransactionError("Atomic operation function returned undefined");
           
-          this.core.set("", mutableClone);
-          return await this._scheduleSave();
+          return await this._commitNow(() => this.core.set("", mutableClone));
       } catch (e) {
           throw e;
       }
   }
 
   public async batch(ops: { type: "set" | "delete" | "push"; path: string; value?: any; values?: any[] }[]): Promise<boolean> {
+      this._assertWritable();
       await this._ensureInitialized();
       this._flushOps();
 
@@ -485,15 +766,14 @@ class JSONDatabase extends EventEmitter {
           }
       }
 
-      this.core.batch(rustOps);
-      return await this._scheduleSave();
+      return await this._commitNow(() => this.core.batch(rustOps));
   }
 
   public async clear(): Promise<boolean> {
+      this._assertWritable();
       await this._ensureInitialized();
       this._writeQueue = []; 
-      this.core.set("", {});
-      return await this._scheduleSave();
+      return await this._commitNow(() => this.core.set("", {}));
   }
   
   public async paginate(path: string, page: number = 1, limit: number = 10): Promise<any> {
@@ -514,16 +794,148 @@ class JSONDatabase extends EventEmitter {
   
   public async createSnapshot(label: string = "backup"): Promise<string> {
     await this._ensureInitialized();
-    await this._scheduleSave(); 
+    if (!this.config.readOnly) await this._scheduleSave();
     
     const backupName = `${this.filename.replace(".json", "")}.${label}-${Date.now()}.bak`;
     await fs.promises.copyFile(this.filename, backupName);
     return backupName;
   }
+
+  /**
+   * Writes a consistent backup of the data to the new directory
+   * `destination` while writes carry on.
+   */
+  public async backup(destination: string): Promise<BackupInfo> {
+    await this._ensureInitialized();
+    this._flushOps();
+    const target = path.resolve(destination);
+    if (!target.startsWith(process.cwd())) {
+      throw new Error("Security Violation: Database path must be inside the project directory.");
+    }
+    return this.core.backup(target);
+  }
+
+  /**
+   * Writes the writes logged since the backup `sinceBackupId` in `destDir`
+   * to a new incremental backup there.
+   */
+  public async incrementalBackup(destDir: string, sinceBackupId: string): Promise<BackupInfo> {
+    await this._ensureInitialized();
+    this._flushOps();
+    const target = path.resolve(destDir);
+    if (!target.startsWith(process.cwd())) {
+      throw new Error("Security Violation: Database path must be inside the project directory.");
+    }
+    return this.core.incrementalBackup(target, sinceBackupId);
+  }
+
+  /**
+   * Replaces the data with a backup as of `lsn`, by default its last write,
+   * once its checksums and key check out.
+   */
+  public async restore(source: string, lsn?: number): Promise<BackupInfo> {
+    this._assertWritable();
+    await this._ensureInitialized();
+    this._flushOps();
+    const target = path.resolve(source);
+    if (!target.startsWith(process.cwd())) {
+      throw new Error("Security Violation: Database path must be inside the project directory.");
+    }
+    return this.core.restore(target, lsn);
+  }
   
+  public async convert(destination: string, codec: StorageCodec): Promise<void> {
+    await this._ensureInitialized();
+    this._flushOps();
+    const target = path.resolve(destination);
+    if (!target.startsWith(process.cwd())) {
+      throw new Error("Security Violation: Database path must be inside the project directory.");
+    }
+    this.core.convert(target, codec);
+  }
+
+  public async exportSnapshot(destination: string): Promise<void> {
+    await this._ensureInitialized();
+    this._flushOps();
+    const target = path.resolve(destination);
+    if (!target.startsWith(process.cwd())) {
+      throw new Error("Security Violation: Database path must be inside the project directory.");
+    }
+    this.core.exportSnapshot(target);
+  }
+
+  /**
+   * Calls `callback` for every committed write that can change a path
+   * matching `pattern`, e.g. `users.*` or `**`. Returns a function that
+   * stops watching.
+   */
+  public watch(pattern: string, callback: (event: ChangeEvent) => void): () => void {
+    const id = this.core.watch(pattern, callback);
+    return () => {
+      this.core.unwatch(id);
+    };
+  }
+
+  /**
+   * The writes logged after `lsn`, oldest first, from the WAL and the ones
+   * kept by `retainWalSegments`. Pass the `lsn` of the last change to
+   * resume; a position that is no longer retained rejects.
+   */
+  public async changesSince(lsn: number, limit?: number): Promise<ChangeEvent[]> {
+    await this._ensureInitialized();
+    this._flushOps();
+    return this.core.changesSince(lsn, limit);
+  }
+
+  /**
+   * Streams every committed write to replicas that connect to `address`:
+   * `host:port` or `tcp://host:port` for TCP, a path or `unix:path` for a
+   * Unix socket. TCP needs a `secret`. Resolves to the address bound.
+   */
+  public async serveReplication(address: string, options?: { synchronous?: boolean; syncTimeoutMs?: number; secret?: string }): Promise<string> {
+    await this._ensureInitialized();
+    this._flushOps();
+    return this.core.serveReplication(address, options);
+  }
+
+  /** Follows the primary serving at `address`; writes are rejected until `stopReplication`. */
+  public async replicateFrom(address: string, options?: { secret?: string }): Promise<void> {
+    this._assertWritable();
+    await this._ensureInitialized();
+    this._flushOps();
+    this.core.replicateFrom(address, options);
+    this._replicaOf = address;
+  }
+
+  /** Stops serving replicas and following a primary, which promotes a replica. */
+  public async stopReplication(): Promise<void> {
+    await this._ensureInitialized();
+    this.core.stopReplication();
+    this._replicaOf = null;
+  }
+
+  public async replicationStatus(): Promise<ReplicationStatus> {
+    await this._ensureInitialized();
+    return this.core.replicationStatus();
+  }
+
+  /** Applies what the writer appended to its WAL since the last read. */
+  public async catchUp(): Promise<number> {
+    await this._ensureInitialized();
+    return this.core.catchUp();
+  }
+
+  /** How far a `follow` database is behind the writer. */
+  public async lag(): Promise<{ lsn: number; latestLsn: number; behind: number; msSinceSync: number }> {
+    await this._ensureInitialized();
+    return this.core.lag();
+  }
+
   public async close(): Promise<void> {
       if (this._savePromise) await this._savePromise;
       this._flushOps(); 
+      // Lets other processes open the database for writing.
+      this.core.close();
       this.removeAllListeners();
   }
 
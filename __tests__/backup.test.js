@@ -0,0 +1,363 @@
+const path = require('path');
+const fs = require('fs').promises;
+const crypto = require('crypto');
+const zlib = require('zlib');
+
+const JSONDatabaseModule = require('../JSONDatabase');
+const JSONDatabase = JSONDatabaseModule.default || JSONDatabaseModule;
+
+const TEST_DATA_DIR = path.join(__dirname, 'test-data-backup');
+
+const getTempDbPath = () => path.join(TEST_DATA_DIR, `backup-db-${Date.now()}-${Math.random()}.json`);
+
+// Resolves once `check` returns true.
+const waitFor = async (check, timeoutMs = 5000) => {
+    const deadline = Date.now() + timeoutMs;
+    while (!(await check())) {
+        if (Date.now() > deadline) throw new Error('Timed out waiting');
+        await new Promise(resolve => setTimeout(resolve, 20));
+    }
+};
+
+// Sizes of every WAL of the database at `dbPath`, kept ones included.
+const walSizes = async (dbPath) => {
+    const base = path.basename(dbPath, '.json');
+    const dirs = [TEST_DATA_DIR, path.join(TEST_DATA_DIR, base, 'shards')];
+    const sizes = [];
+    for (const dir of dirs) {
+        const names = await fs.readdir(dir).catch(() => []);
+        for (const name of names) {
+            if (dir === TEST_DATA_DIR && !name.startsWith(`${base}.`)) continue;
+            if (/\.wal(\.\d+)?$/.test(name)) sizes.push((await fs.stat(path.join(dir, name))).size);
+        }
+    }
+    return sizes;
+};
+
+beforeAll(async () => {
+    await fs.mkdir(TEST_DATA_DIR, { recursive: true });
+});
+
+afterAll(async () => {
+    await fs.rm(TEST_DATA_DIR, { recursive: true, force: true });
+});
+
+describe('Backups', () => {
+    let dbPath;
+    let db;
+
+    const open = (options = {}) => {
+        db = new JSONDatabase(dbPath, { silent: true, saveDelay: 20, ...options });
+        return db;
+    };
+
+    beforeEach(() => {
+        dbPath = getTempDbPath();
+    });
+
+    afterEach(async () => {
+        await db.close();
+    });
+
+    test('createSnapshot copies the data file to a .bak file', async () => {
+        open();
+        await db.set('users.u1', { name: 'Alice' });
+
+        const backupName = await db.createSnapshot('daily');
+        expect(backupName).toMatch(/\.daily-\d+\.bak$/);
+        expect((await fs.stat(backupName)).isFile()).toBe(true);
+        expect(await fs.readFile(backupName)).toEqual(await fs.readFile(dbPath));
+    });
+
+    test('backup writes a directory with the data and a manifest of its LSN', async () => {
+        open();
+        await db.set('a', 1);
+        await db.set('b', 2);
+
+        const dest = path.join(TEST_DATA_DIR, `full-${Math.random()}`);
+        const info = await db.backup(dest);
+        expect(info.kind).toBe('full');
+        expect(info.lsn).toBe((await db.replicationStatus()).lsn);
+
+        const manifest = JSON.parse(await fs.readFile(path.join(dest, 'manifest.json'), 'utf8'));
+        expect(manifest.id).toBe(info.id);
+        expect(manifest.lsn).toBe(info.lsn);
+        expect((await fs.readdir(dest)).sort()).toEqual(['data', 'manifest.json']);
+    });
+
+    test('restore brings back the data as of the backup as a write of its own', async () => {
+        open();
+        await db.set('users.u1', { name: 'Alice' });
+        const dest = path.join(TEST_DATA_DIR, `full-${Math.random()}`);
+        const info = await db.backup(dest);
+        await db.set('users.u2', { name: 'Bob' });
+        await db.delete('users.u1');
+        const before = (await db.replicationStatus()).lsn;
+
+        const restored = await db.restore(dest);
+        expect(restored.lsn).toBe(info.lsn);
+        expect(await db.get('users')).toEqual({ u1: { name: 'Alice' } });
+        expect((await db.replicationStatus()).lsn).toBe(before + 1);
+
+        await db.set('users.u3', { name: 'Carol' });
+        await db.close();
+        open();
+        expect(await db.get('users')).toEqual({ u1: { name: 'Alice' }, u3: { name: 'Carol' } });
+    });
+
+    test.each([
+        ['one data file', {}],
+        ['shards', { shards: 4 }],
+    ])('restore into %s does not log the restored data to the WALs', async (_, options) => {
+        open({ ...options, retainWalSegments: 3 });
+        const big = 'x'.repeat(10000);
+        for (let i = 0; i < 8; i++) await db.set(`k${i}`, big);
+        const dest = path.join(TEST_DATA_DIR, `full-${Math.random()}`);
+        await db.backup(dest);
+        await db.set('k0', 'changed');
+
+        await db.restore(dest);
+
+        expect(await db.get('k0')).toBe(big);
+        for (const size of await walSizes(dbPath)) expect(size).toBeLessThan(1000);
+    });
+
+    test('changesSince rejects every position from before a restore', async () => {
+        open({ retainWalSegments: 5 });
+        await db.set('a', 1);
+        const dest = path.join(TEST_DATA_DIR, `full-${Math.random()}`);
+        const info = await db.backup(dest);
+        for (let i = 0; i < 3; i++) await db.set(`b${i}`, i);
+        const consumer = (await db.replicationStatus()).lsn;
+
+        await db.restore(dest);
+        const restored = (await db.replicationStatus()).lsn;
+        await db.set('c', 3);
+        await db.set('d', 4);
+
+        for (const position of [0, info.lsn, consumer]) {
+            await expect(db.changesSince(position)).rejects.toThrow(
+                `the oldest available position is LSN ${restored}`,
+            );
+        }
+        expect((await db.changesSince(restored)).map(change => change.path)).toEqual(['c', 'd']);
+    });
+
+    test('followers load the restored data', async () => {
+        open({ saveDelay: 20 });
+        await db.set('a', 1);
+        const dest = path.join(TEST_DATA_DIR, `full-${Math.random()}`);
+        await db.backup(dest);
+        for (let i = 0; i < 3; i++) await db.set(`b${i}`, i);
+        const follower = new JSONDatabase(dbPath, { silent: true, follow: true });
+        try {
+            expect(await follower.get('')).toEqual({ a: 1, b0: 0, b1: 1, b2: 2 });
+
+            await db.restore(dest);
+            await db.set('c', 3);
+            expect(await follower.get('')).toEqual({ a: 1, c: 3 });
+        } finally {
+            await follower.close();
+        }
+    });
+
+    test('watchers see the restore as a write of the whole data', async () => {
+        open();
+        await db.set('users.u1', { name: 'Alice' });
+        const dest = path.join(TEST_DATA_DIR, `full-${Math.random()}`);
+        await db.backup(dest);
+        await db.set('users.u1', { name: 'Alicia' });
+
+        const events = [];
+        const stop = db.watch('users.*', event => events.push(event));
+        await db.restore(dest);
+        await waitFor(() => events.length > 0);
+        stop();
+
+        expect(events[0].path).toBe('');
+        expect(events[0].newValue).toEqual({ users: { u1: { name: 'Alice' } } });
+    });
+
+    test('connected replicas are sent the restored data', async () => {
+        open();
+        await db.set('a', 1);
+        const dest = path.join(TEST_DATA_DIR, `full-${Math.random()}`);
+        await db.backup(dest);
+        await db.set('a', 2);
+        await db.set('b', 3);
+        const address = await db.serveReplication(`unix:${path.join(TEST_DATA_DIR, `restore-${process.pid}.sock`)}`);
+
+        const replica = new JSONDatabase(getTempDbPath(), { silent: true, saveDelay: 20 });
+        try {
+            await replica.replicateFrom(address);
+            await waitFor(async () => (await replica.get('b')) === 3);
+
+            await db.restore(dest);
+            await waitFor(async () => (await replica.get('a')) === 1);
+            expect(await replica.get('')).toEqual({ a: 1 });
+
+            await db.set('c', 4);
+            await waitFor(async () => (await replica.get('c')) === 4);
+        } finally {
+            await replica.close();
+        }
+    });
+
+    test('restore rejects a corrupt backup and leaves the data as it is', async () => {
+        open();
+        await db.set('a', 1);
+        const dest = path.join(TEST_DATA_DIR, `full-${Math.random()}`);
+        await db.backup(dest);
+        await db.set('a', 2);
+
+        const dataFile = path.join(dest, 'data');
+        const bytes = await fs.readFile(dataFile);
+        bytes[bytes.length - 2] ^= 0xff;
+        await fs.writeFile(dataFile, bytes);
+
+        await expect(db.restore(dest)).rejects.toThrow('data is corrupt');
+        expect(await db.get('a')).toBe(2);
+    });
+
+    test('restore rejects a backup that does not decrypt with the key', async () => {
+        open({ encryptionKey: crypto.randomBytes(32).toString('hex') });
+        await db.set('a', 1);
+        const dest = path.join(TEST_DATA_DIR, `full-${Math.random()}`);
+        await db.backup(dest);
+        await db.close();
+
+        dbPath = getTempDbPath();
+        open({ encryptionKey: crypto.randomBytes(32).toString('hex') });
+        await db.set('a', 2);
+        await expect(db.restore(dest)).rejects.toThrow('Failed to decode backup');
+        expect(await db.get('a')).toBe(2);
+    });
+
+    test('restore rejects sources outside the project directory', async () => {
+        open();
+        await expect(db.restore(path.join(path.parse(process.cwd()).root, 'tmp', 'backup'))).rejects.toThrow('Security Violation');
+    });
+});
+
+describe('Incremental backups', () => {
+    let dbPath;
+    let db;
+    let dir;
+
+    const open = (options = {}) => {
+        db = new JSONDatabase(dbPath, { silent: true, saveDelay: 20, ...options });
+        return db;
+    };
+
+    // A full backup and two incremental ones on top, with the LSN of each
+    // state the chain can be restored to.
+    const writeChain = async () => {
+        open({ retainWalSegments: 10 });
+        await db.set('a', 1);
+        const base = await db.backup(path.join(dir, 'base'));
+        await db.set('b', 2);
+        const afterB = (await db.replicationStatus()).lsn;
+        const first = await db.incrementalBackup(dir, base.id);
+        await db.set('a', 3);
+        await db.delete('b');
+        const second = await db.incrementalBackup(dir, first.id);
+        return { base, first, second, afterB };
+    };
+
+    // Opens an empty database to restore into.
+    const reopenEmpty = async () => {
+        await db.close();
+        dbPath = getTempDbPath();
+        open();
+    };
+
+    beforeEach(async () => {
+        dbPath = getTempDbPath();
+        dir = path.join(TEST_DATA_DIR, `chain-${Date.now()}-${Math.random()}`);
+        await fs.mkdir(dir);
+    });
+
+    afterEach(async () => {
+        await db.close();
+    });
+
+    test('each builds on the backup before it', async () => {
+        const { base, first, second } = await writeChain();
+
+        expect(first).toMatchObject({ kind: 'incremental', parent: base.id });
+        expect(second).toMatchObject({ kind: 'incremental', parent: first.id });
+        expect(second.lsn).toBe((await db.replicationStatus()).lsn);
+    });
+
+    test('restore applies the chain on top of the full backup', async () => {
+        const { second } = await writeChain();
+        await reopenEmpty();
+
+        const restored = await db.restore(path.join(dir, second.id));
+        expect(restored.lsn).toBe(second.lsn);
+        expect(await db.get('')).toEqual({ a: 3 });
+    });
+
+    test('restore stops at the LSN it is given', async () => {
+        const { base, second, afterB } = await writeChain();
+        await reopenEmpty();
+
+        await db.restore(path.join(dir, second.id), afterB);
+        expect(await db.get('')).toEqual({ a: 1, b: 2 });
+
+        await db.restore(path.join(dir, second.id), base.lsn);
+        expect(await db.get('')).toEqual({ a: 1 });
+
+        await expect(db.restore(path.join(dir, second.id), second.lsn + 1)).rejects.toThrow(
+            `can be restored as of LSN ${base.lsn} to ${second.lsn}, not ${second.lsn + 1}`,
+        );
+    });
+
+    test('rejects when the WAL no longer reaches back to the backup', async () => {
+        open();
+        await db.set('a', 1);
+        const base = await db.backup(path.join(dir, 'base'));
+        await db.set('b', 2);
+        await db.set('c', 3);
+
+        await expect(db.incrementalBackup(dir, base.id)).rejects.toThrow('are no longer retained');
+    });
+
+    test('restore rejects a damaged changes file', async () => {
+        const { second } = await writeChain();
+        const changes = path.join(dir, second.id, 'changes');
+        const bytes = await fs.readFile(changes);
+        bytes[bytes.length - 1] ^= 0xff;
+        await fs.writeFile(changes, bytes);
+        await reopenEmpty();
+
+        await expect(db.restore(path.join(dir, second.id))).rejects.toThrow('changes is corrupt');
+        expect(await db.get('')).toEqual({});
+    });
+
+    test('restore rejects a record that does not match its own checksum', async () => {
+        const { second } = await writeChain();
+        // Damages the last record and updates the manifest to match, so
+        // only the checksum stored with the record tells.
+        const changes = path.join(dir, second.id, 'changes');
+        const bytes = await fs.readFile(changes);
+        bytes[bytes.length - 1] ^= 0xff;
+        await fs.writeFile(changes, bytes);
+        const manifestPath = path.join(dir, second.id, 'manifest.json');
+        const manifest = JSON.parse(await fs.readFile(manifestPath, 'utf8'));
+        manifest.files.find(file => file.name === 'changes').checksum = zlib.crc32(bytes);
+        await fs.writeFile(manifestPath, JSON.stringify(manifest));
+        await reopenEmpty();
+
+        await expect(db.restore(path.join(dir, second.id))).rejects.toThrow(/record \d+ is corrupt/);
+        expect(await db.get('')).toEqual({});
+    });
+
+    test('restore rejects a chain with a backup missing', async () => {
+        const { base, second } = await writeChain();
+        await fs.rm(path.join(dir, 'base'), { recursive: true });
+        await reopenEmpty();
+
+        await expect(db.restore(path.join(dir, second.id))).rejects.toThrow(`No backup ${base.id}`);
+    });
+});
@@ -0,0 +1,259 @@
+const path = require('path');
+const fs = require('fs').promises;
+
+const JSONDatabaseModule = require('../JSONDatabase');
+const JSONDatabase = JSONDatabaseModule.default || JSONDatabaseModule;
+
+const TEST_DATA_DIR = path.join(__dirname, 'test-data-changes');
+
+const getTempDbPath = () => path.join(TEST_DATA_DIR, `changes-db-${Date.now()}-${Math.random()}.json`);
+
+beforeAll(async () => {
+    await fs.mkdir(TEST_DATA_DIR, { recursive: true });
+});
+
+afterAll(async () => {
+    await fs.rm(TEST_DATA_DIR, { recursive: true, force: true });
+});
+
+// Resolves once `check` returns true; watch events arrive asynchronously.
+const waitFor = async (check, timeoutMs = 5000) => {
+    const deadline = Date.now() + timeoutMs;
+    while (!(await check())) {
+        if (Date.now() > deadline) throw new Error('Timed out waiting for change events');
+        await new Promise(resolve => setTimeout(resolve, 10));
+    }
+};
+
+// Lets queued events reach JavaScript before checking that none came.
+const settle = () => new Promise(resolve => setTimeout(resolve, 50));
+
+describe('watch', () => {
+    let db;
+    let events;
+
+    const watchAll = (pattern, target = db) => {
+        const seen = [];
+        const stop = target.watch(pattern, event => seen.push(event));
+        return { seen, stop };
+    };
+
+    beforeEach(() => {
+        db = new JSONDatabase(getTempDbPath(), { silent: true, saveDelay: 20 });
+        events = watchAll('**').seen;
+    });
+
+    afterEach(async () => {
+        await db.close();
+    });
+
+    test('reports each write with the values before and after it, in commit order', async () => {
+        await db.set('users.u1', { name: 'Ann' });
+        await db.set('users.u1.name', 'Bea');
+        await db.add('counter', 2);
+        await db.delete('users.u1');
+        await waitFor(() => events.length === 4);
+
+        expect(events.map(({ op, path, oldValue, newValue }) => ({ op, path, oldValue, newValue }))).toEqual([
+            { op: 'set', path: 'users.u1', oldValue: undefined, newValue: { name: 'Ann' } },
+            { op: 'set', path: 'users.u1.name', oldValue: 'Ann', newValue: 'Bea' },
+            { op: 'set', path: 'counter', oldValue: undefined, newValue: 2 },
+            { op: 'delete', path: 'users.u1', oldValue: { name: 'Bea' }, newValue: undefined },
+        ]);
+        const lsns = events.map(event => event.lsn);
+        expect(lsns).toEqual([...lsns].sort((a, b) => a - b));
+        expect(new Set(lsns).size).toBe(4);
+    });
+
+    test('matches writes at, below and above the paths a pattern matches', async () => {
+        const oneLevel = watchAll('users.*').seen;
+        const named = watchAll('users.*.name').seen;
+        const prefixed = watchAll('users.admin*').seen;
+        const watched = watchAll('settings.*').seen;
+        const deep = watchAll('**.theme').seen;
+
+        await db.set('users.u1', { name: 'Ann', email: 'a@x' });
+        await db.set('users.u1.name', 'Bea');
+        await db.set('users.admin1.email', 'root@x');
+        await db.set('users', {});
+        await db.set('settings.theme', 'dark');
+        await waitFor(() => events.length === 5);
+        await settle();
+        const paths = seen => seen.map(event => event.path);
+
+        expect(paths(oneLevel)).toEqual(['users.u1', 'users.u1.name', 'users.admin1.email', 'users']);
+        expect(paths(named)).toEqual(['users.u1', 'users.u1.name', 'users']);
+        expect(paths(prefixed)).toEqual(['users.admin1.email', 'users']);
+        // Any write can hold a deeper match.
+        expect(paths(deep)).toEqual(paths(events));
+        expect(paths(watched)).toEqual(['settings.theme']);
+    });
+
+    test('stops reporting once unwatched', async () => {
+        const { seen, stop } = watchAll('a');
+        await db.set('a', 1);
+        await waitFor(() => seen.length === 1);
+
+        stop();
+        stop();
+        await db.set('a', 2);
+        await waitFor(() => events.length === 2);
+        await settle();
+
+        expect(seen.map(event => event.newValue)).toEqual([1]);
+        const id = db.core.watch('a', () => {});
+        expect(db.core.unwatch(id)).toBe(true);
+        expect(db.core.unwatch(id)).toBe(false);
+    });
+
+    test('reports writes made through a raw batch', async () => {
+        await db.get();
+        db.core.batch([{ type: 'set', path: 'a', value: 1 }, { type: 'delete', path: 'a' }]);
+        await waitFor(() => events.length === 2);
+
+        expect(events.map(event => event.op)).toEqual(['set', 'delete']);
+    });
+
+    test('reports the writes replayed from the WAL on load', async () => {
+        const dbPath = getTempDbPath();
+        const writer = new JSONDatabase(dbPath, { silent: true });
+        await writer.get();
+        writer.core.set('a', 1);
+        // Flushes the WAL without saving the data file.
+        writer.core.close();
+
+        const reopened = new JSONDatabase(dbPath, { silent: true });
+        const { seen } = watchAll('**', reopened);
+        expect(await reopened.get('a')).toBe(1);
+        await waitFor(() => seen.length === 1);
+        await reopened.close();
+
+        expect(seen[0]).toMatchObject({ op: 'set', path: 'a', newValue: 1 });
+    });
+
+    test('reports the writes a follow database catches up on', async () => {
+        const dbPath = getTempDbPath();
+        const writer = new JSONDatabase(dbPath, { silent: true, saveDelay: 1000, allowFollowers: true });
+        await writer.set('seed', 0);
+        const follower = new JSONDatabase(dbPath, { silent: true, follow: true });
+        const { seen } = watchAll('users.*', follower);
+        try {
+            await follower.get();
+            const saved = [writer.set('users.u1', 'Ann'), writer.set('other', 1)];
+            await writer.has('users');
+            expect(await follower.catchUp()).toBe(2);
+            await waitFor(() => seen.length === 1);
+            await Promise.all(saved);
+        } finally {
+            await writer.close();
+            await follower.close();
+        }
+
+        expect(seen[0]).toMatchObject({ op: 'set', path: 'users.u1', newValue: 'Ann' });
+    });
+});
+
+describe('changesSince', () => {
+    let dbPath;
+    let db;
+
+    const open = (options = {}) => {
+        db = new JSONDatabase(dbPath, { silent: true, saveDelay: 20, ...options });
+        return db;
+    };
+
+    // Each write resolves after the save it schedules, so each is one save.
+    const writeEach = async (count, from = 0) => {
+        for (let i = from; i < from + count; i++) await db.set(`k${i}`, i);
+    };
+
+    beforeEach(() => {
+        dbPath = getTempDbPath();
+    });
+
+    afterEach(async () => {
+        await db.close();
+    });
+
+    test('returns the writes after a position in order, as many as the limit allows', async () => {
+        open({ saveDelay: 1000 });
+        const pending = [db.set('a', 1), db.set('b', 2), db.delete('a')];
+        const changes = await db.changesSince(0);
+        const first = await db.changesSince(0, 2);
+        const rest = await db.changesSince(first[1].lsn);
+        await Promise.all(pending);
+
+        expect(changes.map(({ op, path, newValue }) => ({ op, path, newValue }))).toEqual([
+            { op: 'set', path: 'a', newValue: 1 },
+            { op: 'set', path: 'b', newValue: 2 },
+            { op: 'delete', path: 'a', newValue: undefined },
+        ]);
+        const lsns = changes.map(change => change.lsn);
+        expect(lsns).toEqual([...lsns].sort((x, y) => x - y));
+
+        expect(first).toEqual(changes.slice(0, 2));
+        expect(rest).toEqual(changes.slice(2));
+    });
+
+    test('rejects a position from before the last save without retained WALs', async () => {
+        open();
+        await writeEach(2);
+        const { lsn } = await db.replicationStatus();
+
+        await expect(db.changesSince(0)).rejects.toThrow(
+            `Changes since LSN 0 are no longer retained; the oldest available position is LSN ${lsn}`,
+        );
+        expect(await db.changesSince(lsn)).toEqual([]);
+    });
+
+    test('reads back across as many saves as retainWalSegments keeps', async () => {
+        open({ retainWalSegments: 3 });
+        await writeEach(3);
+
+        const changes = await db.changesSince(0);
+        expect(changes.map(change => change.path)).toEqual(['k0', 'k1', 'k2']);
+
+        const retained = (await fs.readdir(TEST_DATA_DIR))
+            .filter(name => name.startsWith(path.basename(dbPath, '.json')) && /\.wal\.\d+$/.test(name));
+        expect(retained).toHaveLength(3);
+    });
+
+    test('rejects a position older than the oldest retained WAL and reads the rest', async () => {
+        open({ retainWalSegments: 2 });
+        await writeEach(5);
+
+        await expect(db.changesSince(0)).rejects.toThrow(/are no longer retained; the oldest available position is LSN \d+/);
+
+        const error = await db.changesSince(0).catch(e => e);
+        const oldest = Number(/LSN (\d+)$/.exec(error.message)[1]);
+        const changes = await db.changesSince(oldest);
+        expect(changes.map(change => change.path)).toEqual(['k3', 'k4']);
+    });
+
+    test('keeps reading back after a restart', async () => {
+        open({ retainWalSegments: 5 });
+        await writeEach(2);
+        await db.close();
+
+        open({ retainWalSegments: 5 });
+        await writeEach(1, 2);
+        expect((await db.changesSince(0)).map(change => change.path)).toEqual(['k0', 'k1', 'k2']);
+    });
+
+    test('merges the WALs of all shards in LSN order', async () => {
+        open({ shards: 4, saveDelay: 1000 });
+        const pending = [];
+        for (let i = 0; i < 12; i++) pending.push(db.set(`k${i}`, i));
+        const changes = await db.changesSince(0);
+        await Promise.all(pending);
+
+        expect(changes.map(change => change.path)).toEqual(Array.from({ length: 12 }, (_, i) => `k${i}`));
+    });
+
+    test('rejects positions behind the data of a database without a WAL', async () => {
+        open({ wal: false });
+        await writeEach(1);
+
+        await expect(db.changesSince(0)).rejects.toThrow('the database has no WAL');
+    });
+});
@@ -0,0 +1,104 @@
+const path = require('path');
+const fs = require('fs').promises;
+
+const JSONDatabaseModule = require('../JSONDatabase');
+const JSONDatabase = JSONDatabaseModule.default || JSONDatabaseModule;
+
+const TEST_DATA_DIR = path.join(__dirname, 'test-data-follow');
+
+const getTempDbPath = () => path.join(TEST_DATA_DIR, `follow-db-${Date.now()}-${Math.random()}.json`);
+
+beforeAll(async () => {
+    await fs.mkdir(TEST_DATA_DIR, { recursive: true });
+});
+
+afterAll(async () => {
+    await fs.rm(TEST_DATA_DIR, { recursive: true, force: true });
+});
+
+describe('Followers', () => {
+    let dbPath;
+    let writer;
+    let follower;
+    let pending;
+
+    // Hands a write to the native core without waiting for the save it
+    // schedules, the way a busy writer sits between saves.
+    const write = async (key, value) => {
+        pending.push(writer.set(key, value));
+        await writer.has(key);
+    };
+
+    beforeEach(async () => {
+        dbPath = getTempDbPath();
+        pending = [];
+        writer = new JSONDatabase(dbPath, { silent: true, saveDelay: 2000, allowFollowers: true });
+        await writer.set('seed', 0);
+        follower = new JSONDatabase(dbPath, { silent: true, follow: true });
+        await follower._ensureInitialized();
+    });
+
+    afterEach(async () => {
+        await Promise.all(pending);
+        await writer.close();
+        await follower.close();
+    });
+
+    test('see a write before the writer saves it', async () => {
+        await write('a', { n: 1 });
+
+        expect(await follower.get('a')).toEqual({ n: 1 });
+    });
+
+    test('see small writes that do not fill a write buffer', async () => {
+        for (let i = 0; i < 5; i++) {
+            await write(`k${i}`, i);
+            expect(await follower.get(`k${i}`)).toBe(i);
+        }
+    });
+
+    test('report how far behind the writer they are', async () => {
+        expect((await follower.lag()).behind).toBe(0);
+
+        await write('a', 1);
+        await write('b', 2);
+        const lag = await follower.lag();
+
+        expect(lag.behind).toBe(2);
+        expect(lag.latestLsn).toBe(lag.lsn + 2);
+        expect(await follower.catchUp()).toBe(2);
+        expect((await follower.lag()).behind).toBe(0);
+    });
+
+    test('load the files again after the writer saves writes they never read', async () => {
+        await writer.set('a', 1);
+        await writer.set('b', 2);
+
+        expect(await follower.get('')).toEqual({ seed: 0, a: 1, b: 2 });
+    });
+
+    test('reject writes', async () => {
+        await expect(follower.set('a', 1)).rejects.toThrow(JSONDatabase.ReadOnlyError);
+    });
+});
+
+describe('A writer without allowFollowers', () => {
+    test('creates no LSN file, and followers see its writes once they are saved', async () => {
+        const dbPath = getTempDbPath();
+        const writer = new JSONDatabase(dbPath, { silent: true, saveDelay: 200 });
+        await writer.set('seed', 0);
+        const follower = new JSONDatabase(dbPath, { silent: true, follow: true });
+        try {
+            const saved = writer.set('a', 1);
+            await writer.has('a');
+            expect(await follower.get('a')).toBeNull();
+
+            await saved;
+            expect(await follower.get('a')).toBe(1);
+            await expect(fs.access(dbPath.replace(/\.json$/, '.lsn'))).rejects.toThrow();
+        } finally {
+            await writer.close();
+            await follower.close();
+        }
+    });
+});
@@ -0,0 +1,1000 @@
+const path = require('path');
+const fs = require('fs').promises;
+
+const JSONDatabaseModule = require('../JSONDatabase');
+const JSONDatabase = JSONDatabaseModule.default || JSONDatabaseModule;
+
+const TEST_DATA_DIR = path.join(__dirname, 'test-data-queries');
+
+const getTempDbPath = () => path.join(TEST_DATA_DIR, `query-db-${Date.now()}-${Math.random()}.json`);
+
+const items = {
+    a: { id: 'a', tags: ['a', 'b'], score: 3 },
+    b: { id: 'b', tags: ['b', 'a'], score: 1 },
+    c: { id: 'c', tags: ['a'], score: 'high' },
+    d: { id: 'd', tags: [[1, 2], 'x'], score: null },
+    e: { id: 'e', tags: [1, 2], score: true },
+    f: { id: 'f', tags: 'a' },
+    g: { id: 'g', tags: [], score: [1] },
+};
+
+const ids = (docs) => docs.map((doc) => doc.id).sort();
+
+beforeAll(async () => {
+    await fs.mkdir(TEST_DATA_DIR, { recursive: true });
+});
+
+afterAll(async () => {
+    await fs.rm(TEST_DATA_DIR, { recursive: true, force: true });
+});
+
+describe('Queries', () => {
+    let indexed;
+    let scanned;
+
+    beforeAll(async () => {
+        indexed = new JSONDatabase(getTempDbPath(), {
+            silent: true,
+            indices: [{ name: 'tags', path: 'items', field: 'tags' }],
+        });
+        scanned = new JSONDatabase(getTempDbPath(), { silent: true });
+        await indexed.set('items', items);
+        await scanned.set('items', items);
+    });
+
+    afterAll(async () => {
+        await indexed.close();
+        await scanned.close();
+    });
+
+    describe('indexed and scanned results agree', () => {
+        test.each([
+            [{ tags: ['a', 'b'] }, ['a']],
+            [{ tags: { $eq: ['a', 'b'] } }, ['a']],
+            [{ tags: { $in: [[1, 2]] } }, ['d', 'e']],
+            [{ tags: { $in: [['b', 'a'], 'x'] } }, ['b', 'd']],
+            [{ tags: [1, 2] }, ['d', 'e']],
+            [{ tags: [] }, ['g']],
+            [{ tags: 'a' }, ['a', 'b', 'c', 'f']],
+            [{ tags: { $in: ['x', 2] } }, ['d', 'e']],
+        ])('for %j', async (filter, expected) => {
+            const fromIndex = await indexed.query('items', filter).exec();
+            const fromScan = await scanned.query('items', filter).exec();
+
+            expect(ids(fromIndex)).toEqual(expected);
+            expect(ids(fromScan)).toEqual(expected);
+            expect((await indexed.query('items', filter).explain()).plan).toBe('indexScan');
+            expect((await scanned.query('items', filter).explain()).plan).toBe('collectionScan');
+        });
+    });
+
+    describe('array fields', () => {
+        test('match a value when any element equals it', async () => {
+            expect(ids(await scanned.query('items', { tags: 'b' }).exec())).toEqual(['a', 'b']);
+        });
+
+        test('match an operator when any element satisfies it', async () => {
+            expect(ids(await scanned.query('items', { tags: { $gt: 1 } }).exec())).toEqual(['e']);
+        });
+
+        test('match $ne and $nin only when no element equals the operand', async () => {
+            expect(ids(await scanned.query('items', { tags: { $ne: 'a' } }).exec())).toEqual(['d', 'e', 'g']);
+            expect(ids(await scanned.query('items', { tags: { $nin: ['a', 1] } }).exec())).toEqual(['d', 'g']);
+        });
+    });
+
+    describe('sorting mixed types', () => {
+        test('orders missing values first, then null, booleans, numbers, strings, arrays and objects', async () => {
+            const sorted = await scanned.query('items', {}).sort({ score: 1 }).exec();
+            expect(sorted.map((doc) => doc.id)).toEqual(['f', 'd', 'e', 'b', 'a', 'c', 'g']);
+        });
+
+        test('reverses the whole order when descending', async () => {
+            const sorted = await scanned.query('items', {}).sort({ score: -1 }).exec();
+            expect(sorted.map((doc) => doc.id)).toEqual(['g', 'c', 'a', 'b', 'e', 'd', 'f']);
+        });
+    });
+});
+
+// A small deterministic generator, so failures reproduce.
+const makeRandom = (seed) => () => {
+    seed = (seed * 1103515245 + 12345) % 2147483648;
+    return seed / 2147483648;
+};
+
+const makeProducts = (count) => {
+    const random = makeRandom(42);
+    const sizeRandom = makeRandom(7);
+    const categories = ['books', 'games', 'music', 'tools'];
+    const products = {};
+    for (let i = 0; i < count; i++) {
+        const product = { id: `p${i}`, rank: (i * 37) % count, category: categories[Math.floor(random() * 4)] };
+        const kind = random();
+        // Mostly numbers, with some missing, null and string prices.
+        if (kind < 0.85) product.price = Math.floor(random() * 50);
+        else if (kind < 0.9) product.price = null;
+        else if (kind < 0.95) product.price = `${Math.floor(random() * 50)}`;
+        // An array field, indexed element by element.
+        product.sizes = [Math.floor(sizeRandom() * 50), Math.floor(sizeRandom() * 50)];
+        products[product.id] = product;
+    }
+    return products;
+};
+
+describe('Range indexes', () => {
+    let indexed;
+    let scanned;
+
+    beforeAll(async () => {
+        const products = makeProducts(300);
+        indexed = new JSONDatabase(getTempDbPath(), {
+            silent: true,
+            indices: [
+                { name: 'price', path: 'products', field: 'price' },
+                { name: 'rank', path: 'products', field: 'rank' },
+                { name: 'categoryPrice', path: 'products', fields: ['category', 'price'] },
+                { name: 'sizes', path: 'products', field: 'sizes' },
+            ],
+        });
+        scanned = new JSONDatabase(getTempDbPath(), { silent: true });
+        await indexed.set('products', products);
+        await scanned.set('products', products);
+    });
+
+    afterAll(async () => {
+        await indexed.close();
+        await scanned.close();
+    });
+
+    test.each([
+        { price: { $gt: 10 } },
+        { price: { $gte: 10, $lt: 20 } },
+        { price: { $lte: 0 } },
+        { price: { $between: [5, 7] } },
+        { price: { $in: [1, 2, '3', null] } },
+        { price: { $gt: '2' } },
+        { price: null },
+        { category: 'books', price: { $lt: 10 } },
+        { category: { $in: ['games', 'tools'] }, price: 4 },
+        { category: 'music' },
+        { sizes: { $gt: 20, $lt: 25 } },
+        { sizes: { $gte: 45, $lte: 5 } },
+        { sizes: { $between: [20, 25] } },
+        { sizes: { $lt: 10, $between: [5, 40] } },
+    ])('returns the documents a scan returns for %j', async (filter) => {
+        const fromIndex = await indexed.query('products', filter).exec();
+        const fromScan = await scanned.query('products', filter).exec();
+
+        expect(fromScan.length).toBeGreaterThan(0);
+        expect(ids(fromIndex)).toEqual(ids(fromScan));
+        expect((await indexed.query('products', filter).explain()).plan).toBe('indexScan');
+    });
+
+    test.each([
+        [{}, { rank: 1 }],
+        [{}, { rank: -1 }],
+        [{ rank: { $gte: 100 } }, { rank: 1 }],
+        [{ category: 'books' }, { rank: -1 }],
+    ])('returns the page a sorted scan returns for %j sorted by %j', async (filter, sort) => {
+        const fromIndex = await indexed.query('products', filter).sort(sort).skip(5).limit(20).exec();
+        const fromScan = await scanned.query('products', filter).sort(sort).skip(5).limit(20).exec();
+
+        expect(fromIndex.map((doc) => doc.id)).toEqual(fromScan.map((doc) => doc.id));
+    });
+
+    test('walks the index in order for a sort on the indexed field', async () => {
+        const explain = await indexed.query('products', {}).sort({ rank: 1 }).limit(10).explain();
+
+        expect(explain.plan).toBe('indexOrderScan');
+        expect(explain.index).toBe('rank');
+        expect(explain.sortedByIndex).toBe(true);
+    });
+
+    test('keeps answering like a scan after writes', async () => {
+        await indexed.set('products.p1.price', 1000);
+        await scanned.set('products.p1.price', 1000);
+        await indexed.delete('products.p2');
+        await scanned.delete('products.p2');
+
+        const filter = { price: { $gte: 40 } };
+        expect(ids(await indexed.query('products', filter).exec())).toEqual(ids(await scanned.query('products', filter).exec()));
+    });
+});
+
+describe('explain', () => {
+    let db;
+
+    beforeAll(async () => {
+        db = new JSONDatabase(getTempDbPath(), {
+            silent: true,
+            parallelThreshold: 100,
+            indices: [
+                { name: 'price', path: 'products', field: 'price' },
+                { name: 'cheap', path: 'products', field: 'category', filter: { price: { $lt: 10 } } },
+            ],
+        });
+        await db.set('products', makeProducts(300));
+        await db.set('notes', {
+            n1: { id: 'n1', body: 'Fast indexes for range queries', at: { lat: 10, lng: 10 } },
+            n2: { id: 'n2', body: 'Slow scans', at: { lat: 50, lng: 50 } },
+        });
+        await db.set('small', { s1: { id: 's1', price: 1 }, s2: { id: 's2', price: 2 } });
+        await db.createTextIndex({ name: 'body', path: 'notes', fields: ['body'] });
+        await db.createGeoIndex({ name: 'at', path: 'notes', field: 'at' });
+    });
+
+    afterAll(async () => {
+        await db.close();
+    });
+
+    test('reports a collection scan over every document when no index applies', async () => {
+        const filter = { category: 'books' };
+        const results = await db.query('products', filter).exec();
+        const explain = await db.query('products', filter).explain();
+
+        expect(explain).toMatchObject({ plan: 'collectionScan', candidatesExamined: 300, returned: results.length, sortedByIndex: false });
+        expect(explain.index).toBeUndefined();
+        expect(explain.parallel).toBe(true);
+    });
+
+    test('does not run small collections in parallel', async () => {
+        const explain = await db.query('small', { id: 's1' }).explain();
+
+        expect(explain).toMatchObject({ plan: 'collectionScan', candidatesExamined: 2, returned: 1, parallel: false });
+    });
+
+    test('reports the index and the candidates it examined', async () => {
+        const filter = { price: { $gte: 45 } };
+        const results = await db.query('products', filter).exec();
+        const explain = await db.query('products', filter).explain();
+
+        expect(explain).toMatchObject({ plan: 'indexScan', index: 'price', returned: results.length });
+        expect(explain.candidatesExamined).toBeGreaterThanOrEqual(results.length);
+        expect(explain.candidatesExamined).toBeLessThan(300);
+    });
+
+    test('uses a partial index only for queries that repeat its filter', async () => {
+        const filter = { category: 'books', price: { $lt: 10 } };
+        const within = await db.query('products', filter).explain();
+        const outside = await db.query('products', { category: 'books' }).explain();
+        const narrower = await db.query('products', { category: 'books', price: { $lt: 5 } }).explain();
+
+        expect(within).toMatchObject({ plan: 'indexScan', index: 'cheap' });
+        expect(within.returned).toBe((await db.query('products', filter).exec()).length);
+        expect(outside.plan).toBe('collectionScan');
+        expect(narrower.index).not.toBe('cheap');
+    });
+
+    test('counts what limit and skip leave', async () => {
+        const explain = await db.query('products', { price: { $gte: 0 } }).skip(2).limit(3).explain();
+
+        expect(explain.returned).toBe(3);
+    });
+
+    test('reports text and geo plans', async () => {
+        const text = await db.query('notes', { $text: 'range' }).explain();
+        const geo = await db.query('notes', { at: { $withinRadius: { center: { lat: 10, lng: 10 }, radius: 1000 } } }).explain();
+
+        expect(text).toMatchObject({ plan: 'textSearch', index: 'body', returned: 1 });
+        expect(geo).toMatchObject({ plan: 'geoScan', index: 'at', returned: 1 });
+    });
+
+    test('is not available for function queries', async () => {
+        await expect(db.query('products', () => true).explain()).rejects.toThrow('explain() is only available for object queries');
+    });
+});
+
+describe('Queries on a database that is still loading', () => {
+    let dbPath;
+
+    beforeAll(async () => {
+        dbPath = getTempDbPath();
+        const db = new JSONDatabase(dbPath, { silent: true });
+        await db.set('items', items);
+        await db.close();
+    });
+
+    test('wait for the data to load', async () => {
+        const db = new JSONDatabase(dbPath, { silent: true });
+        const results = await db.query('items', { tags: 'a' }).exec();
+        await db.close();
+
+        expect(ids(results)).toEqual(['a', 'b', 'c', 'f']);
+    });
+
+    test('use indexes declared in the options', async () => {
+        const db = new JSONDatabase(dbPath, { silent: true, indices: [{ name: 'tags', path: 'items', field: 'tags' }] });
+        const explain = await db.query('items', { tags: 'a' }).explain();
+        await db.close();
+
+        expect(explain).toMatchObject({ plan: 'indexScan', returned: 4 });
+    });
+
+    test('page and iterate the loaded data', async () => {
+        const db = new JSONDatabase(dbPath, { silent: true });
+        const page = await db.query('items', {}).sort({ id: 1 }).limit(2).page();
+        await db.close();
+
+        const reopened = new JSONDatabase(dbPath, { silent: true });
+        const iterated = [];
+        for await (const doc of reopened.query('items', {})) iterated.push(doc.id);
+        await reopened.close();
+
+        expect(page.items.map((doc) => doc.id)).toEqual(['a', 'b']);
+        expect(iterated.sort()).toEqual(Object.keys(items));
+    });
+});
+
+describe('Async methods', () => {
+    let dbPath;
+    let db;
+
+    beforeEach(async () => {
+        dbPath = getTempDbPath();
+        db = new JSONDatabase(dbPath, { silent: true });
+        await db.set('items', items);
+    });
+
+    afterEach(async () => {
+        await db.close();
+    });
+
+    test('findAsync returns what find returns, with its options', async () => {
+        const options = { sort: { id: -1 }, skip: 1, limit: 2, select: ['id'] };
+        const queries = [{ tags: 'a' }, { score: { $gt: 0 } }, {}];
+
+        const results = await Promise.all(queries.map((query) => db.core.findAsync('items', query, options)));
+
+        expect(results).toEqual(queries.map((query) => db.core.find('items', query, options)));
+        expect(results[0]).toEqual([{ id: 'c' }, { id: 'b' }]);
+    });
+
+    test('findAsync rejects a query the sync find rejects', async () => {
+        const query = { $text: 'a' };
+        expect(() => db.core.find('items', query)).toThrow("No text index on 'items' for $text");
+        await expect(db.core.findAsync('items', query)).rejects.toThrow("No text index on 'items' for $text");
+    });
+
+    test('saveAsync writes the data to the file', async () => {
+        db.core.set('saved', 1);
+        await db.core.saveAsync();
+
+        expect(JSON.parse(await fs.readFile(dbPath, 'utf8')).saved).toBe(1);
+    });
+
+    test.each([
+        ['without a WAL drops unsaved writes', { wal: false }, 1],
+        ['with a WAL replays the writes it logged', {}, 2],
+    ])('loadAsync %s', async (_, options, expected) => {
+        await db.close();
+        db = new JSONDatabase(dbPath, { silent: true, ...options });
+        await db.set('value', 1);
+        db.core.set('value', 2);
+
+        await db.core.loadAsync();
+        expect(db.core.get('value')).toBe(expected);
+    });
+});
+
+describe('aggregate', () => {
+    const products = {
+        p1: { category: 'books', price: 10, stock: 3 },
+        p2: { category: 'books', price: 30 },
+        p3: { category: 'toys', price: 5, stock: 'many' },
+        p4: { price: 7, stock: 1 },
+        p5: { category: 'toys', price: 50, stock: 0 },
+    };
+    let db;
+
+    beforeAll(async () => {
+        db = new JSONDatabase(getTempDbPath(), {
+            silent: true,
+            indices: [{ name: 'price', path: 'products', field: 'price' }],
+        });
+        await db.set('products', products);
+    });
+
+    afterAll(async () => {
+        await db.close();
+    });
+
+    test('computes each accumulator per group, in group order', async () => {
+        const groups = await db.aggregate('products', {}, {
+            groupBy: 'category',
+            accumulators: {
+                count: { $count: true },
+                stock: { $sum: 'stock' },
+                avgPrice: { $avg: 'price' },
+                cheapest: { $min: 'price' },
+                dearest: { $max: 'price' },
+            },
+        });
+
+        expect(groups).toEqual([
+            { _id: null, count: 1, stock: 1, avgPrice: 7, cheapest: 7, dearest: 7 },
+            { _id: 'books', count: 2, stock: 3, avgPrice: 20, cheapest: 10, dearest: 30 },
+            { _id: 'toys', count: 2, stock: 0, avgPrice: 27.5, cheapest: 5, dearest: 50 },
+        ]);
+    });
+
+    test('groups only the documents matching the query, through an index', async () => {
+        const spec = { groupBy: 'category', accumulators: { count: { $count: true } } };
+
+        expect(await db.aggregate('products', { price: { $lt: 20 } }, spec)).toEqual([
+            { _id: null, count: 1 },
+            { _id: 'books', count: 1 },
+            { _id: 'toys', count: 1 },
+        ]);
+        expect(db.core.aggregate('products', { price: { $lt: 20 } }, spec))
+            .toEqual(await db.core.aggregateAsync('products', { price: { $lt: 20 } }, spec));
+    });
+
+    test('without groupBy returns one group, even when nothing matches', async () => {
+        const spec = { accumulators: { count: { $count: true }, avgPrice: { $avg: 'price' }, dearest: { $max: 'price' } } };
+
+        expect(await db.aggregate('products', {}, spec)).toEqual([{ _id: null, count: 5, avgPrice: 20.4, dearest: 50 }]);
+        expect(await db.aggregate('products', { price: { $gt: 100 } }, spec))
+            .toEqual([{ _id: null, count: 0, avgPrice: null, dearest: null }]);
+    });
+
+    test.each([
+        [{ total: { $product: 'price' } }, "Accumulator 'total' must be one of $count, $sum, $avg, $min or $max"],
+        [{ total: { $sum: 1 } }, "$sum of accumulator 'total' must name a field"],
+        [[], 'Aggregate accumulators must be an object'],
+    ])('rejects the accumulators %j', async (accumulators, message) => {
+        await expect(db.aggregate('products', {}, { accumulators })).rejects.toThrow(message);
+    });
+});
+
+describe('Cursors', () => {
+    let db;
+
+    beforeEach(async () => {
+        db = new JSONDatabase(getTempDbPath(), { silent: true });
+        await db.set('items', items);
+    });
+
+    afterEach(async () => {
+        await db.close();
+    });
+
+    test('nextBatch hands out the results in batches until none are left', () => {
+        const cursor = db.core.findCursor('items', { tags: 'a' }, { sort: { id: 1 } });
+
+        expect(cursor.remaining).toBe(4);
+        expect(cursor.nextBatch(3).map((doc) => doc.id)).toEqual(['a', 'b', 'c']);
+        expect(cursor.remaining).toBe(1);
+        expect(cursor.nextBatch(3).map((doc) => doc.id)).toEqual(['f']);
+        expect(cursor.nextBatch(3)).toEqual([]);
+        expect(cursor.remaining).toBe(0);
+    });
+
+    test('nextBatch rejects a batch size of 0', () => {
+        const cursor = db.core.findCursor('items', {});
+
+        expect(() => cursor.nextBatch(0)).toThrow('Batch size must be at least 1');
+        expect(cursor.remaining).toBe(Object.keys(items).length);
+    });
+
+    test('close releases the results left', () => {
+        const cursor = db.core.findCursor('items', {});
+        cursor.nextBatch(1);
+        cursor.close();
+
+        expect(cursor.remaining).toBe(0);
+        expect(cursor.nextBatch(10)).toEqual([]);
+    });
+
+    test('for await iterates every result in order, a batch at a time', async () => {
+        const seen = [];
+        for await (const doc of db.query('items', {}).sort({ id: -1 }).select(['id']).batchSize(2)) seen.push(doc);
+
+        expect(seen).toEqual(Object.keys(items).sort().reverse().map((id) => ({ id })));
+    });
+
+    test('a cursor returns the data as of its query, whatever is written while it is open', async () => {
+        const cursor = db.core.findCursor('items', { tags: 'a' }, { sort: { id: 1 }, select: ['id', 'score'] });
+        expect(cursor.nextBatch(1)).toEqual([{ id: 'a', score: 3 }]);
+
+        await db.set('items.b.score', 100);
+        await db.delete('items.c');
+        await db.set('items.h', { id: 'h', tags: ['a'] });
+
+        expect(cursor.nextBatch(10)).toEqual([{ id: 'b', score: 1 }, { id: 'c', score: 'high' }, { id: 'f' }]);
+        expect(ids(db.core.findCursor('items', { tags: 'a' }).nextBatch(10))).toEqual(['a', 'b', 'f', 'h']);
+        expect(await db.get('items.b.score')).toBe(100);
+    });
+
+    test('cursors opened between writes each keep their own data', async () => {
+        const before = db.core.findCursor('items', {}, { sort: { id: 1 }, select: ['id'] });
+        await db.delete('items.a');
+        const between = db.core.findCursor('items', {}, { sort: { id: 1 }, select: ['id'] });
+        await db.delete('items.b');
+
+        expect(before.nextBatch(2)).toEqual([{ id: 'a' }, { id: 'b' }]);
+        expect(between.nextBatch(2)).toEqual([{ id: 'b' }, { id: 'c' }]);
+    });
+});
+
+describe('Text search', () => {
+    let db;
+
+    const articles = {
+        a: { id: 'a', title: 'Rust databases', body: 'An embedded database written in Rust.', year: 2020 },
+        b: { id: 'b', title: 'Gardening', body: 'Planting tomatoes and running a compost heap.', year: 2021 },
+        c: { id: 'c', title: 'Databases', body: 'Database indexes, database files and database backups.', year: 2022 },
+        d: { id: 'd', title: 'Running', body: 'She runs every morning; the runner ran a marathon.', year: 2023 },
+    };
+
+    beforeEach(async () => {
+        db = new JSONDatabase(getTempDbPath(), { silent: true });
+        await db.set('articles', articles);
+        await db.createTextIndex({ name: 'content', path: 'articles', fields: ['title', 'body'] });
+    });
+
+    afterEach(async () => {
+        await db.close();
+    });
+
+    test('ranks the documents that use a term most, relative to their length, first', async () => {
+        const hits = await db.search('articles', 'database');
+
+        expect(hits.map((hit) => hit.document.id)).toEqual(['c', 'a']);
+        expect(hits[0].score).toBeGreaterThan(hits[1].score);
+        expect(hits[1].score).toBeGreaterThan(0);
+    });
+
+    test('a term counts for less once more documents use it', async () => {
+        const hits = await db.search('articles', 'rust tomatoes');
+        const scores = Object.fromEntries(hits.map((hit) => [hit.document.id, hit.score]));
+        await db.set('articles.e', { id: 'e', title: 'Rust', body: 'More rust.' });
+        await db.createTextIndex({ name: 'content', path: 'articles', fields: ['title', 'body'] });
+        const after = Object.fromEntries((await db.search('articles', 'rust tomatoes')).map((hit) => [hit.document.id, hit.score]));
+
+        expect(Object.keys(scores).sort()).toEqual(['a', 'b']);
+        expect(after.a).toBeLessThan(scores.a);
+        expect(after.b).toBeGreaterThan(scores.b);
+    });
+
+    test('matches words by their stem, whatever their case', async () => {
+        expect((await db.search('articles', 'RUNNING')).map((hit) => hit.document.id).sort()).toEqual(['b', 'd']);
+        expect((await db.search('articles', 'databases')).map((hit) => hit.document.id).sort()).toEqual(['a', 'c']);
+    });
+
+    test('does not match stop words, or words not stemmed without a language', async () => {
+        expect(await db.search('articles', 'the and a')).toEqual([]);
+
+        await db.createTextIndex({ name: 'content', path: 'articles', fields: ['body'], language: 'none', stopWords: [] });
+        expect((await db.search('articles', 'runs')).map((hit) => hit.document.id)).toEqual(['d']);
+        expect((await db.search('articles', 'the')).map((hit) => hit.document.id)).toEqual(['d']);
+    });
+
+    test('search applies its filter, skip, limit and select after ranking', async () => {
+        const hits = await db.search('articles', 'database running', {
+            filter: { year: { $gte: 2021 } },
+            skip: 1,
+            limit: 1,
+            select: ['id'],
+        });
+        const ranked = (await db.search('articles', 'database running', { filter: { year: { $gte: 2021 } } }))
+            .map((hit) => hit.document.id);
+
+        expect(ranked).toHaveLength(3);
+        expect(hits.map((hit) => hit.document)).toEqual([{ id: ranked[1] }]);
+        expect(typeof hits[0].score).toBe('number');
+    });
+
+    test('$text returns the hits in relevance order, filtered by the rest of the query', async () => {
+        const ranked = (await db.search('articles', 'database')).map((hit) => hit.document.id);
+
+        expect(ids(await db.query('articles', { $text: 'database' }).exec())).toEqual(ranked.slice().sort());
+        expect((await db.query('articles', { $text: 'database' }).exec()).map((doc) => doc.id)).toEqual(ranked);
+        expect((await db.query('articles', { $text: { $search: 'database' }, year: { $lt: 2021 } }).exec()).map((doc) => doc.id)).toEqual(['a']);
+        expect((await db.query('articles', { $text: 'database' }).sort({ year: -1 }).exec()).map((doc) => doc.id)).toEqual(['c', 'a']);
+        expect((await db.query('articles', { $text: 'database' }).sort({ year: 1 }).exec()).map((doc) => doc.id)).toEqual(['a', 'c']);
+    });
+
+    test('$text picks the index named by $index', async () => {
+        await db.createTextIndex({ name: 'titles', path: 'articles', fields: ['title'] });
+
+        expect((await db.query('articles', { $text: { $search: 'marathon', $index: 'content' } }).exec()).map((doc) => doc.id)).toEqual(['d']);
+        expect(await db.query('articles', { $text: { $search: 'marathon', $index: 'titles' } }).exec()).toEqual([]);
+    });
+
+    test('the index follows writes to the collection', async () => {
+        await db.set('articles.e', { id: 'e', title: 'Database tuning', body: 'Tuning a database.' });
+        await db.delete('articles.c');
+        await db.set('articles.a.body', 'An embedded store.');
+
+        expect(ids(await db.query('articles', { $text: 'database' }).exec())).toEqual(['a', 'e']);
+        expect(await db.search('articles', 'embedded store')).toHaveLength(1);
+    });
+
+    test('rejects a bad index definition or $text', async () => {
+        await expect(db.createTextIndex({ name: 'bad', path: 'articles', fields: ['body'], tokenizer: 'ngram' })).rejects.toThrow("Unknown tokenizer 'ngram'");
+        await expect(db.createTextIndex({ name: 'bad', path: 'articles', fields: ['body'], language: 'klingon' })).rejects.toThrow("Unsupported stemming language 'klingon'");
+        await expect(db.createTextIndex({ name: 'bad', path: 'articles', fields: [] })).rejects.toThrow('Text index needs a name and at least one field');
+        await expect(db.search('other', 'database')).rejects.toThrow("No text index on 'other'");
+        await expect(db.query('articles', { $text: 5 }).exec()).rejects.toThrow('$text must be a string or { $search }');
+    });
+});
+
+describe('Geo queries', () => {
+    let indexed;
+    let scanned;
+
+    const PARIS = { lat: 48.8566, lng: 2.3522 };
+    const places = {
+        paris: { id: 'paris', at: PARIS },
+        brussels: { id: 'brussels', at: { lat: 50.8503, lng: 4.3517 } },
+        london: { id: 'london', at: { lat: 51.5074, lng: -0.1278 } },
+        berlin: { id: 'berlin', at: { lat: 52.52, lng: 13.405 } },
+        nyc: { id: 'nyc', at: { lat: 40.7128, lng: -74.006 } },
+        // A chain of islands on both sides of the antimeridian.
+        islands: { id: 'islands', at: [{ lat: -16.8, lng: 179.9 }, { lat: -16.9, lng: -179.9 }] },
+        suva: { id: 'suva', at: { lat: -18.1248, lng: 178.4501 } },
+        nowhere: { id: 'nowhere' },
+        bad: { id: 'bad', at: { lat: 95, lng: 0 } },
+    };
+
+    const order = async (db, query, sort) => {
+        let cursor = db.query('places', query);
+        if (sort) cursor = cursor.sort(sort);
+        return (await cursor.exec()).map((doc) => doc.id);
+    };
+
+    beforeAll(async () => {
+        indexed = new JSONDatabase(getTempDbPath(), { silent: true });
+        scanned = new JSONDatabase(getTempDbPath(), { silent: true });
+        await indexed.set('places', places);
+        await scanned.set('places', places);
+        await indexed.createGeoIndex({ name: 'at', path: 'places', field: 'at', cellSize: 1 });
+    });
+
+    afterAll(async () => {
+        await indexed.close();
+        await scanned.close();
+    });
+
+    test('$near orders the results by distance, within its bounds', async () => {
+        for (const db of [indexed, scanned]) {
+            expect(await order(db, { at: { $near: { point: PARIS, maxDistance: 1_000_000 } } })).toEqual(['paris', 'brussels', 'london', 'berlin']);
+            expect(await order(db, { at: { $near: { point: PARIS, minDistance: 300_000, maxDistance: 1_000_000 } } })).toEqual(['london', 'berlin']);
+            expect(await order(db, { at: { $near: PARIS } })).toEqual(['paris', 'brussels', 'london', 'berlin', 'nyc', 'islands', 'suva']);
+        }
+    });
+
+    test('an explicit sort takes precedence over distance', async () => {
+        expect(await order(indexed, { at: { $near: { point: PARIS, maxDistance: 1_000_000 } } }, { id: 1 })).toEqual(['berlin', 'brussels', 'london', 'paris']);
+    });
+
+    test('$near orders a document with several points by the closest', async () => {
+        expect(await order(indexed, { at: { $near: { lat: -16.85, lng: -179.95 } } }).then((ids) => ids.slice(0, 2))).toEqual(['islands', 'suva']);
+    });
+
+    test('$withinRadius and $withinBox match documents with any point inside', async () => {
+        for (const db of [indexed, scanned]) {
+            expect(ids(await db.query('places', { at: { $withinRadius: { center: PARIS, radius: 300_000 } } }).exec())).toEqual(['brussels', 'paris']);
+            expect(ids(await db.query('places', { at: { $withinBox: { min: { lat: 48, lng: -1 }, max: { lat: 52, lng: 5 } } } }).exec())).toEqual(['brussels', 'london', 'paris']);
+            // min.lng > max.lng crosses the antimeridian.
+            expect(ids(await db.query('places', { at: { $withinBox: { min: { lat: -17, lng: 179 }, max: { lat: -16, lng: -179 } } } }).exec())).toEqual(['islands']);
+            expect(ids(await db.query('places', { at: { $withinRadius: { center: { lat: -16.9, lng: 179.5 }, radius: 100_000 } } }).exec())).toEqual(['islands']);
+        }
+    });
+
+    test('the geo index finds the same documents as a scan', async () => {
+        const queries = [
+            { at: { $near: { point: PARIS, maxDistance: 500_000 } } },
+            { at: { $withinRadius: { center: { lat: 50, lng: 5 }, radius: 800_000 } }, id: { $ne: 'berlin' } },
+            { at: { $withinBox: { min: { lat: -90, lng: -180 }, max: { lat: 90, lng: 180 } } } },
+            { at: { $withinRadius: { center: { lat: 89, lng: 0 }, radius: 5_000_000 } } },
+        ];
+        for (const query of queries) {
+            const explain = await indexed.query('places', query).explain();
+            expect(explain).toMatchObject({ plan: 'geoScan', index: 'at' });
+            expect(explain.candidatesExamined).toBeLessThanOrEqual(Object.keys(places).length);
+            expect(await order(indexed, query, { id: 1 })).toEqual(await order(scanned, query, { id: 1 }));
+        }
+        expect((await scanned.query('places', queries[0]).explain()).plan).toBe('collectionScan');
+    });
+
+    test('the geo index only examines the cells a shape overlaps', async () => {
+        const explain = await indexed.query('places', { at: { $withinRadius: { center: PARIS, radius: 10_000 } } }).explain();
+
+        expect(explain).toMatchObject({ plan: 'geoScan', candidatesExamined: 1, returned: 1 });
+    });
+
+    test('the geo index follows writes to the collection', async () => {
+        await indexed.set('places.lyon', { id: 'lyon', at: { lat: 45.764, lng: 4.8357 } });
+        await indexed.set('places.paris.at', { lat: 0, lng: 0 });
+        const near = { at: { $withinRadius: { center: { lat: 46, lng: 4 }, radius: 500_000 } } };
+
+        expect(ids(await indexed.query('places', near).exec())).toEqual(['lyon']);
+        await indexed.delete('places.lyon');
+        await indexed.set('places.paris.at', PARIS);
+        expect(ids(await indexed.query('places', near).exec())).toEqual(['paris']);
+    });
+
+    test('an invalid condition matches nothing, and a bad cell size is rejected', async () => {
+        for (const db of [indexed, scanned]) {
+            expect(await db.query('places', { at: { $withinRadius: { center: PARIS } } }).exec()).toEqual([]);
+            expect(await db.query('places', { at: { $withinBox: { min: { lat: 10, lng: 0 }, max: { lat: 0, lng: 1 } } } }).exec()).toEqual([]);
+            expect(await db.query('places', { at: { $near: { point: { lat: 91, lng: 0 } } } }).exec()).toEqual([]);
+        }
+        await expect(indexed.createGeoIndex({ name: 'bad', path: 'places', field: 'at', cellSize: 0 })).rejects.toThrow('Invalid geo index cell size 0');
+    });
+});
+
+describe('Parallel queries', () => {
+    let parallel;
+    let sequential;
+
+    // Deterministic documents with many ties and mixed types in the sort fields.
+    const docs = Array.from({ length: 3000 }, (_, i) => ({
+        id: `d${String(i).padStart(4, '0')}`,
+        group: i % 7,
+        score: i % 11 === 0 ? null : i % 13 === 0 ? `s${i % 5}` : (i * 37) % 101,
+        tags: [`t${i % 3}`, `t${i % 5}`],
+        nested: { rank: (i * 7919) % 500 },
+    }));
+    const byId = Object.fromEntries(docs.map((doc) => [doc.id, doc]));
+
+    const queries = [
+        [{}, { sort: { score: 1 } }],
+        [{ group: { $in: [1, 3] } }, { sort: { score: -1, id: 1 } }],
+        [{ tags: 't2', 'nested.rank': { $lt: 250 } }, { sort: { 'nested.rank': 1 }, skip: 10, limit: 50 }],
+        [{ score: { $gte: 50 } }, { sort: { group: 1 } }],
+        [{ score: { $exists: true }, group: { $nin: [0, 1] } }, {}],
+        [{ group: { $ne: 4 } }, { sort: { score: -1 }, limit: 5 }],
+    ];
+
+    beforeAll(async () => {
+        parallel = new JSONDatabase(getTempDbPath(), { silent: true, parallelThreshold: 1, queryThreads: 3 });
+        sequential = new JSONDatabase(getTempDbPath(), { silent: true, parallelThreshold: 1_000_000 });
+        for (const db of [parallel, sequential]) {
+            await db.set('list', docs);
+            await db.set('map', byId);
+        }
+    });
+
+    afterAll(async () => {
+        await parallel.close();
+        await sequential.close();
+    });
+
+    test.each(['list', 'map'])('filters and sorts %s collections exactly as the sequential path does', async (collection) => {
+        for (const [query, options] of queries) {
+            const run = (db) => db.core.find(collection, query, options);
+            const result = run(parallel);
+
+            expect(result).toEqual(run(sequential));
+            expect(result.length).toBeGreaterThan(0);
+        }
+    });
+
+    test('ties keep collection order when sorted in parallel', async () => {
+        const result = parallel.core.find('list', {}, { sort: { group: 1 } });
+        for (let i = 1; i < result.length; i++) {
+            if (result[i].group === result[i - 1].group) expect(result[i].id > result[i - 1].id).toBe(true);
+        }
+    });
+
+    test('explain reports which path ran', async () => {
+        const [query, options] = queries[1];
+        const explainOf = (db) => db.query('map', query).sort(options.sort).explain();
+
+        expect(await explainOf(parallel)).toMatchObject({ plan: 'collectionScan', parallel: true, candidatesExamined: 3000 });
+        expect(await explainOf(sequential)).toMatchObject({ plan: 'collectionScan', parallel: false, candidatesExamined: 3000 });
+    });
+
+    test('aggregate sees the same matches on both paths', async () => {
+        const spec = { groupBy: 'group', accumulators: { n: { $count: true }, top: { $max: 'nested.rank' } } };
+
+        expect(await parallel.aggregate('map', { tags: 't1' }, spec)).toEqual(await sequential.aggregate('map', { tags: 't1' }, spec));
+    });
+});
+
+describe('Page tokens', () => {
+    let db;
+
+    const people = Object.fromEntries(Array.from({ length: 25 }, (_, i) => {
+        const id = `p${String(i).padStart(2, '0')}`;
+        return [id, { id, age: 20 + (i % 4), name: `n${(i * 7) % 25}` }];
+    }));
+
+    const pageAll = async (makeCursor) => {
+        const seen = [];
+        let token;
+        for (;;) {
+            let cursor = makeCursor();
+            if (token) cursor = cursor.after(token);
+            const { items, nextToken } = await cursor.page();
+            seen.push(...items.map((doc) => doc.id));
+            if (!nextToken) return seen;
+            expect(items.length).toBeGreaterThan(0);
+            token = nextToken;
+        }
+    };
+
+    beforeEach(async () => {
+        db = new JSONDatabase(getTempDbPath(), { silent: true });
+        await db.set('people', people);
+    });
+
+    afterEach(async () => {
+        await db.close();
+    });
+
+    test('pages through a sort with ties, one limit at a time, to the same order as find', async () => {
+        for (const sort of [{ age: 1 }, { age: -1, name: 1 }, { name: -1 }]) {
+            const all = (await db.query('people', {}).sort(sort).exec()).map((doc) => doc.id);
+            expect(await pageAll(() => db.query('people', {}).sort(sort).limit(4))).toEqual(all);
+        }
+    });
+
+    test('pages through collection order and through arrays', async () => {
+        await db.set('list', Object.values(people));
+
+        expect(await pageAll(() => db.query('people', { age: { $gte: 21 } }).limit(5))).toEqual(
+            Object.values(people).filter((p) => p.age >= 21).map((p) => p.id)
+        );
+        expect(await pageAll(() => db.query('list', {}).sort({ age: 1 }).limit(7))).toEqual(
+            (await db.query('list', {}).sort({ age: 1 }).exec()).map((doc) => doc.id)
+        );
+    });
+
+    test('the last page has no next token', async () => {
+        expect(await db.query('people', {}).limit(25).page()).toEqual({ items: Object.values(people) });
+        expect((await db.query('people', {}).limit(24).page()).nextToken).toBeDefined();
+        expect(await db.query('people', { age: 99 }).limit(5).page()).toEqual({ items: [] });
+    });
+
+    test('pages neither repeat nor skip documents written in between', async () => {
+        const sort = { age: 1 };
+        const first = await db.query('people', {}).sort(sort).limit(10).page();
+        const firstIds = first.items.map((doc) => doc.id);
+
+        // Remove a document already returned and add one before the position:
+        // skip-based paging would now repeat one document.
+        await db.delete(`people.${firstIds[0]}`);
+        await db.set('people.a0', { id: 'a0', age: 19, name: 'new' });
+        await db.set('people.z9', { id: 'z9', age: 23, name: 'late' });
+        const rest = await pageAll(() => db.query('people', {}).sort(sort).limit(10).after(first.nextToken));
+
+        expect(rest.filter((id) => firstIds.includes(id))).toEqual([]);
+        expect([...firstIds, ...rest].sort()).toEqual([...Object.keys(people), 'z9'].sort());
+    });
+
+    test('a token still resumes after its document has been deleted', async () => {
+        const first = await db.query('people', {}).sort({ name: 1 }).limit(3).page();
+        await db.delete(`people.${first.items[2].id}`);
+        const next = await db.query('people', {}).sort({ name: 1 }).limit(3).after(first.nextToken).page();
+
+        expect(next.items.map((doc) => doc.name)).toEqual(['n11', 'n12', 'n13']);
+    });
+
+    test('pages through an index in key order', async () => {
+        db.core.createIndex({ name: 'age', path: 'people', field: 'age' });
+        const cursor = () => db.query('people', {}).sort({ age: -1 }).limit(6);
+
+        expect((await cursor().explain()).plan).toBe('indexOrderScan');
+        expect(await pageAll(cursor)).toEqual((await db.query('people', {}).sort({ age: -1 }).exec()).map((doc) => doc.id));
+    });
+
+    test('rejects malformed tokens and tokens of another sort', async () => {
+        const { nextToken } = await db.query('people', {}).sort({ age: 1 }).limit(5).page();
+
+        await expect(db.query('people', {}).after('not a token').limit(5).page()).rejects.toThrow('Invalid page token');
+        await expect(db.query('people', {}).after(nextToken.slice(0, -4)).limit(5).page()).rejects.toThrow('Invalid page token');
+        await expect(db.query('people', {}).sort({ age: -1 }).after(nextToken).limit(5).page()).rejects.toThrow('Page token was issued for a different sort');
+        await expect(db.query('people', {}).after(nextToken).limit(5).page()).rejects.toThrow('Page token was issued for a different sort');
+        await expect(db.query('people', (p) => p.age > 20).page()).rejects.toThrow('page() is only available for object queries');
+    });
+
+    test('$text queries cannot be paged, and $near ones only with an explicit sort', async () => {
+        await db.createTextIndex({ name: 'names', path: 'people', fields: ['name'] });
+        await db.set('people.p00.at', { lat: 0, lng: 0 });
+        await db.set('people.p01.at', { lat: 0, lng: 1 });
+        const near = { at: { $near: { lat: 0, lng: 0 } } };
+
+        await expect(db.query('people', { $text: 'n1' }).limit(1).page()).rejects.toThrow('Page tokens are not supported for $text queries');
+        await expect(db.query('people', near).limit(1).page()).rejects.toThrow('Page tokens on $near queries need an explicit sort');
+        expect(await pageAll(() => db.query('people', near).sort({ id: -1 }).limit(1))).toEqual(['p01', 'p00']);
+    });
+});
+
+describe('Index snapshots', () => {
+    const indices = [{ name: 'age', path: 'users', field: 'age' }];
+    const users = { a: { id: 'a', age: 1 }, b: { id: 'b', age: 2 }, c: { id: 'c', age: 1 } };
+
+    const idxPath = (dbPath) => dbPath.replace(/\.json$/, '.idx');
+
+    const saveWithSnapshot = async () => {
+        const dbPath = getTempDbPath();
+        const db = new JSONDatabase(dbPath, { silent: true, persistIndexes: true, indices });
+        await db.set('users', users);
+        await db.close();
+        return dbPath;
+    };
+
+    // Drops 'c' from the entries of age 1 in the snapshot, so an index that
+    // was reused rather than rebuilt gives itself away.
+    const tamper = async (dbPath, change = () => {}) => {
+        const snapshot = JSON.parse(await fs.readFile(idxPath(dbPath), 'utf8'));
+        for (const entry of snapshot.indexes[0].entries) entry[1] = entry[1].filter((id) => id !== 'c');
+        change(snapshot);
+        await fs.writeFile(idxPath(dbPath), JSON.stringify(snapshot));
+    };
+
+    const ageOne = async (dbPath, options = {}) => {
+        const db = new JSONDatabase(dbPath, { silent: true, persistIndexes: true, indices, ...options });
+        try {
+            expect((await db.query('users', { age: 1 }).explain()).plan).toBe('indexScan');
+            return ids(await db.query('users', { age: 1 }).exec());
+        } finally {
+            await db.close();
+        }
+    };
+
+    test('saves the indexes with the checksum and LSN of the data they match', async () => {
+        const dbPath = await saveWithSnapshot();
+        const snapshot = JSON.parse(await fs.readFile(idxPath(dbPath), 'utf8'));
+
+        expect(snapshot).toMatchObject({ version: 1, lsn: 1 });
+        expect(typeof snapshot.checksum).toBe('number');
+        expect(snapshot.indexes.map((index) => index.name)).toEqual(['age']);
+    });
+
+    test('reuses the snapshot when it matches the data', async () => {
+        const dbPath = await saveWithSnapshot();
+        await tamper(dbPath);
+
+        expect(await ageOne(dbPath)).toEqual(['a']);
+        expect(await ageOne(dbPath, { indices: [] })).toEqual(['a']);
+    });
+
+    test('rebuilds when the data file no longer matches the checksum', async () => {
+        const dbPath = await saveWithSnapshot();
+        await tamper(dbPath);
+        const data = JSON.parse(await fs.readFile(dbPath, 'utf8'));
+        data.users.d = { id: 'd', age: 1 };
+        await fs.writeFile(dbPath, JSON.stringify(data));
+
+        expect(await ageOne(dbPath)).toEqual(['a', 'c', 'd']);
+    });
+
+    test('rebuilds when the WAL is at another LSN, or the snapshot has another version', async () => {
+        for (const change of [(s) => { s.lsn += 5; }, (s) => { s.version += 1; }, (s) => { s.checksum += 1; }]) {
+            const dbPath = await saveWithSnapshot();
+            await tamper(dbPath, change);
+
+            expect(await ageOne(dbPath)).toEqual(['a', 'c']);
+        }
+    });
+
+    test('rebuilds when the snapshot is unreadable or the index was redefined', async () => {
+        const dbPath = await saveWithSnapshot();
+        await fs.writeFile(idxPath(dbPath), '{"version":');
+        expect(await ageOne(dbPath)).toEqual(['a', 'c']);
+
+        const redefined = await saveWithSnapshot();
+        await tamper(redefined);
+        expect(await ageOne(redefined, { indices: [{ ...indices[0], sparse: true }] })).toEqual(['a', 'c']);
+    });
+
+    test('is only written with persistIndexes, and removed once there are no indexes', async () => {
+        const plain = getTempDbPath();
+        const db = new JSONDatabase(plain, { silent: true, indices });
+        await db.set('users', users);
+        await db.close();
+        await expect(fs.access(idxPath(plain))).rejects.toThrow();
+
+        const dbPath = await saveWithSnapshot();
+        const reopened = new JSONDatabase(dbPath, { silent: true, persistIndexes: true });
+        await reopened.get('users');
+        reopened.core.dropIndex('age');
+        await reopened.set('users.d', { id: 'd', age: 4 });
+        await reopened.close();
+        await expect(fs.access(idxPath(dbPath))).rejects.toThrow();
+    });
+});
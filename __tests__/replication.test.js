@@ -0,0 +1,307 @@
+const path = require('path');
+const fs = require('fs').promises;
+const { spawn } = require('child_process');
+const crypto = require('crypto');
+const net = require('net');
+
+const JSONDatabaseModule = require('../JSONDatabase');
+const JSONDatabase = JSONDatabaseModule.default || JSONDatabaseModule;
+
+const TEST_DATA_DIR = path.join(__dirname, 'test-data-replication');
+
+const getTempDbPath = () => path.join(TEST_DATA_DIR, `replication-db-${Date.now()}-${Math.random()}.json`);
+
+const SECRET = crypto.randomBytes(32).toString('hex');
+
+// Resolves once `check` returns true, polling the way a client would.
+const waitFor = async (check, timeoutMs = 5000) => {
+    const deadline = Date.now() + timeoutMs;
+    while (!(await check())) {
+        if (Date.now() > deadline) throw new Error('Timed out waiting for replication');
+        await new Promise(resolve => setTimeout(resolve, 20));
+    }
+};
+
+// A replica in its own process, driven over IPC: each message names a method
+// of the database and is answered with its result or the error's name.
+const REPLICA_PROCESS = `
+const JSONDatabase = require(${JSON.stringify(path.join(__dirname, '..', 'JSONDatabase'))});
+let db;
+process.on('message', async ({ id, method, args }) => {
+    try {
+        if (method === 'open') {
+            db = new JSONDatabase(args[0], { silent: true, saveDelay: 20 });
+            await db.replicateFrom(args[1], { secret: args[2] });
+            return process.send({ id });
+        }
+        const result = await db[method](...args);
+        process.send({ id, result });
+        if (method === 'close') process.disconnect();
+    } catch (e) {
+        process.send({ id, error: e.name });
+    }
+});
+`;
+
+const spawnReplica = () => {
+    const child = spawn(process.execPath, ['-e', REPLICA_PROCESS], { stdio: ['ignore', 'inherit', 'inherit', 'ipc'] });
+    const pending = new Map();
+    let nextId = 0;
+    child.on('message', message => {
+        pending.get(message.id)(message);
+        pending.delete(message.id);
+    });
+    const call = (method, ...args) => new Promise(resolve => {
+        const id = nextId++;
+        pending.set(id, resolve);
+        child.send({ id, method, args });
+    });
+    const exited = new Promise(resolve => child.on('exit', resolve));
+    return { child, call, exited };
+};
+
+// A replica speaking the protocol by hand, to see what goes over the wire.
+const connectRaw = (address) => {
+    const socket = address.startsWith('unix:') ? net.connect(address.slice(5)) : net.connect(...address.split(':').reverse());
+    const lines = [];
+    let waiting = null;
+    let buffered = '';
+    socket.on('data', chunk => {
+        buffered += chunk;
+        const parts = buffered.split('\n');
+        buffered = parts.pop();
+        lines.push(...parts);
+        if (waiting && lines.length) waiting();
+    });
+    const readLine = async () => {
+        while (!lines.length) await new Promise(resolve => { waiting = resolve; });
+        return lines.shift();
+    };
+    const writeLine = (message) => socket.write(`${message}\n`);
+    const closed = new Promise(resolve => socket.on('close', resolve));
+    return { socket, readLine, writeLine, closed };
+};
+
+// Sealed lines are the IV, the ciphertext and the tag, in base64.
+const seal = (secret, plaintext) => {
+    const iv = crypto.randomBytes(12);
+    const cipher = crypto.createCipheriv('aes-256-gcm', Buffer.from(secret, 'hex'), iv);
+    const ciphertext = Buffer.concat([cipher.update(plaintext), cipher.final()]);
+    return Buffer.concat([iv, ciphertext, cipher.getAuthTag()]).toString('base64');
+};
+
+const unseal = (secret, line) => {
+    const sealed = Buffer.from(line, 'base64');
+    const decipher = crypto.createDecipheriv('aes-256-gcm', Buffer.from(secret, 'hex'), sealed.subarray(0, 12));
+    decipher.setAuthTag(sealed.subarray(sealed.length - 16));
+    return JSON.parse(Buffer.concat([decipher.update(sealed.subarray(12, sealed.length - 16)), decipher.final()]));
+};
+
+beforeAll(async () => {
+    await fs.mkdir(TEST_DATA_DIR, { recursive: true });
+});
+
+afterAll(async () => {
+    await fs.rm(TEST_DATA_DIR, { recursive: true, force: true });
+});
+
+describe('Replication', () => {
+    let primary;
+
+    afterEach(async () => {
+        if (primary) await primary.close();
+        primary = null;
+    });
+
+    test('streams writes to a replica in another process', async () => {
+        primary = new JSONDatabase(getTempDbPath(), { silent: true, saveDelay: 20 });
+        await primary.set('users.u1', { name: 'Alice' });
+        const address = await primary.serveReplication('127.0.0.1:0', { secret: SECRET });
+
+        const replica = spawnReplica();
+        try {
+            expect((await replica.call('open', getTempDbPath(), address, SECRET)).error).toBeUndefined();
+            await waitFor(async () => (await replica.call('get', 'users.u1')).result != null);
+            expect((await replica.call('get', 'users.u1')).result).toEqual({ name: 'Alice' });
+
+            await primary.set('users.u2', { name: 'Bob' });
+            await waitFor(async () => (await replica.call('get', 'users.u2')).result != null);
+            expect((await replica.call('get', 'users.u2')).result).toEqual({ name: 'Bob' });
+
+            expect((await replica.call('set', 'users.u3', {})).error).toBe('ReadOnlyError');
+        } finally {
+            await replica.call('close');
+            await replica.exited;
+        }
+    });
+
+    test('synchronous writes resolve once a replica in another process acknowledges them', async () => {
+        primary = new JSONDatabase(getTempDbPath(), { silent: true, saveDelay: 20 });
+        const address = await primary.serveReplication('127.0.0.1:0', { synchronous: true, syncTimeoutMs: 5000, secret: SECRET });
+
+        const replica = spawnReplica();
+        try {
+            await replica.call('open', getTempDbPath(), address, SECRET);
+            await waitFor(async () => (await primary.replicationStatus()).replicas.length === 1);
+
+            await expect(primary.set('a', 1)).resolves.toBe(true);
+            const { lsn } = await primary.replicationStatus();
+            expect((await primary.replicationStatus()).replicas[0].ackedLsn).toBeGreaterThanOrEqual(lsn);
+        } finally {
+            await replica.call('close');
+            await replica.exited;
+        }
+    });
+
+    test('a synchronous write no replica acknowledges rejects as committed, after it is saved', async () => {
+        const dbPath = getTempDbPath();
+        primary = new JSONDatabase(dbPath, { silent: true, saveDelay: 20 });
+        await primary.serveReplication('127.0.0.1:0', { synchronous: true, syncTimeoutMs: 100, secret: SECRET });
+
+        const error = await primary.set('a', 1).catch(e => e);
+        expect(error).toBeInstanceOf(JSONDatabase.UnacknowledgedWriteError);
+        expect(error.lsn).toBe((await primary.replicationStatus()).lsn);
+
+        await primary.close();
+        primary = null;
+        const reopened = new JSONDatabase(dbPath, { silent: true });
+        expect(await reopened.get('a')).toBe(1);
+        await reopened.close();
+    });
+
+    test.each([
+        ['saved without a WAL', async (dbPath) => {
+            const db = new JSONDatabase(dbPath, { silent: true, saveDelay: 20, wal: false });
+            await db.set('users.u1', { name: 'Alice' });
+            await db.close();
+        }],
+        ['saved with its WAL lost', async (dbPath) => {
+            const db = new JSONDatabase(dbPath, { silent: true, saveDelay: 20 });
+            await db.set('users.u1', { name: 'Alice' });
+            await db.close();
+            await fs.rm(dbPath.replace(/\.json$/, '.wal'), { force: true });
+        }],
+    ])('an empty replica of data %s is sent a snapshot', async (_, create) => {
+        const dbPath = getTempDbPath();
+        await create(dbPath);
+        primary = new JSONDatabase(dbPath, { silent: true, saveDelay: 20 });
+        const address = await primary.serveReplication('127.0.0.1:0', { secret: SECRET });
+
+        const replica = new JSONDatabase(getTempDbPath(), { silent: true, saveDelay: 20 });
+        try {
+            await replica.replicateFrom(address, { secret: SECRET });
+            await waitFor(async () => (await replica.get('users.u1')) != null);
+            expect(await replica.get('users')).toEqual({ u1: { name: 'Alice' } });
+        } finally {
+            await replica.close();
+        }
+    });
+
+    test('a replica takes writes once it stops replicating', async () => {
+        primary = new JSONDatabase(getTempDbPath(), { silent: true, saveDelay: 20 });
+        await primary.set('a', 1);
+        const address = await primary.serveReplication('127.0.0.1:0', { secret: SECRET });
+
+        const replica = new JSONDatabase(getTempDbPath(), { silent: true, saveDelay: 20 });
+        try {
+            await replica.replicateFrom(address, { secret: SECRET });
+            await waitFor(async () => (await replica.get('a')) === 1);
+            await expect(replica.set('b', 2)).rejects.toThrow(JSONDatabase.ReadOnlyError);
+
+            await replica.stopReplication();
+            await replica.set('b', 2);
+            expect(await replica.get('b')).toBe(2);
+            expect((await replica.replicationStatus()).role).toBe('none');
+        } finally {
+            await replica.close();
+        }
+    });
+
+    test('serving over TCP needs a secret', async () => {
+        primary = new JSONDatabase(getTempDbPath(), { silent: true });
+
+        await expect(primary.serveReplication('127.0.0.1:0')).rejects.toThrow('Serving replicas over TCP on 127.0.0.1:0 needs a secret');
+        await expect(primary.serveReplication('127.0.0.1:0', { secret: 'abcd' })).rejects.toThrow('Replication secret must be 32 bytes of hex');
+    });
+
+    test.each([
+        ['the wrong secret', crypto.randomBytes(32).toString('hex'), 'The primary rejected the replication secret'],
+        ['no secret', undefined, 'The primary requires a secret'],
+    ])('a replica with %s is sent nothing', async (_, secret, error) => {
+        primary = new JSONDatabase(getTempDbPath(), { silent: true, saveDelay: 20 });
+        await primary.set('a', 1);
+        const address = await primary.serveReplication('127.0.0.1:0', { secret: SECRET });
+
+        const replica = new JSONDatabase(getTempDbPath(), { silent: true, saveDelay: 20 });
+        try {
+            await replica.replicateFrom(address, { secret });
+            await waitFor(async () => (await replica.replicationStatus()).error === error);
+            expect(await replica.replicationStatus()).toMatchObject({ connected: false });
+            expect(await replica.get('a')).toBeNull();
+            expect((await primary.replicationStatus()).replicas).toEqual([]);
+        } finally {
+            await replica.close();
+        }
+    });
+
+    test('a replica with a secret refuses a primary without one', async () => {
+        primary = new JSONDatabase(getTempDbPath(), { silent: true, saveDelay: 20 });
+        await primary.set('a', 1);
+        const address = await primary.serveReplication(`unix:${path.join(TEST_DATA_DIR, `p-${process.pid}.sock`)}`);
+
+        const replica = new JSONDatabase(getTempDbPath(), { silent: true, saveDelay: 20 });
+        try {
+            await replica.replicateFrom(address, { secret: SECRET });
+            await waitFor(async () => (await replica.replicationStatus()).error === 'The primary has no secret to authenticate it with');
+            expect(await replica.get('a')).toBeNull();
+        } finally {
+            await replica.close();
+        }
+    });
+
+    test('the stream is sealed with the secret after the handshake', async () => {
+        primary = new JSONDatabase(getTempDbPath(), { silent: true, saveDelay: 20 });
+        await primary.set('users.u1', { name: 'Alice' });
+        const address = await primary.serveReplication('127.0.0.1:0', { secret: SECRET });
+
+        const raw = connectRaw(address);
+        try {
+            const { challenge } = JSON.parse(await raw.readLine());
+            expect(challenge.nonce).toMatch(/^[0-9a-f]{64}$/);
+            raw.writeLine(JSON.stringify({ hello: { lsn: 0, proof: seal(SECRET, challenge.nonce) } }));
+            expect(JSON.parse(await raw.readLine())).toBe('accepted');
+
+            const first = await raw.readLine();
+            expect(first).not.toContain('Alice');
+            expect(unseal(SECRET, first)).toMatchObject({ snapshot: { data: { users: { u1: { name: 'Alice' } } } } });
+
+            await primary.set('users.u2', { name: 'Bob' });
+            const next = await raw.readLine();
+            expect(next).not.toContain('Bob');
+            expect(unseal(SECRET, next)).toMatchObject({ record: { op: { Set: { path: 'users.u2' } } } });
+        } finally {
+            raw.socket.destroy();
+        }
+    });
+
+    test('a replica whose queue overflows is disconnected', async () => {
+        primary = new JSONDatabase(getTempDbPath(), { silent: true, saveDelay: 1000 });
+        const address = await primary.serveReplication(`unix:${path.join(TEST_DATA_DIR, `q-${process.pid}.sock`)}`);
+
+        // Completes the handshake and then never reads.
+        const raw = connectRaw(address);
+        await raw.readLine();
+        raw.writeLine(JSON.stringify({ hello: { lsn: 0 } }));
+        expect(JSON.parse(await raw.readLine())).toBe('accepted');
+        raw.socket.pause();
+        await waitFor(async () => (await primary.replicationStatus()).replicas.length === 1);
+
+        const value = 'x'.repeat(4096);
+        for (let i = 0; i < 20000 && (await primary.replicationStatus()).replicas.length; i++) {
+            primary.core.set(`k${i % 100}`, value);
+        }
+        expect((await primary.replicationStatus()).replicas).toEqual([]);
+        raw.socket.resume();
+        await raw.closed;
+    });
+});
@@ -0,0 +1,802 @@
+const path = require('path');
+const crypto = require('crypto');
+const fs = require('fs').promises;
+const { spawn } = require('child_process');
+
+const JSONDatabaseModule = require('../JSONDatabase');
+const JSONDatabase = JSONDatabaseModule.default || JSONDatabaseModule;
+
+const TEST_DATA_DIR = path.join(__dirname, 'test-data-storage');
+const FIXTURE_SHARDS = path.join(__dirname, 'perf-test-data', 'sharded', 'shards');
+
+const getTempDbPath = () => path.join(TEST_DATA_DIR, `storage-db-${Date.now()}-${Math.random()}.json`);
+
+// The shard a key hashes to: FNV-1a, as the native core computes it.
+const shardOf = (key, count) => {
+    let hash = 0xcbf29ce484222325n;
+    for (const byte of Buffer.from(key)) {
+        hash ^= BigInt(byte);
+        hash = (hash * 0x100000001b3n) & 0xffffffffffffffffn;
+    }
+    return Number(hash % BigInt(count));
+};
+
+const readShard = async (dir, i) => JSON.parse(await fs.readFile(path.join(dir, `shard_${i}.json`), 'utf8'));
+
+beforeAll(async () => {
+    await fs.mkdir(TEST_DATA_DIR, { recursive: true });
+});
+
+afterAll(async () => {
+    await fs.rm(TEST_DATA_DIR, { recursive: true, force: true });
+});
+
+describe('Sharded storage', () => {
+    test('spreads the entries of the shard key over the shards and reloads them', async () => {
+        const dbPath = getTempDbPath();
+        const shardDir = path.join(dbPath.replace(/\.json$/, ''), 'shards');
+        const db = new JSONDatabase(dbPath, { silent: true, shards: 4, shardKey: 'users' });
+        for (let i = 0; i < 40; i++) await db.set(`users.u${i}`, { id: i });
+        await db.set('settings', { theme: 'dark' });
+        await db.close();
+
+        for (let i = 0; i < 4; i++) {
+            const shard = await readShard(shardDir, i);
+            expect(Object.keys(shard.users || {}).filter((key) => shardOf(key, 4) !== i)).toEqual([]);
+        }
+        const reopened = new JSONDatabase(dbPath, { silent: true, shards: 4, shardKey: 'users' });
+        expect(Object.keys(await reopened.get('users'))).toHaveLength(40);
+        expect(await reopened.get('settings.theme')).toBe('dark');
+        await reopened.close();
+    });
+
+    test('reshards when the shard count changes between runs', async () => {
+        const dbPath = getTempDbPath();
+        const shardDir = path.join(dbPath.replace(/\.json$/, ''), 'shards');
+        const db = new JSONDatabase(dbPath, { silent: true, shards: 4 });
+        for (let i = 0; i < 40; i++) await db.set(`k${i}`, i);
+        await db.close();
+
+        const resharded = new JSONDatabase(dbPath, { silent: true, shards: 3 });
+        expect(await resharded.get('k39')).toBe(39);
+        await resharded.set('k40', 40);
+        await resharded.close();
+
+        expect((await fs.readdir(shardDir)).filter((f) => f.endsWith('.json')).sort()).toEqual(['shard_0.json', 'shard_1.json', 'shard_2.json']);
+        const reopened = new JSONDatabase(dbPath, { silent: true, shards: 3 });
+        expect(Object.keys(await reopened.get(''))).toHaveLength(41);
+        await reopened.close();
+    });
+
+    describe('with shard files whose keys are not where they hash to', () => {
+        let dbPath;
+        let shardDir;
+
+        beforeEach(async () => {
+            dbPath = getTempDbPath();
+            shardDir = path.join(dbPath.replace(/\.json$/, ''), 'shards');
+            await fs.cp(FIXTURE_SHARDS, shardDir, { recursive: true });
+        });
+
+        test('loads every key of the fixture', async () => {
+            const expected = new Set();
+            for (let i = 0; i < 16; i++) {
+                Object.keys(await readShard(shardDir, i)).forEach((key) => expected.add(key));
+            }
+
+            const db = new JSONDatabase(dbPath, { silent: true, shards: 16 });
+            const keys = Object.keys(await db.get(''));
+            await db.close();
+
+            expect(keys.sort()).toEqual([...expected].sort());
+        });
+
+        test('moves misplaced keys to their shard on save', async () => {
+            const before = await readShard(shardDir, 0);
+            const misplaced = Object.keys(before).filter((key) => shardOf(key, 16) !== 0);
+            expect(misplaced.length).toBeGreaterThan(0);
+
+            const db = new JSONDatabase(dbPath, { silent: true, shards: 16 });
+            await db.set('added', { id: 0 });
+            await db.close();
+
+            for (let i = 0; i < 16; i++) {
+                const keys = Object.keys(await readShard(shardDir, i));
+                expect(keys.filter((key) => shardOf(key, 16) !== i)).toEqual([]);
+            }
+            const reopened = new JSONDatabase(dbPath, { silent: true, shards: 16 });
+            for (const key of misplaced.slice(0, 50)) {
+                expect(await reopened.get(key)).toEqual(before[key]);
+            }
+            await reopened.close();
+        });
+
+        test('does not bring back a deleted misplaced key', async () => {
+            const key = Object.keys(await readShard(shardDir, 0)).find((k) => shardOf(k, 16) !== 0);
+
+            const db = new JSONDatabase(dbPath, { silent: true, shards: 16 });
+            await db.delete(key);
+            await db.close();
+
+            const reopened = new JSONDatabase(dbPath, { silent: true, shards: 16 });
+            expect(await reopened.has(key)).toBe(false);
+            await reopened.close();
+        });
+
+        test('keeps an update to a misplaced key', async () => {
+            const key = Object.keys(await readShard(shardDir, 0)).find((k) => shardOf(k, 16) !== 0);
+
+            const db = new JSONDatabase(dbPath, { silent: true, shards: 16 });
+            await db.set(`${key}.name`, 'Updated');
+            await db.close();
+
+            const reopened = new JSONDatabase(dbPath, { silent: true, shards: 16 });
+            expect(await reopened.get(`${key}.name`)).toBe('Updated');
+            await reopened.close();
+        });
+    });
+
+    test('queries on the sharded object fan out across shards and agree with an unsharded database', async () => {
+        const users = Object.fromEntries(Array.from({ length: 200 }, (_, i) => [`u${i}`, { id: i, team: i % 6, score: (i * 31) % 97 }]));
+        const sharded = new JSONDatabase(getTempDbPath(), { silent: true, shards: 4, shardKey: 'users' });
+        const plain = new JSONDatabase(getTempDbPath(), { silent: true });
+        for (const db of [sharded, plain]) {
+            await db.set('users', users);
+            await db.set('teams', [{ id: 0 }, { id: 1 }]);
+        }
+        const run = (db, query, { sort, skip, limit, select } = {}) => {
+            let cursor = db.query('users', query);
+            if (sort) cursor = cursor.sort(sort);
+            if (skip) cursor = cursor.skip(skip);
+            if (limit) cursor = cursor.limit(limit);
+            if (select) cursor = cursor.select(select);
+            return cursor.exec();
+        };
+        const cases = [
+            [{}, {}],
+            [{ team: 3 }, {}],
+            [{ score: { $gte: 50 } }, { sort: { score: -1 }, skip: 5, limit: 10 }],
+            [{ team: { $in: [1, 2] } }, { select: ['id'] }],
+        ];
+
+        try {
+            for (const [query, options] of cases) {
+                const result = await run(sharded, query, options);
+                expect(result).toEqual(await run(plain, query, options));
+                expect(result.length).toBeGreaterThan(0);
+            }
+            const explain = await sharded.query('users', { team: 3 }).explain();
+            expect(explain).toMatchObject({ plan: 'shardScan', parallel: true, candidatesExamined: 200, returned: 33 });
+            expect((await sharded.query('teams', {}).explain()).plan).toBe('collectionScan');
+
+            const pages = [];
+            let token;
+            do {
+                let cursor = sharded.query('users', { team: 0 }).limit(7);
+                if (token) cursor = cursor.after(token);
+                const page = await cursor.page();
+                pages.push(...page.items.map((user) => user.id));
+                token = page.nextToken;
+            } while (token);
+            expect(pages).toEqual((await plain.query('users', { team: 0 }).exec()).map((user) => user.id));
+        } finally {
+            await sharded.close();
+            await plain.close();
+        }
+    });
+
+    test('queries on the top-level keys fan out when they are sharded', async () => {
+        const db = new JSONDatabase(getTempDbPath(), { silent: true, shards: 3 });
+        for (let i = 0; i < 30; i++) await db.set(`k${i}`, { n: i });
+
+        expect(await db.query('', { n: { $lt: 3 } }).exec()).toEqual([{ n: 0 }, { n: 1 }, { n: 2 }]);
+        expect((await db.query('', {}).explain()).plan).toBe('shardScan');
+        await db.close();
+    });
+});
+
+describe('Incremental saves', () => {
+    const segmentFile = (dbPath) => path.join(dbPath.replace(/\.json$/, ''), 'segments.jsonl');
+    const fileSize = async (file) => (await fs.stat(file)).size;
+
+    test('appends only the collections written since the last save', async () => {
+        const dbPath = getTempDbPath();
+        const db = new JSONDatabase(dbPath, { silent: true, incremental: true });
+        for (let i = 0; i < 50; i++) await db.set(`c${i}`, { items: Array.from({ length: 20 }, (_, j) => j) });
+        await db.close();
+        const full = await fileSize(segmentFile(dbPath));
+
+        const reopened = new JSONDatabase(dbPath, { silent: true, incremental: true });
+        await reopened.set('c7.items', [1]);
+        await reopened.delete('c8');
+        await reopened.close();
+
+        expect((await fileSize(segmentFile(dbPath))) - full).toBeLessThan(full / 10);
+        const check = new JSONDatabase(dbPath, { silent: true, incremental: true });
+        expect(await check.get('c7.items')).toEqual([1]);
+        expect(await check.has('c8')).toBe(false);
+        expect(await check.get('c49.items')).toHaveLength(20);
+        await check.close();
+    });
+
+    test('splits the data deeper with segmentDepth', async () => {
+        const dbPath = getTempDbPath();
+        const options = { silent: true, incremental: true, segmentDepth: 2 };
+        const db = new JSONDatabase(dbPath, options);
+        const users = {};
+        for (let i = 0; i < 200; i++) users[`u${i}`] = { id: i, bio: 'x'.repeat(50) };
+        await db.set('users', users);
+        await db.close();
+        const full = await fileSize(segmentFile(dbPath));
+
+        const reopened = new JSONDatabase(dbPath, options);
+        await reopened.set('users.u3.bio', 'changed');
+        await reopened.close();
+
+        expect((await fileSize(segmentFile(dbPath))) - full).toBeLessThan(full / 50);
+        const check = new JSONDatabase(dbPath, options);
+        expect(await check.get('users.u3.bio')).toBe('changed');
+        expect(Object.keys(await check.get('users'))).toHaveLength(200);
+        await check.close();
+    });
+
+    test('compacts the file once most of it is stale', async () => {
+        const dbPath = getTempDbPath();
+        const db = new JSONDatabase(dbPath, { silent: true, incremental: true, saveDelay: 0 });
+        // Files under 1 MB are only appended to.
+        for (let i = 0; i < 30; i++) {
+            await db.set('big', `${i % 10}`.repeat(100000));
+        }
+        await db.close();
+
+        expect(await fileSize(segmentFile(dbPath))).toBeLessThan(1500000);
+        const reopened = new JSONDatabase(dbPath, { silent: true, incremental: true });
+        expect(await reopened.get('big')).toBe('9'.repeat(100000));
+        await reopened.close();
+    });
+
+    test('loads a database saved as a single file and splits it on save', async () => {
+        const dbPath = getTempDbPath();
+        const plain = new JSONDatabase(dbPath, { silent: true });
+        await plain.set('a', { b: 1 });
+        await plain.close();
+
+        const db = new JSONDatabase(dbPath, { silent: true, incremental: true });
+        expect(await db.get('a.b')).toBe(1);
+        await db.set('c', 2);
+        await db.close();
+
+        const reopened = new JSONDatabase(dbPath, { silent: true, incremental: true });
+        expect(await reopened.get('')).toEqual({ a: { b: 1 }, c: 2 });
+        await reopened.close();
+    });
+});
+
+describe('Lazy loading', () => {
+    // About 200 KB per collection, so a 1 MB budget holds only a few.
+    const collection = (n) => Object.fromEntries(
+        Array.from({ length: 100 }, (_, i) => [`d${i}`, { n, i, text: 'y'.repeat(2000) }])
+    );
+    const options = { silent: true, lazy: true, memoryBudgetMb: 1 };
+    let dbPath;
+
+    beforeAll(async () => {
+        dbPath = getTempDbPath();
+        const db = new JSONDatabase(dbPath, { silent: true, incremental: true });
+        for (let n = 0; n < 20; n++) await db.set(`c${n}`, collection(n));
+        await db.close();
+    });
+
+    test('reads every collection within the memory budget', async () => {
+        const db = new JSONDatabase(dbPath, options);
+        for (let n = 0; n < 20; n++) {
+            expect(await db.get(`c${n}.d99.n`)).toBe(n);
+        }
+        expect(await db.has('c3.d5')).toBe(true);
+        expect(await db.has('missing')).toBe(false);
+        expect(Object.keys(await db.get(''))).toHaveLength(20);
+        await db.close();
+    });
+
+    test('queries collections that were not loaded yet', async () => {
+        const db = new JSONDatabase(dbPath, options);
+        const found = await db.query('c12', { i: { $lt: 3 } }).exec();
+        expect(found.map((doc) => doc.n)).toEqual([12, 12, 12]);
+        await db.close();
+    });
+
+    test('keeps unsaved writes to collections it would evict', async () => {
+        const db = new JSONDatabase(dbPath, { ...options, saveDelay: 1000 });
+        await db._ensureInitialized();
+        // Writes resolve once saved, so they are not awaited here.
+        const saved = Promise.all([db.set('c0.d0.text', 'written'), db.delete('c1')]);
+        // Reading the rest goes far past the budget before anything is saved.
+        for (let n = 2; n < 20; n++) await db.get(`c${n}.d0`);
+        expect(await db.get('c0.d0.text')).toBe('written');
+        expect(await db.has('c1')).toBe(false);
+        await saved;
+        await db.close();
+
+        const reopened = new JSONDatabase(dbPath, options);
+        expect(await reopened.get('c0.d0.text')).toBe('written');
+        expect(await reopened.has('c1')).toBe(false);
+        expect(await reopened.get('c19.d0.n')).toBe(19);
+        await reopened.close();
+    });
+
+    test('answers indexed queries on a lazy collection', async () => {
+        const db = new JSONDatabase(dbPath, { ...options, indices: [{ name: 'i', path: 'c5', field: 'i' }] });
+        const explain = await db.query('c5', { i: 42 }).explain();
+        const found = await db.query('c5', { i: 42 }).exec();
+
+        expect(explain.plan).toBe('indexScan');
+        expect(found).toHaveLength(1);
+        expect(found[0].n).toBe(5);
+        await db.close();
+    });
+});
+
+describe('Storage codecs', () => {
+    const MAGIC = Buffer.from('\0JDB');
+    const sample = { users: { u1: { name: 'Ann', tags: ['a', 'b'], score: 1.5, active: true, note: null } }, count: 2 };
+
+    // Logs `value` at `key` to the WAL and releases the files without
+    // saving, as if the process had died before its next save.
+    const writeWithoutSaving = async (db, key, value) => {
+        await db._ensureInitialized();
+        db.core.set(key, value);
+        db.core.close();
+    };
+
+    describe.each(['json', 'json-compact', 'msgpack', 'cbor'])('%s', (codec) => {
+        const binary = codec === 'msgpack' || codec === 'cbor';
+
+        test('saves, replays the WAL and reloads', async () => {
+            const dbPath = getTempDbPath();
+            const db = new JSONDatabase(dbPath, { silent: true, codec });
+            await db.set('', sample);
+            await writeWithoutSaving(db, 'users.u2', { name: 'Bo' });
+
+            const content = await fs.readFile(dbPath);
+            const wal = await fs.readFile(dbPath.replace(/\.json$/, '.wal'));
+            expect(content.subarray(0, 4).equals(MAGIC)).toBe(binary);
+            expect(wal.subarray(0, 4).equals(MAGIC)).toBe(binary);
+
+            const reopened = new JSONDatabase(dbPath, { silent: true, codec });
+            expect(await reopened.get('')).toEqual({ ...sample, users: { ...sample.users, u2: { name: 'Bo' } } });
+            await reopened.close();
+        });
+
+        test('opens files written in another codec and converts them on save', async () => {
+            const dbPath = getTempDbPath();
+            const other = binary ? 'json' : 'msgpack';
+            const db = new JSONDatabase(dbPath, { silent: true, codec: other });
+            await db.set('', sample);
+            await writeWithoutSaving(db, 'count', 3);
+
+            const reopened = new JSONDatabase(dbPath, { silent: true, codec });
+            expect(await reopened.get('count')).toBe(3);
+            await reopened.set('count', 4);
+            await reopened.close();
+
+            expect((await fs.readFile(dbPath)).subarray(0, 4).equals(MAGIC)).toBe(binary);
+            const check = new JSONDatabase(dbPath, { silent: true });
+            expect(await check.get('')).toEqual({ ...sample, count: 4 });
+            await check.close();
+        });
+
+        test('converts a database to a copy in this codec', async () => {
+            const db = new JSONDatabase(getTempDbPath(), { silent: true });
+            await db.set('', sample);
+            const copyPath = getTempDbPath();
+            await db.convert(copyPath, codec);
+            await db.close();
+
+            const written = (await fs.readdir(TEST_DATA_DIR)).filter(name => name.startsWith(path.basename(copyPath, '.json')));
+            expect(written).toEqual([path.basename(copyPath)]);
+            expect((await fs.readFile(copyPath)).subarray(0, 4).equals(MAGIC)).toBe(binary);
+            const copy = new JSONDatabase(copyPath, { silent: true });
+            expect(await copy.get('')).toEqual(sample);
+            await copy.close();
+        });
+    });
+
+    test('convert rejects a destination that exists and leaves it as it was', async () => {
+        const db = new JSONDatabase(getTempDbPath(), { silent: true });
+        await db.set('', sample);
+        const copyPath = getTempDbPath();
+        await fs.writeFile(copyPath, '{"kept":true}');
+
+        await expect(db.convert(copyPath, 'msgpack')).rejects.toThrow(`${copyPath} already exists`);
+        await expect(db.convert(copyPath, 'bson')).rejects.toThrow('Unknown storage codec: bson');
+        await db.close();
+        expect(await fs.readFile(copyPath, 'utf8')).toBe('{"kept":true}');
+    });
+
+    test('convert writes an encrypted database as an encrypted copy', async () => {
+        const options = { silent: true, encryptionKey: crypto.randomBytes(32).toString('hex') };
+        const db = new JSONDatabase(getTempDbPath(), options);
+        await db.set('secret', 'plain-text-marker');
+        const copyPath = getTempDbPath();
+        await db.convert(copyPath, 'cbor');
+        await db.close();
+
+        expect((await fs.readFile(copyPath)).includes('plain-text-marker')).toBe(false);
+        const copy = new JSONDatabase(copyPath, options);
+        expect(await copy.get('secret')).toBe('plain-text-marker');
+        await copy.close();
+    });
+
+    test('keeps encrypted binary files encrypted', async () => {
+        const dbPath = getTempDbPath();
+        const options = { silent: true, codec: 'msgpack', encryptionKey: crypto.randomBytes(32).toString('hex') };
+        const db = new JSONDatabase(dbPath, options);
+        await db.set('secret', 'plain-text-marker');
+        await db.close();
+
+        expect((await fs.readFile(dbPath)).includes('plain-text-marker')).toBe(false);
+        const reopened = new JSONDatabase(dbPath, options);
+        expect(await reopened.get('secret')).toBe('plain-text-marker');
+        await reopened.close();
+    });
+});
+
+describe('Compression', () => {
+    const MAGIC = { zstd: Buffer.from([0x28, 0xb5, 0x2f, 0xfd]), gzip: Buffer.from([0x1f, 0x8b]) };
+    const rows = Object.fromEntries(Array.from({ length: 500 }, (_, i) => [`r${i}`, { id: i, status: 'active', note: 'repeated text' }]));
+
+    describe.each(['zstd', 'gzip'])('%s', (compression) => {
+        test('compresses the data file and the WAL and reads them back', async () => {
+            const plainPath = getTempDbPath();
+            const plain = new JSONDatabase(plainPath, { silent: true });
+            await plain.set('rows', rows);
+            await plain.close();
+
+            const dbPath = getTempDbPath();
+            const db = new JSONDatabase(dbPath, { silent: true, compression });
+            await db.set('rows', rows);
+            await db._ensureInitialized();
+            db.core.set('big', 'z'.repeat(1000));
+            db.core.close();
+
+            const content = await fs.readFile(dbPath);
+            // A compressed JSON file is framed, after a 5 byte header.
+            expect(content.subarray(0, 4).toString('latin1')).toBe('\0JDB');
+            expect(content.subarray(5, 5 + MAGIC[compression].length).equals(MAGIC[compression])).toBe(true);
+            expect(content.length).toBeLessThan((await fs.stat(plainPath)).size / 5);
+            expect((await fs.readFile(dbPath.replace(/\.json$/, '.wal'))).includes('z'.repeat(1000))).toBe(false);
+
+            const reopened = new JSONDatabase(dbPath, { silent: true, compression });
+            expect(await reopened.get('rows.r499.id')).toBe(499);
+            expect(await reopened.get('big')).toBe('z'.repeat(1000));
+            await reopened.close();
+        });
+
+        test('reads compressed files without the option and writes them uncompressed', async () => {
+            const dbPath = getTempDbPath();
+            const db = new JSONDatabase(dbPath, { silent: true, compression, codec: 'msgpack' });
+            await db.set('rows', rows);
+            await db.close();
+
+            const reopened = new JSONDatabase(dbPath, { silent: true, codec: 'msgpack' });
+            expect(await reopened.get('rows.r0')).toEqual(rows.r0);
+            await reopened.set('rows.r0.status', 'done');
+            await reopened.close();
+
+            const content = await fs.readFile(dbPath);
+            expect(content.includes('repeated text')).toBe(true);
+        });
+
+        test('convert writes a compressed copy', async () => {
+            const db = new JSONDatabase(getTempDbPath(), { silent: true, compression });
+            await db.set('rows', rows);
+            const copyPath = getTempDbPath();
+            await db.convert(copyPath, 'json');
+            await db.close();
+
+            const content = await fs.readFile(copyPath);
+            expect(content.subarray(5, 5 + MAGIC[compression].length).equals(MAGIC[compression])).toBe(true);
+            const copy = new JSONDatabase(copyPath, { silent: true, compression });
+            expect(await copy.get('rows')).toEqual(rows);
+            await copy.close();
+        });
+
+        test('compresses before encrypting', async () => {
+            const dbPath = getTempDbPath();
+            const options = { silent: true, compression, encryptionKey: crypto.randomBytes(32).toString('hex') };
+            const db = new JSONDatabase(dbPath, options);
+            await db.set('rows', rows);
+            await db.close();
+
+            const reopened = new JSONDatabase(dbPath, options);
+            expect(await reopened.get('rows.r250')).toEqual(rows.r250);
+            await reopened.close();
+        });
+    });
+
+    test('rejects compression levels out of range', () => {
+        expect(() => new JSONDatabase(getTempDbPath(), { silent: true, compression: 'zstd', compressionLevel: 23 })).toThrow('Invalid zstd compression level');
+        expect(() => new JSONDatabase(getTempDbPath(), { silent: true, compression: 'gzip', compressionLevel: 10 })).toThrow('Invalid gzip compression level');
+        expect(() => new JSONDatabase(getTempDbPath(), { silent: true, compression: 'lz4' })).toThrow('Unknown compression');
+    });
+});
+
+describe('Mapped snapshots', () => {
+    const data = {
+        users: {
+            u1: { name: 'Ann', age: 31, tags: ['admin'], address: { city: 'Oslo' } },
+            u2: { name: 'Bø', age: 27, tags: [], address: { city: 'Bergen' } },
+            u3: { name: 'Cyd', age: -4, tags: ['x', 'y'], active: false },
+        },
+        list: [1, 2.5, 'three', null, true, { n: 5 }],
+        settings: { ratio: 0.25, empty: {}, big: 2 ** 40 },
+    };
+
+    const exported = async (value = data) => {
+        const db = new JSONDatabase(getTempDbPath(), { silent: true });
+        await db.set('', value);
+        const snapPath = getTempDbPath().replace(/\.json$/, '.snap');
+        await db.exportSnapshot(snapPath);
+        return { db, snapPath };
+    };
+
+    test('serves get, has and find from the exported data', async () => {
+        const { db, snapPath } = await exported();
+        await db.close();
+        const snapshot = new JSONDatabase.MappedSnapshot(snapPath);
+
+        expect(await snapshot.get()).toEqual(data);
+        expect(await snapshot.get('users.u2')).toEqual(data.users.u2);
+        expect(await snapshot.get('list.5.n')).toBe(5);
+        expect(await snapshot.get('settings.big')).toBe(2 ** 40);
+        expect(await snapshot.get('users.u9', 'none')).toBe('none');
+        expect(await snapshot.has('users.u3.active')).toBe(true);
+        expect(await snapshot.has('users.u3.address')).toBe(false);
+        expect(await snapshot.has('list.6')).toBe(false);
+        expect(await snapshot.find('users', { 'address.city': 'Bergen' })).toEqual(data.users.u2);
+        expect(await snapshot.find('users', { age: { $lt: 0 }, tags: 'y' })).toEqual(data.users.u3);
+        expect(await snapshot.find('list', { n: { $gte: 5 } })).toEqual({ n: 5 });
+        expect(await snapshot.find('users', { name: { $in: ['Zed'] } })).toBeUndefined();
+    });
+
+    test('keeps the data as it was exported, and a new export replaces it', async () => {
+        const { db, snapPath } = await exported();
+        await db.set('users.u1.age', 32);
+        expect(await new JSONDatabase.MappedSnapshot(snapPath).get('users.u1.age')).toBe(31);
+
+        await db.exportSnapshot(snapPath);
+        await db.close();
+        expect(await new JSONDatabase.MappedSnapshot(snapPath).get('users.u1.age')).toBe(32);
+    });
+
+    test('rejects files that are not snapshots, and answers from damaged ones without failing', async () => {
+        const { db, snapPath } = await exported();
+        await db.close();
+        const bytes = await fs.readFile(snapPath);
+
+        const notSnap = getTempDbPath();
+        await fs.writeFile(notSnap, '{"users":{}}');
+        expect(() => new JSONDatabase.MappedSnapshot(notSnap)).toThrow('is not a database snapshot');
+        expect(() => new JSONDatabase.MappedSnapshot(`${notSnap}.missing`)).toThrow('Failed to open snapshot');
+
+        const otherVersion = Buffer.from(bytes);
+        otherVersion[7] = 9;
+        await fs.writeFile(notSnap, otherVersion);
+        expect(() => new JSONDatabase.MappedSnapshot(notSnap)).toThrow('Unsupported snapshot version 9');
+
+        const badRoot = Buffer.from(bytes);
+        badRoot.writeBigUInt64LE(BigInt(bytes.length + 10), 8);
+        await fs.writeFile(notSnap, badRoot);
+        expect(() => new JSONDatabase.MappedSnapshot(notSnap)).toThrow('Invalid snapshot root offset');
+
+        // Overwrite the middle of the file with garbage: lookups still return.
+        const damaged = Buffer.from(bytes);
+        crypto.randomFillSync(damaged, 16, Math.floor((bytes.length - 16) / 2));
+        await fs.writeFile(notSnap, damaged);
+        const snapshot = new JSONDatabase.MappedSnapshot(notSnap);
+        await snapshot.get();
+        await snapshot.has('users.u1.name');
+        await snapshot.find('users', { age: 31 });
+    });
+
+    test('encrypted databases cannot be exported', async () => {
+        const db = new JSONDatabase(getTempDbPath(), { silent: true, encryptionKey: crypto.randomBytes(32).toString('hex') });
+        await db.set('a', 1);
+
+        await expect(db.exportSnapshot(getTempDbPath())).rejects.toThrow('Encrypted databases cannot be exported as snapshots');
+        await db.close();
+    });
+});
+
+describe('Read-only mode', () => {
+    // Every file next to the database with its contents and modification time.
+    const listing = async (dbPath) => {
+        const dir = path.dirname(dbPath);
+        const base = path.basename(dbPath, '.json');
+        const files = (await fs.readdir(dir)).filter((name) => name.startsWith(base)).sort();
+        return Promise.all(files.map(async (name) => {
+            const file = path.join(dir, name);
+            const stat = await fs.stat(file);
+            return [name, stat.mtimeMs, (await fs.readFile(file)).toString('hex')];
+        }));
+    };
+
+    const written = async (data) => {
+        const dbPath = getTempDbPath();
+        const db = new JSONDatabase(dbPath, { silent: true });
+        await db.set('', data);
+        await db.close();
+        return dbPath;
+    };
+
+    test('creates and modifies no file, not even an interrupted save', async () => {
+        const dbPath = await written({ a: 1 });
+        await fs.writeFile(dbPath.replace(/\.json$/, '.tmp'), '{"a":2}');
+        const before = await listing(dbPath);
+
+        const db = new JSONDatabase(dbPath, { silent: true, readOnly: true, reloadOnChange: true });
+        expect(await db.get('a')).toBe(1);
+        await db.close();
+        expect(await listing(dbPath)).toEqual(before);
+
+        const missing = getTempDbPath();
+        const empty = new JSONDatabase(missing, { silent: true, readOnly: true });
+        expect(await empty.get()).toEqual({});
+        await empty.close();
+        expect(await listing(missing)).toEqual([]);
+    });
+
+    test('replays the WAL without writing to it', async () => {
+        const dbPath = getTempDbPath();
+        const writer = new JSONDatabase(dbPath, { silent: true });
+        await writer.get();
+        writer.core.set('a', 1);
+        writer.core.save();
+        // Logged to the WAL only; a record this large is not held in its buffer.
+        const big = 'x'.repeat(10_000);
+        writer.core.set('b', big);
+        expect(JSON.parse(await fs.readFile(dbPath, 'utf8'))).toEqual({ a: 1 });
+        const before = await listing(dbPath);
+
+        const reader = new JSONDatabase(dbPath, { silent: true, readOnly: true });
+        expect(await reader.get()).toEqual({ a: 1, b: big });
+        await reader.close();
+        expect(await listing(dbPath)).toEqual(before);
+        await writer.close();
+    });
+
+    test('writes reject with ReadOnlyError', async () => {
+        const db = new JSONDatabase(await written({ n: 1, list: [1] }), { silent: true, readOnly: true });
+        const writes = [
+            () => db.set('n', 2),
+            () => db.delete('n'),
+            () => db.push('list', 2),
+            () => db.pull('list', 1),
+            () => db.add('n', 1),
+            () => db.subtract('n', 1),
+            () => db.transaction((data) => data),
+            () => db.batch([{ type: 'set', path: 'n', value: 3 }]),
+            () => db.clear(),
+        ];
+        for (const write of writes) {
+            await expect(write()).rejects.toThrow(JSONDatabase.ReadOnlyError);
+        }
+        expect(() => db.core.set('n', 5)).toThrow('Database is open read-only');
+        expect(() => db.core.save()).toThrow('Database is open read-only');
+        expect(await db.get()).toEqual({ n: 1, list: [1] });
+        await db.close();
+    });
+
+    test('reloadOnChange picks up what another process saves', async () => {
+        const dbPath = await written({ v: 1 });
+        const reloading = new JSONDatabase(dbPath, { silent: true, readOnly: true, reloadOnChange: true });
+        const fixed = new JSONDatabase(dbPath, { silent: true, readOnly: true });
+        expect(await reloading.get('v')).toBe(1);
+        expect(await fixed.get('v')).toBe(1);
+
+        const writer = new JSONDatabase(dbPath, { silent: true });
+        await writer.set('v', 2);
+        await writer.set('w', 'new');
+        await writer.close();
+
+        expect(await reloading.get()).toEqual({ v: 2, w: 'new' });
+        expect(await fixed.get()).toEqual({ v: 1 });
+
+        await fs.rm(dbPath);
+        expect(await reloading.get()).toEqual({});
+        await reloading.close();
+        await fixed.close();
+    });
+});
+
+describe('Writer lock', () => {
+    // Opens the database for writing in another process, which closes it
+    // when told to, after `closeAfter` ms.
+    const HOLDER_PROCESS = `
+const JSONDatabase = require(${JSON.stringify(path.join(__dirname, '..', 'JSONDatabase'))});
+const db = new JSONDatabase(process.argv[1], { silent: true });
+db.set('holder', process.pid).then(() => process.send('ready'));
+process.on('message', ({ closeAfter }) => setTimeout(async () => {
+    await db.close();
+    process.send('closed');
+    process.disconnect();
+}, closeAfter));
+`;
+
+    const holdLock = async (dbPath) => {
+        const child = spawn(process.execPath, ['-e', HOLDER_PROCESS, dbPath], { stdio: ['ignore', 'inherit', 'inherit', 'ipc'] });
+        const messages = [];
+        child.on('message', (message) => messages.push(message));
+        const received = (message) => new Promise((resolve) => {
+            const check = () => (messages.includes(message) ? resolve() : setTimeout(check, 10));
+            check();
+        });
+        const exited = new Promise((resolve) => child.on('exit', resolve));
+        await received('ready');
+        return { child, exited, release: (closeAfter = 0) => { child.send({ closeAfter }); return received('closed'); } };
+    };
+
+    const lockFile = (dbPath) => dbPath.replace(/\.json$/, '.lock');
+
+    test('a second process cannot open the database for writing until the first closes it', async () => {
+        const dbPath = getTempDbPath();
+        const holder = await holdLock(dbPath);
+
+        expect(() => new JSONDatabase(dbPath, { silent: true })).toThrow(`Database is locked by process ${holder.child.pid}`);
+        expect(await fs.readFile(lockFile(dbPath), 'utf8')).toBe(String(holder.child.pid));
+        const reader = new JSONDatabase(dbPath, { silent: true, readOnly: true });
+        expect(await reader.get('holder')).toBe(holder.child.pid);
+        await reader.close();
+
+        await holder.release();
+        await holder.exited;
+        const db = new JSONDatabase(dbPath, { silent: true });
+        await db.set('holder', process.pid);
+        expect(await fs.readFile(lockFile(dbPath), 'utf8')).toBe(String(process.pid));
+        await db.close();
+    });
+
+    test('lockTimeoutMs waits for the other process to close the database', async () => {
+        const dbPath = getTempDbPath();
+        const holder = await holdLock(dbPath);
+
+        expect(() => new JSONDatabase(dbPath, { silent: true, lockTimeoutMs: 100 })).toThrow(
+            `Database is still locked by process ${holder.child.pid} after waiting 100 ms`
+        );
+        const closed = holder.release(200);
+        const db = new JSONDatabase(dbPath, { silent: true, lockTimeoutMs: 5000 });
+        await closed;
+        expect(await db.get('holder')).toBe(holder.child.pid);
+        await db.close();
+        await holder.exited;
+    });
+
+    test('the lock of a process that died is taken over', async () => {
+        const dbPath = getTempDbPath();
+        const holder = await holdLock(dbPath);
+        holder.child.kill('SIGKILL');
+        await holder.exited;
+        // The lock file still names the dead process.
+        expect(await fs.readFile(lockFile(dbPath), 'utf8')).toBe(String(holder.child.pid));
+
+        const db = new JSONDatabase(dbPath, { silent: true });
+        expect(await db.get('holder')).toBe(holder.child.pid);
+        expect(await fs.readFile(lockFile(dbPath), 'utf8')).toBe(String(process.pid));
+        await db.close();
+    });
+
+    test('databases opened twice in one process share the lock until both are closed', async () => {
+        const dbPath = getTempDbPath();
+        const first = new JSONDatabase(dbPath, { silent: true });
+        const second = new JSONDatabase(dbPath, { silent: true });
+        await first.set('a', 1);
+
+        await first.close();
+        await expect(first.set('a', 2)).rejects.toThrow('Database is closed');
+        await second.set('b', 2);
+        expect(await fs.readFile(lockFile(dbPath), 'utf8')).toBe(String(process.pid));
+
+        await second.close();
+        expect(await fs.readFile(lockFile(dbPath), 'utf8')).toBe('');
+    });
+});
@@ -112,6 +112,7 @@ if (!nativeBinding) {
   throw new Error(`Failed to load native binding`)
 }
 
-const { DatabaseCore } = nativeBinding
+const { DatabaseCore, MappedDatabase } = nativeBinding
 
-module.exports.DatabaseCore = DatabaseCore
\ No newline at end of file
+module.exports.DatabaseCore = DatabaseCore
+module.exports.MappedDatabase = MappedDatabase
\ No newline at end of file
@@ -0,0 +1,176 @@
+use napi::{Error, Result, Status};
+use napi_derive::napi;
+use serde_json::{Map, Value};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+use crate::get_value_by_path;
+use crate::indexes::{compare_parts, KeyPart};
+
+/// How `aggregate` groups the matched documents and what it computes per group.
+#[napi(object)]
+pub struct AggregateSpec {
+    /// Field whose value keys the groups; all documents form one group when unset.
+    pub group_by: Option<String>,
+    /// Output field → `{ $count: true }`, or `{ $sum | $avg | $min | $max: field }`.
+    pub accumulators: Value,
+}
+
+enum Accumulator {
+    Count,
+    Sum(String),
+    Avg(String),
+    Min(String),
+    Max(String),
+}
+
+impl Accumulator {
+    fn parse(name: &str, spec: &Value) -> Result<Self> {
+        let invalid = || {
+            Error::new(
+                Status::InvalidArg,
+                format!(
+                    "Accumulator '{}' must be one of $count, $sum, $avg, $min or $max",
+                    name
+                ),
+            )
+        };
+        let (op, arg) = match spec.as_object() {
+            Some(obj) if obj.len() == 1 => obj.iter().next().unwrap(),
+            _ => return Err(invalid()),
+        };
+        if op == "$count" {
+            return Ok(Accumulator::Count);
+        }
+        let field = arg.as_str().ok_or_else(|| {
+            Error::new(
+                Status::InvalidArg,
+                format!("{} of accumulator '{}' must name a field", op, name),
+            )
+        })?;
+        let field = field.to_string();
+        match op.as_str() {
+            "$sum" => Ok(Accumulator::Sum(field)),
+            "$avg" => Ok(Accumulator::Avg(field)),
+            "$min" => Ok(Accumulator::Min(field)),
+            "$max" => Ok(Accumulator::Max(field)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// The running value of one accumulator in one group.
+enum State<'a> {
+    Count(u64),
+    Sum(f64),
+    Avg(f64, u64),
+    Extreme(Option<&'a Value>),
+}
+
+impl<'a> State<'a> {
+    fn new(acc: &Accumulator) -> Self {
+        match acc {
+            Accumulator::Count => State::Count(0),
+            Accumulator::Sum(_) => State::Sum(0.0),
+            Accumulator::Avg(_) => State::Avg(0.0, 0),
+            Accumulator::Min(_) | Accumulator::Max(_) => State::Extreme(None),
+        }
+    }
+
+    /// Non-numbers are left out of `$sum` and `$avg`, and missing or `null`
+    /// values out of `$min` and `$max`.
+    fn add(&mut self, acc: &Accumulator, doc: &'a Value) {
+        match (self, acc) {
+            (State::Count(n), _) => *n += 1,
+            (State::Sum(sum), Accumulator::Sum(field)) => {
+                if let Some(v) = get_value_by_path(doc, field).and_then(Value::as_f64) {
+                    *sum += v;
+                }
+            }
+            (State::Avg(sum, n), Accumulator::Avg(field)) => {
+                if let Some(v) = get_value_by_path(doc, field).and_then(Value::as_f64) {
+                    *sum += v;
+                    *n += 1;
+                }
+            }
+            (State::Extreme(best), Accumulator::Min(field) | Accumulator::Max(field)) => {
+                let wanted = match acc {
+                    Accumulator::Min(_) => Ordering::Less,
+                    _ => Ordering::Greater,
+                };
+                if let Some(v) = get_value_by_path(doc, field).filter(|v| !v.is_null()) {
+                    if best.is_none_or(|b| compare_parts(Some(v), Some(b)) == wanted) {
+                        *best = Some(v);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn finish(self) -> Value {
+        match self {
+            State::Count(n) => Value::from(n),
+            State::Sum(sum) => Value::from(sum),
+            State::Avg(_, 0) => Value::Null,
+            State::Avg(sum, n) => Value::from(sum / n as f64),
+            State::Extreme(best) => best.cloned().unwrap_or(Value::Null),
+        }
+    }
+}
+
+/// Groups `docs` and computes the accumulators of each group. Returns one
+/// `{ _id, ...accumulators }` object per group in group key order, with a
+/// missing group field keyed as `null`. Without `group_by` there is always
+/// exactly one group, keyed `null`, even when nothing matched.
+pub(crate) fn aggregate<'a>(
+    docs: impl Iterator<Item = &'a Value>,
+    spec: &AggregateSpec,
+) -> Result<Vec<Value>> {
+    let accumulators = match &spec.accumulators {
+        Value::Object(obj) => obj
+            .iter()
+            .map(|(name, acc)| Ok((name.clone(), Accumulator::parse(name, acc)?)))
+            .collect::<Result<Vec<_>>>()?,
+        _ => {
+            return Err(Error::new(
+                Status::InvalidArg,
+                "Aggregate accumulators must be an object".to_string(),
+            ))
+        }
+    };
+    let new_group = || {
+        accumulators
+            .iter()
+            .map(|(_, acc)| State::new(acc))
+            .collect::<Vec<_>>()
+    };
+
+    let mut groups: BTreeMap<KeyPart, Vec<State>> = BTreeMap::new();
+    if spec.group_by.is_none() {
+        groups.insert(KeyPart(None), new_group());
+    }
+    for doc in docs {
+        let key = spec.group_by.as_deref().and_then(|field| {
+            get_value_by_path(doc, field)
+                .filter(|v| !v.is_null())
+                .cloned()
+        });
+        let states = groups.entry(KeyPart(key)).or_insert_with(new_group);
+        for (state, (_, acc)) in states.iter_mut().zip(&accumulators) {
+            state.add(acc, doc);
+        }
+    }
+
+    Ok(groups
+        .into_iter()
+        .map(|(key, states)| {
+            let mut out = Map::new();
+            out.insert("_id".to_string(), key.0.unwrap_or(Value::Null));
+            for (state, (name, _)) in states.into_iter().zip(&accumulators) {
+                out.insert(name.clone(), state.finish());
+            }
+            Value::Object(out)
+        })
+        .collect())
+}
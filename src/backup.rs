@@ -0,0 +1,322 @@
+//! Backups: a directory with a manifest that records the checksum of every
+//! file in it. A full backup holds the data of a database as of one LSN,
+//! encoded like its data file. An incremental one holds the writes logged
+//! between the backup it builds on and its own LSN, each with a checksum of
+//! its own. The manifest is written last and the directory renamed into
+//! place, so a backup that exists is complete.
+
+use napi::{Error, Result, Status};
+use napi_derive::napi;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::codec::Codec;
+
+const MANIFEST: &str = "manifest.json";
+const DATA: &str = "data";
+const CHANGES: &str = "changes";
+const BACKUP_VERSION: u32 = 1;
+
+const FULL: &str = "full";
+const INCREMENTAL: &str = "incremental";
+
+#[derive(Serialize, Deserialize)]
+struct BackupFile {
+    name: String,
+    size: u64,
+    checksum: u32,
+}
+
+fn full() -> String {
+    FULL.to_string()
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Manifest {
+    version: u32,
+    id: String,
+    #[serde(default = "full")]
+    kind: String,
+    /// The backup an incremental one builds on, and its LSN.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    parent: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    since_lsn: Option<u64>,
+    /// LSN of the last write the backup holds.
+    lsn: u64,
+    created_at: u64,
+    /// Whether the data is encrypted; it can only be restored into a
+    /// database with the same key.
+    encrypted: bool,
+    files: Vec<BackupFile>,
+}
+
+/// A backup written by `backup` or `incrementalBackup`, or read by
+/// `restore`.
+#[napi(object)]
+pub struct BackupInfo {
+    pub id: String,
+    /// `full` or `incremental`.
+    pub kind: String,
+    /// The backup an incremental one builds on.
+    pub parent: Option<String>,
+    pub lsn: i64,
+    /// Milliseconds since the Unix epoch.
+    pub created_at: i64,
+}
+
+impl Manifest {
+    fn new(lsn: u64, encrypted: bool) -> Self {
+        let created_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        Manifest {
+            version: BACKUP_VERSION,
+            id: format!("{}-{}-{:08x}", created_at, lsn, rand::random::<u32>()),
+            kind: full(),
+            parent: None,
+            since_lsn: None,
+            lsn,
+            created_at,
+            encrypted,
+            files: Vec::new(),
+        }
+    }
+
+    fn info(&self) -> BackupInfo {
+        BackupInfo {
+            id: self.id.clone(),
+            kind: self.kind.clone(),
+            parent: self.parent.clone(),
+            lsn: self.lsn as i64,
+            created_at: self.created_at as i64,
+        }
+    }
+}
+
+fn write_error(e: io::Error) -> Error {
+    Error::new(
+        Status::GenericFailure,
+        format!("Failed to write backup: {}", e),
+    )
+}
+
+/// Writes `files` and then `manifest`, which lists them, to the new
+/// directory `dir`.
+fn write_dir(dir: &Path, mut manifest: Manifest, files: &[(&str, &[u8])]) -> Result<BackupInfo> {
+    if dir.exists() {
+        return Err(Error::new(
+            Status::InvalidArg,
+            format!("Backup destination {} already exists", dir.display()),
+        ));
+    }
+    let mut tmp = dir.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = Path::new(&tmp);
+    if tmp.exists() {
+        fs::remove_dir_all(tmp).map_err(write_error)?;
+    }
+    fs::create_dir_all(tmp).map_err(write_error)?;
+    for (name, content) in files {
+        fs::write(tmp.join(name), content).map_err(write_error)?;
+        manifest.files.push(BackupFile {
+            name: name.to_string(),
+            size: content.len() as u64,
+            checksum: crc32fast::hash(content),
+        });
+    }
+    let manifest_json = serde_json::to_vec_pretty(&manifest)
+        .map_err(|e| Error::new(Status::GenericFailure, e.to_string()))?;
+    fs::write(tmp.join(MANIFEST), manifest_json).map_err(write_error)?;
+    fs::rename(tmp, dir).map_err(write_error)?;
+    Ok(manifest.info())
+}
+
+/// Writes `content`, the data as of `lsn` encoded as a data file, as a
+/// full backup in the new directory `dir`.
+pub(crate) fn write(dir: &Path, lsn: u64, encrypted: bool, content: &[u8]) -> Result<BackupInfo> {
+    write_dir(dir, Manifest::new(lsn, encrypted), &[(DATA, content)])
+}
+
+/// The backup with the id `id` among the backups in `dir`.
+fn find(dir: &Path, id: &str) -> Result<(PathBuf, Manifest)> {
+    let entries = fs::read_dir(dir).map_err(|e| {
+        Error::new(
+            Status::InvalidArg,
+            format!("Failed to read backups in {}: {}", dir.display(), e),
+        )
+    })?;
+    entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter_map(|path| Some((read_manifest(&path).ok()?, path)))
+        .find(|(manifest, _)| manifest.id == id)
+        .map(|(manifest, path)| (path, manifest))
+        .ok_or_else(|| {
+            Error::new(
+                Status::InvalidArg,
+                format!("No backup {} in {}", id, dir.display()),
+            )
+        })
+}
+
+/// The LSN of the backup with the id `id` among the backups in `dir`.
+pub(crate) fn lsn_of(dir: &Path, id: &str) -> Result<u64> {
+    Ok(find(dir, id)?.1.lsn)
+}
+
+/// Writes the writes logged after the backup `since_id` in `dir` and up to
+/// `lsn`, each encoded as a WAL record, as an incremental backup in a new
+/// directory of `dir` named after its id.
+pub(crate) fn write_incremental(
+    dir: &Path,
+    since_id: &str,
+    lsn: u64,
+    encrypted: bool,
+    codec: Codec,
+    records: &[Vec<u8>],
+) -> Result<BackupInfo> {
+    let (_, parent) = find(dir, since_id)?;
+    let mut manifest = Manifest::new(lsn, encrypted);
+    manifest.kind = INCREMENTAL.to_string();
+    manifest.parent = Some(parent.id);
+    manifest.since_lsn = Some(parent.lsn);
+
+    // Each record is its length, the checksum of its payload and the
+    // payload.
+    let mut changes = codec.header();
+    for record in records {
+        changes.extend_from_slice(&(record.len() as u32).to_le_bytes());
+        changes.extend_from_slice(&crc32fast::hash(record).to_le_bytes());
+        changes.extend_from_slice(record);
+    }
+    let path = dir.join(&manifest.id);
+    write_dir(&path, manifest, &[(CHANGES, &changes)])
+}
+
+fn invalid(dir: &Path, reason: String) -> Error {
+    Error::new(
+        Status::InvalidArg,
+        format!("Invalid backup {}: {}", dir.display(), reason),
+    )
+}
+
+fn read_manifest(dir: &Path) -> Result<Manifest> {
+    let manifest = fs::read(dir.join(MANIFEST)).map_err(|e| invalid(dir, e.to_string()))?;
+    let manifest: Manifest =
+        serde_json::from_slice(&manifest).map_err(|e| invalid(dir, e.to_string()))?;
+    if manifest.version != BACKUP_VERSION {
+        return Err(invalid(
+            dir,
+            format!("unsupported version {}", manifest.version),
+        ));
+    }
+    Ok(manifest)
+}
+
+/// A backup read back and checked against its manifest.
+pub(crate) struct Backup {
+    pub(crate) info: BackupInfo,
+    pub(crate) encrypted: bool,
+    /// The data of a full backup, encoded as a data file.
+    pub(crate) content: Vec<u8>,
+    /// The records of an incremental one, and the LSN they start after.
+    changes: Vec<u8>,
+    since_lsn: Option<u64>,
+    dir: PathBuf,
+}
+
+impl Backup {
+    /// The payloads of the records of an incremental backup, failing on
+    /// the first whose checksum does not match.
+    pub(crate) fn records(&self) -> Result<(Codec, Vec<&[u8]>)> {
+        let (codec, header_len) =
+            Codec::detect(&self.changes).map_err(|e| invalid(&self.dir, e))?;
+        let mut records = Vec::new();
+        let mut rest = &self.changes[header_len..];
+        while !rest.is_empty() {
+            let corrupt = || {
+                invalid(
+                    &self.dir,
+                    format!("record {} is corrupt", records.len() + 1),
+                )
+            };
+            let len = rest.get(..4).ok_or_else(corrupt)?;
+            let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+            let checksum = rest.get(4..8).ok_or_else(corrupt)?;
+            let checksum = u32::from_le_bytes(checksum.try_into().unwrap());
+            let payload = rest.get(8..8 + len).ok_or_else(corrupt)?;
+            if crc32fast::hash(payload) != checksum {
+                return Err(corrupt());
+            }
+            records.push(payload);
+            rest = &rest[8 + len..];
+        }
+        Ok((codec, records))
+    }
+}
+
+/// Reads the backup in `dir`, failing if a file is missing or does not
+/// match the size and checksum the manifest recorded.
+fn read(dir: &Path) -> Result<Backup> {
+    let manifest = read_manifest(dir)?;
+    let mut content = Vec::new();
+    let mut changes = Vec::new();
+    for file in &manifest.files {
+        let bytes = fs::read(dir.join(&file.name))
+            .map_err(|e| invalid(dir, format!("{}: {}", file.name, e)))?;
+        if bytes.len() as u64 != file.size || crc32fast::hash(&bytes) != file.checksum {
+            return Err(invalid(dir, format!("{} is corrupt", file.name)));
+        }
+        match file.name.as_str() {
+            DATA => content = bytes,
+            CHANGES => changes = bytes,
+            _ => {}
+        }
+    }
+    let expected = if manifest.kind == INCREMENTAL {
+        CHANGES
+    } else {
+        DATA
+    };
+    if !manifest.files.iter().any(|file| file.name == expected) {
+        return Err(invalid(dir, format!("no {} file", expected)));
+    }
+    Ok(Backup {
+        info: manifest.info(),
+        encrypted: manifest.encrypted,
+        content,
+        changes,
+        since_lsn: manifest.since_lsn,
+        dir: dir.to_path_buf(),
+    })
+}
+
+/// Reads the backup in `dir` and, for an incremental one, the backups it
+/// builds on, which are looked for next to it. Returns them oldest first,
+/// starting with a full backup.
+pub(crate) fn read_chain(dir: &Path) -> Result<Vec<Backup>> {
+    let siblings = dir
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let mut chain = vec![read(dir)?];
+    while let Some(parent) = chain.last().unwrap().info.parent.clone() {
+        let child = chain.last().unwrap();
+        let (path, manifest) = find(siblings, &parent)?;
+        let looped = chain.iter().any(|backup| backup.info.id == parent);
+        if looped || child.since_lsn != Some(manifest.lsn) {
+            return Err(invalid(
+                &child.dir,
+                format!("it does not start where backup {} ends", parent),
+            ));
+        }
+        chain.push(read(&path)?);
+    }
+    chain.reverse();
+    Ok(chain)
+}
@@ -0,0 +1,187 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::io::{self, BufRead, Read};
+
+/// Files written with a binary codec start with these bytes followed by the
+/// codec's id. JSON files have no header, so files written before codecs
+/// existed load as JSON.
+const MAGIC: &[u8; 4] = b"\0JDB";
+
+/// How values are serialized in the data files and the logs.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Codec {
+    Json,
+    JsonCompact,
+    MsgPack,
+    Cbor,
+    /// Compact JSON in a binary file, whose records can hold compressed
+    /// values.
+    JsonFramed,
+}
+
+impl Codec {
+    pub(crate) fn from_name(name: &str) -> Option<Codec> {
+        match name {
+            "json" => Some(Codec::Json),
+            "json-compact" => Some(Codec::JsonCompact),
+            "msgpack" => Some(Codec::MsgPack),
+            "cbor" => Some(Codec::Cbor),
+            _ => None,
+        }
+    }
+
+    fn id(self) -> u8 {
+        match self {
+            Codec::Json | Codec::JsonCompact => 0,
+            Codec::MsgPack => 1,
+            Codec::Cbor => 2,
+            Codec::JsonFramed => 3,
+        }
+    }
+
+    /// Whether files are binary: they start with a header, their records
+    /// are length-prefixed and encrypted values are stored as raw bytes.
+    pub(crate) fn is_binary(self) -> bool {
+        self.id() != 0
+    }
+
+    /// The codec to use when values are compressed, which only binary files
+    /// can hold.
+    pub(crate) fn binary(self) -> Codec {
+        match self {
+            Codec::Json | Codec::JsonCompact => Codec::JsonFramed,
+            codec => codec,
+        }
+    }
+
+    /// Whether files written with one codec can be read and appended to
+    /// with the other.
+    pub(crate) fn same_format(self, other: Codec) -> bool {
+        self.id() == other.id()
+    }
+
+    /// The codec for records of the WAL and the segment file, which are
+    /// never pretty-printed.
+    pub(crate) fn for_records(self) -> Codec {
+        match self {
+            Codec::Json => Codec::JsonCompact,
+            codec => codec,
+        }
+    }
+
+    /// What files written with this codec start with.
+    pub(crate) fn header(self) -> Vec<u8> {
+        if !self.is_binary() {
+            return Vec::new();
+        }
+        let mut header = MAGIC.to_vec();
+        header.push(self.id());
+        header
+    }
+
+    /// Detects the codec of a file from its first bytes. Returns it with the
+    /// length of the header.
+    pub(crate) fn detect(content: &[u8]) -> Result<(Codec, usize), String> {
+        if !content.starts_with(MAGIC) {
+            return Ok((Codec::JsonCompact, 0));
+        }
+        match content.get(MAGIC.len()) {
+            Some(1) => Ok((Codec::MsgPack, MAGIC.len() + 1)),
+            Some(2) => Ok((Codec::Cbor, MAGIC.len() + 1)),
+            Some(3) => Ok((Codec::JsonFramed, MAGIC.len() + 1)),
+            Some(id) => Err(format!("Unknown storage codec id {}", id)),
+            None => Err("Truncated file header".to_string()),
+        }
+    }
+
+    pub(crate) fn encode<T: Serialize + ?Sized>(self, value: &T) -> Result<Vec<u8>, String> {
+        match self {
+            Codec::Json => serde_json::to_vec_pretty(value).map_err(|e| e.to_string()),
+            Codec::JsonCompact | Codec::JsonFramed => {
+                serde_json::to_vec(value).map_err(|e| e.to_string())
+            }
+            Codec::MsgPack => rmp_serde::to_vec_named(value).map_err(|e| e.to_string()),
+            Codec::Cbor => {
+                let mut output = Vec::new();
+                ciborium::ser::into_writer(value, &mut output).map_err(|e| e.to_string())?;
+                Ok(output)
+            }
+        }
+    }
+
+    pub(crate) fn decode<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T, String> {
+        match self {
+            Codec::Json | Codec::JsonCompact | Codec::JsonFramed => {
+                serde_json::from_slice(bytes).map_err(|e| e.to_string())
+            }
+            Codec::MsgPack => rmp_serde::from_slice(bytes).map_err(|e| e.to_string()),
+            Codec::Cbor => ciborium::de::from_reader(bytes).map_err(|e| e.to_string()),
+        }
+    }
+
+    /// Wraps one record of the WAL or the segment file: JSON records are
+    /// lines, binary ones are prefixed with their length.
+    pub(crate) fn frame(self, payload: Vec<u8>) -> Vec<u8> {
+        if self.is_binary() {
+            let mut output = (payload.len() as u32).to_le_bytes().to_vec();
+            output.extend_from_slice(&payload);
+            output
+        } else {
+            let mut output = payload;
+            output.push(b'\n');
+            output
+        }
+    }
+
+    /// The payload of a framed record; `None` if it was cut off by a crash.
+    pub(crate) fn unframe(self, record: &[u8]) -> Option<&[u8]> {
+        if self.is_binary() {
+            let len = u32::from_le_bytes(record.get(..4)?.try_into().ok()?) as usize;
+            record.get(4..).filter(|payload| payload.len() == len)
+        } else {
+            record.strip_suffix(b"\n")
+        }
+    }
+
+    /// Reads the next framed record into `record`. Returns its length, 0 at
+    /// the end of the file.
+    pub(crate) fn read_frame(
+        self,
+        reader: &mut impl BufRead,
+        record: &mut Vec<u8>,
+    ) -> io::Result<usize> {
+        record.clear();
+        if !self.is_binary() {
+            return reader.read_until(b'\n', record);
+        }
+        reader.by_ref().take(4).read_to_end(record)?;
+        if record.len() < 4 {
+            return Ok(record.len());
+        }
+        let len = u32::from_le_bytes([record[0], record[1], record[2], record[3]]) as u64;
+        reader.by_ref().take(len).read_to_end(record)?;
+        Ok(record.len())
+    }
+
+    /// Splits the records of a log held in memory, header excluded.
+    pub(crate) fn frames(self, mut content: &[u8]) -> Vec<&[u8]> {
+        let mut frames = Vec::new();
+        while !content.is_empty() {
+            let len = if self.is_binary() {
+                match content.get(..4) {
+                    Some(prefix) => 4 + u32::from_le_bytes(prefix.try_into().unwrap()) as usize,
+                    None => content.len(),
+                }
+            } else {
+                content
+                    .iter()
+                    .position(|b| *b == b'\n')
+                    .map_or(content.len(), |i| i + 1)
+            };
+            let (frame, rest) = content.split_at(len.min(content.len()));
+            frames.push(frame);
+            content = rest;
+        }
+        frames
+    }
+}
@@ -0,0 +1,71 @@
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use std::borrow::Cow;
+use std::io::{self, Read, Write};
+
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+
+/// Values smaller than this are stored uncompressed: the frame overhead
+/// would eat most of the gain on single WAL records.
+const MIN_COMPRESSED_BYTES: usize = 128;
+
+/// How serialized values are compressed before they are encrypted and
+/// written. Compressed values are recognized by their magic bytes, so
+/// reading never depends on the configured compression.
+#[derive(Clone, Copy)]
+pub(crate) enum Compression {
+    Zstd(i32),
+    Gzip(u32),
+}
+
+impl Compression {
+    /// Parses the `compression` option; `None` for `"none"`.
+    pub(crate) fn from_name(name: &str, level: Option<i32>) -> Result<Option<Self>, String> {
+        match name {
+            "none" => Ok(None),
+            "zstd" => {
+                let level = level.unwrap_or(zstd::DEFAULT_COMPRESSION_LEVEL);
+                if !zstd::compression_level_range().contains(&level) {
+                    return Err(format!("Invalid zstd compression level: {}", level));
+                }
+                Ok(Some(Compression::Zstd(level)))
+            }
+            "gzip" => match level.unwrap_or(6) {
+                level @ 0..=9 => Ok(Some(Compression::Gzip(level as u32))),
+                level => Err(format!("Invalid gzip compression level: {}", level)),
+            },
+            _ => Err(format!("Unknown compression: {}", name)),
+        }
+    }
+
+    pub(crate) fn compress(self, bytes: Vec<u8>) -> io::Result<Vec<u8>> {
+        if bytes.len() < MIN_COMPRESSED_BYTES {
+            return Ok(bytes);
+        }
+        match self {
+            Compression::Zstd(level) => zstd::bulk::compress(&bytes, level),
+            Compression::Gzip(level) => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::new(level));
+                encoder.write_all(&bytes)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+/// Decompresses `bytes` if they start with the magic bytes of a supported
+/// compression format. Serialized values never do: JSON starts with text,
+/// and a MessagePack or CBOR value starting with one of these bytes is a
+/// single-byte integer.
+pub(crate) fn decompress(bytes: &[u8]) -> io::Result<Cow<'_, [u8]>> {
+    let mut output = Vec::new();
+    if bytes.starts_with(ZSTD_MAGIC) {
+        zstd::stream::read::Decoder::new(bytes)?.read_to_end(&mut output)?;
+    } else if bytes.starts_with(GZIP_MAGIC) {
+        GzDecoder::new(bytes).read_to_end(&mut output)?;
+    } else {
+        return Ok(Cow::Borrowed(bytes));
+    }
+    Ok(Cow::Owned(output))
+}
@@ -0,0 +1,158 @@
+use napi::{Error, Result, Status};
+use napi_derive::napi;
+use parking_lot::Mutex;
+use serde_json::Value;
+use std::sync::{Arc, Weak};
+
+use crate::indexes::DocId;
+use crate::{get_value_by_path, project};
+
+/// Where a cursor reads its remaining results from.
+enum Source {
+    /// The data as the query saw it, and the ids of the matches left.
+    Shared {
+        tree: Arc<Value>,
+        ids: std::vec::IntoIter<DocId>,
+    },
+    /// The matches left, copied out when a write was about to change the
+    /// data they were in.
+    Copied(std::vec::IntoIter<Value>),
+}
+
+pub(crate) struct CursorState {
+    source: Source,
+    path: String,
+    select: Option<Vec<String>>,
+}
+
+impl CursorState {
+    fn take(&mut self, n: usize) -> Vec<Value> {
+        match &mut self.source {
+            Source::Shared { tree, ids } => {
+                let collection = match get_value_by_path(tree, &self.path) {
+                    Some(c) => c,
+                    None => return Vec::new(),
+                };
+                ids.by_ref()
+                    .take(n)
+                    .filter_map(|id| id.resolve(collection))
+                    .map(|item| match &self.select {
+                        Some(fields) => project(item, fields),
+                        None => item.clone(),
+                    })
+                    .collect()
+            }
+            Source::Copied(docs) => docs.by_ref().take(n).collect(),
+        }
+    }
+
+    fn remaining(&self) -> usize {
+        match &self.source {
+            Source::Shared { ids, .. } => ids.len(),
+            Source::Copied(docs) => docs.len(),
+        }
+    }
+
+    fn release(&mut self) {
+        self.source = Source::Copied(Vec::new().into_iter());
+    }
+}
+
+/// Hands out the results of a `find` in batches. The cursor keeps the data
+/// as it was when the query ran, so writes made while it is open do not
+/// change what it returns; it only holds the ids of the matches, and
+/// documents are copied out one batch at a time. The first write while it
+/// is open copies out the matches left instead, so that the data is not
+/// copied whole.
+#[napi]
+pub struct FindCursor {
+    state: Arc<Mutex<CursorState>>,
+}
+
+impl FindCursor {
+    pub(crate) fn new(
+        cursors: &OpenCursors,
+        tree: Arc<Value>,
+        path: String,
+        ids: Vec<DocId>,
+        select: Option<Vec<String>>,
+    ) -> Self {
+        let state = Arc::new(Mutex::new(CursorState {
+            source: Source::Shared {
+                tree,
+                ids: ids.into_iter(),
+            },
+            path,
+            select: select.filter(|fields| !fields.is_empty()),
+        }));
+        cursors.register(&state);
+        FindCursor { state }
+    }
+}
+
+#[napi]
+impl FindCursor {
+    /// Returns up to `size` more results, or an empty array once the cursor
+    /// is exhausted.
+    #[napi]
+    pub fn next_batch(&mut self, size: u32) -> Result<Vec<Value>> {
+        if size == 0 {
+            return Err(Error::new(
+                Status::InvalidArg,
+                "Batch size must be at least 1".to_string(),
+            ));
+        }
+        let mut state = self.state.lock();
+        let batch = state.take(size as usize);
+        if state.remaining() == 0 {
+            state.release();
+        }
+        Ok(batch)
+    }
+
+    /// Number of results not yet returned.
+    #[napi(getter)]
+    pub fn remaining(&self) -> u32 {
+        self.state.lock().remaining() as u32
+    }
+
+    /// Releases the snapshot before the cursor is exhausted.
+    #[napi]
+    pub fn close(&mut self) {
+        self.state.lock().release();
+    }
+}
+
+/// The cursors of a database, so that writers can have them let go of the
+/// data before modifying it.
+#[derive(Default)]
+pub(crate) struct OpenCursors(Mutex<Vec<Weak<Mutex<CursorState>>>>);
+
+impl OpenCursors {
+    fn register(&self, state: &Arc<Mutex<CursorState>>) {
+        let mut cursors = self.0.lock();
+        cursors.retain(|cursor| cursor.strong_count() > 0);
+        cursors.push(Arc::downgrade(state));
+    }
+
+    /// Has every cursor still reading `tree` copy out the matches it has
+    /// left, so a writer can modify `tree` in place.
+    pub(crate) fn detach(&self, tree: &Arc<Value>) {
+        let mut cursors = self.0.lock();
+        cursors.retain(|cursor| {
+            let Some(state) = cursor.upgrade() else {
+                return false;
+            };
+            let mut state = state.lock();
+            match &state.source {
+                Source::Shared { tree: held, .. } if Arc::ptr_eq(held, tree) => {
+                    let left = state.take(usize::MAX);
+                    state.source = Source::Copied(left.into_iter());
+                    false
+                }
+                Source::Shared { .. } => true,
+                Source::Copied(_) => false,
+            }
+        });
+    }
+}
@@ -0,0 +1,234 @@
+use memmap2::{Mmap, MmapMut};
+use std::collections::BTreeSet;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+use crate::codec::Codec;
+use crate::{Operation, WalLine};
+
+/// The LSN of the last write the writing process committed, in an 8 byte
+/// file it keeps mapped. Followers compare against it to tell how far
+/// behind they are.
+pub(crate) struct PublishedLsn {
+    map: MmapMut,
+}
+
+impl PublishedLsn {
+    pub(crate) fn create(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        file.set_len(8)?;
+        // SAFETY: only writers map the file for writing, and the writer
+        // lock keeps other processes from writing it at the same time.
+        let map = unsafe { MmapMut::map_mut(&file) }?;
+        Ok(PublishedLsn { map })
+    }
+
+    pub(crate) fn store(&self, lsn: u64) {
+        // SAFETY: the map is page-aligned, at least 8 bytes long and lives
+        // as long as `self`.
+        let cell = unsafe { AtomicU64::from_ptr(self.map.as_ptr() as *mut u64) };
+        cell.store(lsn, Ordering::Release);
+    }
+}
+
+/// The LSN the writer of `path` published, if there is one.
+pub(crate) fn published_lsn(path: &Path) -> Option<u64> {
+    let file = File::open(path).ok()?;
+    // SAFETY: the writer only ever stores whole LSNs into the file, and
+    // never shrinks it.
+    let map = unsafe { Mmap::map(&file) }.ok()?;
+    if map.len() < 8 {
+        return None;
+    }
+    // SAFETY: as above; an atomic load of a native word is sound on
+    // read-only memory.
+    let cell = unsafe { AtomicU64::from_ptr(map.as_ptr() as *mut u64) };
+    Some(cell.load(Ordering::Acquire))
+}
+
+/// How far a follower has read one WAL.
+#[derive(Clone, Default)]
+struct WalTail {
+    /// Bytes read, up to the end of the last complete record.
+    offset: u64,
+    /// The header and first record of the WAL as they were read. `save`
+    /// truncates the WAL and starts it over, so a different head means
+    /// the writer saved since.
+    head: Vec<u8>,
+    codec: Option<Codec>,
+    /// LSN of the last record read.
+    last_lsn: u64,
+}
+
+/// What the WALs hold beyond what a follower has applied.
+pub(crate) struct Tailed {
+    /// The new writes, in LSN order.
+    pub(crate) records: Vec<(u64, Operation)>,
+    /// The writer saved writes the follower never read, so it has to load
+    /// the files again.
+    pub(crate) reload: bool,
+    /// The LSN of the last write in the files.
+    pub(crate) latest_lsn: u64,
+}
+
+/// Keeps a read-only copy of a database up to date with the WALs of the
+/// process that writes it, reading only what was appended since.
+#[derive(Clone)]
+pub(crate) struct Follower {
+    paths: Vec<PathBuf>,
+    tails: Vec<WalTail>,
+    /// Writes to the whole sharded object applied beyond the LSN every WAL
+    /// was read up to. They are logged to every WAL, and applied once.
+    shared: BTreeSet<u64>,
+    /// The last write to the whole sharded object. A write to one shard
+    /// that shows up after it was applied may have to come before it.
+    last_shared_lsn: u64,
+    pub(crate) synced_at: Instant,
+}
+
+impl Follower {
+    pub(crate) fn new(paths: Vec<PathBuf>) -> Self {
+        Follower {
+            tails: vec![WalTail::default(); paths.len()],
+            paths,
+            shared: BTreeSet::new(),
+            last_shared_lsn: 0,
+            synced_at: Instant::now(),
+        }
+    }
+
+    /// Starts over from the WALs as `load` read them, one per path, after
+    /// it applied them up to `lsn`.
+    pub(crate) fn reset(&mut self, logs: &[Vec<u8>], lsn: u64) {
+        for (tail, content) in self.tails.iter_mut().zip(logs) {
+            *tail = WalTail {
+                last_lsn: lsn,
+                ..WalTail::default()
+            };
+            if let Ok((codec, header_len)) = Codec::detect(content) {
+                let frames = codec.frames(&content[header_len..]);
+                let complete = frames
+                    .iter()
+                    .take_while(|frame| codec.unframe(frame).is_some())
+                    .map(|frame| frame.len())
+                    .collect::<Vec<_>>();
+                let head_len = header_len + complete.first().copied().unwrap_or(0);
+                tail.head = content[..head_len].to_vec();
+                tail.offset = (header_len + complete.iter().sum::<usize>()) as u64;
+                tail.codec = (!content.is_empty()).then_some(codec);
+            }
+        }
+        self.shared.clear();
+        self.last_shared_lsn = 0;
+        self.synced_at = Instant::now();
+    }
+
+    /// Reads what the WALs gained since they were last read. Writes up to
+    /// `applied` are already in memory.
+    pub(crate) fn poll(
+        &mut self,
+        applied: u64,
+        decode: impl Fn(Codec, &[u8]) -> Option<WalLine>,
+    ) -> io::Result<Tailed> {
+        let mut tailed = Tailed {
+            records: Vec::new(),
+            reload: false,
+            latest_lsn: applied,
+        };
+        for (tail, path) in self.tails.iter_mut().zip(&self.paths) {
+            let mut file = match File::open(path) {
+                Ok(file) => file,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            let len = file.metadata()?.len();
+            let mut head = vec![0; tail.head.len()];
+            if len < tail.offset || file.read_exact(&mut head).is_err() || head != tail.head {
+                *tail = WalTail {
+                    last_lsn: tail.last_lsn,
+                    ..WalTail::default()
+                };
+            }
+            if len == tail.offset {
+                continue;
+            }
+            let mut content = Vec::new();
+            file.seek(SeekFrom::Start(tail.offset))?;
+            file.read_to_end(&mut content)?;
+
+            let mut start = 0;
+            let codec = match tail.codec {
+                Some(codec) => codec,
+                None => match Codec::detect(&content) {
+                    // The writer is still writing the header.
+                    Err(_) => continue,
+                    Ok((codec, header_len)) => {
+                        start = header_len;
+                        tail.head = content[..header_len].to_vec();
+                        tail.codec = Some(codec);
+                        codec
+                    }
+                },
+            };
+            let header_len = codec.header().len();
+            for frame in codec.frames(&content[start..]) {
+                // A record the writer is still appending is read next time.
+                let payload = match codec.unframe(frame) {
+                    Some(payload) => payload,
+                    None => break,
+                };
+                if tail.head.len() == header_len {
+                    tail.head.extend_from_slice(frame);
+                }
+                start += frame.len();
+                let (lsn, op) = match decode(codec, payload) {
+                    Some(WalLine::Record(record)) => (record.lsn, record.op),
+                    Some(WalLine::Legacy(op)) => (tail.last_lsn + 1, Some(op)),
+                    None => continue,
+                };
+                tail.last_lsn = tail.last_lsn.max(lsn);
+                tailed.latest_lsn = tailed.latest_lsn.max(lsn);
+                match op {
+                    Some(op) => tailed.records.push((lsn, op)),
+                    // Everything up to a checkpoint was saved to the data
+                    // files and dropped from the WAL.
+                    None => tailed.reload |= lsn > applied,
+                }
+            }
+            tail.offset += start as u64;
+        }
+
+        tailed.records.sort_by_key(|(lsn, _)| *lsn);
+        tailed.records.dedup_by_key(|(lsn, _)| *lsn);
+        let shared = &self.shared;
+        tailed.records.retain(|(lsn, _)| !shared.contains(lsn));
+        // A write that was logged after a write to the whole object but
+        // only reached the disk now cannot be applied in order.
+        tailed.reload |= tailed
+            .records
+            .iter()
+            .any(|(lsn, _)| *lsn <= applied && *lsn <= self.last_shared_lsn);
+        Ok(tailed)
+    }
+
+    /// Records that the write `lsn`, which touched every shard, was applied.
+    pub(crate) fn applied_shared(&mut self, lsn: u64) {
+        self.shared.insert(lsn);
+        self.last_shared_lsn = self.last_shared_lsn.max(lsn);
+    }
+
+    /// Forgets shared writes every WAL has been read past.
+    pub(crate) fn synced(&mut self) {
+        let floor = self.tails.iter().map(|t| t.last_lsn).min().unwrap_or(0);
+        self.shared = self.shared.split_off(&(floor + 1));
+        self.synced_at = Instant::now();
+    }
+}
@@ -0,0 +1,323 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::get_value_by_path;
+use crate::indexes::{CollectionIndex, DocId, SyncState};
+
+/// Mean Earth radius in meters.
+const EARTH_RADIUS_M: f64 = 6_371_008.8;
+
+/// A `{ lat, lng }` point in degrees.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct GeoPoint {
+    pub(crate) lat: f64,
+    pub(crate) lng: f64,
+}
+
+impl GeoPoint {
+    pub(crate) fn from_value(value: &Value) -> Option<GeoPoint> {
+        let lat = value.get("lat")?.as_f64()?;
+        let lng = value.get("lng")?.as_f64()?;
+        if !(-90.0..=90.0).contains(&lat) || !(-180.0..=180.0).contains(&lng) {
+            return None;
+        }
+        Some(GeoPoint { lat, lng })
+    }
+}
+
+/// Great-circle distance in meters.
+pub(crate) fn haversine_m(a: GeoPoint, b: GeoPoint) -> f64 {
+    let (lat1, lat2) = (a.lat.to_radians(), b.lat.to_radians());
+    let d_lat = lat2 - lat1;
+    let d_lng = (b.lng - a.lng).to_radians();
+    let h = (d_lat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (d_lng / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_M * h.sqrt().min(1.0).asin()
+}
+
+/// The points stored in a field: a single point or an array of points.
+fn field_points(value: &Value) -> Vec<GeoPoint> {
+    match value {
+        Value::Array(elems) => elems.iter().filter_map(GeoPoint::from_value).collect(),
+        other => GeoPoint::from_value(other).into_iter().collect(),
+    }
+}
+
+/// Whether `op` is one of the geo operators.
+pub(crate) fn is_geo_operator(op: &str) -> bool {
+    matches!(op, "$near" | "$withinRadius" | "$withinBox")
+}
+
+/// A parsed geo condition.
+enum GeoShape {
+    /// Ring around `center`, distances in meters.
+    Circle {
+        center: GeoPoint,
+        min: f64,
+        max: f64,
+    },
+    /// `min.lng > max.lng` crosses the antimeridian.
+    Box { min: GeoPoint, max: GeoPoint },
+}
+
+impl GeoShape {
+    /// `$near: { point, maxDistance?, minDistance? }` (or just a point),
+    /// `$withinRadius: { center, radius }`, `$withinBox: { min, max }`.
+    fn parse(op: &str, target: &Value) -> Option<GeoShape> {
+        let distance = |key: &str| target.get(key).and_then(|d| d.as_f64());
+        match op {
+            "$near" => {
+                let center = match target.get("point") {
+                    Some(point) => GeoPoint::from_value(point)?,
+                    None => GeoPoint::from_value(target)?,
+                };
+                Some(GeoShape::Circle {
+                    center,
+                    min: distance("minDistance").unwrap_or(0.0),
+                    max: distance("maxDistance").unwrap_or(f64::INFINITY),
+                })
+            }
+            "$withinRadius" => Some(GeoShape::Circle {
+                center: GeoPoint::from_value(target.get("center")?)?,
+                min: 0.0,
+                max: distance("radius")?,
+            }),
+            "$withinBox" => {
+                let min = GeoPoint::from_value(target.get("min")?)?;
+                let max = GeoPoint::from_value(target.get("max")?)?;
+                if min.lat > max.lat {
+                    return None;
+                }
+                Some(GeoShape::Box { min, max })
+            }
+            _ => None,
+        }
+    }
+
+    fn contains(&self, point: GeoPoint) -> bool {
+        match self {
+            GeoShape::Circle { center, min, max } => {
+                let d = haversine_m(*center, point);
+                d >= *min && d <= *max
+            }
+            GeoShape::Box { min, max } => {
+                let in_lng = if min.lng <= max.lng {
+                    point.lng >= min.lng && point.lng <= max.lng
+                } else {
+                    point.lng >= min.lng || point.lng <= max.lng
+                };
+                in_lng && point.lat >= min.lat && point.lat <= max.lat
+            }
+        }
+    }
+
+    /// A lat/lng rectangle holding the whole shape, as a latitude range and
+    /// one or two longitude ranges.
+    fn bounds(&self) -> (f64, f64, Vec<(f64, f64)>) {
+        match self {
+            GeoShape::Circle { center, max, .. } => {
+                let d_lat = (max / EARTH_RADIUS_M).to_degrees();
+                let (lo, hi) = (center.lat - d_lat, center.lat + d_lat);
+                if !d_lat.is_finite() || lo <= -90.0 || hi >= 90.0 {
+                    // Reaches a pole: every longitude is in range.
+                    return (lo.max(-90.0), hi.min(90.0), vec![(-180.0, 180.0)]);
+                }
+                let d_lng = (d_lat / lo.abs().max(hi.abs()).to_radians().cos()).min(180.0);
+                (lo, hi, lng_ranges(center.lng - d_lng, center.lng + d_lng))
+            }
+            GeoShape::Box { min, max } => {
+                let ranges = if min.lng <= max.lng {
+                    vec![(min.lng, max.lng)]
+                } else {
+                    vec![(min.lng, 180.0), (-180.0, max.lng)]
+                };
+                (min.lat, max.lat, ranges)
+            }
+        }
+    }
+}
+
+/// Splits a longitude range that wraps around the antimeridian.
+fn lng_ranges(lo: f64, hi: f64) -> Vec<(f64, f64)> {
+    if hi - lo >= 360.0 {
+        vec![(-180.0, 180.0)]
+    } else if lo < -180.0 {
+        vec![(lo + 360.0, 180.0), (-180.0, hi)]
+    } else if hi > 180.0 {
+        vec![(lo, 180.0), (-180.0, hi - 360.0)]
+    } else {
+        vec![(lo, hi)]
+    }
+}
+
+/// Matches a geo operator against a field holding a point or an array of
+/// points. An invalid condition matches nothing.
+pub(crate) fn match_geo(value: &Value, op: &str, target: &Value) -> bool {
+    match GeoShape::parse(op, target) {
+        Some(shape) => field_points(value).into_iter().any(|p| shape.contains(p)),
+        None => false,
+    }
+}
+
+/// The `$near` condition of a query, if any: results are ordered by their
+/// distance to its point unless an explicit sort is given.
+pub(crate) fn near_point(query: &Value) -> Option<(&str, GeoPoint)> {
+    query.as_object()?.iter().find_map(|(field, condition)| {
+        let target = condition.get("$near")?;
+        match GeoShape::parse("$near", target)? {
+            GeoShape::Circle { center, .. } => Some((field.as_str(), center)),
+            GeoShape::Box { .. } => None,
+        }
+    })
+}
+
+/// Distance from `point` to the closest point stored at `field` of `doc`.
+pub(crate) fn distance_to(doc: &Value, field: &str, point: GeoPoint) -> f64 {
+    get_value_by_path(doc, field)
+        .map(field_points)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|p| haversine_m(point, p))
+        .fold(f64::INFINITY, f64::min)
+}
+
+type Cell = (i32, i32);
+
+/// A grid index over the points of one field: documents are bucketed by the
+/// `cell_size`-degree cell their points fall in.
+pub(crate) struct GeoIndex {
+    pub(crate) name: String,
+    pub(crate) collection: String,
+    pub(crate) field: String,
+    pub(crate) cell_size: f64,
+    cells: BTreeMap<Cell, BTreeSet<DocId>>,
+    sync: SyncState,
+}
+
+impl GeoIndex {
+    pub(crate) fn new(
+        name: String,
+        collection: String,
+        field: String,
+        cell_size: f64,
+    ) -> std::result::Result<Self, String> {
+        if !(cell_size > 0.0 && cell_size <= 180.0) {
+            return Err(format!("Invalid geo index cell size {}", cell_size));
+        }
+        Ok(GeoIndex {
+            name,
+            collection,
+            field,
+            cell_size,
+            cells: BTreeMap::new(),
+            sync: SyncState::default(),
+        })
+    }
+
+    fn cell(&self, lat: f64, lng: f64) -> Cell {
+        (
+            (lat / self.cell_size).floor() as i32,
+            (lng / self.cell_size).floor() as i32,
+        )
+    }
+
+    fn doc_cells(&self, doc: &Value) -> Vec<Cell> {
+        let mut cells: Vec<Cell> = get_value_by_path(doc, &self.field)
+            .map(field_points)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|p| self.cell(p.lat, p.lng))
+            .collect();
+        cells.sort();
+        cells.dedup();
+        cells
+    }
+
+    /// Picks the first geo condition on the indexed field of `query` and
+    /// returns the documents in the cells it overlaps, sorted and
+    /// deduplicated. The result is a superset of the matches.
+    pub(crate) fn candidates(&self, query: &Map<String, Value>) -> Option<Vec<DocId>> {
+        let condition = query.get(&self.field)?.as_object()?;
+        let shape = condition
+            .iter()
+            .find_map(|(op, target)| GeoShape::parse(op, target))?;
+
+        let (lat_lo, lat_hi, lng_ranges) = shape.bounds();
+        let mut ids = Vec::new();
+        for (lng_lo, lng_hi) in lng_ranges {
+            let (row_lo, col_lo) = self.cell(lat_lo, lng_lo);
+            let (row_hi, col_hi) = self.cell(lat_hi, lng_hi);
+            for row in row_lo..=row_hi {
+                for (_, docs) in self.cells.range((row, col_lo)..=(row, col_hi)) {
+                    ids.extend(docs.iter().cloned());
+                }
+            }
+        }
+        ids.sort();
+        ids.dedup();
+        Some(ids)
+    }
+
+    pub(crate) fn to_snapshot(&self) -> GeoIndexSnapshot {
+        GeoIndexSnapshot {
+            name: self.name.clone(),
+            collection: self.collection.clone(),
+            field: self.field.clone(),
+            cell_size: self.cell_size,
+        }
+    }
+
+    /// Geo indexes are persisted by definition only and rebuilt on first use.
+    pub(crate) fn from_snapshot(snapshot: GeoIndexSnapshot) -> Option<Self> {
+        GeoIndex::new(
+            snapshot.name,
+            snapshot.collection,
+            snapshot.field,
+            snapshot.cell_size,
+        )
+        .ok()
+    }
+}
+
+impl CollectionIndex for GeoIndex {
+    fn collection(&self) -> &str {
+        &self.collection
+    }
+
+    fn sync(&self) -> &SyncState {
+        &self.sync
+    }
+
+    fn sync_mut(&mut self) -> &mut SyncState {
+        &mut self.sync
+    }
+
+    fn clear(&mut self) {
+        self.cells.clear();
+    }
+
+    fn insert_doc(&mut self, id: DocId, doc: &Value) {
+        for cell in self.doc_cells(doc) {
+            self.cells.entry(cell).or_default().insert(id.clone());
+        }
+    }
+
+    fn remove_doc(&mut self, id: &DocId, doc: &Value) {
+        for cell in self.doc_cells(doc) {
+            if let Some(docs) = self.cells.get_mut(&cell) {
+                docs.remove(id);
+                if docs.is_empty() {
+                    self.cells.remove(&cell);
+                }
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct GeoIndexSnapshot {
+    name: String,
+    collection: String,
+    field: String,
+    cell_size: f64,
+}
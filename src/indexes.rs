@@ -0,0 +1,903 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound;
+
+use crate::geo::{GeoIndex, GeoIndexSnapshot};
+use crate::text::{TextIndex, TextIndexSnapshot};
+use crate::{compare_json, get_value_by_path, matches_query};
+
+/// Identifies a document inside an indexed collection: its position for
+/// array collections, its key for object collections.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(untagged)]
+pub(crate) enum DocId {
+    Pos(usize),
+    Key(String),
+}
+
+impl DocId {
+    pub(crate) fn resolve<'a>(&self, collection: &'a Value) -> Option<&'a Value> {
+        match (self, collection) {
+            (DocId::Pos(i), Value::Array(arr)) => arr.get(*i),
+            (DocId::Key(k), Value::Object(map)) => map.get(k),
+            _ => None,
+        }
+    }
+}
+
+/// An indexed field value. `None` stands for a missing field, which sorts
+/// first, matching `sort_json`. Serialized as `[]` or `[value]` so that a
+/// missing field stays distinct from `null`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "Vec<Value>", into = "Vec<Value>")]
+pub(crate) struct KeyPart(pub(crate) Option<Value>);
+
+impl From<Vec<Value>> for KeyPart {
+    fn from(v: Vec<Value>) -> Self {
+        KeyPart(v.into_iter().next())
+    }
+}
+
+impl From<KeyPart> for Vec<Value> {
+    fn from(k: KeyPart) -> Self {
+        k.0.into_iter().collect()
+    }
+}
+
+fn type_rank(v: Option<&Value>) -> u8 {
+    match v {
+        None => 0,
+        Some(Value::Null) => 1,
+        Some(Value::Bool(_)) => 2,
+        Some(Value::Number(_)) => 3,
+        Some(Value::String(_)) => 4,
+        Some(Value::Array(_)) => 5,
+        Some(Value::Object(_)) => 6,
+    }
+}
+
+impl KeyPart {
+    fn rank(&self) -> u8 {
+        type_rank(self.0.as_ref())
+    }
+}
+
+impl Ord for KeyPart {
+    fn cmp(&self, other: &Self) -> Ordering {
+        compare_parts(self.0.as_ref(), other.0.as_ref())
+    }
+}
+
+/// Total order on (possibly missing) field values. Values of the same type
+/// follow `compare_json`; values of different types are ordered by type.
+/// Shared by index keys and `sort_json`, so index walks and sorts agree.
+pub(crate) fn compare_parts(a: Option<&Value>, b: Option<&Value>) -> Ordering {
+    let (ra, rb) = (type_rank(a), type_rank(b));
+    if ra != rb {
+        return ra.cmp(&rb);
+    }
+    match (a, b) {
+        (Some(Value::Bool(a)), Some(Value::Bool(b))) => a.cmp(b),
+        (Some(a @ Value::Number(_)), Some(b @ Value::Number(_))) => match compare_json(a, b) {
+            Some(c) => c.cmp(&0),
+            None => {
+                let fa = a.as_f64().unwrap_or(f64::NAN);
+                let fb = b.as_f64().unwrap_or(f64::NAN);
+                fa.total_cmp(&fb)
+            }
+        },
+        (Some(Value::String(a)), Some(Value::String(b))) => a.cmp(b),
+        (Some(a @ Value::Array(_)), Some(b @ Value::Array(_)))
+        | (Some(a @ Value::Object(_)), Some(b @ Value::Object(_))) => {
+            a.to_string().cmp(&b.to_string())
+        }
+        _ => Ordering::Equal,
+    }
+}
+
+impl PartialOrd for KeyPart {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for KeyPart {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for KeyPart {}
+
+/// A full index key: one part per indexed field.
+pub(crate) type IndexKey = Vec<KeyPart>;
+
+/// Upper bound on the number of key prefixes a compound lookup expands to
+/// (e.g. `$in` on several leading fields) before falling back to fewer fields.
+const MAX_PREFIXES: usize = 256;
+
+/// An ordered index over one or more fields of the documents in a
+/// collection. Array values are indexed element by element (multikey).
+///
+/// A sparse index skips documents missing all of its fields, and a partial
+/// index only holds the documents matching its `filter`.
+pub(crate) struct Index {
+    pub(crate) name: String,
+    pub(crate) collection: String,
+    pub(crate) fields: Vec<String>,
+    pub(crate) sparse: bool,
+    pub(crate) filter: Option<Value>,
+    entries: BTreeMap<IndexKey, BTreeSet<DocId>>,
+    multikey: bool,
+    sync: SyncState,
+}
+
+/// Tracks whether an index still mirrors its collection.
+pub(crate) struct SyncState {
+    /// Set when a write could not be applied incrementally; the index is
+    /// rebuilt before its next use.
+    pub(crate) stale: bool,
+    /// Length of an array collection when the index was last in sync.
+    collection_len: usize,
+}
+
+impl Default for SyncState {
+    fn default() -> Self {
+        SyncState {
+            stale: true,
+            collection_len: 0,
+        }
+    }
+}
+
+/// Where a write lands relative to an index's collection.
+enum WriteTarget<'p> {
+    Unrelated,
+    /// The collection itself or one of its ancestors.
+    Collection,
+    /// Inside the document with this key or position.
+    Doc(&'p str),
+}
+
+fn write_target<'p>(collection: &str, path: &'p str) -> WriteTarget<'p> {
+    let rest = if path.is_empty() || path == collection {
+        return WriteTarget::Collection;
+    } else if collection.is_empty() {
+        Some(path)
+    } else {
+        path.strip_prefix(collection)
+            .and_then(|r| r.strip_prefix('.'))
+    };
+    match rest {
+        Some(r) => WriteTarget::Doc(r.split('.').next().unwrap_or(r)),
+        None if collection.starts_with(&format!("{}.", path)) => WriteTarget::Collection,
+        None => WriteTarget::Unrelated,
+    }
+}
+
+/// An index over the documents of one collection, kept in sync with writes
+/// document by document.
+pub(crate) trait CollectionIndex {
+    fn collection(&self) -> &str;
+    fn sync(&self) -> &SyncState;
+    fn sync_mut(&mut self) -> &mut SyncState;
+    fn clear(&mut self);
+    fn insert_doc(&mut self, id: DocId, doc: &Value);
+    fn remove_doc(&mut self, id: &DocId, doc: &Value);
+
+    fn rebuild(&mut self, root: &Value) {
+        self.clear();
+        let mut len = 0;
+        match get_value_by_path(root, self.collection()) {
+            Some(Value::Array(arr)) => {
+                len = arr.len();
+                for (i, doc) in arr.iter().enumerate() {
+                    self.insert_doc(DocId::Pos(i), doc);
+                }
+            }
+            Some(Value::Object(map)) => {
+                for (k, doc) in map {
+                    self.insert_doc(DocId::Key(k.clone()), doc);
+                }
+            }
+            _ => {}
+        }
+        let sync = self.sync_mut();
+        sync.collection_len = len;
+        sync.stale = false;
+    }
+
+    /// Drops the entries of the document a write to `path` is about to change.
+    fn before_write(&mut self, root: &Value, path: &str) {
+        if self.sync().stale {
+            return;
+        }
+        let doc_seg = match write_target(self.collection(), path) {
+            WriteTarget::Unrelated => return,
+            WriteTarget::Collection => {
+                self.sync_mut().stale = true;
+                return;
+            }
+            WriteTarget::Doc(seg) => seg,
+        };
+        match get_value_by_path(root, self.collection()) {
+            Some(Value::Array(arr)) => {
+                if let Ok(idx) = doc_seg.parse::<usize>() {
+                    if let Some(doc) = arr.get(idx) {
+                        self.remove_doc(&DocId::Pos(idx), doc);
+                    }
+                }
+            }
+            Some(Value::Object(map)) => {
+                if let Some(doc) = map.get(doc_seg) {
+                    self.remove_doc(&DocId::Key(doc_seg.to_string()), doc);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Indexes the document a write to `path` has changed.
+    fn after_write(&mut self, root: &Value, path: &str) {
+        if self.sync().stale {
+            return;
+        }
+        let doc_seg = match write_target(self.collection(), path) {
+            WriteTarget::Doc(seg) => seg,
+            _ => return,
+        };
+        match get_value_by_path(root, self.collection()) {
+            Some(Value::Array(arr)) => {
+                // Removals and padding shift positions, so only in-place
+                // updates are applied incrementally.
+                if arr.len() != self.sync().collection_len {
+                    self.sync_mut().stale = true;
+                    return;
+                }
+                if let Ok(idx) = doc_seg.parse::<usize>() {
+                    if let Some(doc) = arr.get(idx) {
+                        self.insert_doc(DocId::Pos(idx), doc);
+                    }
+                }
+            }
+            Some(Value::Object(map)) => {
+                if let Some(doc) = map.get(doc_seg) {
+                    self.insert_doc(DocId::Key(doc_seg.to_string()), doc);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Index {
+    pub(crate) fn new(
+        name: String,
+        collection: String,
+        fields: Vec<String>,
+        sparse: bool,
+        filter: Option<Value>,
+    ) -> Self {
+        Index {
+            name,
+            collection,
+            fields,
+            sparse,
+            filter,
+            entries: BTreeMap::new(),
+            multikey: false,
+            sync: SyncState::default(),
+        }
+    }
+
+    /// Computes the keys of a document: the cartesian product of the values
+    /// of each field, where an array contributes each of its elements.
+    /// Returns `None` for documents the index does not hold.
+    fn doc_keys(&self, doc: &Value) -> Option<(Vec<IndexKey>, bool)> {
+        if let Some(filter) = &self.filter {
+            if !matches_query(doc, filter) {
+                return None;
+            }
+        }
+        let values: Vec<Option<&Value>> = self
+            .fields
+            .iter()
+            .map(|f| get_value_by_path(doc, f))
+            .collect();
+        if self.sparse && values.iter().all(|v| v.is_none()) {
+            return None;
+        }
+
+        let mut multikey = false;
+        let mut keys: Vec<IndexKey> = vec![Vec::with_capacity(self.fields.len())];
+        for value in values {
+            let parts: Vec<KeyPart> = match value {
+                Some(Value::Array(elems)) if !elems.is_empty() => {
+                    multikey = true;
+                    elems.iter().map(|e| KeyPart(Some(e.clone()))).collect()
+                }
+                other => vec![KeyPart(other.cloned())],
+            };
+            if let [part] = parts.as_slice() {
+                for key in &mut keys {
+                    key.push(part.clone());
+                }
+                continue;
+            }
+            keys = keys
+                .into_iter()
+                .flat_map(|prefix| {
+                    parts.iter().map(move |part| {
+                        let mut key = prefix.clone();
+                        key.push(part.clone());
+                        key
+                    })
+                })
+                .collect();
+        }
+        if multikey {
+            keys.sort();
+            keys.dedup();
+        }
+        Some((keys, multikey))
+    }
+
+    /// Collects the documents whose key starts with `prefix` and whose next
+    /// part (if `next` is given) satisfies it.
+    fn scan(&self, prefix: &[KeyPart], next: Option<&PartScan>, out: &mut Vec<DocId>) {
+        if let Some(PartScan::Points(points)) = next {
+            for point in points {
+                let mut key = prefix.to_vec();
+                key.push(point.clone());
+                self.scan(&key, None, out);
+            }
+            return;
+        }
+
+        let n = prefix.len();
+        let mut start: IndexKey = prefix.to_vec();
+        if let Some(PartScan::Range {
+            lower: Some((k, _)),
+            ..
+        }) = next
+        {
+            start.push(k.clone());
+        }
+
+        for (key, ids) in self
+            .entries
+            .range::<IndexKey, _>((Bound::Included(&start), Bound::Unbounded))
+        {
+            if key.len() < n || key[..n] != *prefix {
+                break;
+            }
+            if let Some(PartScan::Range { lower, upper, rank }) = next {
+                let part = &key[n];
+                if let Some((u, inclusive)) = upper {
+                    if part > u || (!inclusive && part == u) {
+                        break;
+                    }
+                }
+                if let Some((l, false)) = lower {
+                    if part == l {
+                        continue;
+                    }
+                }
+                if part.rank() != *rank {
+                    continue;
+                }
+            }
+            out.extend(ids.iter().cloned());
+        }
+    }
+
+    /// All documents in index order.
+    pub(crate) fn ordered_ids(&self, descending: bool) -> Box<dyn Iterator<Item = &DocId> + '_> {
+        if descending {
+            Box::new(self.entries.values().rev().flat_map(|ids| ids.iter()))
+        } else {
+            Box::new(self.entries.values().flat_map(|ids| ids.iter()))
+        }
+    }
+
+    /// Plans a lookup for `query`: key prefixes built from equality conditions
+    /// on the leading fields, optionally followed by a condition on the next
+    /// field. Returns the plan and the number of fields it constrains.
+    fn plan(
+        &self,
+        query: &serde_json::Map<String, Value>,
+    ) -> Option<(Vec<IndexKey>, Option<PartScan>, usize)> {
+        if !self.covers(query) {
+            return None;
+        }
+        let mut prefixes: Vec<IndexKey> = vec![Vec::new()];
+        for (used, field) in self.fields.iter().enumerate() {
+            let scan = match query
+                .get(field)
+                .and_then(|condition| part_scan(condition, self.multikey))
+            {
+                // Documents without the field are not in a sparse index.
+                Some(PartScan::Points(points))
+                    if self.sparse && points.iter().any(|p| p.0.is_none()) =>
+                {
+                    None
+                }
+                other => other,
+            };
+            let scan = match scan {
+                Some(s) => s,
+                None => return (used > 0).then_some((prefixes, None, used)),
+            };
+            match scan {
+                PartScan::Points(points) if prefixes.len() * points.len() <= MAX_PREFIXES => {
+                    prefixes = prefixes
+                        .into_iter()
+                        .flat_map(|prefix| {
+                            points.iter().map(move |p| {
+                                let mut key = prefix.clone();
+                                key.push(p.clone());
+                                key
+                            })
+                        })
+                        .collect();
+                }
+                scan => return Some((prefixes, Some(scan), used + 1)),
+            }
+        }
+        let used = self.fields.len();
+        Some((prefixes, None, used))
+    }
+
+    fn same_definition(&self, other: &Index) -> bool {
+        self.collection == other.collection
+            && self.fields == other.fields
+            && self.sparse == other.sparse
+            && self.filter == other.filter
+    }
+
+    pub(crate) fn to_snapshot(&self) -> IndexSnapshot {
+        IndexSnapshot {
+            name: self.name.clone(),
+            collection: self.collection.clone(),
+            fields: self.fields.clone(),
+            sparse: self.sparse,
+            filter: self.filter.clone(),
+            multikey: self.multikey,
+            collection_len: self.sync.collection_len,
+            entries: self
+                .entries
+                .iter()
+                .map(|(key, ids)| (key.clone(), ids.iter().cloned().collect()))
+                .collect(),
+        }
+    }
+
+    /// Restores an index from a snapshot. When `consistent` is false only the
+    /// definition is kept and the index is rebuilt on first use.
+    fn from_snapshot(snapshot: IndexSnapshot, consistent: bool) -> Self {
+        let mut index = Index::new(
+            snapshot.name,
+            snapshot.collection,
+            snapshot.fields,
+            snapshot.sparse,
+            snapshot.filter,
+        );
+        if consistent {
+            index.entries = snapshot
+                .entries
+                .into_iter()
+                .map(|(key, ids)| (key, ids.into_iter().collect()))
+                .collect();
+            index.multikey = snapshot.multikey;
+            index.sync.collection_len = snapshot.collection_len;
+            index.sync.stale = false;
+        }
+        index
+    }
+
+    /// Whether every document matching `query` is guaranteed to be in the
+    /// index. A partial filter is covered when the query repeats each of its
+    /// conditions, or constrains a field the filter requires to exist.
+    fn covers(&self, query: &serde_json::Map<String, Value>) -> bool {
+        let filter = match &self.filter {
+            Some(Value::Object(filter)) => filter,
+            Some(_) => return false,
+            None => return true,
+        };
+        filter
+            .iter()
+            .all(|(field, condition)| match query.get(field) {
+                Some(q) if q == condition => true,
+                Some(q) => {
+                    let requires_field = condition.as_object().is_some_and(|c| {
+                        c.len() == 1 && c.get("$exists") == Some(&Value::Bool(true))
+                    });
+                    requires_field && excludes_missing(q)
+                }
+                None => false,
+            })
+    }
+}
+
+impl CollectionIndex for Index {
+    fn collection(&self) -> &str {
+        &self.collection
+    }
+
+    fn sync(&self) -> &SyncState {
+        &self.sync
+    }
+
+    fn sync_mut(&mut self) -> &mut SyncState {
+        &mut self.sync
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.multikey = false;
+    }
+
+    fn insert_doc(&mut self, id: DocId, doc: &Value) {
+        let (keys, multikey) = match self.doc_keys(doc) {
+            Some(k) => k,
+            None => return,
+        };
+        self.multikey |= multikey;
+        for key in keys {
+            self.entries.entry(key).or_default().insert(id.clone());
+        }
+    }
+
+    fn remove_doc(&mut self, id: &DocId, doc: &Value) {
+        let (keys, _) = match self.doc_keys(doc) {
+            Some(k) => k,
+            None => return,
+        };
+        for key in keys {
+            if let Some(ids) = self.entries.get_mut(&key) {
+                ids.remove(id);
+                if ids.is_empty() {
+                    self.entries.remove(&key);
+                }
+            }
+        }
+    }
+}
+
+/// How a query condition constrains one key part.
+enum PartScan {
+    Points(Vec<KeyPart>),
+    Range {
+        lower: Option<(KeyPart, bool)>,
+        upper: Option<(KeyPart, bool)>,
+        rank: u8,
+    },
+}
+
+/// Serialized form of an index, written next to the data file by `save()`.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct IndexSnapshot {
+    name: String,
+    collection: String,
+    fields: Vec<String>,
+    sparse: bool,
+    filter: Option<Value>,
+    multikey: bool,
+    collection_len: usize,
+    entries: Vec<(IndexKey, Vec<DocId>)>,
+}
+
+/// The index snapshot file: the indexes plus the checksum of the data file
+/// and the LSN they were taken at.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct IndexSnapshotFile {
+    pub(crate) version: u32,
+    pub(crate) lsn: u64,
+    pub(crate) checksum: u32,
+    pub(crate) indexes: Vec<IndexSnapshot>,
+    #[serde(default)]
+    pub(crate) text_indexes: Vec<TextIndexSnapshot>,
+    #[serde(default)]
+    pub(crate) geo_indexes: Vec<GeoIndexSnapshot>,
+}
+
+pub(crate) const INDEX_SNAPSHOT_VERSION: u32 = 1;
+
+/// The set of indexes registered on a database.
+#[derive(Default)]
+pub(crate) struct IndexSet {
+    indexes: Vec<Index>,
+    text: Vec<TextIndex>,
+    geo: Vec<GeoIndex>,
+}
+
+impl IndexSet {
+    pub(crate) fn add(&mut self, mut index: Index, root: &Value) {
+        if let Some(existing) = self.indexes.iter().find(|i| i.name == index.name) {
+            // Re-registering a restored index must not throw its entries away.
+            if existing.same_definition(&index) {
+                return;
+            }
+        }
+        index.rebuild(root);
+        self.remove(&index.name);
+        self.indexes.push(index);
+    }
+
+    pub(crate) fn add_text(&mut self, mut index: TextIndex, root: &Value) {
+        if let Some(existing) = self.text.iter().find(|i| i.name == index.name) {
+            if existing.collection == index.collection && existing.config == index.config {
+                return;
+            }
+        }
+        index.rebuild(root);
+        self.remove(&index.name);
+        self.text.push(index);
+    }
+
+    pub(crate) fn add_geo(&mut self, mut index: GeoIndex, root: &Value) {
+        if let Some(existing) = self.geo.iter().find(|i| i.name == index.name) {
+            if existing.collection == index.collection
+                && existing.field == index.field
+                && existing.cell_size == index.cell_size
+            {
+                return;
+            }
+        }
+        index.rebuild(root);
+        self.remove(&index.name);
+        self.geo.push(index);
+    }
+
+    pub(crate) fn remove(&mut self, name: &str) -> bool {
+        let before = self.indexes.len() + self.text.len() + self.geo.len();
+        self.indexes.retain(|i| i.name != name);
+        self.text.retain(|i| i.name != name);
+        self.geo.retain(|i| i.name != name);
+        self.indexes.len() + self.text.len() + self.geo.len() != before
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &Index> {
+        self.indexes.iter()
+    }
+
+    pub(crate) fn iter_text(&self) -> impl Iterator<Item = &TextIndex> {
+        self.text.iter()
+    }
+
+    pub(crate) fn iter_geo(&self) -> impl Iterator<Item = &GeoIndex> {
+        self.geo.iter()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.indexes.is_empty() && self.text.is_empty() && self.geo.is_empty()
+    }
+
+    /// The collections the registered indexes cover.
+    pub(crate) fn collections(&self) -> Vec<String> {
+        self.indexes
+            .iter()
+            .map(|i| i.collection())
+            .chain(self.text.iter().map(|i| i.collection()))
+            .chain(self.geo.iter().map(|i| i.collection()))
+            .map(String::from)
+            .collect()
+    }
+
+    fn all_mut(&mut self) -> impl Iterator<Item = &mut dyn CollectionIndex> {
+        self.indexes
+            .iter_mut()
+            .map(|i| i as &mut dyn CollectionIndex)
+            .chain(self.text.iter_mut().map(|i| i as &mut dyn CollectionIndex))
+            .chain(self.geo.iter_mut().map(|i| i as &mut dyn CollectionIndex))
+    }
+
+    pub(crate) fn has_stale(&self) -> bool {
+        self.indexes.iter().any(|i| i.sync.stale)
+            || self.text.iter().any(|i| i.sync().stale)
+            || self.geo.iter().any(|i| i.sync().stale)
+    }
+
+    pub(crate) fn refresh(&mut self, root: &Value) {
+        for index in self.all_mut().filter(|i| i.sync().stale) {
+            index.rebuild(root);
+        }
+    }
+
+    pub(crate) fn invalidate(&mut self) {
+        for index in self.all_mut() {
+            index.sync_mut().stale = true;
+        }
+    }
+
+    /// Replaces the registered indexes with the ones from a snapshot file;
+    /// indexes that are not in the snapshot are kept but rebuilt on first use.
+    pub(crate) fn restore(&mut self, snapshot: IndexSnapshotFile, consistent: bool) {
+        self.invalidate();
+        for snapshot in snapshot.indexes {
+            let index = Index::from_snapshot(snapshot, consistent);
+            self.remove(&index.name);
+            self.indexes.push(index);
+        }
+        for snapshot in snapshot.text_indexes {
+            if let Some(index) = TextIndex::from_snapshot(snapshot) {
+                self.remove(&index.name);
+                self.text.push(index);
+            }
+        }
+        for snapshot in snapshot.geo_indexes {
+            if let Some(index) = GeoIndex::from_snapshot(snapshot) {
+                self.remove(&index.name);
+                self.geo.push(index);
+            }
+        }
+    }
+
+    /// Must be called before a write to `path` is applied to `root`.
+    pub(crate) fn before_write(&mut self, root: &Value, path: &str) {
+        for index in self.all_mut() {
+            index.before_write(root, path);
+        }
+    }
+
+    /// Must be called after a write to `path` has been applied to `root`.
+    pub(crate) fn after_write(&mut self, root: &Value, path: &str) {
+        for index in self.all_mut() {
+            index.after_write(root, path);
+        }
+    }
+
+    /// The text index on the collection at `path`, or the one named `name`.
+    pub(crate) fn text_index(&self, path: &str, name: Option<&str>) -> Option<&TextIndex> {
+        self.text
+            .iter()
+            .find(|i| i.collection == path && name.is_none_or(|n| i.name == n))
+    }
+
+    /// Candidates from a geo index on a field of `query` with a geo condition.
+    pub(crate) fn geo_candidates(
+        &self,
+        path: &str,
+        query: &Value,
+    ) -> Option<(&GeoIndex, Vec<DocId>)> {
+        let query_map = query.as_object()?;
+        self.geo
+            .iter()
+            .filter(|i| i.collection == path)
+            .find_map(|i| i.candidates(query_map).map(|ids| (i, ids)))
+    }
+
+    /// Picks the index constraining the most fields of `query` on the
+    /// collection at `path`. Returns the candidate documents (possibly with
+    /// duplicates for multikey indexes), or `None` when no index applies.
+    pub(crate) fn candidates(&self, path: &str, query: &Value) -> Option<(&Index, Vec<DocId>)> {
+        let query_map = query.as_object()?;
+        let (index, (prefixes, next, _)) = self
+            .indexes
+            .iter()
+            .filter(|i| i.collection == path)
+            .filter_map(|i| i.plan(query_map).map(|p| (i, p)))
+            .max_by_key(|(i, (_, next, used))| {
+                // Prefer more constrained fields, then lookups that end on
+                // equality, then narrower indexes.
+                (*used, next.is_none(), std::cmp::Reverse(i.fields.len()))
+            })?;
+
+        let mut ids = Vec::new();
+        for prefix in &prefixes {
+            index.scan(prefix, next.as_ref(), &mut ids);
+        }
+        Some((index, ids))
+    }
+
+    /// Finds an index whose key order is exactly the sort order on `fields`
+    /// and which holds every document that can match `query`.
+    pub(crate) fn sort_index(&self, path: &str, fields: &[&str], query: &Value) -> Option<&Index> {
+        let query_map = query.as_object()?;
+        self.indexes.iter().find(|i| {
+            i.collection == path
+                && !i.multikey
+                && !i.sparse
+                && i.covers(query_map)
+                && i.fields.len() == fields.len()
+                && i.fields.iter().zip(fields).all(|(a, b)| a == b)
+        })
+    }
+}
+
+/// Whether a query condition can only match documents that have the field.
+fn excludes_missing(condition: &Value) -> bool {
+    match part_scan(condition, false) {
+        Some(PartScan::Points(points)) => points.iter().all(|p| p.0.is_some()),
+        Some(PartScan::Range { .. }) => true,
+        None => false,
+    }
+}
+
+/// The keys under which a document whose field equals `target` is indexed.
+/// A field holding an array is indexed under each of its elements, so a
+/// non-empty array is looked up by its first element as well as whole, the
+/// way it is indexed as an element of an array field.
+fn equal_points(target: &Value) -> Vec<KeyPart> {
+    let mut points = vec![KeyPart(Some(target.clone()))];
+    if let Some(first) = target.as_array().and_then(|elems| elems.first()) {
+        points.push(KeyPart(Some(first.clone())));
+    }
+    points
+}
+
+fn has_operators(condition: &Value) -> bool {
+    match condition {
+        Value::Object(map) => map.keys().any(|k| k.starts_with('$')),
+        _ => false,
+    }
+}
+
+/// Translates a query condition into a key-part constraint. The result is a
+/// superset of the matching documents; callers still run `matches_query` on
+/// each one. On a `multikey` index, separate bounds can each be met by a
+/// different element, so only the lower one is scanned.
+fn part_scan(condition: &Value, multikey: bool) -> Option<PartScan> {
+    if !has_operators(condition) {
+        let mut points = equal_points(condition);
+        if condition.is_null() {
+            points.insert(0, KeyPart(None));
+        }
+        return Some(PartScan::Points(points));
+    }
+
+    let ops = condition.as_object()?;
+    if let Some(target) = ops.get("$eq") {
+        return Some(PartScan::Points(equal_points(target)));
+    }
+    if let Some(Value::Array(targets)) = ops.get("$in") {
+        let mut points: Vec<KeyPart> = targets.iter().flat_map(equal_points).collect();
+        points.sort();
+        points.dedup();
+        return Some(PartScan::Points(points));
+    }
+
+    let mut lower: Option<(KeyPart, bool)> = None;
+    let mut upper: Option<(KeyPart, bool)> = None;
+    let mut operators = 0;
+    for (op, target) in ops {
+        operators += 1;
+        match op.as_str() {
+            "$gt" => lower = Some((KeyPart(Some(target.clone())), false)),
+            "$gte" => lower = Some((KeyPart(Some(target.clone())), true)),
+            "$lt" => upper = Some((KeyPart(Some(target.clone())), false)),
+            "$lte" => upper = Some((KeyPart(Some(target.clone())), true)),
+            "$between" => {
+                if let Value::Array(bounds) = target {
+                    if bounds.len() == 2 {
+                        lower = Some((KeyPart(Some(bounds[0].clone())), true));
+                        upper = Some((KeyPart(Some(bounds[1].clone())), true));
+                    }
+                }
+            }
+            _ => operators -= 1,
+        }
+    }
+    // `$between` holds both bounds to the same element.
+    if multikey && operators > 1 && lower.is_some() {
+        upper = None;
+    }
+
+    // Range operators only match values of the same type as their operand.
+    let rank = match (&lower, &upper) {
+        (Some((l, _)), _) => l.rank(),
+        (None, Some((u, _))) => u.rank(),
+        (None, None) => return None,
+    };
+    if rank != 3 && rank != 4 {
+        return Some(PartScan::Points(Vec::new()));
+    }
+    if let (Some((l, l_incl)), Some((u, u_incl))) = (&lower, &upper) {
+        if l.rank() != u.rank() || l > u || (l == u && !(*l_incl && *u_incl)) {
+            return Some(PartScan::Points(Vec::new()));
+        }
+    }
+    Some(PartScan::Range { lower, upper, rank })
+}
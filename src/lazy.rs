@@ -0,0 +1,97 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+/// The top-level key a path reads or writes under; `None` for the root.
+pub(crate) fn top_key(path: &str) -> Option<&str> {
+    path.split('.').next().filter(|key| !key.is_empty())
+}
+
+/// Which top-level collections of a lazily loaded database are in memory.
+/// Collections are read from the segment file on first use and evicted,
+/// least recently used first, once the records of the collections in
+/// memory add up to more than the budget.
+pub(crate) struct Residency {
+    budget: u64,
+    clock: u64,
+    last_used: HashMap<String, u64>,
+    /// Collections that are only in the segment file.
+    evicted: BTreeSet<String>,
+}
+
+impl Residency {
+    pub(crate) fn new(budget: u64) -> Self {
+        Residency {
+            budget,
+            clock: 0,
+            last_used: HashMap::new(),
+            evicted: BTreeSet::new(),
+        }
+    }
+
+    /// Starts over with every collection of the file on disk only.
+    pub(crate) fn reset<'a>(&mut self, keys: impl Iterator<Item = &'a str>) {
+        self.evicted = keys.map(String::from).collect();
+        self.last_used.clear();
+    }
+
+    pub(crate) fn evicted(&self) -> &BTreeSet<String> {
+        &self.evicted
+    }
+
+    /// The collections a read or write of `path` needs that are not in
+    /// memory: all of them for the root.
+    pub(crate) fn missing(&self, path: &str) -> Vec<String> {
+        match top_key(path) {
+            Some(key) if self.evicted.contains(key) => vec![key.to_string()],
+            Some(_) => Vec::new(),
+            None => self.evicted.iter().cloned().collect(),
+        }
+    }
+
+    pub(crate) fn loaded(&mut self, key: &str) {
+        self.evicted.remove(key);
+        self.touch(key);
+    }
+
+    pub(crate) fn touch(&mut self, path: &str) {
+        if let Some(key) = top_key(path) {
+            self.clock += 1;
+            self.last_used.insert(key.to_string(), self.clock);
+        }
+    }
+
+    /// Picks the collections to evict so that the ones in memory fit the
+    /// budget. `sizes` are the collections of the file with the size of
+    /// their records; those `keep` accepts are never picked.
+    pub(crate) fn evict(
+        &mut self,
+        sizes: &BTreeMap<&str, u64>,
+        keep: impl Fn(&str) -> bool,
+    ) -> Vec<String> {
+        let mut total: u64 = sizes
+            .iter()
+            .filter(|(key, _)| !self.evicted.contains(**key))
+            .map(|(_, size)| size)
+            .sum();
+        if total <= self.budget {
+            return Vec::new();
+        }
+        let mut candidates: Vec<(u64, &str, u64)> = sizes
+            .iter()
+            .filter(|(key, _)| !self.evicted.contains(**key) && !keep(key))
+            .map(|(key, size)| (self.last_used.get(*key).copied().unwrap_or(0), *key, *size))
+            .collect();
+        candidates.sort();
+
+        let mut evicted = Vec::new();
+        for (_, key, size) in candidates {
+            if total <= self.budget {
+                break;
+            }
+            total -= size;
+            self.last_used.remove(key);
+            self.evicted.insert(key.to_string());
+            evicted.push(key.to_string());
+        }
+        evicted
+    }
+}
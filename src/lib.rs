@@ -2,18 +2,62 @@ use aes_gcm::{
     aead::{Aead, KeyInit},
     Aes256Gcm, Key, Nonce,
 };
-use napi::{Error, Result, Status};
+use napi::bindgen_prelude::AsyncTask;
+use napi::threadsafe_function::ThreadSafeCallContext;
+use napi::{Env, Error, JsFunction, Result, Status};
 use napi_derive::napi;
-use parking_lot::{Mutex, RwLock};
+use parking_lot::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
 use rand::{rngs::OsRng, RngCore};
+use rayon::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use std::borrow::Cow;
 use std::cmp::Ordering;
 use std::fs;
-use std::fs::OpenOptions;
-use std::io::{BufWriter, Write};
-use std::path::PathBuf;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+mod aggregate;
+mod backup;
+mod codec;
+mod compression;
+mod cursor;
+mod follow;
+mod geo;
+mod indexes;
+mod lazy;
+mod lock;
+mod mapped;
+mod paging;
+mod replication;
+mod segments;
+mod storage;
+mod tasks;
+mod text;
+mod watch;
+
+use aggregate::AggregateSpec;
+use backup::BackupInfo;
+use codec::Codec;
+use compression::{decompress, Compression};
+use cursor::{FindCursor, OpenCursors};
+use follow::{published_lsn, Follower, PublishedLsn};
+use geo::GeoIndex;
+use indexes::{compare_parts, DocId, Index, IndexSet, IndexSnapshotFile, INDEX_SNAPSHOT_VERSION};
+use lock::WriterLock;
+use mapped::write_snapshot;
+use paging::PageToken;
+use replication::{ReplicaOptions, Replication, ReplicationOptions, ReplicationStatus};
+use segments::insert_segment;
+use storage::{merge_part, FileStamp, Layout, Route, Storage, WalGuard};
+use tasks::{AggregateTask, FindTask, LoadTask, SaveTask};
+use text::{TextConfig, TextIndex};
+use watch::{ChangeCallback, ChangeEvent, Watchers};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 enum Operation {
@@ -21,15 +65,136 @@ enum Operation {
     Delete { path: String },
 }
 
+impl Operation {
+    fn path(&self) -> &str {
+        match self {
+            Operation::Set { path, .. } | Operation::Delete { path } => path,
+        }
+    }
+}
+
+/// A WAL line: an operation tagged with its log sequence number. A record
+/// without an operation is the checkpoint written when `save()` truncates
+/// the WAL, so the LSN survives restarts.
+#[derive(Serialize)]
+struct WalRecordRef<'a> {
+    lsn: u64,
+    op: Option<&'a Operation>,
+}
+
+#[derive(Deserialize)]
+struct WalRecord {
+    lsn: u64,
+    op: Option<Operation>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum WalLine {
+    Record(WalRecord),
+    // WAL files written before LSNs were introduced.
+    Legacy(Operation),
+}
+
+/// Collections with at least this many items are filtered and sorted in
+/// parallel unless `parallelThreshold` says otherwise.
+const DEFAULT_PARALLEL_THRESHOLD: u32 = 10_000;
+
+/// Memory a lazy database keeps collections in unless `memoryBudgetMb` says
+/// otherwise.
+const DEFAULT_MEMORY_BUDGET_MB: u32 = 256;
+
+#[napi(object)]
+#[derive(Default)]
+pub struct CoreOptions {
+    /// Write index snapshots next to the data file on `save()`.
+    pub persist_indexes: Option<bool>,
+    /// Minimum collection size for parallel filtering and sorting.
+    pub parallel_threshold: Option<u32>,
+    /// Threads used for parallel queries. Defaults to rayon's global pool,
+    /// which has one thread per CPU.
+    pub query_threads: Option<u32>,
+    /// Split the data over this many shard files, each with its own WAL.
+    pub shards: Option<u32>,
+    /// Dotted path of the object whose entries are sharded. Defaults to the
+    /// top-level keys.
+    pub shard_key: Option<String>,
+    /// Save only the parts of the data written since the last save, into an
+    /// append-structured segment file.
+    pub incremental: Option<bool>,
+    /// Levels below the root at which incremental saves split the data.
+    /// Defaults to 1, one segment per top-level key.
+    pub segment_depth: Option<u32>,
+    /// Load top-level collections from the segment file on first use
+    /// instead of all at once. Implies `incremental`.
+    pub lazy: Option<bool>,
+    /// Megabytes of collection records kept in memory by a lazy database
+    /// before the least recently used collections are evicted.
+    pub memory_budget_mb: Option<u32>,
+    /// Storage codec of the data files and the WAL: `json`, `json-compact`,
+    /// `msgpack` or `cbor`. Defaults to `json`, or `json-compact` when
+    /// pretty printing is off. Files in another codec are read and
+    /// converted on load.
+    pub codec: Option<String>,
+    /// Compress data files and WAL records with `zstd` or `gzip` before
+    /// they are encrypted. Defaults to `none`.
+    pub compression: Option<String>,
+    /// Level for `compression`: 1 to 22 for zstd (default 3), 0 to 9 for
+    /// gzip (default 6).
+    pub compression_level: Option<i32>,
+    /// Never create or modify any file: the WALs are only read, and writes
+    /// and saves are rejected.
+    pub read_only: Option<bool>,
+    /// With `read_only`, read the data files and WALs again before a read
+    /// when another process changed them.
+    pub reload_on_change: Option<bool>,
+    /// Follow a database another process writes: implies `read_only`, and
+    /// reads first apply whatever the writer appended to its WALs since.
+    /// Without a WAL, the files are loaded again when they change.
+    pub follow: Option<bool>,
+    /// Let `follow` databases in other processes see each commit as it is
+    /// made: the WAL is written through on every commit and the LSN
+    /// published in `<db>.lsn`. Otherwise followers see writes once saved.
+    pub allow_followers: Option<bool>,
+    /// Milliseconds to wait for another process to release the database
+    /// before giving up. Defaults to failing right away.
+    pub lock_timeout_ms: Option<u32>,
+    /// WALs of earlier saves to keep for `changes_since`. Defaults to none:
+    /// a save empties the WAL.
+    pub retain_wal_segments: Option<u32>,
+}
+
+/// Clones share the same state; they hand the database to background tasks.
 #[napi]
+#[derive(Clone)]
 pub struct DatabaseCore {
-    data: Arc<RwLock<Value>>,
+    /// Copy-on-write: snapshots hold on to the current tree while writers
+    /// modify a copy of it. Writers go through `data_mut`.
+    data: Arc<RwLock<Arc<Value>>>,
+    cursors: Arc<OpenCursors>,
     filename: PathBuf,
-    wal_path: PathBuf,
-    wal_file: Option<Arc<Mutex<BufWriter<fs::File>>>>,
+    storage: Arc<Storage>,
     encryption_key: Option<Vec<u8>>,
-    pretty_print: bool,
-    use_wal: bool,
+    codec: Codec,
+    compression: Option<Compression>,
+    indexes: Arc<RwLock<IndexSet>>,
+    index_path: PathBuf,
+    persist_indexes: bool,
+    lsn: Arc<AtomicU64>,
+    parallel_threshold: usize,
+    query_pool: Option<Arc<ThreadPool>>,
+    read_only: bool,
+    reload_on_change: bool,
+    /// The files as they were when they were last loaded or followed.
+    stamps: Arc<Mutex<Vec<FileStamp>>>,
+    follower: Option<Arc<Mutex<Follower>>>,
+    /// Held by a writer until it is closed, so that no other process writes
+    /// the same files.
+    writer_lock: Arc<Mutex<Option<Arc<WriterLock>>>>,
+    /// Where a writer publishes its LSN for followers.
+    published_lsn: Option<Arc<PublishedLsn>>,
+    watchers: Arc<Watchers>,
+    replication: Arc<Replication>,
 }
 
 #[napi]
@@ -40,9 +205,10 @@ impl DatabaseCore {
         encryption_key: Option<String>,
         pretty_print: Option<bool>,
         use_wal: Option<bool>,
+        options: Option<CoreOptions>,
     ) -> Result<Self> {
         let path = PathBuf::from(filename);
-        let wal_path = path.with_extension("wal");
+        let index_path = path.with_extension("idx");
         let should_use_wal = use_wal.unwrap_or(true);
 
         let key_bytes = encryption_key
@@ -61,27 +227,134 @@ impl DatabaseCore {
             }
         }
 
-        let wal_file = if should_use_wal {
-            let wal_file_raw = OpenOptions::new()
-                .create(true)
-                .append(true)
-                .open(&wal_path)
-                .map_err(|e| {
-                    Error::new(Status::GenericFailure, format!("Failed to open WAL: {}", e))
-                })?;
-            Some(Arc::new(Mutex::new(BufWriter::new(wal_file_raw))))
+        let options = options.unwrap_or_default();
+        let codec = match options.codec.as_deref() {
+            Some(name) => Codec::from_name(name).ok_or_else(|| {
+                Error::new(
+                    Status::InvalidArg,
+                    format!("Unknown storage codec: {}", name),
+                )
+            })?,
+            None if pretty_print.unwrap_or(true) => Codec::Json,
+            None => Codec::JsonCompact,
+        };
+        let compression = match options.compression.as_deref() {
+            Some(name) => Compression::from_name(name, options.compression_level)
+                .map_err(|e| Error::new(Status::InvalidArg, e))?,
+            None => None,
+        };
+        // Compressed records need length-prefixed framing.
+        let codec = if compression.is_some() {
+            codec.binary()
+        } else {
+            codec
+        };
+        let sharded = options.shards.is_some_and(|count| count > 1);
+        let lazy = options.lazy.unwrap_or(false);
+        let incremental = lazy || options.incremental.unwrap_or(false);
+        if sharded && incremental {
+            return Err(Error::new(
+                Status::InvalidArg,
+                "Incremental saves cannot be combined with shards".to_string(),
+            ));
+        }
+        let layout = match options.shards {
+            _ if incremental => Layout::Segmented {
+                depth: options.segment_depth.unwrap_or(1) as usize,
+                memory_budget: lazy.then(|| {
+                    options.memory_budget_mb.unwrap_or(DEFAULT_MEMORY_BUDGET_MB) as u64
+                        * 1024
+                        * 1024
+                }),
+            },
+            Some(count) if count > 1 => Layout::Sharded {
+                count: count as usize,
+                key: options
+                    .shard_key
+                    .as_deref()
+                    .filter(|key| !key.is_empty())
+                    .map(|key| key.split('.').map(String::from).collect())
+                    .unwrap_or_default(),
+            },
+            _ => Layout::Single,
+        };
+        let follow = options.follow.unwrap_or(false);
+        let read_only = follow || options.read_only.unwrap_or(false);
+        let writer_lock = if read_only {
+            None
         } else {
+            let timeout = Duration::from_millis(options.lock_timeout_ms.unwrap_or(0) as u64);
+            Some(
+                lock::acquire(&path.with_extension("lock"), timeout)
+                    .map_err(|e| Error::new(Status::GenericFailure, e))?,
+            )
+        };
+        let published_lsn = if read_only || !options.allow_followers.unwrap_or(false) {
             None
+        } else {
+            Some(Arc::new(
+                PublishedLsn::create(&path.with_extension("lsn")).map_err(|e| {
+                    Error::new(
+                        Status::GenericFailure,
+                        format!("Failed to open LSN file: {}", e),
+                    )
+                })?,
+            ))
+        };
+        let retained_wals = options.retain_wal_segments.unwrap_or(0) as usize;
+        let storage = Storage::open(
+            &path,
+            layout,
+            should_use_wal,
+            read_only,
+            codec,
+            retained_wals,
+        )
+        .map_err(|e| Error::new(Status::GenericFailure, format!("Failed to open WAL: {}", e)))?;
+
+        let query_pool = match options.query_threads {
+            Some(threads) => Some(Arc::new(
+                ThreadPoolBuilder::new()
+                    .num_threads(threads as usize)
+                    .build()
+                    .map_err(|e| {
+                        Error::new(
+                            Status::GenericFailure,
+                            format!("Failed to start query threads: {}", e),
+                        )
+                    })?,
+            )),
+            None => None,
         };
 
+        let follower = (follow && should_use_wal)
+            .then(|| Arc::new(Mutex::new(Follower::new(storage.wal_paths().to_vec()))));
+
         let db = DatabaseCore {
-            data: Arc::new(RwLock::new(Value::Object(serde_json::Map::new()))),
+            data: Arc::new(RwLock::new(Arc::new(Value::Object(serde_json::Map::new())))),
+            cursors: Arc::new(OpenCursors::default()),
             filename: path,
-            wal_path,
-            wal_file,
+            storage: Arc::new(storage),
             encryption_key: key_bytes,
-            pretty_print: pretty_print.unwrap_or(true),
-            use_wal: should_use_wal,
+            codec,
+            compression,
+            indexes: Arc::new(RwLock::new(IndexSet::default())),
+            index_path,
+            persist_indexes: options.persist_indexes.unwrap_or(false),
+            lsn: Arc::new(AtomicU64::new(0)),
+            parallel_threshold: options
+                .parallel_threshold
+                .unwrap_or(DEFAULT_PARALLEL_THRESHOLD) as usize,
+            query_pool,
+            read_only,
+            reload_on_change: read_only
+                && (options.reload_on_change.unwrap_or(false) || (follow && !should_use_wal)),
+            stamps: Arc::new(Mutex::new(Vec::new())),
+            follower,
+            writer_lock: Arc::new(Mutex::new(writer_lock)),
+            published_lsn,
+            watchers: Arc::new(Watchers::default()),
+            replication: Arc::new(Replication::default()),
         };
 
         Ok(db)
@@ -89,526 +362,2137 @@ impl DatabaseCore {
 
     #[napi]
     pub fn load(&self) -> Result<()> {
-        // Crash Recovery
-        let tmp_path = self.filename.with_extension("tmp");
-        if tmp_path.exists() {
-            let _ = fs::rename(&tmp_path, &self.filename);
+        let mut follower = self.follower.as_ref().map(|f| f.lock());
+        let (resave, logs) = self.load_files()?;
+        if let Some(follower) = follower.as_mut() {
+            follower.reset(&logs, self.lsn.load(AtomicOrdering::SeqCst));
+        }
+        self.publish_lsn();
+        // A read-only database reads the files as they are.
+        if resave && !self.read_only {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    /// Reads the data files and replays the WALs. Returns whether they have
+    /// to be saved again, because they were written with another codec or
+    /// no checkpoint tells the LSN of the data files, and the WALs as they
+    /// were read.
+    fn load_files(&self) -> Result<(bool, Vec<Vec<u8>>)> {
+        // Writers wait until the data and the WAL agree again. What this
+        // process logged is read back from the files with the rest.
+        let mut wals = self.storage.lock_wals();
+        if let Some(wals) = wals.as_mut() {
+            wals.iter_mut().try_for_each(|wal| wal.flush())?;
+        }
+        // Taken first, so changes made while the files are read are seen by
+        // the next check.
+        if self.reload_on_change || self.follower.is_some() {
+            *self.stamps.lock() = self.storage.stamps();
+        }
+
+        // Crash Recovery. A read-only database ignores data files left
+        // behind by an interrupted save: the WAL still has their writes.
+        if !self.read_only {
+            self.storage.recover();
+        }
+        fn read_error(e: impl std::fmt::Display) -> Error {
+            Error::new(
+                Status::GenericFailure,
+                format!("Failed to read file: {}", e),
+            )
+        }
+        let mut parts = self.storage.read_parts().map_err(read_error)?;
+        let mut logs = self.storage.read_wals();
+
+        // A database saved as a single file is split up on its first save.
+        let migrating = (self.storage.is_sharded() || self.storage.is_segmented())
+            && parts.iter().all(Option::is_none);
+        if migrating {
+            let tmp_path = self.filename.with_extension("tmp");
+            if tmp_path.exists() && !self.read_only {
+                let _ = fs::rename(&tmp_path, &self.filename);
+            }
+            if self.filename.exists() {
+                parts = vec![Some(fs::read(&self.filename).map_err(read_error)?)];
+                if self.storage.is_sharded() {
+                    logs.extend(fs::read(self.filename.with_extension("wal")).ok());
+                }
+            }
+        }
+
+        self.lsn.store(0, AtomicOrdering::SeqCst);
+        self.storage.mark_all_dirty();
+        let mut snapshot_lsn = None;
+        let mut converting = false;
+        for log in logs.iter().filter(|log| !log.is_empty()) {
+            let (codec, _) = Codec::detect(log).map_err(read_error)?;
+            converting |= !codec.same_format(self.codec);
         }
 
-        if !self.filename.exists() {
+        if parts.iter().all(Option::is_none) {
             let mut data = self.data.write();
-            *data = Value::Object(serde_json::Map::new());
+            *data = Arc::new(Value::Object(serde_json::Map::new()));
+            self.indexes.write().invalidate();
         } else {
-            let content = fs::read(&self.filename).map_err(|e| {
-                Error::new(
-                    Status::GenericFailure,
-                    format!("Failed to read file: {}", e),
-                )
-            })?;
-
-            let json_val: Value = if let Some(key) = &self.encryption_key {
-                self.decrypt_content(&content, key)?
-            } else {
-                serde_json::from_slice(&content)
-                    .unwrap_or_else(|_| Value::Object(serde_json::Map::new()))
-            };
+            let mut json_val = Value::Object(serde_json::Map::new());
+            let mut misplaced = Vec::new();
+            for (part, content) in parts.iter().enumerate() {
+                let content = match content {
+                    Some(c) => c.as_slice(),
+                    None => continue,
+                };
+                let (value, checksum) = if self.storage.is_segmented() && !migrating {
+                    let (value, checksum, codec) = self
+                        .storage
+                        .restore_segments(content, |codec, record| self.decode_line(codec, record))
+                        .map_err(read_error)?;
+                    converting |= !codec.same_format(self.codec);
+                    (value, checksum)
+                } else {
+                    let (codec, header_len) = Codec::detect(content).map_err(read_error)?;
+                    converting |= !codec.same_format(self.codec);
+                    let body = &content[header_len..];
+                    let value = if self.encryption_key.is_some() {
+                        self.decode_payload(codec, body)?
+                    } else {
+                        self.decode_payload(codec, body)
+                            .unwrap_or_else(|_| Value::Object(serde_json::Map::new()))
+                    };
+                    (value, crc32fast::hash(content))
+                };
+                let current = !migrating && part < self.storage.layout.parts();
+                if current {
+                    let homes = self.storage.layout.misplaced(&value, part);
+                    if !homes.is_empty() {
+                        misplaced.push(part);
+                        misplaced.extend(homes);
+                    }
+                }
+                merge_part(&mut json_val, value);
+                if current {
+                    self.storage.loaded_part(part, checksum);
+                }
+            }
+            // Entries found in a shard other than the one they hash to are
+            // moved by the next save, which rewrites both shards.
+            for part in misplaced {
+                self.storage.mark_part_dirty(part);
+            }
+            // Shards of an earlier shard count are spread over the current
+            // ones, which all have to be written again.
+            let resharded = migrating || parts.len() > self.storage.layout.parts();
+            if resharded {
+                self.storage.mark_all_dirty();
+            }
 
             let mut data = self.data.write();
-            *data = json_val;
+            *data = Arc::new(json_val);
+
+            // Reuse persisted indexes if they were taken from these exact files.
+            let mut indexes = self.indexes.write();
+            match self.read_index_snapshot() {
+                Some(snapshot) => {
+                    let consistent = snapshot.version == INDEX_SNAPSHOT_VERSION
+                        && !resharded
+                        && snapshot.checksum == self.storage.checksum();
+                    if consistent {
+                        snapshot_lsn = Some(snapshot.lsn);
+                        self.lsn.store(snapshot.lsn, AtomicOrdering::SeqCst);
+                    }
+                    indexes.restore(snapshot, consistent);
+                }
+                None => indexes.invalidate(),
+            }
+        }
+
+        // A save that moved the WAL aside may have died before it started the
+        // new one; the kept WAL still has its LSN.
+        let retained = self.storage.retained_wals();
+        if let Some((lsn, _)) = retained.iter().filter_map(|kept| kept.last()).max() {
+            self.lsn.fetch_max(*lsn, AtomicOrdering::SeqCst);
         }
 
         // Replay WAL
-        if self.wal_path.exists() {
-            self.replay_wal()?;
+        let mut checkpoint = None;
+        if !logs.is_empty() {
+            checkpoint = self.replay_wal(&logs)?;
+            if snapshot_lsn.is_some() && checkpoint.is_some() && checkpoint != snapshot_lsn {
+                self.indexes.write().invalidate();
+            }
         }
 
-        Ok(())
+        // Data files without a checkpoint (saved without a WAL, or with the
+        // WAL lost) hold writes no WAL has. They count as a write of their
+        // own, and a save starts the WAL at it, so replicas and change
+        // streams at an earlier LSN are not taken to be up to date.
+        let anchored = checkpoint.is_some() || retained.iter().any(|kept| !kept.is_empty());
+        let unanchored = !anchored && parts.iter().any(Option::is_some);
+        if unanchored {
+            self.lsn.fetch_max(1, AtomicOrdering::SeqCst);
+        }
+
+        // Indexes are kept up to date by writes, so their collections stay
+        // in memory.
+        if self.storage.is_lazy() {
+            let pinned = self.indexes.read().collections();
+            let mut data = self.data.write();
+            let root = self.data_mut(&mut data);
+            for path in pinned {
+                self.ensure_loaded(root, &path)?;
+            }
+        }
+
+        Ok((converting || unanchored && self.storage.uses_wal(), logs))
     }
 
-    fn decrypt_content(&self, content: &[u8], key: &[u8]) -> Result<Value> {
-        let json_str = String::from_utf8(content.to_vec()).map_err(|_| {
-            Error::new(
-                Status::GenericFailure,
-                "File content is not valid UTF-8".to_string(),
-            )
-        })?;
-        let encrypted_data: Value = serde_json::from_str(&json_str).map_err(|e| {
-            Error::new(
-                Status::GenericFailure,
-                format!("Invalid JSON structure for encrypted file: {}", e),
-            )
-        })?;
+    /// Replays the WALs on top of the loaded data in LSN order, keeping
+    /// indexes in sync. Operations on the sharded object itself are logged
+    /// to every shard's WAL and applied once. Returns the LSN of the
+    /// checkpoint the WALs start from, if any.
+    fn replay_wal(&self, logs: &[Vec<u8>]) -> Result<Option<u64>> {
+        let mut records = Vec::new();
+        let mut checkpoint: Option<u64> = None;
+
+        for content in logs {
+            let (codec, header_len) = Codec::detect(content).map_err(|e| {
+                Error::new(Status::GenericFailure, format!("Failed to read WAL: {}", e))
+            })?;
+            let mut last_lsn = self.lsn.load(AtomicOrdering::SeqCst);
+            let mut log_checkpoint = None;
+            for record in codec.frames(&content[header_len..]) {
+                // Torn or unreadable records are skipped rather than applied.
+                let decoded = codec
+                    .unframe(record)
+                    .and_then(|record| self.decode_line(codec, record));
+                let (lsn, op) = match decoded {
+                    Some(WalLine::Record(record)) => (record.lsn, record.op),
+                    Some(WalLine::Legacy(op)) => (last_lsn + 1, Some(op)),
+                    None => continue,
+                };
+                last_lsn = last_lsn.max(lsn);
+
+                match op {
+                    Some(op) => records.push((lsn, op)),
+                    None => {
+                        log_checkpoint.get_or_insert(lsn);
+                    }
+                }
+            }
+            self.lsn.fetch_max(last_lsn, AtomicOrdering::SeqCst);
+            if let Some(lsn) = log_checkpoint {
+                checkpoint = Some(checkpoint.map_or(lsn, |c| c.min(lsn)));
+            }
+        }
 
-        let iv_hex = encrypted_data["iv"]
-            .as_str()
-            .ok_or_else(|| Error::new(Status::GenericFailure, "Missing IV".to_string()))?;
-        let content_hex = encrypted_data["content"]
-            .as_str()
-            .ok_or_else(|| Error::new(Status::GenericFailure, "Missing content".to_string()))?;
-        let tag_hex = encrypted_data["tag"]
-            .as_str()
-            .ok_or_else(|| Error::new(Status::GenericFailure, "Missing tag".to_string()))?;
+        records.sort_by_key(|(lsn, _)| *lsn);
+        records.dedup_by_key(|(lsn, _)| *lsn);
 
-        let iv = hex::decode(iv_hex)
-            .map_err(|_| Error::new(Status::GenericFailure, "Invalid IV hex".to_string()))?;
-        let ciphertext = hex::decode(content_hex)
-            .map_err(|_| Error::new(Status::GenericFailure, "Invalid content hex".to_string()))?;
-        let tag = hex::decode(tag_hex)
-            .map_err(|_| Error::new(Status::GenericFailure, "Invalid tag hex".to_string()))?;
+        let mut data = self.data.write();
+        let data = self.data_mut(&mut data);
+        let mut indexes = self.indexes.write();
+        for (lsn, op) in records {
+            self.ensure_loaded(data, op.path())?;
+            let route = self.storage.layout.route(data, op.path());
+            self.storage.mark_dirty(&route, op.path());
+            self.watchers.observe(data, op, lsn, |data, op| {
+                apply_indexed(data, &mut indexes, op)
+            });
+        }
+        Ok(checkpoint)
+    }
 
-        let mut full_payload = ciphertext;
-        full_payload.extend_from_slice(&tag);
+    /// Decodes one record of the WAL or the segment file; `None` if it is
+    /// corrupt or cannot be decrypted.
+    fn decode_line<T: DeserializeOwned>(&self, codec: Codec, record: &[u8]) -> Option<T> {
+        self.decode_payload(codec, record).ok()
+    }
 
-        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
-        let nonce = Nonce::from_slice(&iv);
+    /// Encodes one record of the WAL or the segment file, encrypting it
+    /// when the database is encrypted.
+    fn encode_line<T: Serialize>(&self, record: &T) -> Result<Vec<u8>> {
+        let codec = self.codec.for_records();
+        Ok(codec.frame(self.encode_payload(codec, record)?))
+    }
 
-        let plaintext = cipher
-            .decrypt(nonce, full_payload.as_ref())
-            .map_err(|_| Error::new(Status::GenericFailure, "Decryption failed".to_string()))?;
+    /// Serializes a value with `codec`, then compresses and encrypts it as
+    /// configured. Encrypted JSON is wrapped in `{ iv, content, tag }`;
+    /// binary codecs store the IV, ciphertext and tag as they are.
+    fn encode_payload<T: Serialize + ?Sized>(&self, codec: Codec, value: &T) -> Result<Vec<u8>> {
+        let encoded = |codec: Codec| {
+            let bytes = codec
+                .encode(value)
+                .map_err(|e| Error::new(Status::GenericFailure, e))?;
+            match self.compression {
+                Some(compression) => Ok(compression.compress(bytes)?),
+                None => Ok(bytes),
+            }
+        };
+        match &self.encryption_key {
+            None => encoded(codec),
+            Some(key) if codec.is_binary() => encrypt_bytes(key, &encoded(codec)?),
+            Some(key) => {
+                let json = encoded(Codec::JsonCompact)?;
+                Ok(serde_json::to_vec(&encrypt_payload(key, &json)?)?)
+            }
+        }
+    }
 
-        serde_json::from_slice(&plaintext).map_err(|e| {
+    /// Reverses `encode_payload`.
+    fn decode_payload<T: DeserializeOwned>(&self, codec: Codec, bytes: &[u8]) -> Result<T> {
+        let key = match &self.encryption_key {
+            Some(key) => key,
+            None => {
+                return codec
+                    .decode(&decompress(bytes)?)
+                    .map_err(|e| Error::new(Status::GenericFailure, e))
+            }
+        };
+        let plaintext = if codec.is_binary() {
+            decrypt_bytes(key, bytes)?
+        } else {
+            let encrypted_data: Value = serde_json::from_slice(bytes).map_err(|e| {
+                Error::new(
+                    Status::GenericFailure,
+                    format!("Invalid JSON structure for encrypted file: {}", e),
+                )
+            })?;
+            decrypt_bytes(key, &unwrap_hex_payload(&encrypted_data)?)?
+        };
+        codec.decode(&decompress(&plaintext)?).map_err(|e| {
             Error::new(
                 Status::GenericFailure,
-                format!("Invalid JSON after decryption: {}", e),
+                format!("Invalid data after decryption: {}", e),
             )
         })
     }
 
-    fn replay_wal(&self) -> Result<()> {
-        let content = fs::read(&self.wal_path).unwrap_or(vec![]);
-        let lines = content.split(|b| *b == b'\n');
-        let mut data = self.data.write();
+    /// Logs operations and applies them, assigning each the next LSN. In
+    /// synchronous replication, waits for a replica to acknowledge them.
+    fn commit(&self, ops: Vec<Operation>) -> Result<()> {
+        self.check_primary()?;
+        let lsn = self.log_and_apply(ops.into_iter().map(|op| (None, op)))?;
+        self.replication.wait_for_ack(lsn)
+    }
 
-        for line in lines {
-            if line.is_empty() {
-                continue;
-            }
+    /// Fails unless the database takes writes of its own.
+    fn check_primary(&self) -> Result<()> {
+        self.check_writable()?;
+        if let Some(primary) = self.replication.replica_of() {
+            return Err(Error::new(
+                Status::GenericFailure,
+                format!("Database is a replica of {}", primary),
+            ));
+        }
+        Ok(())
+    }
 
-            let op: Operation = if let Some(_key) = &self.encryption_key {
-                let json_str = String::from_utf8(line.to_vec()).unwrap_or_default();
-                if json_str.trim().is_empty() {
-                    continue;
-                }
-                let encrypted_data: Value = serde_json::from_str(&json_str).unwrap_or(Value::Null);
-                if encrypted_data == Value::Null {
-                    continue;
-                }
-                match self.decrypt_value(encrypted_data) {
-                    Ok(v) => serde_json::from_value(v)
-                        .unwrap_or_else(|_| Operation::Delete { path: "".into() }),
-                    Err(_) => continue,
+    /// Logs operations to the WAL of the parts they touch, streams them to
+    /// replicas and applies them. Operations without an LSN get the next
+    /// one. The WAL lock is held until they are applied, so `save` never
+    /// truncates an operation it did not write. Returns the last LSN.
+    fn log_and_apply(
+        &self,
+        ops: impl IntoIterator<Item = (Option<u64>, Operation)>,
+    ) -> Result<u64> {
+        let mut wals = self.storage.lock_wals();
+        let mut data = self.data.write();
+        let data = self.data_mut(&mut data);
+        let mut indexes = self.indexes.write();
+        for (lsn, op) in ops {
+            self.ensure_loaded(data, op.path())?;
+            let route = self.storage.layout.route(data, op.path());
+            let lsn = match lsn {
+                Some(lsn) => {
+                    self.lsn.fetch_max(lsn, AtomicOrdering::SeqCst);
+                    lsn
                 }
-            } else {
-                serde_json::from_slice(line)
-                    .unwrap_or_else(|_| Operation::Delete { path: "".into() })
+                None => self.lsn.fetch_add(1, AtomicOrdering::SeqCst) + 1,
             };
-
-            match op {
-                Operation::Set { path, value } => {
-                    if path.is_empty() {
-                        *data = value;
-                    } else {
-                        set_value_by_path(&mut data, &path, value);
-                    }
-                }
-                Operation::Delete { path } => {
-                    if path.is_empty() {
-                        *data = Value::Object(serde_json::Map::new());
-                    } else {
-                        delete_value_by_path(&mut data, &path);
-                    }
-                }
+            if let Some(wals) = wals.as_mut() {
+                let line = self.encode_line(&WalRecordRef { lsn, op: Some(&op) })?;
+                self.storage.append(wals, &route, &line)?;
             }
+            self.replication.publish(lsn, &op);
+            self.storage.mark_dirty(&route, op.path());
+            self.watchers.observe(data, op, lsn, |data, op| {
+                apply_indexed(data, &mut indexes, op)
+            });
         }
-        Ok(())
+        // With followers allowed, they read the WAL files in other
+        // processes, so each commit reaches them. This is a write, not an fsync.
+        if let Some(wals) = wals.as_mut().filter(|_| self.published_lsn.is_some()) {
+            wals.iter_mut().try_for_each(|wal| wal.flush())?;
+        }
+        self.publish_lsn();
+        Ok(self.lsn.load(AtomicOrdering::SeqCst))
     }
 
-    fn decrypt_value(&self, encrypted_data: Value) -> Result<Value> {
-        let key = self.encryption_key.as_ref().unwrap();
-        let iv_hex = encrypted_data["iv"]
-            .as_str()
-            .ok_or_else(|| Error::new(Status::GenericFailure, "Missing IV".to_string()))?;
-        let content_hex = encrypted_data["content"]
-            .as_str()
-            .ok_or_else(|| Error::new(Status::GenericFailure, "Missing content".to_string()))?;
-        let tag_hex = encrypted_data["tag"]
-            .as_str()
-            .ok_or_else(|| Error::new(Status::GenericFailure, "Missing tag".to_string()))?;
-
-        let iv = hex::decode(iv_hex)
-            .map_err(|_| Error::new(Status::GenericFailure, "Invalid IV hex".to_string()))?;
-        let ciphertext = hex::decode(content_hex)
-            .map_err(|_| Error::new(Status::GenericFailure, "Invalid content hex".to_string()))?;
-        let tag = hex::decode(tag_hex)
-            .map_err(|_| Error::new(Status::GenericFailure, "Invalid tag hex".to_string()))?;
-
-        let mut full_payload = ciphertext;
-        full_payload.extend_from_slice(&tag);
-
-        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
-        let nonce = Nonce::from_slice(&iv);
-
-        let plaintext = cipher
-            .decrypt(nonce, full_payload.as_ref())
-            .map_err(|_| Error::new(Status::GenericFailure, "Decryption failed".to_string()))?;
-
-        serde_json::from_slice(&plaintext)
-            .map_err(|e| Error::new(Status::GenericFailure, e.to_string()))
-    }
-
-    fn append_wal(&self, op: &Operation) -> Result<()> {
-        if let Some(wal_file_arc) = &self.wal_file {
-            let mut wal_file = wal_file_arc.lock();
-
-            let output = if let Some(key) = &self.encryption_key {
-                let json_string = serde_json::to_string(op).unwrap();
-                let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
-                let mut iv = [0u8; 12];
-                OsRng.fill_bytes(&mut iv);
-                let nonce = Nonce::from_slice(&iv);
-                let ciphertext_with_tag = cipher
-                    .encrypt(nonce, json_string.as_bytes())
-                    .map_err(|_| Error::from_status(Status::GenericFailure))?;
-                let tag_len = 16;
-                let split_idx = ciphertext_with_tag.len() - tag_len;
-                let ciphertext = &ciphertext_with_tag[..split_idx];
-                let tag = &ciphertext_with_tag[split_idx..];
-
-                let wrapper = serde_json::json!({
-                    "iv": hex::encode(iv),
-                    "content": hex::encode(ciphertext),
-                    "tag": hex::encode(tag)
-                });
-                serde_json::to_vec(&wrapper)?
-            } else {
-                serde_json::to_vec(op)?
-            };
+    /// Lets followers see the LSN of the last write this writer committed.
+    fn publish_lsn(&self) {
+        if let Some(published) = &self.published_lsn {
+            published.store(self.lsn.load(AtomicOrdering::SeqCst));
+        }
+    }
+
+    /// Applies a write streamed from the primary, unless it already was.
+    pub(crate) fn apply_replicated(&self, lsn: u64, op: Operation) -> Result<()> {
+        if lsn > self.lsn.load(AtomicOrdering::SeqCst) {
+            self.log_and_apply([(Some(lsn), op)])?;
+        }
+        Ok(())
+    }
 
-            wal_file.write_all(&output)?;
-            wal_file.write_all(b"\n")?;
-            // wal_file.flush()?; // REMOVED FOR PERFORMANCE: BufWriter will flush when needed or on save()
+    /// Replaces the data with the primary's as of `lsn` and saves it. The
+    /// replacement is not logged: until the save, the files still hold the
+    /// old data, and a replica that restarts is sent the snapshot again.
+    pub(crate) fn install_snapshot(&self, value: Value, lsn: u64) -> Result<()> {
+        {
+            let _wals = self.storage.lock_wals();
+            self.replace_data(value, lsn)?;
         }
+        self.save()
+    }
+
+    /// Swaps in `value` as the data as of `lsn`, reported to watchers as a
+    /// write of the root. The caller holds the WAL lock.
+    fn replace_data(&self, value: Value, lsn: u64) -> Result<()> {
+        let mut data = self.data.write();
+        let data = self.data_mut(&mut data);
+        let mut indexes = self.indexes.write();
+        self.ensure_loaded(data, "")?;
+        let route = self.storage.layout.route(data, "");
+        self.storage.mark_dirty(&route, "");
+        let op = Operation::Set {
+            path: String::new(),
+            value,
+        };
+        self.watchers.observe(data, op, lsn, |data, op| {
+            apply_indexed(data, &mut indexes, op)
+        });
+        self.lsn.store(lsn, AtomicOrdering::SeqCst);
+        self.publish_lsn();
         Ok(())
     }
 
+    /// The data and its LSN at a point no write is halfway through, with
+    /// every collection loaded. `at_cut` runs while writers are held off,
+    /// with the WALs flushed up to that point.
+    pub(crate) fn cut(&self, at_cut: impl FnOnce(u64)) -> Result<(Arc<Value>, u64)> {
+        let mut wals = self.storage.lock_wals();
+        if let Some(wals) = wals.as_mut() {
+            wals.iter_mut().try_for_each(|wal| wal.flush())?;
+        }
+        let data = self.read_data("")?;
+        let lsn = self.lsn.load(AtomicOrdering::SeqCst);
+        at_cut(lsn);
+        Ok((Arc::clone(&data), lsn))
+    }
+
+    pub(crate) fn current_lsn(&self) -> u64 {
+        self.lsn.load(AtomicOrdering::SeqCst)
+    }
+
+    /// Flushes the WALs. Returns the LSN of the last write they hold.
+    pub(crate) fn flush_wals(&self) -> Result<u64> {
+        let mut wals = self.storage.lock_wals();
+        if let Some(wals) = wals.as_mut() {
+            wals.iter_mut().try_for_each(|wal| wal.flush())?;
+        }
+        Ok(self.lsn.load(AtomicOrdering::SeqCst))
+    }
+
+    /// `load` on the libuv threadpool.
+    #[napi]
+    pub fn load_async(&self) -> AsyncTask<LoadTask> {
+        AsyncTask::new(LoadTask { core: self.clone() })
+    }
+
     #[napi]
     pub fn save(&self) -> Result<()> {
+        self.check_writable()?;
+        self.save_locked(self.storage.lock_wals(), false)
+    }
+
+    /// Saves the data with the WALs held. With `restart`, the WALs start
+    /// over at the saved LSN instead of being kept for `retainWalSegments`.
+    fn save_locked(&self, mut wals: WalGuard<'_>, restart: bool) -> Result<()> {
         let data = self.data.read();
+        let lsn = self.lsn.load(AtomicOrdering::SeqCst);
 
-        let output = if let Some(key) = &self.encryption_key {
-            let json_string = serde_json::to_string(&*data).unwrap();
-            let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
-            let mut iv = [0u8; 12];
-            OsRng.fill_bytes(&mut iv);
-            let nonce = Nonce::from_slice(&iv);
-            let ciphertext_with_tag = cipher
-                .encrypt(nonce, json_string.as_bytes())
-                .map_err(|_| Error::from_status(Status::GenericFailure))?;
-            let tag_len = 16;
-            let split_idx = ciphertext_with_tag.len() - tag_len;
-            let ciphertext = &ciphertext_with_tag[..split_idx];
-            let tag = &ciphertext_with_tag[split_idx..];
-            let wrapper = serde_json::json!({
-                "iv": hex::encode(iv),
-                "content": hex::encode(ciphertext),
-                "tag": hex::encode(tag)
-            });
-            serde_json::to_vec(&wrapper)?
+        if self.storage.is_segmented() {
+            self.storage
+                .save_segments(&data, lsn, |record| self.encode_line(record))?;
         } else {
-            if self.pretty_print {
-                serde_json::to_vec_pretty(&*data)?
-            } else {
-                serde_json::to_vec(&*data)?
+            // Only parts written to since they were last read or saved.
+            let layout = &self.storage.layout;
+            let dirty = self.storage.dirty_parts();
+            let outputs = dirty
+                .par_iter()
+                .map(|&part| self.encode_data(&layout.view(&data, part)))
+                .collect::<Result<Vec<_>>>()?;
+            for (&part, output) in dirty.iter().zip(&outputs) {
+                self.storage.write_part(part, output)?;
             }
-        };
-
-        let tmp_path = self.filename.with_extension("tmp");
-        {
-            let mut file = fs::File::create(&tmp_path)?;
-            file.write_all(&output)?;
-            // Removed sync_all() for performance, rename handles atomicity
+            self.storage.remove_stale_files()?;
         }
-        fs::rename(&tmp_path, &self.filename)?;
 
         // Truncate WAL
-        if let Some(wal_file_arc) = &self.wal_file {
-            let mut wal_file = wal_file_arc.lock();
-            let wal_raw = OpenOptions::new()
-                .create(true)
-                .write(true)
-                .truncate(true)
-                .open(&self.wal_path)
-                .map_err(|e| {
-                    Error::new(
-                        Status::GenericFailure,
-                        format!("Failed to truncate WAL: {}", e),
-                    )
-                })?;
-            *wal_file = BufWriter::new(wal_raw);
+        if let Some(wals) = wals.as_mut() {
+            let checkpoint = self.encode_line(&WalRecordRef { lsn, op: None })?;
+            let reset = if restart {
+                self.storage.restart_wals(wals, &checkpoint)
+            } else {
+                self.storage.reset_wals(wals, &checkpoint, lsn)
+            };
+            reset.map_err(|e| {
+                Error::new(
+                    Status::GenericFailure,
+                    format!("Failed to truncate WAL: {}", e),
+                )
+            })?;
+        }
+        drop(wals);
+
+        if self.persist_indexes {
+            self.write_index_snapshot(&data, self.storage.checksum(), lsn)?;
+        }
+        drop(data);
+
+        // Collections with unsaved writes stay in memory until now.
+        if self.storage.is_lazy() {
+            self.evict_collections(&mut self.data.write(), None);
         }
 
         Ok(())
     }
 
-    #[napi(js_name = "get")]
-    pub fn get_value(&self, path: Option<String>) -> Result<serde_json::Value> {
-        let data = self.data.read();
-        match path {
-            Some(p) => {
-                if p.is_empty() {
-                    Ok(data.clone())
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only {
+            return Err(Error::new(
+                Status::GenericFailure,
+                "Database is open read-only".to_string(),
+            ));
+        }
+        if self.writer_lock.lock().is_none() {
+            return Err(Error::new(
+                Status::GenericFailure,
+                "Database is closed".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Flushes the WAL and releases the lock on the files, letting another
+    /// process open the database for writing. Reads still work; writes and
+    /// saves fail from now on.
+    #[napi]
+    pub fn close(&self) -> Result<()> {
+        self.replication.stop();
+        self.flush_wals()?;
+        self.writer_lock.lock().take();
+        Ok(())
+    }
+
+    /// Brings a database that reads the files of another process up to
+    /// date with them.
+    fn sync_with_files(&self) -> Result<()> {
+        if let Some(follower) = &self.follower {
+            self.follow_wals(follower)?;
+        } else if self.reload_on_change && *self.stamps.lock() != self.storage.stamps() {
+            self.load_files()?;
+        }
+        Ok(())
+    }
+
+    /// Applies what the writer appended to its WALs since they were last
+    /// read, or loads the files again if it saved writes that were never
+    /// read. Returns the number of writes applied.
+    fn follow_wals(&self, follower: &Mutex<Follower>) -> Result<u32> {
+        let mut follower = follower.lock();
+        let stamps = self.storage.stamps();
+        let previous = std::mem::replace(&mut *self.stamps.lock(), stamps.clone());
+        if previous == stamps {
+            follower.synced();
+            return Ok(0);
+        }
+        let applied = self.lsn.load(AtomicOrdering::SeqCst);
+        let tailed = follower
+            .poll(applied, |codec, record| self.decode_line(codec, record))
+            .map_err(|e| {
+                Error::new(Status::GenericFailure, format!("Failed to read WAL: {}", e))
+            })?;
+
+        // A lazy database reads collections from where they were in the
+        // segment file, which a save may have moved.
+        let parts = self.storage.layout.parts();
+        let moved = self.storage.is_lazy() && previous.get(..parts) != stamps.get(..parts);
+        if tailed.reload || moved {
+            let (_, logs) = self.load_files()?;
+            let lsn = self.lsn.load(AtomicOrdering::SeqCst);
+            follower.reset(&logs, lsn);
+            return Ok(lsn.saturating_sub(applied) as u32);
+        }
+
+        let count = tailed.records.len() as u32;
+        let mut data = self.data.write();
+        let data = self.data_mut(&mut data);
+        let mut indexes = self.indexes.write();
+        for (lsn, op) in tailed.records {
+            self.ensure_loaded(data, op.path())?;
+            let route = self.storage.layout.route(data, op.path());
+            if matches!(route, Route::All) {
+                follower.applied_shared(lsn);
+            }
+            self.storage.mark_dirty(&route, op.path());
+            self.watchers.observe(data, op, lsn, |data, op| {
+                apply_indexed(data, &mut indexes, op)
+            });
+            self.lsn.fetch_max(lsn, AtomicOrdering::SeqCst);
+        }
+        follower.synced();
+        Ok(count)
+    }
+
+    /// Calls `callback` with a `ChangeEvent` for every write committed from
+    /// now on that can change a path matching `pattern`, including writes
+    /// made through `batch`, replayed from the WAL on load, or followed
+    /// from another process. Returns an id for `unwatch`.
+    #[napi]
+    pub fn watch(&self, env: Env, pattern: String, callback: JsFunction) -> Result<u32> {
+        let mut callback: ChangeCallback = callback
+            .create_threadsafe_function(0, |ctx: ThreadSafeCallContext<ChangeEvent>| {
+                Ok(vec![ctx.value])
+            })?;
+        // Like an event listener, a watch does not keep the process alive.
+        callback.unref(&env)?;
+        Ok(self.watchers.add(&pattern, callback))
+    }
+
+    /// Stops a watch. Returns whether it was still active.
+    #[napi]
+    pub fn unwatch(&self, id: u32) -> bool {
+        self.watchers.remove(id)
+    }
+
+    /// The writes logged after `lsn`, oldest first and at most `limit` of
+    /// them, as change events without `oldValue`. They are read from the
+    /// WALs and the ones `retainWalSegments` keeps from earlier saves; a
+    /// position from before the oldest of those fails instead of skipping
+    /// writes.
+    #[napi]
+    pub fn changes_since(&self, lsn: i64, limit: Option<u32>) -> Result<Vec<ChangeEvent>> {
+        self.flush_wals()?;
+        let mut records = self.logged_changes(u64::try_from(lsn).unwrap_or(0))?;
+        records.truncate(limit.map_or(usize::MAX, |limit| limit as usize));
+        Ok(records
+            .into_iter()
+            .map(|(lsn, op)| ChangeEvent::logged(lsn, op))
+            .collect())
+    }
+
+    /// The writes in the WAL files logged after `since`, in LSN order.
+    pub(crate) fn logged_changes(&self, since: u64) -> Result<Vec<(u64, Operation)>> {
+        if !self.storage.uses_wal() && since < self.lsn.load(AtomicOrdering::SeqCst) {
+            return Err(Error::new(
+                Status::GenericFailure,
+                format!(
+                    "Changes since LSN {} are not retained: the database has no WAL",
+                    since
+                ),
+            ));
+        }
+        // The current WALs are read before the kept ones are listed: a save
+        // in between moves what was read to a kept WAL, where it is read
+        // again rather than missed.
+        let current = self
+            .storage
+            .wal_paths()
+            .iter()
+            .map(|path| fs::read(path).unwrap_or_default())
+            .collect::<Vec<_>>();
+        let retained = self.storage.retained_wals();
+
+        // Each log goes back to where its oldest WAL starts: the checkpoint
+        // of the save that started it, or the beginning for one that was
+        // never saved.
+        let mut records = Vec::new();
+        let mut oldest = 0;
+        for (content, kept) in current.into_iter().zip(retained) {
+            let mut start = None;
+            for (end, path) in &kept {
+                // Later WALs are only read for writes after `since`.
+                if start.is_some() && *end <= since {
+                    continue;
+                }
+                // Removed by a save since it was listed.
+                let content = match fs::read(path) {
+                    Ok(content) => content,
+                    Err(_) => continue,
+                };
+                let checkpoint = self.scan_changes(&content, since, &mut records)?;
+                start.get_or_insert(checkpoint.unwrap_or(0));
+            }
+            let checkpoint = self.scan_changes(&content, since, &mut records)?;
+            oldest = oldest.max(start.unwrap_or(checkpoint.unwrap_or(0)));
+        }
+        if since < oldest {
+            return Err(Error::new(
+                Status::GenericFailure,
+                format!(
+                    "Changes since LSN {} are no longer retained; the oldest available position is LSN {}",
+                    since, oldest
+                ),
+            ));
+        }
+
+        records.sort_by_key(|(lsn, _)| *lsn);
+        records.dedup_by_key(|(lsn, _)| *lsn);
+        Ok(records)
+    }
+
+    /// Collects the records of one WAL logged after `since`. Returns the
+    /// LSN of the checkpoint it starts with, if it does.
+    fn scan_changes(
+        &self,
+        content: &[u8],
+        since: u64,
+        records: &mut Vec<(u64, Operation)>,
+    ) -> Result<Option<u64>> {
+        if content.is_empty() {
+            return Ok(None);
+        }
+        let (codec, header_len) = Codec::detect(content).map_err(|e| {
+            Error::new(Status::GenericFailure, format!("Failed to read WAL: {}", e))
+        })?;
+        let mut start = None;
+        let mut last_lsn = 0;
+        for frame in codec.frames(&content[header_len..]) {
+            // A record still being appended is read next time.
+            let record = match codec.unframe(frame) {
+                Some(record) => record,
+                None => break,
+            };
+            let (lsn, op) = match self.decode_line(codec, record) {
+                Some(WalLine::Record(record)) => (record.lsn, record.op),
+                Some(WalLine::Legacy(op)) => (last_lsn + 1, Some(op)),
+                None => continue,
+            };
+            last_lsn = last_lsn.max(lsn);
+            start.get_or_insert(op.is_none().then_some(lsn));
+            if let Some(op) = op.filter(|_| lsn > since) {
+                records.push((lsn, op));
+            }
+        }
+        Ok(start.flatten())
+    }
+
+    /// Serves this database's writes to replicas on `address`: `host:port`
+    /// for TCP, or a path for a Unix socket. Returns the address bound.
+    #[napi]
+    pub fn serve_replication(
+        &self,
+        address: String,
+        options: Option<ReplicationOptions>,
+    ) -> Result<String> {
+        self.check_writable()?;
+        self.replication
+            .serve(self, &address, options)
+            .map_err(|e| Error::new(Status::GenericFailure, e))
+    }
+
+    /// Makes this database a replica of the primary serving on `address`.
+    /// It applies and logs the primary's writes with their LSNs and rejects
+    /// writes of its own until `stopReplication`.
+    #[napi]
+    pub fn replicate_from(&self, address: String, options: Option<ReplicaOptions>) -> Result<()> {
+        self.check_writable()?;
+        self.replication
+            .follow(self, &address, options)
+            .map_err(|e| Error::new(Status::GenericFailure, e))
+    }
+
+    /// Stops serving replicas and following a primary; a replica accepts
+    /// writes again.
+    #[napi]
+    pub fn stop_replication(&self) {
+        self.replication.stop();
+    }
+
+    #[napi]
+    pub fn replication_status(&self) -> ReplicationStatus {
+        self.replication.status(self.current_lsn())
+    }
+
+    /// Brings a follower up to date with the files now instead of on its
+    /// next read. Returns the number of writes applied.
+    #[napi]
+    pub fn catch_up(&self) -> Result<u32> {
+        match &self.follower {
+            Some(follower) => self.follow_wals(follower),
+            None => Err(Error::new(
+                Status::GenericFailure,
+                "Database is not open in follower mode".to_string(),
+            )),
+        }
+    }
+
+    /// How far a follower is behind the files of the writer it follows.
+    #[napi]
+    pub fn lag(&self) -> Result<FollowerLag> {
+        let follower = self.follower.as_ref().ok_or_else(|| {
+            Error::new(
+                Status::GenericFailure,
+                "Database is not open in follower mode".to_string(),
+            )
+        })?;
+        // Reads the WALs without moving the follower forward.
+        let mut peek = follower.lock().clone();
+        let applied = self.lsn.load(AtomicOrdering::SeqCst);
+        let tailed = peek
+            .poll(applied, |codec, record| self.decode_line(codec, record))
+            .map_err(|e| {
+                Error::new(Status::GenericFailure, format!("Failed to read WAL: {}", e))
+            })?;
+        // Writers publish their LSN on every commit; the WALs tell it for
+        // files written before they did.
+        let latest = published_lsn(&self.filename.with_extension("lsn"))
+            .unwrap_or(0)
+            .max(tailed.latest_lsn);
+        Ok(FollowerLag {
+            lsn: applied as i64,
+            latest_lsn: latest as i64,
+            behind: latest.saturating_sub(applied) as i64,
+            ms_since_sync: peek.synced_at.elapsed().as_secs_f64() * 1000.0,
+        })
+    }
+
+    /// The data, to modify. Cursors still reading it copy out the matches
+    /// they have left first, so that it is not copied whole for them.
+    fn data_mut<'a>(&self, data: &'a mut Arc<Value>) -> &'a mut Value {
+        if Arc::strong_count(data) > 1 {
+            self.cursors.detach(data);
+        }
+        Arc::make_mut(data)
+    }
+
+    /// Locks the data for reading, with the collection `path` is in loaded.
+    /// A lazy database reads missing collections back from the segment file
+    /// here, evicting others to stay within its memory budget.
+    fn read_data(&self, path: &str) -> Result<RwLockReadGuard<'_, Arc<Value>>> {
+        self.sync_with_files()?;
+        let data = self.data.read();
+        if self.storage.missing_collections(path).is_empty() {
+            self.storage.touch(path);
+            return Ok(data);
+        }
+        drop(data);
+
+        let mut data = self.data.write();
+        self.ensure_loaded(self.data_mut(&mut data), path)?;
+        self.storage.touch(path);
+        // Everything is in memory after a read of the root.
+        if !path.is_empty() {
+            self.evict_collections(&mut data, Some(path));
+        }
+        Ok(RwLockWriteGuard::downgrade(data))
+    }
+
+    /// Reads the collections a read or write of `path` needs back from the
+    /// segment file.
+    fn ensure_loaded(&self, root: &mut Value, path: &str) -> Result<()> {
+        for key in self.storage.missing_collections(path) {
+            let segments = self
+                .storage
+                .read_collection(&key, |codec, record| self.decode_line(codec, record))
+                .map_err(|e| {
+                    Error::new(
+                        Status::GenericFailure,
+                        format!("Failed to read collection {}: {}", key, e),
+                    )
+                })?;
+            for (seg, value) in segments {
+                insert_segment(root, &seg, value);
+            }
+            self.storage.loaded_collection(&key);
+        }
+        Ok(())
+    }
+
+    /// Drops collections from memory until a lazy database fits its memory
+    /// budget again. Collections with an index stay.
+    fn evict_collections(&self, data: &mut Arc<Value>, keep: Option<&str>) {
+        let pinned = self.indexes.read().collections();
+        let evicted = self.storage.evict(keep, &pinned);
+        if evicted.is_empty() {
+            return;
+        }
+        if let Value::Object(map) = self.data_mut(data) {
+            for key in &evicted {
+                map.remove(key);
+            }
+        }
+    }
+
+    /// Serializes data for a data file with the configured codec, after
+    /// the codec's header.
+    fn encode_data<T: Serialize>(&self, data: &T) -> Result<Vec<u8>> {
+        let mut output = self.codec.header();
+        output.extend(self.encode_payload(self.codec, data)?);
+        Ok(output)
+    }
+
+    fn write_index_snapshot(&self, data: &Value, checksum: u32, lsn: u64) -> Result<()> {
+        let mut indexes = self.indexes.write();
+        if indexes.is_empty() {
+            if self.index_path.exists() {
+                fs::remove_file(&self.index_path)?;
+            }
+            return Ok(());
+        }
+        indexes.refresh(data);
+
+        let snapshot = IndexSnapshotFile {
+            version: INDEX_SNAPSHOT_VERSION,
+            lsn,
+            checksum,
+            indexes: indexes.iter().map(|i| i.to_snapshot()).collect(),
+            text_indexes: indexes.iter_text().map(|i| i.to_snapshot()).collect(),
+            geo_indexes: indexes.iter_geo().map(|i| i.to_snapshot()).collect(),
+        };
+        let output = self.encode_payload(Codec::JsonCompact, &snapshot)?;
+
+        let tmp_path = self.index_path.with_extension("idx.tmp");
+        fs::write(&tmp_path, &output)?;
+        fs::rename(&tmp_path, &self.index_path)?;
+        Ok(())
+    }
+
+    /// Reads the index snapshot file. Any problem reading it just means the
+    /// indexes get rebuilt, so errors are not reported.
+    fn read_index_snapshot(&self) -> Option<IndexSnapshotFile> {
+        let content = fs::read(&self.index_path).ok()?;
+        self.decode_payload(Codec::JsonCompact, &content).ok()
+    }
+
+    /// `save` on the libuv threadpool.
+    #[napi]
+    pub fn save_async(&self) -> AsyncTask<SaveTask> {
+        AsyncTask::new(SaveTask { core: self.clone() })
+    }
+
+    /// Writes a copy of the data to `destination` as a single-file database
+    /// stored with `codec`, compressed and encrypted like this one. Fails if
+    /// `destination` exists. Opening a database with another codec converts
+    /// it in place instead.
+    #[napi]
+    pub fn convert(&self, destination: String, codec: String) -> Result<()> {
+        let codec = Codec::from_name(&codec).ok_or_else(|| {
+            Error::new(
+                Status::InvalidArg,
+                format!("Unknown storage codec: {}", codec),
+            )
+        })?;
+        // Compressed records need length-prefixed framing.
+        let codec = if self.compression.is_some() {
+            codec.binary()
+        } else {
+            codec
+        };
+        let destination = PathBuf::from(destination);
+        if destination.exists() {
+            return Err(Error::new(
+                Status::GenericFailure,
+                format!("{} already exists", destination.display()),
+            ));
+        }
+        let data = Arc::clone(&*self.read_data("")?);
+        let mut content = codec.header();
+        content.extend(self.encode_payload(codec, &*data)?);
+
+        let tmp_path = destination.with_extension("tmp");
+        fs::write(&tmp_path, &content)?;
+        fs::rename(&tmp_path, &destination)?;
+        Ok(())
+    }
+
+    /// Writes the data to `destination` as a snapshot that `MappedDatabase`
+    /// serves lookups from without loading it. Snapshots are not
+    /// encrypted, so encrypted databases cannot be exported.
+    #[napi]
+    pub fn export_snapshot(&self, destination: String) -> Result<()> {
+        if self.encryption_key.is_some() {
+            return Err(Error::new(
+                Status::InvalidArg,
+                "Encrypted databases cannot be exported as snapshots".to_string(),
+            ));
+        }
+        let data = Arc::clone(&*self.read_data("")?);
+        write_snapshot(&data, destination.as_ref()).map_err(|e| {
+            Error::new(
+                Status::GenericFailure,
+                format!("Failed to write snapshot: {}", e),
+            )
+        })
+    }
+
+    /// Writes a backup of the data as of the current LSN to the new
+    /// directory `destination`. Writers are only held off while the WALs
+    /// are flushed; the data is encoded, compressed and encrypted like the
+    /// data file after they resume.
+    #[napi]
+    pub fn backup(&self, destination: String) -> Result<BackupInfo> {
+        let (data, lsn) = self.cut(|_| {})?;
+        let content = self.encode_data(&*data)?;
+        drop(data);
+        backup::write(
+            destination.as_ref(),
+            lsn,
+            self.encryption_key.is_some(),
+            &content,
+        )
+    }
+
+    /// Writes the writes logged since the backup `since_backup_id` in
+    /// `dest_dir` to a new incremental backup there. They are read from the
+    /// WAL and the ones kept by `retainWalSegments`, which have to reach
+    /// back to that backup.
+    #[napi]
+    pub fn incremental_backup(
+        &self,
+        dest_dir: String,
+        since_backup_id: String,
+    ) -> Result<BackupInfo> {
+        let dir = Path::new(&dest_dir);
+        let since = backup::lsn_of(dir, &since_backup_id)?;
+        let lsn = self.flush_wals()?;
+        if since > lsn {
+            return Err(Error::new(
+                Status::InvalidArg,
+                format!(
+                    "Backup {} is at LSN {}, ahead of the database at LSN {}",
+                    since_backup_id, since, lsn
+                ),
+            ));
+        }
+        let codec = self.codec.for_records();
+        let records = self
+            .logged_changes(since)?
+            .into_iter()
+            .filter(|(record_lsn, _)| *record_lsn <= lsn)
+            .map(|(lsn, op)| self.encode_payload(codec, &WalRecordRef { lsn, op: Some(&op) }))
+            .collect::<Result<Vec<_>>>()?;
+        backup::write_incremental(
+            dir,
+            &since_backup_id,
+            lsn,
+            self.encryption_key.is_some(),
+            codec,
+            &records,
+        )
+    }
+
+    /// Replaces the data with the backup in `source` as of `lsn`, by
+    /// default its last write. An incremental backup is applied on top of
+    /// the ones it builds on, which have to be in the same directory. Every
+    /// file and record is checked against its checksum, and the data has
+    /// to decrypt with this database's key, before anything is replaced.
+    /// The restore is saved directly as a write of its own, at the next
+    /// LSN, without a WAL record: the WALs start over there, replicas are
+    /// sent the restored data, and change streams from before it have to
+    /// start over.
+    #[napi]
+    pub fn restore(&self, source: String, lsn: Option<i64>) -> Result<BackupInfo> {
+        self.check_primary()?;
+        let chain = backup::read_chain(source.as_ref())?;
+        for backup in &chain {
+            if backup.encrypted != self.encryption_key.is_some() {
+                return Err(Error::new(
+                    Status::InvalidArg,
+                    if backup.encrypted {
+                        "Backup is encrypted, but the database has no encryption key"
+                    } else {
+                        "Backup is not encrypted, but the database is"
+                    }
+                    .to_string(),
+                ));
+            }
+        }
+        let decode_error = |e: Error| {
+            Error::new(
+                Status::InvalidArg,
+                format!("Failed to decode backup: {}", e.reason),
+            )
+        };
+        let (base, latest) = (&chain[0], &chain[chain.len() - 1]);
+        let target = match lsn {
+            Some(lsn) => u64::try_from(lsn).unwrap_or(0),
+            None => latest.info.lsn as u64,
+        };
+        if target < base.info.lsn as u64 || target > latest.info.lsn as u64 {
+            return Err(Error::new(
+                Status::InvalidArg,
+                format!(
+                    "Backup {} can be restored as of LSN {} to {}, not {}",
+                    latest.info.id, base.info.lsn, latest.info.lsn, target
+                ),
+            ));
+        }
+
+        let (codec, header_len) = Codec::detect(&base.content)
+            .map_err(|e| Error::new(Status::InvalidArg, format!("Invalid backup data: {}", e)))?;
+        let mut value = self
+            .decode_payload(codec, &base.content[header_len..])
+            .map_err(decode_error)?;
+        for backup in &chain[1..] {
+            let (codec, records) = backup.records()?;
+            for payload in records {
+                let record: WalRecord =
+                    self.decode_payload(codec, payload).map_err(decode_error)?;
+                match record.op {
+                    Some(op) if record.lsn <= target => apply_operation(&mut value, op),
+                    _ => {}
+                }
+            }
+        }
+
+        // The current data is saved first, so the WALs hold no writes that
+        // a crash halfway through would replay onto the restored data.
+        self.save()?;
+        let wals = self.storage.lock_wals();
+        // A new LSN, so positions given out before never name restored data.
+        let restored = self.lsn.load(AtomicOrdering::SeqCst) + 1;
+        self.replace_data(value, restored)?;
+        self.replication
+            .publish_snapshot(restored, &self.data.read());
+        // What the WALs logged was written to the replaced data, so they
+        // start over once it is saved.
+        self.save_locked(wals, true)?;
+        let mut info = chain.into_iter().next_back().unwrap().info;
+        info.lsn = target as i64;
+        Ok(info)
+    }
+
+    #[napi(js_name = "get")]
+    pub fn get_value(&self, path: Option<String>) -> Result<serde_json::Value> {
+        let data = self.read_data(path.as_deref().unwrap_or(""))?;
+        match path {
+            Some(p) => {
+                if p.is_empty() {
+                    Ok(Value::clone(&data))
+                } else {
+                    let v = get_value_by_path(&data, &p);
+                    Ok(v.cloned().unwrap_or(Value::Null))
+                }
+            }
+            None => Ok(Value::clone(&data)),
+        }
+    }
+
+    #[napi]
+    pub fn has(&self, path: String) -> Result<bool> {
+        if path.is_empty() {
+            return Ok(true);
+        }
+        let data = self.read_data(&path)?;
+        Ok(get_value_by_path(&data, &path).is_some())
+    }
+
+    #[napi]
+    pub fn set(&self, path: String, value: serde_json::Value) -> Result<()> {
+        let ops = vec![Operation::Set { path, value }];
+        self.commit(ops)
+    }
+
+    #[napi]
+    pub fn delete(&self, path: String) -> Result<()> {
+        let ops = vec![Operation::Delete { path }];
+        self.commit(ops)
+    }
+
+    /// Returns the index set, rebuilding any index invalidated by earlier writes.
+    fn read_indexes(&self, data: &Value) -> RwLockReadGuard<'_, IndexSet> {
+        {
+            let indexes = self.indexes.read();
+            if !indexes.has_stale() {
+                return indexes;
+            }
+        }
+        let mut indexes = self.indexes.write();
+        indexes.refresh(data);
+        RwLockWriteGuard::downgrade(indexes)
+    }
+
+    #[napi]
+    pub fn batch_from_json(&self, ops_json: String) -> Result<()> {
+        let ops: Vec<serde_json::Value> = serde_json::from_str(&ops_json)
+            .map_err(|e| Error::new(Status::InvalidArg, format!("Invalid JSON: {}", e)))?;
+        self.batch(ops)
+    }
+
+    #[napi]
+    pub fn batch(&self, ops: Vec<serde_json::Value>) -> Result<()> {
+        let mut operations = Vec::with_capacity(ops.len());
+        for op_val in ops {
+            let type_str = op_val.get("type").and_then(|t| t.as_str()).unwrap_or("");
+            let path = op_val
+                .get("path")
+                .and_then(|p| p.as_str())
+                .unwrap_or("")
+                .to_string();
+
+            match type_str {
+                "set" => {
+                    let val = op_val.get("value").cloned().unwrap_or(Value::Null);
+                    operations.push(Operation::Set { path, value: val });
+                }
+                "delete" => {
+                    operations.push(Operation::Delete { path });
+                }
+                _ => {}
+            }
+        }
+
+        self.commit(operations)
+    }
+
+    #[napi]
+    pub fn find(
+        &self,
+        path: String,
+        query: serde_json::Value,
+        options: Option<QueryOptions>,
+    ) -> Result<Vec<serde_json::Value>> {
+        let (results, _) = self.run_find(&path, &query, options.as_ref())?;
+        Ok(results)
+    }
+
+    /// Keyset pagination: returns up to `limit` results and a token that
+    /// `after` takes to resume strictly after the last of them.
+    #[napi]
+    pub fn find_page(
+        &self,
+        path: String,
+        query: serde_json::Value,
+        options: Option<QueryOptions>,
+    ) -> Result<QueryPage> {
+        let (items, next_token, _) = self.run_query(&path, &query, options.as_ref(), true)?;
+        Ok(QueryPage { items, next_token })
+    }
+
+    /// `find` on the libuv threadpool.
+    #[napi]
+    pub fn find_async(
+        &self,
+        path: String,
+        query: serde_json::Value,
+        options: Option<QueryOptions>,
+    ) -> AsyncTask<FindTask> {
+        AsyncTask::new(FindTask {
+            core: self.clone(),
+            path,
+            query,
+            options,
+        })
+    }
+
+    /// Groups the documents matching `query` and computes `spec`'s
+    /// accumulators for each group.
+    #[napi]
+    pub fn aggregate(
+        &self,
+        path: String,
+        query: serde_json::Value,
+        spec: AggregateSpec,
+    ) -> Result<Vec<serde_json::Value>> {
+        self.run_aggregate(&path, &query, &spec)
+    }
+
+    /// `aggregate` on the libuv threadpool.
+    #[napi]
+    pub fn aggregate_async(
+        &self,
+        path: String,
+        query: serde_json::Value,
+        spec: AggregateSpec,
+    ) -> AsyncTask<AggregateTask> {
+        AsyncTask::new(AggregateTask {
+            core: self.clone(),
+            path,
+            query,
+            spec,
+        })
+    }
+
+    /// Runs a `find` and returns a cursor that hands out its results in
+    /// batches, as of the moment the query ran.
+    #[napi]
+    pub fn find_cursor(
+        &self,
+        path: String,
+        query: serde_json::Value,
+        options: Option<QueryOptions>,
+    ) -> Result<FindCursor> {
+        let data = self.read_data(&path)?;
+        let ids = self
+            .select_docs(
+                &data,
+                &path,
+                &query,
+                options.as_ref(),
+                false,
+                &mut QueryExplain::new(),
+            )?
+            .0
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        // Registered before writers can get to the data.
+        Ok(FindCursor::new(
+            &self.cursors,
+            Arc::clone(&data),
+            path,
+            ids,
+            options.and_then(|o| o.select),
+        ))
+    }
+
+    /// Runs a `find` and reports how it was executed instead of its results.
+    #[napi]
+    pub fn explain(
+        &self,
+        path: String,
+        query: serde_json::Value,
+        options: Option<QueryOptions>,
+    ) -> Result<QueryExplain> {
+        let (_, explain) = self.run_find(&path, &query, options.as_ref())?;
+        Ok(explain)
+    }
+
+    /// Runs parallel query work on the configured pool, or rayon's global one.
+    fn in_query_pool<R: Send>(&self, work: impl FnOnce() -> R + Send) -> R {
+        match &self.query_pool {
+            Some(pool) => pool.install(work),
+            None => work(),
+        }
+    }
+
+    pub(crate) fn run_find(
+        &self,
+        path: &str,
+        query: &Value,
+        options: Option<&QueryOptions>,
+    ) -> Result<(Vec<Value>, QueryExplain)> {
+        let (results, _, explain) = self.run_query(path, query, options, false)?;
+        Ok((results, explain))
+    }
+
+    pub(crate) fn run_aggregate(
+        &self,
+        path: &str,
+        query: &Value,
+        spec: &AggregateSpec,
+    ) -> Result<Vec<Value>> {
+        let data = self.read_data(path)?;
+        let (docs, _) =
+            self.select_docs(&data, path, query, None, false, &mut QueryExplain::new())?;
+        aggregate::aggregate(docs.into_iter().map(|(_, doc)| doc), spec)
+    }
+
+    /// Runs a query; with `paged`, also returns the continuation token of the
+    /// next page when there is one.
+    fn run_query(
+        &self,
+        path: &str,
+        query: &Value,
+        options: Option<&QueryOptions>,
+        paged: bool,
+    ) -> Result<(Vec<Value>, Option<String>, QueryExplain)> {
+        let mut explain = QueryExplain::new();
+        let data = self.read_data(path)?;
+        let (docs, more) = self.select_docs(&data, path, query, options, paged, &mut explain)?;
+        let sort_opts = options.and_then(|o| o.sort.as_ref());
+        let next_token = match docs.last() {
+            Some((id, item)) if paged && more => {
+                Some(PageToken::new(item, id.clone(), sort_opts).encode())
+            }
+            _ => None,
+        };
+
+        // 4. Project (Select)
+        let started = Instant::now();
+        let select = options.and_then(|o| o.select.as_ref());
+        let selected_results: Vec<Value> = docs
+            .into_iter()
+            .map(|(_, item)| match select {
+                Some(fields) if !fields.is_empty() => project(item, fields),
+                _ => item.clone(),
+            })
+            .collect();
+        explain.projection_ms = elapsed_ms(started);
+        explain.returned = selected_results.len() as u32;
+
+        Ok((selected_results, next_token, explain))
+    }
+
+    /// Runs the filter, sort and skip/limit phases of a query on `data`,
+    /// returning the selected documents with their ids and whether more
+    /// documents follow them. `paged` queries must have an order that page
+    /// tokens can resume: the sort fields, then the document ids.
+    fn select_docs<'a>(
+        &self,
+        data: &'a Value,
+        path: &str,
+        query: &Value,
+        options: Option<&QueryOptions>,
+        paged: bool,
+        explain: &mut QueryExplain,
+    ) -> Result<(Vec<Match<'a>>, bool)> {
+        let collection = match get_value_by_path(data, path) {
+            Some(c @ (Value::Array(_) | Value::Object(_))) => c,
+            _ => return Ok((Vec::new(), false)),
+        };
+        let indexes = self.read_indexes(data);
+        let parallel = collection_len(collection) >= self.parallel_threshold;
+
+        // `$text` is answered by a text index; the rest of the query filters its hits.
+        let (text_query, query) = split_text_query(query)?;
+        let query = query.as_ref();
+
+        let skip = options.map(|o| o.skip.unwrap_or(0) as usize).unwrap_or(0);
+        let limit = options
+            .map(|o| o.limit.unwrap_or(u32::MAX) as usize)
+            .unwrap_or(usize::MAX);
+        let sort_opts = options.and_then(|o| o.sort.as_ref());
+
+        let after = options
+            .and_then(|o| o.after.as_deref())
+            .map(|token| PageToken::decode(token, sort_opts))
+            .transpose()?;
+        if paged || after.is_some() {
+            if text_query.is_some() {
+                return Err(Error::new(
+                    Status::InvalidArg,
+                    "Page tokens are not supported for $text queries".to_string(),
+                ));
+            }
+            if sort_opts.is_none() && geo::near_point(query).is_some() {
+                return Err(Error::new(
+                    Status::InvalidArg,
+                    "Page tokens on $near queries need an explicit sort".to_string(),
+                ));
+            }
+        }
+        let past_token = |(id, item): &Match| {
+            after
+                .as_ref()
+                .is_none_or(|token| token.precedes(item, id, sort_opts))
+        };
+
+        // An index whose key order is the sort order lets a limited query
+        // walk the collection in order and stop early instead of sorting.
+        let sort_index = match (sort_fields(sort_opts), options) {
+            (Some((fields, descending)), Some(QueryOptions { limit: Some(_), .. })) => indexes
+                .sort_index(path, &fields, query)
+                .map(|index| (index, descending)),
+            _ => None,
+        };
+
+        // 1. Filter
+        let started = Instant::now();
+        let mut examined = 0usize;
+        let mut results: Vec<Match> = if let Some(text_query) = &text_query {
+            let index = indexes
+                .text_index(path, text_query.index.as_deref())
+                .ok_or_else(|| {
+                    Error::new(
+                        Status::InvalidArg,
+                        format!("No text index on '{}' for $text", path),
+                    )
+                })?;
+            explain.plan = "textSearch".to_string();
+            explain.index = Some(index.name.clone());
+            let hits = index.search(&text_query.search);
+            examined = hits.len();
+            // Hits are in relevance order; an explicit sort is stable on top of it.
+            hits.into_iter()
+                .filter_map(|(id, _)| resolve_match(collection, id, query))
+                .collect()
+        } else if let Some((index, candidates, n)) =
+            indexed_matches(&indexes, collection, path, query)
+        {
+            explain.plan = "indexScan".to_string();
+            explain.index = Some(index.name.clone());
+            examined = n;
+            candidates
+        } else if let Some((index, candidates)) = indexes.geo_candidates(path, query) {
+            explain.plan = "geoScan".to_string();
+            explain.index = Some(index.name.clone());
+            examined = candidates.len();
+            candidates
+                .into_iter()
+                .filter_map(|id| resolve_match(collection, id, query))
+                .collect()
+        } else if let Some((index, descending)) = sort_index {
+            explain.plan = "indexOrderScan".to_string();
+            explain.index = Some(index.name.clone());
+            explain.sorted_by_index = true;
+            index
+                .ordered_ids(descending)
+                .inspect(|_| examined += 1)
+                .filter_map(|id| resolve_match(collection, id.clone(), query))
+                .filter(past_token)
+                // One more than needed tells whether another page follows.
+                .take(skip.saturating_add(limit).saturating_add(1))
+                .collect()
+        } else if let Some(shards) = self.storage.layout.shard_entries(path, collection) {
+            // The sharded object fans out: each shard is filtered on its own
+            // thread, and the matches are merged back into collection order.
+            explain.plan = "shardScan".to_string();
+            explain.parallel = true;
+            examined = collection_len(collection);
+            self.in_query_pool(|| {
+                let mut matches: Vec<Match> = shards
+                    .into_par_iter()
+                    .flat_map_iter(|entries| {
+                        entries
+                            .into_iter()
+                            .filter(|(_, item)| matches_query(item, query))
+                            .map(|(key, item)| (DocId::Key(key.clone()), item))
+                    })
+                    .collect();
+                matches.par_sort_unstable_by(|a, b| a.0.cmp(&b.0));
+                matches
+            })
+        } else if parallel {
+            examined = collection_len(collection);
+            explain.parallel = true;
+            self.in_query_pool(|| match collection {
+                Value::Array(arr) => arr
+                    .par_iter()
+                    .enumerate()
+                    .filter(|(_, item)| matches_query(item, query))
+                    .map(|(i, item)| (DocId::Pos(i), item))
+                    .collect(),
+                _ => collection_entries(collection)
+                    .collect::<Vec<_>>()
+                    .into_par_iter()
+                    .filter(|(_, item)| matches_query(item, query))
+                    .collect(),
+            })
+        } else {
+            collection_entries(collection)
+                .inspect(|_| examined += 1)
+                .filter(|(_, item)| matches_query(item, query))
+                .collect()
+        };
+        if after.is_some() && !explain.sorted_by_index {
+            results.retain(past_token);
+        }
+        explain.candidates_examined = examined as u32;
+        explain.filter_ms = elapsed_ms(started);
+
+        // 2. Sort
+        let started = Instant::now();
+        if !explain.sorted_by_index {
+            let near = geo::near_point(query);
+            let parallel_sort = results.len() >= self.parallel_threshold;
+            explain.parallel |= parallel_sort && (sort_opts.is_some() || near.is_some());
+            if let Some(sort_opts) = sort_opts {
+                let by_fields = |a: &Match, b: &Match| sort_json(a.1, b.1, sort_opts);
+                if parallel_sort {
+                    self.in_query_pool(|| results.par_sort_by(by_fields));
                 } else {
-                    let v = get_value_by_path(&data, &p);
-                    Ok(v.cloned().unwrap_or(Value::Null))
+                    results.sort_by(by_fields);
+                }
+            } else if let Some((field, point)) = near {
+                // `$near` without an explicit sort orders by distance.
+                let mut by_distance: Vec<(f64, Match)> = results
+                    .into_iter()
+                    .map(|doc| (geo::distance_to(doc.1, field, point), doc))
+                    .collect();
+                if parallel_sort {
+                    self.in_query_pool(|| by_distance.par_sort_by(|a, b| a.0.total_cmp(&b.0)));
+                } else {
+                    by_distance.sort_by(|a, b| a.0.total_cmp(&b.0));
                 }
+                results = by_distance.into_iter().map(|(_, doc)| doc).collect();
             }
-            None => Ok(data.clone()),
         }
+        explain.sort_ms = elapsed_ms(started);
+
+        // 3. Skip & Limit
+        let more = results.len() > skip.saturating_add(limit);
+        Ok((results.into_iter().skip(skip).take(limit).collect(), more))
     }
 
     #[napi]
-    pub fn has(&self, path: String) -> Result<bool> {
-        if path.is_empty() {
-            return Ok(true);
+    pub fn find_one(
+        &self,
+        path: String,
+        query: serde_json::Value,
+    ) -> Result<Option<serde_json::Value>> {
+        let data = self.read_data(&path)?;
+        let collection = match get_value_by_path(&data, &path) {
+            Some(c @ (Value::Array(_) | Value::Object(_))) => c,
+            _ => return Ok(None),
+        };
+        let indexes = self.read_indexes(&data);
+
+        if let Some((_, candidates, _)) = indexed_matches(&indexes, collection, &path, &query) {
+            return Ok(candidates.first().map(|(_, item)| (*item).clone()));
         }
-        let data = self.data.read();
-        Ok(get_value_by_path(&data, &path).is_some())
+        let found = collection_items(collection)
+            .find(|item| matches_query(item, &query))
+            .cloned();
+        Ok(found)
     }
 
+    /// Creates (or replaces) an ordered index on the documents of the
+    /// collection at `path`, keyed by `field` or by the compound `fields`.
+    /// `sparse` and `filter` restrict which documents are indexed.
     #[napi]
-    pub fn set(&self, path: String, value: serde_json::Value) -> Result<()> {
-        let op = Operation::Set {
-            path: path.clone(),
-            value: value.clone(),
-        };
-        if self.use_wal {
-            self.append_wal(&op)?;
+    pub fn create_index(&self, definition: IndexDefinition) -> Result<()> {
+        if definition.name.is_empty() {
+            return Err(Error::new(
+                Status::InvalidArg,
+                "Index name must not be empty".to_string(),
+            ));
         }
-        let mut data = self.data.write();
-        if path.is_empty() {
-            *data = value;
-        } else {
-            set_value_by_path(&mut data, &path, value);
+        let fields = match (definition.field, definition.fields) {
+            (Some(field), None) => vec![field],
+            (None, Some(fields)) if !fields.is_empty() => fields,
+            _ => {
+                return Err(Error::new(
+                    Status::InvalidArg,
+                    "Index needs exactly one of 'field' or a non-empty 'fields'".to_string(),
+                ))
+            }
+        };
+        let data = self.read_data(&definition.path)?;
+        if let Some(filter) = &definition.filter {
+            if !filter.is_object() {
+                return Err(Error::new(
+                    Status::InvalidArg,
+                    "Index filter must be a query object".to_string(),
+                ));
+            }
         }
+        let index = Index::new(
+            definition.name,
+            definition.path,
+            fields,
+            definition.sparse.unwrap_or(false),
+            definition.filter,
+        );
+        self.indexes.write().add(index, &data);
         Ok(())
     }
 
+    /// Creates (or replaces) a full-text index over `fields` of the documents
+    /// of the collection at `path`, used by `search` and `$text` queries.
     #[napi]
-    pub fn delete(&self, path: String) -> Result<()> {
-        let op = Operation::Delete { path: path.clone() };
-        if self.use_wal {
-            self.append_wal(&op)?;
-        }
-        let mut data = self.data.write();
-        if path.is_empty() {
-            *data = Value::Object(serde_json::Map::new());
-        } else {
-            delete_value_by_path(&mut data, &path);
+    pub fn create_text_index(&self, definition: TextIndexDefinition) -> Result<()> {
+        if definition.name.is_empty() || definition.fields.is_empty() {
+            return Err(Error::new(
+                Status::InvalidArg,
+                "Text index needs a name and at least one field".to_string(),
+            ));
         }
+        let language = definition.language.unwrap_or_else(|| "english".to_string());
+        let config = TextConfig {
+            fields: definition.fields,
+            tokenizer: definition.tokenizer.unwrap_or_else(|| "word".to_string()),
+            lowercase: definition.lowercase.unwrap_or(true),
+            stem_language: (language != "none").then_some(language),
+            stop_words: definition.stop_words,
+        };
+        let index = TextIndex::new(definition.name, definition.path, config)
+            .map_err(|e| Error::new(Status::InvalidArg, e))?;
+        let data = self.read_data(&index.collection)?;
+        self.indexes.write().add_text(index, &data);
         Ok(())
     }
 
+    /// Creates (or replaces) a grid index over the points stored at `field`,
+    /// used by `$near`, `$withinRadius` and `$withinBox` queries.
     #[napi]
-    pub fn batch_from_json(&self, ops_json: String) -> Result<()> {
-        let ops: Vec<serde_json::Value> = serde_json::from_str(&ops_json)
-            .map_err(|e| Error::new(Status::InvalidArg, format!("Invalid JSON: {}", e)))?;
-        self.batch(ops)
+    pub fn create_geo_index(&self, definition: GeoIndexDefinition) -> Result<()> {
+        if definition.name.is_empty() || definition.field.is_empty() {
+            return Err(Error::new(
+                Status::InvalidArg,
+                "Geo index needs a name and a field".to_string(),
+            ));
+        }
+        let index = GeoIndex::new(
+            definition.name,
+            definition.path,
+            definition.field,
+            definition.cell_size.unwrap_or(0.1),
+        )
+        .map_err(|e| Error::new(Status::InvalidArg, e))?;
+        let data = self.read_data(&index.collection)?;
+        self.indexes.write().add_geo(index, &data);
+        Ok(())
     }
 
     #[napi]
-    pub fn batch(&self, ops: Vec<serde_json::Value>) -> Result<()> {
-        let mut operations = Vec::with_capacity(ops.len());
-        for op_val in ops {
-            let type_str = op_val.get("type").and_then(|t| t.as_str()).unwrap_or("");
-            let path = op_val
-                .get("path")
-                .and_then(|p| p.as_str())
-                .unwrap_or("")
-                .to_string();
-
-            match type_str {
-                "set" => {
-                    let val = op_val.get("value").cloned().unwrap_or(Value::Null);
-                    operations.push(Operation::Set { path, value: val });
-                }
-                "delete" => {
-                    operations.push(Operation::Delete { path });
-                }
-                _ => {}
-            }
-        }
-
-        {
-            if self.use_wal {
-                if let Some(wal_file_arc) = &self.wal_file {
-                    let mut wal_file = wal_file_arc.lock();
-                    if let Some(key) = &self.encryption_key {
-                        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
-                        for op in &operations {
-                            let json_string = serde_json::to_string(op).unwrap();
-                            let mut iv = [0u8; 12];
-                            OsRng.fill_bytes(&mut iv);
-                            let nonce = Nonce::from_slice(&iv);
-                            let ciphertext_with_tag = cipher
-                                .encrypt(nonce, json_string.as_bytes())
-                                .map_err(|_| Error::from_status(Status::GenericFailure))?;
-                            let tag_len = 16;
-                            let split_idx = ciphertext_with_tag.len() - tag_len;
-                            let ciphertext = &ciphertext_with_tag[..split_idx];
-                            let tag = &ciphertext_with_tag[split_idx..];
-
-                            let wrapper = serde_json::json!({
-                                "iv": hex::encode(iv),
-                                "content": hex::encode(ciphertext),
-                                "tag": hex::encode(tag)
-                            });
-                            serde_json::to_writer(&mut *wal_file, &wrapper)?;
-                            wal_file.write_all(b"\n")?;
-                        }
-                    } else {
-                        for op in &operations {
-                            serde_json::to_writer(&mut *wal_file, op)?;
-                            wal_file.write_all(b"\n")?;
-                        }
-                    }
-                }
-            }
-        }
+    pub fn list_geo_indexes(&self) -> Result<Vec<GeoIndexDefinition>> {
+        Ok(self
+            .indexes
+            .read()
+            .iter_geo()
+            .map(|i| GeoIndexDefinition {
+                name: i.name.clone(),
+                path: i.collection.clone(),
+                field: i.field.clone(),
+                cell_size: Some(i.cell_size),
+            })
+            .collect())
+    }
 
-        let mut data = self.data.write();
-        for op in operations {
-            match op {
-                Operation::Set { path, value } => {
-                    if path.is_empty() {
-                        *data = value;
-                    } else {
-                        set_value_by_path(&mut data, &path, value);
-                    }
-                }
-                Operation::Delete { path } => {
-                    if path.is_empty() {
-                        *data = Value::Object(serde_json::Map::new());
-                    } else {
-                        delete_value_by_path(&mut data, &path);
-                    }
-                }
-            }
-        }
-        Ok(())
+    #[napi]
+    pub fn list_text_indexes(&self) -> Result<Vec<TextIndexDefinition>> {
+        Ok(self
+            .indexes
+            .read()
+            .iter_text()
+            .map(|i| TextIndexDefinition {
+                name: i.name.clone(),
+                path: i.collection.clone(),
+                fields: i.config.fields.clone(),
+                tokenizer: Some(i.config.tokenizer.clone()),
+                lowercase: Some(i.config.lowercase),
+                language: Some(
+                    i.config
+                        .stem_language
+                        .clone()
+                        .unwrap_or_else(|| "none".to_string()),
+                ),
+                stop_words: i.config.stop_words.clone(),
+            })
+            .collect())
     }
 
+    /// Full-text search over the collection at `path`, ranked by BM25.
     #[napi]
-    pub fn find(
+    pub fn search(
         &self,
         path: String,
-        query: serde_json::Value,
-        options: Option<QueryOptions>,
-    ) -> Result<Vec<serde_json::Value>> {
-        let data = self.data.read();
-        let collection = get_value_by_path(&data, &path);
-
-        let items_ref: Vec<&Value> = match collection {
-            Some(Value::Array(arr)) => arr.iter().collect(),
-            Some(Value::Object(map)) => map.values().collect(),
-            _ => return Ok(Vec::new()),
+        text: String,
+        options: Option<SearchOptions>,
+    ) -> Result<Vec<SearchHit>> {
+        let data = self.read_data(&path)?;
+        let indexes = self.read_indexes(&data);
+        let options = options.unwrap_or(SearchOptions {
+            index: None,
+            filter: None,
+            limit: None,
+            skip: None,
+            select: None,
+        });
+        let index = indexes
+            .text_index(&path, options.index.as_deref())
+            .ok_or_else(|| {
+                Error::new(Status::InvalidArg, format!("No text index on '{}'", path))
+            })?;
+        let collection = match get_value_by_path(&data, &path) {
+            Some(c) => c,
+            None => return Ok(Vec::new()),
         };
 
-        let mut results: Vec<&Value> = items_ref
-            .into_iter()
-            .filter(|item| matches_query(item, &query))
-            .collect();
-
-        // 1. Sort
-        if let Some(opts) = &options {
-            if let Some(sort_opts) = &opts.sort {
-                results.sort_by(|a, b| sort_json(a, b, sort_opts));
-            }
-        }
-
-        // 2. Skip
-        let skip = options
-            .as_ref()
-            .map(|o| o.skip.unwrap_or(0) as usize)
-            .unwrap_or(0);
-        let iter = results.into_iter().skip(skip);
-
-        // 3. Limit
-        let limit = options
-            .as_ref()
-            .map(|o| o.limit.unwrap_or(u32::MAX) as usize)
-            .unwrap_or(usize::MAX);
-        let limited_results: Vec<&Value> = iter.take(limit).collect();
-
-        // 4. Project (Select)
-        let selected_results: Vec<Value> = limited_results
+        let hits = index
+            .search(&text)
             .into_iter()
-            .map(|item| {
-                if let Some(opts) = &options {
-                    if let Some(fields) = &opts.select {
-                        if fields.is_empty() {
-                            return item.clone();
-                        }
-                        let mut map = serde_json::Map::new();
-                        for field in fields {
-                            if let Some(val) = get_value_by_path(item, field) {
-                                set_value_by_path(
-                                    &mut Value::Object(map.clone()),
-                                    field,
-                                    val.clone(),
-                                );
-                            }
-                        }
-                        return Value::Object(map);
-                    }
-                }
-                item.clone()
+            .filter_map(|(id, score)| id.resolve(collection).map(|doc| (doc, score)))
+            .filter(|(doc, _)| {
+                options
+                    .filter
+                    .as_ref()
+                    .is_none_or(|filter| matches_query(doc, filter))
+            })
+            .skip(options.skip.unwrap_or(0) as usize)
+            .take(options.limit.unwrap_or(u32::MAX) as usize)
+            .map(|(doc, score)| SearchHit {
+                score,
+                document: match &options.select {
+                    Some(fields) if !fields.is_empty() => project(doc, fields),
+                    _ => doc.clone(),
+                },
             })
             .collect();
-
-        Ok(selected_results)
+        Ok(hits)
     }
 
     #[napi]
-    pub fn find_one(
-        &self,
-        path: String,
-        query: serde_json::Value,
-    ) -> Result<Option<serde_json::Value>> {
-        let data = self.data.read();
-        let collection = get_value_by_path(&data, &path);
+    pub fn drop_index(&self, name: String) -> Result<bool> {
+        Ok(self.indexes.write().remove(&name))
+    }
 
-        match collection {
-            Some(Value::Array(arr)) => {
-                for item in arr {
-                    if matches_query(item, &query) {
-                        return Ok(Some(item.clone()));
-                    }
-                }
-                Ok(None)
-            }
-            Some(Value::Object(map)) => {
-                for item in map.values() {
-                    if matches_query(item, &query) {
-                        return Ok(Some(item.clone()));
-                    }
+    #[napi]
+    pub fn list_indexes(&self) -> Result<Vec<IndexDefinition>> {
+        Ok(self
+            .indexes
+            .read()
+            .iter()
+            .map(|i| {
+                let (field, fields) = match i.fields.as_slice() {
+                    [single] => (Some(single.clone()), None),
+                    many => (None, Some(many.to_vec())),
+                };
+                IndexDefinition {
+                    name: i.name.clone(),
+                    path: i.collection.clone(),
+                    field,
+                    fields,
+                    sparse: Some(i.sparse),
+                    filter: i.filter.clone(),
                 }
-                Ok(None)
-            }
-            _ => Ok(None),
-        }
+            })
+            .collect())
     }
 }
 
+#[napi(object)]
+pub struct FollowerLag {
+    /// LSN of the last write applied to the in-memory copy.
+    pub lsn: i64,
+    /// LSN of the last write in the writer's files.
+    pub latest_lsn: i64,
+    /// Writes in the files that are not applied yet.
+    pub behind: i64,
+    /// Milliseconds since the copy was last brought up to date.
+    pub ms_since_sync: f64,
+}
+
+#[napi(object)]
+pub struct TextIndexDefinition {
+    pub name: String,
+    pub path: String,
+    /// Text fields to index. Arrays of strings are indexed element by element.
+    pub fields: Vec<String>,
+    /// `word` (default) or `whitespace`.
+    pub tokenizer: Option<String>,
+    /// Defaults to true.
+    pub lowercase: Option<bool>,
+    /// Snowball stemming language such as `english`; `none` disables
+    /// stemming. Defaults to `english`.
+    pub language: Option<String>,
+    /// Replaces the built-in English stop words; pass `[]` to keep every word.
+    pub stop_words: Option<Vec<String>>,
+}
+
+#[napi(object)]
+pub struct GeoIndexDefinition {
+    pub name: String,
+    pub path: String,
+    /// Field holding a `{ lat, lng }` point or an array of points.
+    pub field: String,
+    /// Grid cell size in degrees. Defaults to 0.1 (about 11 km of latitude).
+    pub cell_size: Option<f64>,
+}
+
+#[napi(object)]
+pub struct SearchOptions {
+    /// Name of the text index to use when the collection has several.
+    pub index: Option<String>,
+    /// Extra query the hits must match.
+    pub filter: Option<serde_json::Value>,
+    pub limit: Option<u32>,
+    pub skip: Option<u32>,
+    pub select: Option<Vec<String>>,
+}
+
+#[napi(object)]
+pub struct SearchHit {
+    pub score: f64,
+    pub document: serde_json::Value,
+}
+
 #[napi(object)]
 pub struct QueryOptions {
     pub limit: Option<u32>,
     pub skip: Option<u32>,
     pub sort: Option<serde_json::Value>,
     pub select: Option<Vec<String>>,
+    /// Continuation token from `findPage`: results resume strictly after
+    /// the last document of that page.
+    pub after: Option<String>,
+}
+
+#[napi(object)]
+pub struct QueryPage {
+    pub items: Vec<serde_json::Value>,
+    /// Token for the next page, or `None` after the last one.
+    pub next_token: Option<String>,
+}
+
+/// How a query was executed, as reported by `explain`.
+#[napi(object)]
+pub struct QueryExplain {
+    /// `collectionScan`, `shardScan` (scan of the sharded object, one
+    /// shard per thread), `indexScan` (index lookup on the query),
+    /// `indexOrderScan` (walk of an index in sort order), `textSearch` or
+    /// `geoScan` (grid cells overlapping a geo condition).
+    pub plan: String,
+    pub index: Option<String>,
+    pub candidates_examined: u32,
+    pub returned: u32,
+    pub sorted_by_index: bool,
+    /// Whether the collection was large enough to filter and sort in parallel.
+    pub parallel: bool,
+    pub filter_ms: f64,
+    pub sort_ms: f64,
+    pub projection_ms: f64,
+}
+
+impl QueryExplain {
+    fn new() -> Self {
+        QueryExplain {
+            plan: "collectionScan".to_string(),
+            index: None,
+            candidates_examined: 0,
+            returned: 0,
+            sorted_by_index: false,
+            parallel: false,
+            filter_ms: 0.0,
+            sort_ms: 0.0,
+            projection_ms: 0.0,
+        }
+    }
+}
+
+#[napi(object)]
+pub struct IndexDefinition {
+    pub name: String,
+    pub path: String,
+    pub field: Option<String>,
+    pub fields: Option<Vec<String>>,
+    /// Skip documents that have none of the indexed fields.
+    pub sparse: Option<bool>,
+    /// Only index documents matching this query (a partial index).
+    pub filter: Option<serde_json::Value>,
 }
 
 // Helpers
 
+/// Encrypts `plaintext` with AES-256-GCM into the IV followed by the
+/// ciphertext and its tag.
+fn encrypt_bytes(key: &[u8], plaintext: &[u8]) -> Result<Vec<u8>> {
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+    let mut iv = [0u8; 12];
+    OsRng.fill_bytes(&mut iv);
+    let nonce = Nonce::from_slice(&iv);
+    let ciphertext_with_tag = cipher
+        .encrypt(nonce, plaintext)
+        .map_err(|_| Error::from_status(Status::GenericFailure))?;
+    let mut output = iv.to_vec();
+    output.extend_from_slice(&ciphertext_with_tag);
+    Ok(output)
+}
+
+/// Reverses `encrypt_bytes`.
+fn decrypt_bytes(key: &[u8], sealed: &[u8]) -> Result<Vec<u8>> {
+    let failed = || Error::new(Status::GenericFailure, "Decryption failed".to_string());
+    if sealed.len() < 12 {
+        return Err(failed());
+    }
+    let (iv, ciphertext_with_tag) = sealed.split_at(12);
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+    cipher
+        .decrypt(Nonce::from_slice(iv), ciphertext_with_tag)
+        .map_err(|_| failed())
+}
+
+/// Encrypts `plaintext` with AES-256-GCM into the `{ iv, content, tag }`
+/// wrapper used by JSON files.
+fn encrypt_payload(key: &[u8], plaintext: &[u8]) -> Result<Value> {
+    let sealed = encrypt_bytes(key, plaintext)?;
+    let (iv, ciphertext_with_tag) = sealed.split_at(12);
+    let tag_len = 16;
+    let split_idx = ciphertext_with_tag.len() - tag_len;
+    let ciphertext = &ciphertext_with_tag[..split_idx];
+    let tag = &ciphertext_with_tag[split_idx..];
+    Ok(serde_json::json!({
+        "iv": hex::encode(iv),
+        "content": hex::encode(ciphertext),
+        "tag": hex::encode(tag)
+    }))
+}
+
+/// The IV, ciphertext and tag of a `{ iv, content, tag }` wrapper, laid out
+/// as `decrypt_bytes` takes them.
+fn unwrap_hex_payload(encrypted_data: &Value) -> Result<Vec<u8>> {
+    let field = |name: &str, missing: &str, invalid: &str| {
+        let hex_value = encrypted_data[name]
+            .as_str()
+            .ok_or_else(|| Error::new(Status::GenericFailure, missing.to_string()))?;
+        hex::decode(hex_value).map_err(|_| Error::new(Status::GenericFailure, invalid.to_string()))
+    };
+    let mut sealed = field("iv", "Missing IV", "Invalid IV hex")?;
+    if sealed.len() != 12 {
+        return Err(Error::new(
+            Status::GenericFailure,
+            "Invalid IV length".to_string(),
+        ));
+    }
+    sealed.extend(field("content", "Missing content", "Invalid content hex")?);
+    sealed.extend(field("tag", "Missing tag", "Invalid tag hex")?);
+    Ok(sealed)
+}
+
+fn apply_operation(data: &mut Value, op: Operation) {
+    match op {
+        Operation::Set { path, value } => {
+            if path.is_empty() {
+                *data = value;
+            } else {
+                set_value_by_path(data, &path, value);
+            }
+        }
+        Operation::Delete { path } => {
+            if path.is_empty() {
+                *data = Value::Object(serde_json::Map::new());
+            } else {
+                delete_value_by_path(data, &path);
+            }
+        }
+    }
+}
+
+fn apply_indexed(data: &mut Value, indexes: &mut IndexSet, op: Operation) {
+    let path = op.path().to_string();
+    indexes.before_write(data, &path);
+    apply_operation(data, op);
+    indexes.after_write(data, &path);
+}
+
+fn collection_len(collection: &Value) -> usize {
+    match collection {
+        Value::Array(arr) => arr.len(),
+        Value::Object(map) => map.len(),
+        _ => 0,
+    }
+}
+
+/// The documents of a collection with their ids, in collection order.
+fn collection_entries(collection: &Value) -> Box<dyn Iterator<Item = (DocId, &Value)> + '_> {
+    match collection {
+        Value::Array(arr) => Box::new(arr.iter().enumerate().map(|(i, v)| (DocId::Pos(i), v))),
+        Value::Object(map) => Box::new(map.iter().map(|(k, v)| (DocId::Key(k.clone()), v))),
+        _ => Box::new(std::iter::empty()),
+    }
+}
+
+fn collection_items(collection: &Value) -> Box<dyn Iterator<Item = &Value> + '_> {
+    match collection {
+        Value::Array(arr) => Box::new(arr.iter()),
+        Value::Object(map) => Box::new(map.values()),
+        _ => Box::new(std::iter::empty()),
+    }
+}
+
+/// A document selected by a query, with its id in the collection.
+type Match<'a> = (DocId, &'a Value);
+
+/// Uses an index to find the documents matching `query`, in collection order.
+/// Returns the index, the matches and the number of candidates examined, or
+/// `None` when no index applies to the query.
+fn indexed_matches<'a, 'i>(
+    indexes: &'i IndexSet,
+    collection: &'a Value,
+    path: &str,
+    query: &Value,
+) -> Option<(&'i Index, Vec<Match<'a>>, usize)> {
+    let (index, mut ids) = indexes.candidates(path, query)?;
+    ids.sort();
+    ids.dedup();
+    let examined = ids.len();
+    let matches = ids
+        .into_iter()
+        .filter_map(|id| resolve_match(collection, id, query))
+        .collect();
+    Some((index, matches, examined))
+}
+
+/// The document `id` names, if it matches `query`.
+fn resolve_match<'a>(collection: &'a Value, id: DocId, query: &Value) -> Option<Match<'a>> {
+    let item = id.resolve(collection)?;
+    matches_query(item, query).then_some((id, item))
+}
+
+/// The `$text` part of a query: `{ $text: { $search, $index? } }`.
+struct TextQuery {
+    search: String,
+    index: Option<String>,
+}
+
+/// Splits `$text` off a query, returning it and the remaining conditions.
+fn split_text_query(query: &Value) -> Result<(Option<TextQuery>, Cow<'_, Value>)> {
+    let text = match query.get("$text") {
+        Some(t) => t,
+        None => return Ok((None, Cow::Borrowed(query))),
+    };
+    let text_query = match text {
+        Value::String(search) => TextQuery {
+            search: search.clone(),
+            index: None,
+        },
+        Value::Object(spec) => TextQuery {
+            search: spec
+                .get("$search")
+                .and_then(|s| s.as_str())
+                .ok_or_else(|| {
+                    Error::new(
+                        Status::InvalidArg,
+                        "$text requires a $search string".to_string(),
+                    )
+                })?
+                .to_string(),
+            index: spec
+                .get("$index")
+                .and_then(|i| i.as_str())
+                .map(String::from),
+        },
+        _ => {
+            return Err(Error::new(
+                Status::InvalidArg,
+                "$text must be a string or { $search }".to_string(),
+            ))
+        }
+    };
+    let mut rest = query.as_object().cloned().unwrap_or_default();
+    rest.remove("$text");
+    Ok((Some(text_query), Cow::Owned(Value::Object(rest))))
+}
+
+fn elapsed_ms(started: Instant) -> f64 {
+    started.elapsed().as_secs_f64() * 1000.0
+}
+
+/// Returns the fields of a sort spec whose keys all share one direction,
+/// along with whether that direction is descending.
+fn sort_fields(sort_opts: Option<&Value>) -> Option<(Vec<&str>, bool)> {
+    let map = sort_opts?.as_object()?;
+    let mut orders = map.values().map(|o| o.as_i64().unwrap_or(1) < 0);
+    let descending = orders.next()?;
+    if orders.any(|d| d != descending) {
+        return None;
+    }
+    Some((map.keys().map(|k| k.as_str()).collect(), descending))
+}
+
+fn project(item: &Value, fields: &[String]) -> Value {
+    let mut projected = Value::Object(serde_json::Map::new());
+    for field in fields {
+        if let Some(val) = get_value_by_path(item, field) {
+            set_value_by_path(&mut projected, field, val.clone());
+        }
+    }
+    projected
+}
+
 fn sort_json(a: &Value, b: &Value, sort_opts: &Value) -> Ordering {
     if let Value::Object(map) = sort_opts {
         for (key, order_val) in map {
@@ -617,22 +2501,8 @@ fn sort_json(a: &Value, b: &Value, sort_opts: &Value) -> Ordering {
 
             let order = order_val.as_i64().unwrap_or(1);
 
-            let cmp = match (val_a, val_b) {
-                (Some(va), Some(vb)) => compare_json(va, vb)
-                    .map(|i| {
-                        if i == 0 {
-                            Ordering::Equal
-                        } else if i < 0 {
-                            Ordering::Less
-                        } else {
-                            Ordering::Greater
-                        }
-                    })
-                    .unwrap_or(Ordering::Equal),
-                (Some(_), None) => Ordering::Greater,
-                (None, Some(_)) => Ordering::Less,
-                (None, None) => Ordering::Equal,
-            };
+            // Missing fields first, then mixed types by type, as in indexes.
+            let cmp = compare_parts(val_a, val_b);
 
             if cmp != Ordering::Equal {
                 return if order < 0 { cmp.reverse() } else { cmp };
@@ -830,7 +2700,7 @@ fn check_condition(value: Option<&Value>, condition: &Value) -> bool {
     if let Value::Object(op_map) = condition {
         let has_ops = op_map.keys().any(|k| k.starts_with('$'));
         if !has_ops {
-            return Some(condition) == value;
+            return value.is_some_and(|v| v == condition || array_contains(v, condition));
         }
 
         for (op, op_val) in op_map {
@@ -841,12 +2711,17 @@ fn check_condition(value: Option<&Value>, condition: &Value) -> bool {
         true
     } else {
         match value {
-            Some(v) => v == condition,
+            Some(v) => v == condition || array_contains(v, condition),
             None => condition.is_null(),
         }
     }
 }
 
+/// Array fields match a condition when any of their elements does.
+fn array_contains(v: &Value, target: &Value) -> bool {
+    matches!(v, Value::Array(elems) if elems.contains(target))
+}
+
 fn match_operator(value: Option<&Value>, op: &str, target: &Value) -> bool {
     let v = match value {
         Some(val) => val,
@@ -854,22 +2729,10 @@ fn match_operator(value: Option<&Value>, op: &str, target: &Value) -> bool {
     };
 
     match op {
-        "$eq" => v == target,
-        "$ne" => v != target,
-        "$gt" => compare_json(v, target).map(|c| c > 0).unwrap_or(false),
-        "$gte" => compare_json(v, target).map(|c| c >= 0).unwrap_or(false),
-        "$lt" => compare_json(v, target).map(|c| c < 0).unwrap_or(false),
-        "$lte" => compare_json(v, target).map(|c| c <= 0).unwrap_or(false),
-        "$in" => {
-            if let Value::Array(arr) = target {
-                arr.contains(v)
-            } else {
-                false
-            }
-        }
+        "$ne" => v != target && !array_contains(v, target),
         "$nin" => {
             if let Value::Array(arr) = target {
-                !arr.contains(v)
+                !arr.contains(v) && !arr.iter().any(|t| array_contains(v, t))
             } else {
                 false
             }
@@ -881,6 +2744,35 @@ fn match_operator(value: Option<&Value>, op: &str, target: &Value) -> bool {
                 false
             }
         }
+        _ => {
+            match_value(v, op, target)
+                || matches!(v, Value::Array(elems) if elems.iter().any(|e| match_value(e, op, target)))
+        }
+    }
+}
+
+fn match_value(v: &Value, op: &str, target: &Value) -> bool {
+    match op {
+        "$eq" => v == target,
+        "$gt" => compare_json(v, target).map(|c| c > 0).unwrap_or(false),
+        "$gte" => compare_json(v, target).map(|c| c >= 0).unwrap_or(false),
+        "$lt" => compare_json(v, target).map(|c| c < 0).unwrap_or(false),
+        "$lte" => compare_json(v, target).map(|c| c <= 0).unwrap_or(false),
+        "$in" => {
+            if let Value::Array(arr) = target {
+                arr.contains(v)
+            } else {
+                false
+            }
+        }
+        op if geo::is_geo_operator(op) => geo::match_geo(v, op, target),
+        "$between" => match target {
+            Value::Array(bounds) if bounds.len() == 2 => {
+                compare_json(v, &bounds[0]).map(|c| c >= 0).unwrap_or(false)
+                    && compare_json(v, &bounds[1]).map(|c| c <= 0).unwrap_or(false)
+            }
+            _ => false,
+        },
         _ => false,
     }
 }
@@ -0,0 +1,136 @@
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The writer locks this process holds, by lock file. Like fcntl locks, a
+/// lock belongs to the process: opening a database it already writes shares
+/// the lock instead of failing.
+static LOCKS: LazyLock<Mutex<HashMap<PathBuf, Weak<WriterLock>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// An exclusive advisory lock on a database's lock file, which records the
+/// PID of the process holding it. The OS releases the lock when the process
+/// exits, so a lock file left behind by a crash is simply taken over.
+pub(crate) struct WriterLock {
+    file: File,
+}
+
+impl Drop for WriterLock {
+    fn drop(&mut self) {
+        // The lock itself goes with the file.
+        let _ = self.file.set_len(0);
+    }
+}
+
+/// Why a lock could not be taken.
+enum Refused {
+    /// Another process holds it; its PID if the lock file records one.
+    Held(Option<u32>),
+    Failed(io::Error),
+}
+
+/// Takes the writer lock on `path`, waiting up to `timeout` for another
+/// process to release it.
+pub(crate) fn acquire(path: &Path, timeout: Duration) -> Result<Arc<WriterLock>, String> {
+    let mut locks = LOCKS.lock();
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir).map_err(|e| format!("Failed to open lock file: {}", e))?;
+    }
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .map_err(|e| format!("Failed to open lock file: {}", e))?;
+    let key = fs::canonicalize(path).map_err(|e| format!("Failed to open lock file: {}", e))?;
+    if let Some(lock) = locks.get(&key).and_then(Weak::upgrade) {
+        return Ok(lock);
+    }
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        match try_lock(&mut file) {
+            Ok(()) => break,
+            Err(Refused::Held(_)) if Instant::now() < deadline => thread::sleep(RETRY_INTERVAL),
+            Err(Refused::Held(pid)) => {
+                let holder = match pid {
+                    Some(pid) => format!("process {}", pid),
+                    None => "another process".to_string(),
+                };
+                return Err(if timeout.is_zero() {
+                    format!("Database is locked by {}", holder)
+                } else {
+                    format!(
+                        "Database is still locked by {} after waiting {} ms",
+                        holder,
+                        timeout.as_millis()
+                    )
+                });
+            }
+            Err(Refused::Failed(e)) => return Err(format!("Failed to lock database: {}", e)),
+        }
+    }
+
+    write_pid(&mut file).map_err(|e| format!("Failed to write lock file: {}", e))?;
+    let lock = Arc::new(WriterLock { file });
+    locks.retain(|_, lock| lock.strong_count() > 0);
+    locks.insert(key, Arc::downgrade(&lock));
+    Ok(lock)
+}
+
+fn try_lock(file: &mut File) -> Result<(), Refused> {
+    match file.try_lock() {
+        Ok(()) => Ok(()),
+        Err(TryLockError::WouldBlock) => Err(Refused::Held(read_pid(file))),
+        // Without advisory locks, a live process recorded in the lock file
+        // holds it; the PID of one that exited is stale.
+        Err(TryLockError::Error(e)) if e.kind() == io::ErrorKind::Unsupported => {
+            match read_pid(file) {
+                Some(pid) if pid != std::process::id() && is_running(pid) => {
+                    Err(Refused::Held(Some(pid)))
+                }
+                _ => Ok(()),
+            }
+        }
+        Err(TryLockError::Error(e)) => Err(Refused::Failed(e)),
+    }
+}
+
+fn read_pid(file: &mut File) -> Option<u32> {
+    let mut content = String::new();
+    file.seek(SeekFrom::Start(0)).ok()?;
+    file.read_to_string(&mut content).ok()?;
+    content.trim().parse().ok()
+}
+
+fn write_pid(file: &mut File) -> io::Result<()> {
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(std::process::id().to_string().as_bytes())?;
+    file.sync_data()
+}
+
+#[cfg(unix)]
+fn is_running(pid: u32) -> bool {
+    let pid = match libc::pid_t::try_from(pid) {
+        Ok(pid) => pid,
+        Err(_) => return false,
+    };
+    // Signal 0 only checks that the process exists; EPERM means it does
+    // but belongs to another user.
+    let alive = unsafe { libc::kill(pid, 0) } == 0;
+    alive || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn is_running(_pid: u32) -> bool {
+    true
+}
@@ -0,0 +1,374 @@
+use memmap2::Mmap;
+use napi::{Error, Result, Status};
+use napi_derive::napi;
+use serde_json::{Map, Number, Value};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::check_condition;
+
+/// Snapshot files start with these bytes, then the format version and the
+/// offset of the root node.
+const MAGIC: &[u8; 7] = b"\0JDBMAP";
+const VERSION: u8 = 1;
+const HEADER_LEN: usize = 16;
+
+const NULL: u8 = 0;
+const FALSE: u8 = 1;
+const TRUE: u8 = 2;
+const INT: u8 = 3;
+const UINT: u8 = 4;
+const FLOAT: u8 = 5;
+const STRING: u8 = 6;
+const ARRAY: u8 = 7;
+const OBJECT: u8 = 8;
+
+/// Writes `value` as a snapshot file that `MappedDatabase` can read in
+/// place. Nodes are written children first, so every node only points
+/// backwards; arrays hold the offsets of their elements and objects their
+/// entries sorted by key, which makes lookups a binary search.
+pub(crate) fn write_snapshot(value: &Value, path: &Path) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut writer = SnapshotWriter {
+        out: BufWriter::new(File::create(&tmp)?),
+        pos: HEADER_LEN as u64,
+    };
+    writer.out.write_all(&[0; HEADER_LEN])?;
+    let root = writer.node(value)?;
+    let mut file = writer.out.into_inner().map_err(|e| e.into_error())?;
+
+    let mut header = MAGIC.to_vec();
+    header.push(VERSION);
+    header.extend_from_slice(&root.to_le_bytes());
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(&header)?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&tmp, path)
+}
+
+struct SnapshotWriter {
+    out: BufWriter<File>,
+    pos: u64,
+}
+
+impl SnapshotWriter {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.out.write_all(bytes)?;
+        self.pos += bytes.len() as u64;
+        Ok(())
+    }
+
+    fn string(&mut self, s: &str) -> io::Result<()> {
+        self.write(&(s.len() as u32).to_le_bytes())?;
+        self.write(s.as_bytes())
+    }
+
+    /// Writes `value` and returns its offset.
+    fn node(&mut self, value: &Value) -> io::Result<u64> {
+        let children = match value {
+            Value::Array(arr) => arr
+                .iter()
+                .map(|v| self.node(v))
+                .collect::<io::Result<_>>()?,
+            Value::Object(map) => {
+                let mut offsets = Vec::with_capacity(map.len() * 2);
+                for (key, v) in map {
+                    offsets.push(self.pos);
+                    self.string(key)?;
+                    offsets.push(self.node(v)?);
+                }
+                offsets
+            }
+            _ => Vec::new(),
+        };
+
+        let offset = self.pos;
+        match value {
+            Value::Null => self.write(&[NULL])?,
+            Value::Bool(false) => self.write(&[FALSE])?,
+            Value::Bool(true) => self.write(&[TRUE])?,
+            Value::Number(n) => {
+                if let Some(i) = n.as_i64() {
+                    self.write(&[INT])?;
+                    self.write(&i.to_le_bytes())?;
+                } else if let Some(u) = n.as_u64() {
+                    self.write(&[UINT])?;
+                    self.write(&u.to_le_bytes())?;
+                } else {
+                    self.write(&[FLOAT])?;
+                    self.write(&n.as_f64().unwrap_or(0.0).to_le_bytes())?;
+                }
+            }
+            Value::String(s) => {
+                self.write(&[STRING])?;
+                self.string(s)?;
+            }
+            Value::Array(arr) => {
+                self.write(&[ARRAY])?;
+                self.write(&(arr.len() as u32).to_le_bytes())?;
+            }
+            Value::Object(map) => {
+                // serde_json maps iterate in key order.
+                self.write(&[OBJECT])?;
+                self.write(&(map.len() as u32).to_le_bytes())?;
+            }
+        }
+        for child in children {
+            self.write(&child.to_le_bytes())?;
+        }
+        Ok(offset)
+    }
+}
+
+/// A node of a mapped snapshot. Reads are bounds-checked, and a child must
+/// come before its parent in the file, so a damaged file can give wrong
+/// answers but cannot make a walk loop or read out of bounds.
+#[derive(Clone, Copy)]
+struct Node<'a> {
+    buf: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Node<'a> {
+    fn tag(self) -> Option<u8> {
+        self.buf.get(self.offset).copied()
+    }
+
+    fn bytes(self, at: usize, len: usize) -> Option<&'a [u8]> {
+        self.buf.get(at..at.checked_add(len)?)
+    }
+
+    fn u32_at(self, at: usize) -> Option<usize> {
+        Some(u32::from_le_bytes(self.bytes(at, 4)?.try_into().ok()?) as usize)
+    }
+
+    fn u64_at(self, at: usize) -> Option<u64> {
+        Some(u64::from_le_bytes(self.bytes(at, 8)?.try_into().ok()?))
+    }
+
+    fn str_at(self, at: usize) -> Option<&'a str> {
+        let len = self.u32_at(at)?;
+        std::str::from_utf8(self.bytes(at + 4, len)?).ok()
+    }
+
+    /// The number of children of an array or object.
+    fn len(self) -> usize {
+        match self.tag() {
+            Some(ARRAY | OBJECT) => self.u32_at(self.offset + 1).unwrap_or(0),
+            _ => 0,
+        }
+    }
+
+    /// The `i`-th offset stored after the child count.
+    fn offset_at(self, i: usize) -> Option<usize> {
+        let offset = self.u64_at(self.offset + 5 + i.checked_mul(8)?)?;
+        usize::try_from(offset).ok().filter(|o| *o < self.offset)
+    }
+
+    fn child(self, i: usize) -> Option<Node<'a>> {
+        Some(Node {
+            buf: self.buf,
+            offset: self.offset_at(i)?,
+        })
+    }
+
+    fn element(self, i: usize) -> Option<Node<'a>> {
+        if self.tag()? != ARRAY || i >= self.len() {
+            return None;
+        }
+        self.child(i)
+    }
+
+    fn entry(self, i: usize) -> Option<(&'a str, Node<'a>)> {
+        Some((self.str_at(self.offset_at(2 * i)?)?, self.child(2 * i + 1)?))
+    }
+
+    fn field(self, key: &str) -> Option<Node<'a>> {
+        if self.tag()? != OBJECT {
+            return None;
+        }
+        let (mut low, mut high) = (0, self.len());
+        while low < high {
+            let mid = low + (high - low) / 2;
+            let (k, value) = self.entry(mid)?;
+            match k.cmp(key) {
+                std::cmp::Ordering::Equal => return Some(value),
+                std::cmp::Ordering::Less => low = mid + 1,
+                std::cmp::Ordering::Greater => high = mid,
+            }
+        }
+        None
+    }
+
+    /// Walks a dotted path like `get_value_by_path`.
+    fn at_path(self, path: &str) -> Option<Node<'a>> {
+        if path.is_empty() {
+            return Some(self);
+        }
+        path.split('.')
+            .try_fold(self, |node, part| match node.tag()? {
+                OBJECT => node.field(part),
+                ARRAY => node.element(part.parse().ok()?),
+                _ => None,
+            })
+    }
+
+    /// The documents of a collection: array elements or object values.
+    fn items(self) -> impl Iterator<Item = Node<'a>> {
+        let tag = self.tag();
+        (0..self.len()).filter_map(move |i| match tag {
+            Some(ARRAY) => self.child(i),
+            _ => self.child(2 * i + 1),
+        })
+    }
+
+    /// Copies the subtree out of the mapping.
+    fn to_value(self) -> Value {
+        // A damaged file can point several parents at one subtree, so the
+        // copy is cut off once it outgrows the file, which a copy of an
+        // intact subtree never does.
+        let mut budget = self.buf.len();
+        self.copy(&mut budget)
+    }
+
+    fn copy(self, budget: &mut usize) -> Value {
+        // Charge for the node itself and for its string or children.
+        let cost = match self.tag() {
+            Some(STRING) => self.u32_at(self.offset + 1).unwrap_or(0),
+            _ => self.len(),
+        } + 1;
+        if cost > *budget {
+            return Value::Null;
+        }
+        *budget -= cost;
+        let at = self.offset + 1;
+        let value = match self.tag() {
+            Some(FALSE) => Some(Value::Bool(false)),
+            Some(TRUE) => Some(Value::Bool(true)),
+            Some(INT) => self
+                .bytes(at, 8)
+                .map(|b| Value::from(i64::from_le_bytes(b.try_into().unwrap()))),
+            Some(UINT) => self
+                .bytes(at, 8)
+                .map(|b| Value::from(u64::from_le_bytes(b.try_into().unwrap()))),
+            Some(FLOAT) => self
+                .bytes(at, 8)
+                .and_then(|b| Number::from_f64(f64::from_le_bytes(b.try_into().unwrap())))
+                .map(Value::Number),
+            Some(STRING) => self.str_at(at).map(|s| Value::String(s.to_string())),
+            Some(ARRAY) => Some(Value::Array(
+                (0..self.len())
+                    .map(|i| {
+                        self.child(i)
+                            .map_or(Value::Null, |child| child.copy(budget))
+                    })
+                    .collect(),
+            )),
+            Some(OBJECT) => Some(Value::Object(
+                (0..self.len())
+                    .filter_map(|i| self.entry(i))
+                    .map(|(k, v)| (k.to_string(), v.copy(budget)))
+                    .collect::<Map<_, _>>(),
+            )),
+            _ => None,
+        };
+        value.unwrap_or(Value::Null)
+    }
+
+    /// `matches_query` on the mapped document: only the queried fields are
+    /// copied out.
+    fn matches(self, query: &Value) -> bool {
+        match query {
+            Value::Object(query_map) => query_map.iter().all(|(key, condition)| {
+                let value = self.at_path(key).map(Node::to_value);
+                check_condition(value.as_ref(), condition)
+            }),
+            _ => false,
+        }
+    }
+}
+
+/// A read-only database answering lookups from a memory-mapped snapshot
+/// written by `exportSnapshot`. Opening it reads only the header, and a
+/// lookup copies out just the values it returns, so large reference
+/// datasets can be served without loading them into memory.
+#[napi]
+pub struct MappedDatabase {
+    map: Mmap,
+    root: usize,
+}
+
+#[napi]
+impl MappedDatabase {
+    #[napi(constructor)]
+    pub fn new(filename: String) -> Result<Self> {
+        let file = File::open(&filename).map_err(|e| {
+            Error::new(
+                Status::GenericFailure,
+                format!("Failed to open snapshot: {}", e),
+            )
+        })?;
+        // SAFETY: snapshots are written to a temporary file and renamed into
+        // place, never modified, and every read of the mapping is
+        // bounds-checked.
+        let map = unsafe { Mmap::map(&file) }.map_err(|e| {
+            Error::new(
+                Status::GenericFailure,
+                format!("Failed to map snapshot: {}", e),
+            )
+        })?;
+        if map.len() < HEADER_LEN || !map.starts_with(MAGIC) {
+            return Err(Error::new(
+                Status::InvalidArg,
+                format!("{} is not a database snapshot", filename),
+            ));
+        }
+        if map[MAGIC.len()] != VERSION {
+            return Err(Error::new(
+                Status::InvalidArg,
+                format!("Unsupported snapshot version {}", map[MAGIC.len()]),
+            ));
+        }
+        let root = u64::from_le_bytes(map[8..16].try_into().unwrap());
+        let root = usize::try_from(root)
+            .ok()
+            .filter(|root| (HEADER_LEN..map.len()).contains(root))
+            .ok_or_else(|| {
+                Error::new(
+                    Status::InvalidArg,
+                    "Invalid snapshot root offset".to_string(),
+                )
+            })?;
+        Ok(MappedDatabase { map, root })
+    }
+
+    fn node(&self, path: &str) -> Option<Node<'_>> {
+        Node {
+            buf: &self.map,
+            offset: self.root,
+        }
+        .at_path(path)
+    }
+
+    #[napi(js_name = "get")]
+    pub fn get_value(&self, path: Option<String>) -> Value {
+        self.node(path.as_deref().unwrap_or(""))
+            .map_or(Value::Null, Node::to_value)
+    }
+
+    #[napi]
+    pub fn has(&self, path: String) -> bool {
+        self.node(&path).is_some()
+    }
+
+    #[napi]
+    pub fn find_one(&self, path: String, query: Value) -> Option<Value> {
+        let collection = self.node(&path)?;
+        collection
+            .items()
+            .find(|item| item.matches(&query))
+            .map(Node::to_value)
+    }
+}
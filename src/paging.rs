@@ -0,0 +1,74 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use napi::{Error, Result, Status};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::cmp::Ordering;
+
+use crate::get_value_by_path;
+use crate::indexes::{compare_parts, DocId, KeyPart};
+
+/// A continuation token: the sort key and id of the last document of a page.
+/// Results are ordered by the sort fields and then by id, so the next page
+/// starts strictly after this position, whatever was written in between.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct PageToken {
+    /// The sort spec the token was issued for (`null` for collection order).
+    sort: Value,
+    key: Vec<KeyPart>,
+    id: DocId,
+}
+
+impl PageToken {
+    pub(crate) fn new(doc: &Value, id: DocId, sort: Option<&Value>) -> Self {
+        let key = sort_keys(sort)
+            .map(|(field, _)| KeyPart(get_value_by_path(doc, field).cloned()))
+            .collect();
+        PageToken {
+            sort: sort.cloned().unwrap_or(Value::Null),
+            key,
+            id,
+        }
+    }
+
+    pub(crate) fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).unwrap_or_default())
+    }
+
+    /// Decodes a token and checks it was issued for the same sort.
+    pub(crate) fn decode(token: &str, sort: Option<&Value>) -> Result<Self> {
+        let token: PageToken = URL_SAFE_NO_PAD
+            .decode(token)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .ok_or_else(|| Error::new(Status::InvalidArg, "Invalid page token".to_string()))?;
+        if token.sort != sort.cloned().unwrap_or(Value::Null)
+            || token.key.len() != sort_keys(sort).count()
+        {
+            return Err(Error::new(
+                Status::InvalidArg,
+                "Page token was issued for a different sort".to_string(),
+            ));
+        }
+        Ok(token)
+    }
+
+    /// Whether `doc` comes strictly after the token in the query's order.
+    pub(crate) fn precedes(&self, doc: &Value, id: &DocId, sort: Option<&Value>) -> bool {
+        for ((field, descending), part) in sort_keys(sort).zip(&self.key) {
+            let cmp = compare_parts(get_value_by_path(doc, field), part.0.as_ref());
+            let cmp = if descending { cmp.reverse() } else { cmp };
+            if cmp != Ordering::Equal {
+                return cmp == Ordering::Greater;
+            }
+        }
+        id > &self.id
+    }
+}
+
+/// The fields of a sort spec with whether each one is descending.
+fn sort_keys(sort: Option<&Value>) -> impl Iterator<Item = (&str, bool)> {
+    sort.and_then(|s| s.as_object())
+        .into_iter()
+        .flatten()
+        .map(|(field, order)| (field.as_str(), order.as_i64().unwrap_or(1) < 0))
+}
@@ -0,0 +1,807 @@
+//! Streams the writes of a primary to replicas over TCP or a Unix socket.
+//!
+//! Both sides exchange JSON lines. The primary opens with a challenge, which
+//! a replica answers with the LSN it has applied and, when the primary has a
+//! shared secret, the challenge sealed with it. The primary answers with the
+//! writes logged since, or with a snapshot of its data when the WAL no longer
+//! reaches back that far, and then streams every write it commits. The
+//! replica acknowledges the LSN it has logged whenever it has caught up with
+//! what it received. With a secret, every line after the handshake is sealed
+//! with AES-256-GCM.
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use napi_derive::napi;
+use parking_lot::{Condvar, Mutex};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{decrypt_bytes, encrypt_bytes, DatabaseCore, Operation};
+
+/// How often a primary checks for new connections and a replica retries a
+/// lost one.
+const RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Messages queued for a replica before it is disconnected for falling
+/// behind. It catches up from the WAL or a snapshot when it reconnects.
+const REPLICA_QUEUE: usize = 1024;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+enum ToReplica {
+    Snapshot { lsn: u64, data: Value },
+    Record { lsn: u64, op: Operation },
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+enum ToReplicaRef<'a> {
+    Snapshot { lsn: u64, data: &'a Value },
+    Record { lsn: u64, op: &'a Operation },
+}
+
+/// The primary's side of the handshake, sent before any sealed line.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+enum Handshake {
+    /// `nonce` is set when the primary has a secret to be proven.
+    Challenge {
+        nonce: Option<String>,
+    },
+    Accepted,
+    Rejected {
+        reason: String,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+enum ToPrimary {
+    Hello {
+        lsn: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        proof: Option<String>,
+    },
+    Ack {
+        lsn: u64,
+    },
+}
+
+fn encode<T: Serialize>(message: &T) -> Vec<u8> {
+    let mut line = serde_json::to_vec(message).unwrap_or_default();
+    line.push(b'\n');
+    line
+}
+
+fn read_message<T: for<'de> Deserialize<'de>>(reader: &mut impl BufRead) -> io::Result<T> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    serde_json::from_str(&line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Parses a shared secret: 32 bytes in hex, like an encryption key.
+fn parse_secret(secret: Option<String>) -> Result<Option<Arc<Vec<u8>>>, String> {
+    let invalid = || "Replication secret must be 32 bytes of hex".to_string();
+    secret
+        .map(|secret| match hex::decode(secret) {
+            Ok(key) if key.len() == 32 => Ok(Arc::new(key)),
+            _ => Err(invalid()),
+        })
+        .transpose()
+}
+
+/// The `nonce` of a challenge sealed with the secret, which only a holder of
+/// the secret can produce.
+fn prove(secret: &[u8], nonce: &str) -> io::Result<String> {
+    encrypt_bytes(secret, nonce.as_bytes())
+        .map(|sealed| STANDARD.encode(sealed))
+        .map_err(|e| io::Error::other(e.reason))
+}
+
+fn verify(secret: &[u8], nonce: &str, proof: Option<&str>) -> bool {
+    proof
+        .and_then(|proof| STANDARD.decode(proof).ok())
+        .and_then(|sealed| decrypt_bytes(secret, &sealed).ok())
+        .is_some_and(|opened| opened == nonce.as_bytes())
+}
+
+/// Encodes and reads the lines of a connection after the handshake, sealed
+/// with the shared secret when there is one.
+#[derive(Clone)]
+struct Frames {
+    secret: Option<Arc<Vec<u8>>>,
+}
+
+impl Frames {
+    fn encode<T: Serialize>(&self, message: &T) -> Vec<u8> {
+        let secret = match &self.secret {
+            Some(secret) => secret,
+            None => return encode(message),
+        };
+        let json = serde_json::to_vec(message).unwrap_or_default();
+        let mut line = encrypt_bytes(secret, &json)
+            .map(|sealed| STANDARD.encode(sealed).into_bytes())
+            .unwrap_or_default();
+        line.push(b'\n');
+        line
+    }
+
+    fn read<T: for<'de> Deserialize<'de>>(&self, reader: &mut impl BufRead) -> io::Result<T> {
+        let secret = match &self.secret {
+            Some(secret) => secret,
+            None => return read_message(reader),
+        };
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, e);
+        let sealed = STANDARD
+            .decode(line.trim_end())
+            .map_err(|e| invalid(e.to_string()))?;
+        let json = decrypt_bytes(secret, &sealed).map_err(|e| invalid(e.reason))?;
+        serde_json::from_slice(&json).map_err(|e| invalid(e.to_string()))
+    }
+}
+
+/// `host:port` or `tcp://host:port` for TCP, a path or `unix:path` for a
+/// Unix socket.
+enum Endpoint {
+    Tcp(String),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl Endpoint {
+    fn parse(address: &str) -> Self {
+        if let Some(addr) = address.strip_prefix("tcp://") {
+            return Endpoint::Tcp(addr.to_string());
+        }
+        #[cfg(unix)]
+        {
+            if let Some(path) = address.strip_prefix("unix:") {
+                return Endpoint::Unix(PathBuf::from(path));
+            }
+            if address.contains('/') || !address.contains(':') {
+                return Endpoint::Unix(PathBuf::from(address));
+            }
+        }
+        Endpoint::Tcp(address.to_string())
+    }
+
+    fn bind(&self) -> io::Result<Listener> {
+        match self {
+            Endpoint::Tcp(addr) => Ok(Listener::Tcp(TcpListener::bind(addr)?)),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => {
+                // A socket file nobody listens on is left from an earlier run.
+                if path.exists() && UnixStream::connect(path).is_err() {
+                    std::fs::remove_file(path)?;
+                }
+                Ok(Listener::Unix(UnixListener::bind(path)?, path.clone()))
+            }
+        }
+    }
+
+    fn connect(&self) -> io::Result<Conn> {
+        match self {
+            Endpoint::Tcp(addr) => {
+                let stream = TcpStream::connect(addr)?;
+                stream.set_nodelay(true)?;
+                Ok(Conn::Tcp(stream))
+            }
+            #[cfg(unix)]
+            Endpoint::Unix(path) => Ok(Conn::Unix(UnixStream::connect(path)?)),
+        }
+    }
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    fn address(&self) -> io::Result<String> {
+        match self {
+            Listener::Tcp(listener) => Ok(listener.local_addr()?.to_string()),
+            #[cfg(unix)]
+            Listener::Unix(_, path) => Ok(format!("unix:{}", path.display())),
+        }
+    }
+
+    fn set_nonblocking(&self) -> io::Result<()> {
+        match self {
+            Listener::Tcp(listener) => listener.set_nonblocking(true),
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => listener.set_nonblocking(true),
+        }
+    }
+
+    fn accept(&self) -> io::Result<Conn> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, _) = listener.accept()?;
+                stream.set_nonblocking(false)?;
+                stream.set_nodelay(true)?;
+                Ok(Conn::Tcp(stream))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => {
+                let (stream, _) = listener.accept()?;
+                stream.set_nonblocking(false)?;
+                Ok(Conn::Unix(stream))
+            }
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Listener::Unix(_, path) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+enum Conn {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Conn {
+    fn try_clone(&self) -> io::Result<Conn> {
+        match self {
+            Conn::Tcp(stream) => Ok(Conn::Tcp(stream.try_clone()?)),
+            #[cfg(unix)]
+            Conn::Unix(stream) => Ok(Conn::Unix(stream.try_clone()?)),
+        }
+    }
+
+    fn shutdown(&self) {
+        let _ = match self {
+            Conn::Tcp(stream) => stream.shutdown(Shutdown::Both),
+            #[cfg(unix)]
+            Conn::Unix(stream) => stream.shutdown(Shutdown::Both),
+        };
+    }
+
+    fn peer(&self) -> String {
+        match self {
+            Conn::Tcp(stream) => stream
+                .peer_addr()
+                .map_or_else(|_| "unknown".to_string(), |addr| addr.to_string()),
+            #[cfg(unix)]
+            Conn::Unix(_) => "local".to_string(),
+        }
+    }
+}
+
+impl Read for Conn {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Conn::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Conn::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Conn {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Conn::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Conn::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Conn::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Conn::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+/// A replica connected to this primary.
+struct Replica {
+    id: u64,
+    peer: String,
+    /// Encoded messages for the connection's writer thread.
+    sender: mpsc::SyncSender<Arc<Vec<u8>>>,
+    conn: Conn,
+    acked_lsn: u64,
+}
+
+/// The serving side: streams commits to connected replicas.
+struct Primary {
+    address: String,
+    replicas: Mutex<Vec<Replica>>,
+    /// Signalled when a replica acknowledges, connects or disconnects.
+    acked: Condvar,
+    next_id: AtomicU64,
+    /// How long `commit` waits for an acknowledgement in synchronous mode.
+    sync_timeout: Option<Duration>,
+    frames: Frames,
+    stopped: AtomicBool,
+}
+
+impl Primary {
+    fn remove(&self, id: u64) {
+        let mut replicas = self.replicas.lock();
+        if let Some(i) = replicas.iter().position(|r| r.id == id) {
+            replicas.remove(i).conn.shutdown();
+        }
+        self.acked.notify_all();
+    }
+
+    /// Queues `message` for every connected replica, disconnecting the ones
+    /// whose queue is full.
+    fn queue<T: Serialize>(&self, message: &T) {
+        let mut replicas = self.replicas.lock();
+        if replicas.is_empty() {
+            return;
+        }
+        let line = Arc::new(self.frames.encode(message));
+        let before = replicas.len();
+        replicas.retain(|replica| match replica.sender.try_send(Arc::clone(&line)) {
+            Err(mpsc::TrySendError::Full(_)) => {
+                replica.conn.shutdown();
+                false
+            }
+            _ => true,
+        });
+        if replicas.len() < before {
+            self.acked.notify_all();
+        }
+    }
+}
+
+/// The replica side: applies what a primary streams.
+struct Link {
+    primary: String,
+    secret: Option<Arc<Vec<u8>>>,
+    connected: AtomicBool,
+    /// Why the last connection to the primary was lost or refused.
+    error: Mutex<Option<String>>,
+    conn: Mutex<Option<Conn>>,
+    stopped: AtomicBool,
+}
+
+#[napi(object)]
+#[derive(Default)]
+pub struct ReplicationOptions {
+    /// Make writes wait until a replica has logged them.
+    pub synchronous: Option<bool>,
+    /// How long a synchronous write waits before failing. Defaults to 5000.
+    pub sync_timeout_ms: Option<u32>,
+    /// Shared secret replicas must prove they hold, 32 bytes in hex; the
+    /// stream is sealed with it. Required over TCP.
+    pub secret: Option<String>,
+}
+
+#[napi(object)]
+pub struct ReplicaOptions {
+    /// The primary's shared secret.
+    pub secret: Option<String>,
+}
+
+#[napi(object)]
+pub struct ReplicaStatus {
+    pub peer: String,
+    /// LSN of the last write the replica acknowledged.
+    pub acked_lsn: i64,
+}
+
+#[napi(object)]
+pub struct ReplicationStatus {
+    /// `primary`, `replica` or `none`.
+    pub role: String,
+    pub lsn: i64,
+    /// The address a primary listens on, or a replica follows.
+    pub address: Option<String>,
+    /// Whether a replica is connected to its primary.
+    pub connected: bool,
+    /// Why a replica's last connection to its primary was lost or refused.
+    pub error: Option<String>,
+    /// The replicas connected to a primary.
+    pub replicas: Vec<ReplicaStatus>,
+}
+
+const DEFAULT_SYNC_TIMEOUT_MS: u32 = 5000;
+
+/// The replication roles of a database: it can serve replicas, follow a
+/// primary, or both to relay its primary's writes.
+#[derive(Default)]
+pub(crate) struct Replication {
+    primary: Mutex<Option<Arc<Primary>>>,
+    link: Mutex<Option<Arc<Link>>>,
+}
+
+impl Replication {
+    /// Starts serving replicas on `address`. Returns the address bound, so
+    /// port 0 picks a free port.
+    pub(crate) fn serve(
+        &self,
+        core: &DatabaseCore,
+        address: &str,
+        options: Option<ReplicationOptions>,
+    ) -> Result<String, String> {
+        let mut slot = self.primary.lock();
+        if let Some(primary) = slot.as_ref() {
+            return Err(format!("Already serving replicas on {}", primary.address));
+        }
+        let options = options.unwrap_or_default();
+        let secret = parse_secret(options.secret)?;
+        let endpoint = Endpoint::parse(address);
+        // Anyone who can reach the port could otherwise read the data.
+        if matches!(endpoint, Endpoint::Tcp(_)) && secret.is_none() {
+            return Err(format!(
+                "Serving replicas over TCP on {} needs a secret",
+                address
+            ));
+        }
+        let listener = endpoint
+            .bind()
+            .and_then(|listener| listener.set_nonblocking().map(|_| listener))
+            .map_err(|e| format!("Failed to listen on {}: {}", address, e))?;
+        let bound = listener
+            .address()
+            .map_err(|e| format!("Failed to listen on {}: {}", address, e))?;
+        let sync_timeout = options.synchronous.unwrap_or(false).then(|| {
+            Duration::from_millis(options.sync_timeout_ms.unwrap_or(DEFAULT_SYNC_TIMEOUT_MS) as u64)
+        });
+        let primary = Arc::new(Primary {
+            address: bound.clone(),
+            replicas: Mutex::new(Vec::new()),
+            acked: Condvar::new(),
+            next_id: AtomicU64::new(0),
+            sync_timeout,
+            frames: Frames { secret },
+            stopped: AtomicBool::new(false),
+        });
+        *slot = Some(Arc::clone(&primary));
+
+        let core = core.clone();
+        thread::spawn(move || {
+            while !primary.stopped.load(Ordering::SeqCst) {
+                match listener.accept() {
+                    Ok(conn) => {
+                        let core = core.clone();
+                        let primary = Arc::clone(&primary);
+                        thread::spawn(move || serve_replica(&core, &primary, conn));
+                    }
+                    Err(_) => thread::sleep(RETRY_INTERVAL),
+                }
+            }
+        });
+        Ok(bound)
+    }
+
+    /// Starts applying what the primary at `address` streams, reconnecting
+    /// whenever the connection is lost.
+    pub(crate) fn follow(
+        &self,
+        core: &DatabaseCore,
+        address: &str,
+        options: Option<ReplicaOptions>,
+    ) -> Result<(), String> {
+        let mut slot = self.link.lock();
+        if let Some(link) = slot.as_ref() {
+            return Err(format!("Already a replica of {}", link.primary));
+        }
+        let link = Arc::new(Link {
+            primary: address.to_string(),
+            secret: parse_secret(options.and_then(|o| o.secret))?,
+            connected: AtomicBool::new(false),
+            error: Mutex::new(None),
+            conn: Mutex::new(None),
+            stopped: AtomicBool::new(false),
+        });
+        *slot = Some(Arc::clone(&link));
+
+        let core = core.clone();
+        let endpoint = Endpoint::parse(address);
+        thread::spawn(move || {
+            while !link.stopped.load(Ordering::SeqCst) {
+                if let Err(e) = replicate(&core, &link, &endpoint) {
+                    *link.error.lock() = Some(e.to_string());
+                }
+                link.connected.store(false, Ordering::SeqCst);
+                if !link.stopped.load(Ordering::SeqCst) {
+                    thread::sleep(RETRY_INTERVAL);
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// Stops serving replicas and following a primary. A replica becomes
+    /// writable again.
+    pub(crate) fn stop(&self) {
+        if let Some(primary) = self.primary.lock().take() {
+            primary.stopped.store(true, Ordering::SeqCst);
+            // Dropping the senders ends the writer threads.
+            for replica in primary.replicas.lock().drain(..) {
+                replica.conn.shutdown();
+            }
+            primary.acked.notify_all();
+        }
+        if let Some(link) = self.link.lock().take() {
+            link.stopped.store(true, Ordering::SeqCst);
+            if let Some(conn) = link.conn.lock().take() {
+                conn.shutdown();
+            }
+        }
+    }
+
+    /// The primary this database follows, if any.
+    pub(crate) fn replica_of(&self) -> Option<String> {
+        self.link.lock().as_ref().map(|link| link.primary.clone())
+    }
+
+    /// Queues a committed write for every connected replica.
+    pub(crate) fn publish(&self, lsn: u64, op: &Operation) {
+        if let Some(primary) = self.primary.lock().clone() {
+            primary.queue(&ToReplicaRef::Record { lsn, op });
+        }
+    }
+
+    /// Queues the data as of `lsn` for every connected replica, to replace
+    /// theirs.
+    pub(crate) fn publish_snapshot(&self, lsn: u64, data: &Value) {
+        if let Some(primary) = self.primary.lock().clone() {
+            primary.queue(&ToReplicaRef::Snapshot { lsn, data });
+        }
+    }
+
+    /// In synchronous mode, waits until a replica has acknowledged `lsn`.
+    pub(crate) fn wait_for_ack(&self, lsn: u64) -> napi::Result<()> {
+        let primary = match self.primary.lock().clone() {
+            Some(primary) => primary,
+            None => return Ok(()),
+        };
+        let timeout = match primary.sync_timeout {
+            Some(timeout) => timeout,
+            None => return Ok(()),
+        };
+        let deadline = Instant::now() + timeout;
+        let mut replicas = primary.replicas.lock();
+        while !primary.stopped.load(Ordering::SeqCst) {
+            if replicas.iter().any(|r| r.acked_lsn >= lsn) {
+                return Ok(());
+            }
+            if primary
+                .acked
+                .wait_until(&mut replicas, deadline)
+                .timed_out()
+            {
+                // The write is already applied and logged here, so this must
+                // not read as a failed write: retrying it would apply it twice.
+                return Err(napi::Error::new(
+                    napi::Status::GenericFailure,
+                    format!(
+                        "Write at LSN {} is committed, but no replica acknowledged it within {} ms",
+                        lsn,
+                        timeout.as_millis()
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn status(&self, lsn: u64) -> ReplicationStatus {
+        let primary = self.primary.lock().clone();
+        let link = self.link.lock().clone();
+        let role = match (&primary, &link) {
+            (_, Some(_)) => "replica",
+            (Some(_), None) => "primary",
+            (None, None) => "none",
+        };
+        ReplicationStatus {
+            role: role.to_string(),
+            lsn: lsn as i64,
+            address: link
+                .as_ref()
+                .map(|link| link.primary.clone())
+                .or_else(|| primary.as_ref().map(|p| p.address.clone())),
+            connected: link
+                .as_ref()
+                .is_some_and(|link| link.connected.load(Ordering::SeqCst)),
+            error: link.as_ref().and_then(|link| link.error.lock().clone()),
+            replicas: primary.map_or_else(Vec::new, |primary| {
+                primary
+                    .replicas
+                    .lock()
+                    .iter()
+                    .map(|r| ReplicaStatus {
+                        peer: r.peer.clone(),
+                        acked_lsn: r.acked_lsn as i64,
+                    })
+                    .collect()
+            }),
+        }
+    }
+}
+
+/// Serves one replica until it disconnects or the primary stops.
+fn serve_replica(core: &DatabaseCore, primary: &Arc<Primary>, conn: Conn) {
+    let id = primary.next_id.fetch_add(1, Ordering::SeqCst);
+    let _ = stream_to_replica(core, primary, id, conn);
+    primary.remove(id);
+}
+
+fn stream_to_replica(
+    core: &DatabaseCore,
+    primary: &Arc<Primary>,
+    id: u64,
+    conn: Conn,
+) -> io::Result<()> {
+    let peer = conn.peer();
+    let registered = conn.try_clone()?;
+    let mut reader = BufReader::new(conn.try_clone()?);
+    let mut out = BufWriter::new(conn);
+    let frames = &primary.frames;
+
+    let nonce = frames.secret.as_ref().map(|_| {
+        let mut nonce = [0u8; 32];
+        OsRng.fill_bytes(&mut nonce);
+        hex::encode(nonce)
+    });
+    out.write_all(&encode(&Handshake::Challenge {
+        nonce: nonce.clone(),
+    }))?;
+    out.flush()?;
+    let (since, proof) = match read_message(&mut reader)? {
+        ToPrimary::Hello { lsn, proof } => (lsn, proof),
+        ToPrimary::Ack { .. } => return Err(io::ErrorKind::InvalidData.into()),
+    };
+    if let (Some(secret), Some(nonce)) = (&frames.secret, &nonce) {
+        if !verify(secret, nonce, proof.as_deref()) {
+            out.write_all(&encode(&Handshake::Rejected {
+                reason: "The primary rejected the replication secret".to_string(),
+            }))?;
+            return out.flush();
+        }
+    }
+    out.write_all(&encode(&Handshake::Accepted))?;
+
+    // Registered while writers are held off, so every later write is
+    // queued and every earlier one is in the snapshot or the WAL.
+    let (sender, receiver) = mpsc::sync_channel(REPLICA_QUEUE);
+    let replica = Replica {
+        id,
+        peer,
+        sender,
+        conn: registered,
+        acked_lsn: since,
+    };
+    let (data, lsn) = core
+        .cut(|_| primary.replicas.lock().push(replica))
+        .map_err(|e| io::Error::other(e.reason))?;
+    if primary.stopped.load(Ordering::SeqCst) {
+        return Ok(());
+    }
+
+    // A replica ahead of the primary has writes it never had, and one the
+    // WAL no longer reaches back to is sent everything.
+    let backlog = match since {
+        since if since > lsn => None,
+        since if since == lsn => Some(Vec::new()),
+        since => core.logged_changes(since).ok(),
+    };
+    match backlog {
+        Some(records) => {
+            for (record_lsn, op) in records.iter().filter(|(l, _)| *l <= lsn) {
+                out.write_all(&frames.encode(&ToReplicaRef::Record {
+                    lsn: *record_lsn,
+                    op,
+                }))?;
+            }
+        }
+        None => out.write_all(&frames.encode(&ToReplicaRef::Snapshot { lsn, data: &data }))?,
+    }
+    out.flush()?;
+    drop(data);
+
+    let acks = Arc::clone(primary);
+    thread::spawn(move || {
+        while let Ok(ToPrimary::Ack { lsn }) = acks.frames.read(&mut reader) {
+            let mut replicas = acks.replicas.lock();
+            if let Some(replica) = replicas.iter_mut().find(|r| r.id == id) {
+                replica.acked_lsn = replica.acked_lsn.max(lsn);
+            }
+            acks.acked.notify_all();
+        }
+        acks.remove(id);
+    });
+
+    // Ends when the replica is removed and its sender dropped.
+    while let Ok(line) = receiver.recv() {
+        out.write_all(&line)?;
+        while let Ok(line) = receiver.try_recv() {
+            out.write_all(&line)?;
+        }
+        out.flush()?;
+    }
+    Ok(())
+}
+
+/// Follows the primary over one connection, until it is lost.
+fn replicate(core: &DatabaseCore, link: &Link, endpoint: &Endpoint) -> io::Result<()> {
+    let conn = endpoint.connect()?;
+    {
+        let mut slot = link.conn.lock();
+        // `stop` may have run while connecting.
+        if link.stopped.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        *slot = Some(conn.try_clone()?);
+    }
+    let mut out = conn.try_clone()?;
+    let mut reader = BufReader::new(conn);
+
+    let nonce = match read_message(&mut reader)? {
+        Handshake::Challenge { nonce } => nonce,
+        _ => return Err(io::ErrorKind::InvalidData.into()),
+    };
+    let proof = match (&link.secret, &nonce) {
+        (Some(secret), Some(nonce)) => Some(prove(secret, nonce)?),
+        (None, None) => None,
+        (None, Some(_)) => return Err(io::Error::other("The primary requires a secret")),
+        // Its lines could come from anyone who reaches the address.
+        (Some(_), None) => {
+            return Err(io::Error::other(
+                "The primary has no secret to authenticate it with",
+            ))
+        }
+    };
+    out.write_all(&encode(&ToPrimary::Hello {
+        lsn: core.current_lsn(),
+        proof,
+    }))?;
+    match read_message(&mut reader)? {
+        Handshake::Accepted => {}
+        Handshake::Rejected { reason } => return Err(io::Error::other(reason)),
+        Handshake::Challenge { .. } => return Err(io::ErrorKind::InvalidData.into()),
+    }
+    link.connected.store(true, Ordering::SeqCst);
+    *link.error.lock() = None;
+
+    let frames = Frames {
+        secret: link.secret.clone(),
+    };
+    let to_io = |e: napi::Error| io::Error::other(e.reason);
+    loop {
+        match frames.read(&mut reader)? {
+            ToReplica::Snapshot { lsn, data } => core.install_snapshot(data, lsn),
+            ToReplica::Record { lsn, op } => core.apply_replicated(lsn, op),
+        }
+        .map_err(to_io)?;
+        // Acknowledged once everything received so far is logged.
+        if reader.buffer().is_empty() {
+            core.flush_wals().map_err(to_io)?;
+            out.write_all(&frames.encode(&ToPrimary::Ack {
+                lsn: core.current_lsn(),
+            }))?;
+        }
+    }
+}
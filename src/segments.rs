@@ -0,0 +1,311 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// The keys leading to a segment, from the root.
+pub(crate) type SegmentPath = Vec<String>;
+
+/// A line of the segment file. Saves append the segments that changed and
+/// end with a commit; the latest record of each segment wins on load.
+#[derive(Serialize)]
+pub(crate) enum SegmentRecordRef<'a> {
+    Put { seg: &'a [String], value: &'a Value },
+    Remove { seg: &'a [String] },
+    Commit { lsn: u64 },
+}
+
+#[derive(Deserialize)]
+pub(crate) enum SegmentRecord {
+    Put { seg: SegmentPath, value: Value },
+    Remove { seg: SegmentPath },
+    // The LSN of a commit is only there for people reading the file.
+    Commit {},
+}
+
+/// Where the latest record of a segment is in the file.
+#[derive(Clone, Copy)]
+pub(crate) struct Location {
+    pub(crate) offset: u64,
+    pub(crate) len: u64,
+}
+
+/// A record written by a save, in file order.
+pub(crate) struct WrittenRecord {
+    pub(crate) seg: SegmentPath,
+    pub(crate) len: u64,
+    /// `false` for removals.
+    pub(crate) put: bool,
+}
+
+/// Tracks which segments of the data changed since the last save. The
+/// data is cut into segments at `depth` levels below the root; shallower
+/// values that are not objects, and empty objects, are segments of their
+/// own.
+pub(crate) struct SegmentTracker {
+    depth: usize,
+    /// Segments in the file, with where their latest record is.
+    live: BTreeMap<SegmentPath, Location>,
+    /// Prefixes of the paths written since the last save.
+    dirty: BTreeSet<SegmentPath>,
+    /// Length of the file up to the end of the last complete save.
+    pub(crate) file_len: u64,
+}
+
+impl SegmentTracker {
+    pub(crate) fn new(depth: usize) -> Self {
+        SegmentTracker {
+            depth: depth.max(1),
+            live: BTreeMap::new(),
+            dirty: BTreeSet::new(),
+            file_len: 0,
+        }
+    }
+
+    pub(crate) fn touch(&mut self, path: &str) {
+        let prefix: SegmentPath = if path.is_empty() {
+            Vec::new()
+        } else {
+            path.split('.').take(self.depth).map(String::from).collect()
+        };
+        self.dirty.insert(prefix);
+    }
+
+    pub(crate) fn touch_all(&mut self) {
+        self.dirty.insert(Vec::new());
+    }
+
+    pub(crate) fn clear_dirty(&mut self) {
+        self.dirty.clear();
+    }
+
+    /// Total size of the latest record of every segment.
+    pub(crate) fn live_bytes(&self) -> u64 {
+        self.live.values().map(|l| l.len).sum()
+    }
+
+    /// Whether anything under the top-level `key` was written since the
+    /// last save.
+    pub(crate) fn is_dirty_key(&self, key: &str) -> bool {
+        self.dirty
+            .iter()
+            .any(|prefix| prefix.first().is_none_or(|first| first == key))
+    }
+
+    /// The top-level keys in the file, with the size of their records.
+    pub(crate) fn top_keys(&self) -> BTreeMap<&str, u64> {
+        let mut keys = BTreeMap::new();
+        for (seg, location) in &self.live {
+            if let Some(key) = seg.first() {
+                *keys.entry(key.as_str()).or_insert(0) += location.len;
+            }
+        }
+        keys
+    }
+
+    /// The segments under the top-level `key`, with their locations.
+    pub(crate) fn segments_of(&self, key: &str) -> Vec<(SegmentPath, Location)> {
+        let start = vec![key.to_string()];
+        self.live
+            .range(start.clone()..)
+            .take_while(|(seg, _)| seg.starts_with(&start))
+            .map(|(seg, location)| (seg.clone(), *location))
+            .collect()
+    }
+
+    /// Size of the live records once `written` is saved on top of them.
+    pub(crate) fn live_bytes_after(&self, written: &[WrittenRecord]) -> u64 {
+        let replaced: u64 = written
+            .iter()
+            .filter_map(|w| self.live.get(&w.seg))
+            .map(|l| l.len)
+            .sum();
+        let added: u64 = written.iter().filter(|w| w.put).map(|w| w.len).sum();
+        self.live_bytes() - replaced + added
+    }
+
+    /// Rebuilds the data from the records of the file, each with its size
+    /// in bytes (`None` for lines that could not be read). Records after the
+    /// last commit belong to an interrupted save and are ignored. Only the
+    /// segments `keep` accepts are placed in the returned tree.
+    pub(crate) fn restore(
+        &mut self,
+        records: Vec<(Option<SegmentRecord>, u64)>,
+        keep: impl Fn(&[String]) -> bool,
+    ) -> Value {
+        let mut latest: BTreeMap<SegmentPath, Value> = BTreeMap::new();
+        let mut pending: Vec<(SegmentPath, Option<Value>, Location)> = Vec::new();
+        let mut offset = 0;
+        self.live.clear();
+        self.file_len = 0;
+
+        for (record, len) in records {
+            let location = Location { offset, len };
+            offset += len;
+            match record {
+                Some(SegmentRecord::Commit { .. }) => {
+                    for (seg, value, location) in pending.drain(..) {
+                        latest.remove(&seg);
+                        match value {
+                            Some(value) => {
+                                if keep(&seg) {
+                                    latest.insert(seg.clone(), value);
+                                }
+                                self.live.insert(seg, location);
+                            }
+                            None => {
+                                self.live.remove(&seg);
+                            }
+                        }
+                    }
+                    self.file_len = offset;
+                }
+                Some(SegmentRecord::Put { seg, value }) => {
+                    pending.push((seg, Some(value), location))
+                }
+                Some(SegmentRecord::Remove { seg }) => pending.push((seg, None, location)),
+                None => {}
+            }
+        }
+
+        let mut root = Value::Object(Map::new());
+        for (seg, value) in latest {
+            insert_segment(&mut root, &seg, value);
+        }
+        root
+    }
+
+    /// The segments to write for the paths written since the last save:
+    /// the current value of every segment under or above a written path,
+    /// and removals for the segments of the file that no longer exist.
+    pub(crate) fn changes<'a>(&self, data: &'a Value) -> Vec<(SegmentPath, Option<&'a Value>)> {
+        let mut changes = BTreeMap::new();
+        for prefix in &self.dirty {
+            // A write to a parent covers this prefix already.
+            if (0..prefix.len()).any(|k| self.dirty.contains(&prefix[..k])) {
+                continue;
+            }
+            let mut current = BTreeMap::new();
+            self.collect_around(data, prefix, &mut current);
+
+            let below = self
+                .live
+                .range(prefix.clone()..)
+                .map(|(seg, _)| seg)
+                .take_while(|seg| seg.starts_with(prefix));
+            let above = (0..prefix.len())
+                .map(|k| prefix[..k].to_vec())
+                .filter(|seg| self.live.contains_key(seg));
+            let stale: Vec<SegmentPath> = below
+                .cloned()
+                .chain(above)
+                .filter(|seg| !current.contains_key(seg))
+                .collect();
+
+            changes.extend(stale.into_iter().map(|seg| (seg, None)));
+            changes.extend(current.into_iter().map(|(seg, value)| (seg, Some(value))));
+        }
+        changes.into_iter().collect()
+    }
+
+    /// Every segment of the data, for rewriting the whole file.
+    pub(crate) fn all_segments<'a>(
+        &self,
+        data: &'a Value,
+    ) -> Vec<(SegmentPath, Option<&'a Value>)> {
+        let mut segments = BTreeMap::new();
+        self.collect(data, Vec::new(), &mut segments);
+        segments
+            .into_iter()
+            .map(|(seg, value)| (seg, Some(value)))
+            .collect()
+    }
+
+    /// Records a save that wrote `written` from offset `start` of the file,
+    /// ending at `file_len`. `replaced` tells whether the file was replaced
+    /// rather than appended to.
+    pub(crate) fn saved(
+        &mut self,
+        written: Vec<WrittenRecord>,
+        start: u64,
+        file_len: u64,
+        replaced: bool,
+    ) {
+        if replaced {
+            self.live.clear();
+        }
+        let mut offset = start;
+        for record in written {
+            let location = Location {
+                offset,
+                len: record.len,
+            };
+            offset += record.len;
+            if record.put {
+                self.live.insert(record.seg, location);
+            } else {
+                self.live.remove(&record.seg);
+            }
+        }
+        self.file_len = file_len;
+        self.dirty.clear();
+    }
+
+    /// Segments along `prefix` and in the subtree below it.
+    fn collect_around<'a>(
+        &self,
+        data: &'a Value,
+        prefix: &[String],
+        out: &mut BTreeMap<SegmentPath, &'a Value>,
+    ) {
+        let mut node = data;
+        for k in 0..prefix.len() {
+            if self.is_segment(node, k) {
+                out.insert(prefix[..k].to_vec(), node);
+                return;
+            }
+            node = match node.get(&prefix[k]) {
+                Some(child) => child,
+                None => return,
+            };
+        }
+        self.collect(node, prefix.to_vec(), out);
+    }
+
+    fn collect<'a>(
+        &self,
+        node: &'a Value,
+        path: SegmentPath,
+        out: &mut BTreeMap<SegmentPath, &'a Value>,
+    ) {
+        if self.is_segment(node, path.len()) {
+            out.insert(path, node);
+            return;
+        }
+        if let Value::Object(map) = node {
+            for (key, child) in map {
+                let mut child_path = path.clone();
+                child_path.push(key.clone());
+                self.collect(child, child_path, out);
+            }
+        }
+    }
+
+    fn is_segment(&self, node: &Value, level: usize) -> bool {
+        level >= self.depth || node.as_object().is_none_or(|map| map.is_empty())
+    }
+}
+
+/// Places a segment read from the file into the tree.
+pub(crate) fn insert_segment(root: &mut Value, seg: &[String], value: Value) {
+    let mut node = root;
+    for key in seg {
+        if !node.is_object() {
+            *node = Value::Object(Map::new());
+        }
+        node = match node {
+            Value::Object(map) => map.entry(key.clone()).or_insert(Value::Null),
+            _ => return,
+        };
+    }
+    *node = value;
+}
@@ -0,0 +1,947 @@
+use parking_lot::{Mutex, MutexGuard};
+use rayon::prelude::*;
+use serde::ser::{Serialize, SerializeMap, Serializer};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::SystemTime;
+
+use crate::codec::Codec;
+use crate::lazy::{top_key, Residency};
+use crate::segments::{
+    Location, SegmentPath, SegmentRecord, SegmentRecordRef, SegmentTracker, WrittenRecord,
+};
+
+/// Segment files smaller than this are appended to rather than compacted.
+const COMPACTION_MIN_BYTES: u64 = 1 << 20;
+
+/// How the data is split into files.
+pub(crate) enum Layout {
+    /// One data file with one WAL.
+    Single,
+    /// The entries of the object at `key` (the top-level keys when `key` is
+    /// empty) are hashed into `count` shard files, each with its own WAL.
+    /// Everything else is placed by hashing its key at the level where it
+    /// leaves the shard key path.
+    Sharded { count: usize, key: Vec<String> },
+    /// One append-structured segment file with one WAL. Saves append the
+    /// segments `depth` levels below the root that changed. With a memory
+    /// budget, top-level collections are read from the file on first use
+    /// and evicted again when the budget runs out.
+    Segmented {
+        depth: usize,
+        memory_budget: Option<u64>,
+    },
+}
+
+/// The parts of the storage a write touches.
+pub(crate) enum Route {
+    Part(usize),
+    All,
+}
+
+/// FNV-1a, so that shard placement never changes between builds.
+fn shard_of(key: &str, count: usize) -> usize {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in key.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    (hash % count as u64) as usize
+}
+
+impl Layout {
+    pub(crate) fn parts(&self) -> usize {
+        match self {
+            Layout::Single | Layout::Segmented { .. } => 1,
+            Layout::Sharded { count, .. } => *count,
+        }
+    }
+
+    /// Finds the parts a write to `path` changes, given the data before it.
+    pub(crate) fn route(&self, root: &Value, path: &str) -> Route {
+        let key = match self {
+            Layout::Single | Layout::Segmented { .. } => return Route::Part(0),
+            Layout::Sharded { key, .. } => key,
+        };
+        if path.is_empty() {
+            return Route::All;
+        }
+        let mut node = Some(root);
+        for (i, segment) in path.split('.').enumerate() {
+            // Only objects are split, so anything else lives whole in the
+            // first shard and a write may turn it into an object.
+            if node.is_some_and(|n| !n.is_object()) {
+                return Route::All;
+            }
+            if key.get(i).map(String::as_str) != Some(segment) {
+                return Route::Part(shard_of(segment, self.parts()));
+            }
+            node = node.and_then(|n| n.get(segment));
+        }
+        // `path` is the shard key or one of its ancestors.
+        Route::All
+    }
+
+    /// Whether `path` is the object whose entries are spread over the shards.
+    pub(crate) fn splits(&self, path: &str) -> bool {
+        match self {
+            Layout::Single | Layout::Segmented { .. } => false,
+            Layout::Sharded { key, .. } if path.is_empty() => key.is_empty(),
+            Layout::Sharded { key, .. } => path.split('.').eq(key.iter().map(String::as_str)),
+        }
+    }
+
+    /// The entries of `collection`, grouped by the shard they are stored
+    /// in, or `None` unless it is the sharded object at `path`.
+    pub(crate) fn shard_entries<'a>(
+        &self,
+        path: &str,
+        collection: &'a Value,
+    ) -> Option<Vec<Vec<(&'a String, &'a Value)>>> {
+        let count = match self {
+            Layout::Sharded { count, .. } if self.splits(path) => *count,
+            _ => return None,
+        };
+        let mut shards = vec![Vec::new(); count];
+        for (k, v) in collection.as_object()? {
+            shards[shard_of(k, count)].push((k, v));
+        }
+        Some(shards)
+    }
+
+    /// The parts that entries read from the file of `part` belong to when
+    /// it is not `part`, e.g. in shard files written by another tool.
+    pub(crate) fn misplaced(&self, value: &Value, part: usize) -> Vec<usize> {
+        let mut homes = Vec::new();
+        if let Layout::Sharded { count, key } = self {
+            misplaced_in(value, key, *count, part, &mut homes);
+        }
+        homes.sort_unstable();
+        homes.dedup();
+        homes
+    }
+
+    /// A serializable view of the data stored in `part`.
+    pub(crate) fn view<'a>(&'a self, root: &'a Value, part: usize) -> PartView<'a> {
+        let key: &[String] = match self {
+            Layout::Single | Layout::Segmented { .. } => &[],
+            Layout::Sharded { key, .. } => key,
+        };
+        PartView {
+            value: root,
+            key,
+            count: self.parts(),
+            part,
+        }
+    }
+}
+
+/// Collects the parts entries of `value` belong to other than `part`, by
+/// the rules `PartView` writes them with.
+fn misplaced_in(value: &Value, key: &[String], count: usize, part: usize, homes: &mut Vec<usize>) {
+    match value {
+        Value::Object(map) if count > 1 => {
+            for (k, v) in map {
+                if key.first() == Some(k) {
+                    misplaced_in(v, &key[1..], count, part, homes);
+                } else if shard_of(k, count) != part {
+                    homes.push(shard_of(k, count));
+                }
+            }
+        }
+        Value::Object(_) => {}
+        _ if part != 0 => homes.push(0),
+        _ => {}
+    }
+}
+
+/// Serializes the subset of a tree that belongs to one part, without copying.
+pub(crate) struct PartView<'a> {
+    value: &'a Value,
+    key: &'a [String],
+    count: usize,
+    part: usize,
+}
+
+impl PartView<'_> {
+    fn child<'b>(&'b self, value: &'b Value) -> PartView<'b> {
+        PartView {
+            value,
+            key: &self.key[1..],
+            count: self.count,
+            part: self.part,
+        }
+    }
+
+    /// Whether this part holds anything below this node.
+    fn is_empty(&self) -> bool {
+        match self.value {
+            Value::Object(map) => !map.iter().any(|(k, v)| {
+                if self.key.first() == Some(k) {
+                    !self.child(v).is_empty()
+                } else {
+                    shard_of(k, self.count) == self.part
+                }
+            }),
+            _ => self.part != 0,
+        }
+    }
+}
+
+impl Serialize for PartView<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let map = match self.value {
+            Value::Object(map) if self.count > 1 => map,
+            other if self.part == 0 => return other.serialize(serializer),
+            _ => return serializer.serialize_map(Some(0))?.end(),
+        };
+        let mut out = serializer.serialize_map(None)?;
+        for (k, v) in map {
+            if self.key.first() == Some(k) {
+                // The first shard keeps the containers along the shard key
+                // even when they are empty.
+                let child = self.child(v);
+                if self.part == 0 || !child.is_empty() {
+                    out.serialize_entry(k, &child)?;
+                }
+            } else if shard_of(k, self.count) == self.part {
+                out.serialize_entry(k, v)?;
+            }
+        }
+        out.end()
+    }
+}
+
+/// Merges a part read from disk into the tree built from the other parts.
+pub(crate) fn merge_part(target: &mut Value, part: Value) {
+    match (target, part) {
+        (Value::Object(target), Value::Object(part)) => {
+            for (k, v) in part {
+                match target.get_mut(&k) {
+                    Some(existing @ Value::Object(_)) if v.is_object() => merge_part(existing, v),
+                    _ => {
+                        target.insert(k, v);
+                    }
+                }
+            }
+        }
+        (target, part) => *target = part,
+    }
+}
+
+/// The size, modification time and inode of a file, or `None` if it does
+/// not exist; tells when another process changed it. Saves replace files by
+/// renaming, so the inode tells a save apart even within one clock tick.
+pub(crate) type FileStamp = Option<(u64, SystemTime, u64)>;
+
+/// The locked WAL files, or `None` without a WAL.
+pub(crate) type WalGuard<'a> = Option<MutexGuard<'a, Vec<BufWriter<File>>>>;
+
+/// The files behind a database: one data file and one WAL per part, plus
+/// which parts changed since they were last saved.
+pub(crate) struct Storage {
+    pub(crate) layout: Layout,
+    data_paths: Vec<PathBuf>,
+    wal_paths: Vec<PathBuf>,
+    /// Shard files of an earlier shard count, merged on load and removed by
+    /// the next save.
+    shard_dir: Option<PathBuf>,
+    wals: Option<Mutex<Vec<BufWriter<File>>>>,
+    /// Whether writes are logged, by this process or the one writing the
+    /// files.
+    use_wal: bool,
+    dirty: Vec<AtomicBool>,
+    checksums: Mutex<Vec<u32>>,
+    segments: Option<Mutex<SegmentTracker>>,
+    residency: Option<Mutex<Residency>>,
+    /// The codec new files are written with.
+    codec: Codec,
+    /// How many WALs of earlier saves to keep next to each WAL.
+    retained_wals: usize,
+}
+
+impl Storage {
+    pub(crate) fn open(
+        filename: &Path,
+        layout: Layout,
+        use_wal: bool,
+        read_only: bool,
+        codec: Codec,
+        retained_wals: usize,
+    ) -> io::Result<Self> {
+        let (data_paths, wal_paths, shard_dir): (Vec<PathBuf>, Vec<PathBuf>, _) = match &layout {
+            Layout::Single => (
+                vec![filename.to_path_buf()],
+                vec![filename.with_extension("wal")],
+                None,
+            ),
+            Layout::Sharded { count, .. } => {
+                let dir = filename.with_extension("").join("shards");
+                if !read_only {
+                    fs::create_dir_all(&dir)?;
+                }
+                (
+                    (0..*count)
+                        .map(|i| dir.join(format!("shard_{}.json", i)))
+                        .collect(),
+                    (0..*count)
+                        .map(|i| dir.join(format!("shard_{}.wal", i)))
+                        .collect(),
+                    Some(dir),
+                )
+            }
+            Layout::Segmented { .. } => {
+                let dir = filename.with_extension("");
+                if !read_only {
+                    fs::create_dir_all(&dir)?;
+                }
+                (
+                    vec![dir.join("segments.jsonl")],
+                    vec![filename.with_extension("wal")],
+                    None,
+                )
+            }
+        };
+
+        // A read-only database still replays the WALs, but never opens them
+        // for writing.
+        let wals = if use_wal && !read_only {
+            let files = wal_paths
+                .iter()
+                .map(|path| {
+                    let file = OpenOptions::new().create(true).append(true).open(path)?;
+                    let empty = file.metadata()?.len() == 0;
+                    let mut wal = BufWriter::new(file);
+                    if empty {
+                        wal.write_all(&codec.header())?;
+                    }
+                    Ok(wal)
+                })
+                .collect::<io::Result<Vec<_>>>()?;
+            Some(Mutex::new(files))
+        } else {
+            None
+        };
+
+        let parts = layout.parts();
+        let (segments, residency) = match layout {
+            Layout::Segmented {
+                depth,
+                memory_budget,
+            } => (
+                Some(Mutex::new(SegmentTracker::new(depth))),
+                memory_budget.map(|budget| Mutex::new(Residency::new(budget))),
+            ),
+            _ => (None, None),
+        };
+        Ok(Storage {
+            layout,
+            data_paths,
+            wal_paths,
+            shard_dir,
+            wals,
+            use_wal,
+            dirty: (0..parts).map(|_| AtomicBool::new(true)).collect(),
+            checksums: Mutex::new(vec![0; parts]),
+            segments,
+            residency,
+            codec,
+            retained_wals,
+        })
+    }
+
+    pub(crate) fn is_sharded(&self) -> bool {
+        self.shard_dir.is_some()
+    }
+
+    pub(crate) fn uses_wal(&self) -> bool {
+        self.use_wal
+    }
+
+    pub(crate) fn is_segmented(&self) -> bool {
+        self.segments.is_some()
+    }
+
+    pub(crate) fn is_lazy(&self) -> bool {
+        self.residency.is_some()
+    }
+
+    /// Locks the WAL files. Writers hold the lock while they log and apply
+    /// their operations, so holding it gives a consistent cut of the data.
+    pub(crate) fn lock_wals(&self) -> WalGuard<'_> {
+        self.wals.as_ref().map(|w| w.lock())
+    }
+
+    /// Appends a WAL line to the parts `route` touches.
+    pub(crate) fn append(
+        &self,
+        wals: &mut [BufWriter<File>],
+        route: &Route,
+        line: &[u8],
+    ) -> io::Result<()> {
+        match route {
+            Route::Part(i) => wals[*i].write_all(line),
+            Route::All => wals.iter_mut().try_for_each(|w| w.write_all(line)),
+        }
+    }
+
+    /// Empties every WAL and starts each with `checkpoint`, the one for
+    /// `lsn`. With WAL retention, the WALs are moved aside first, named
+    /// after the LSN they end at.
+    pub(crate) fn reset_wals(
+        &self,
+        wals: &mut [BufWriter<File>],
+        checkpoint: &[u8],
+        lsn: u64,
+    ) -> io::Result<()> {
+        for (wal, path) in wals.iter_mut().zip(&self.wal_paths) {
+            // A WAL that ends at the LSN of a kept one has no writes since.
+            let archived = retained_path(path, lsn);
+            if self.retained_wals > 0 && !archived.exists() {
+                wal.flush()?;
+                fs::rename(path, &archived)?;
+                for old in retained_wals(path).iter().rev().skip(self.retained_wals) {
+                    fs::remove_file(&old.1)?;
+                }
+            }
+            // Whatever is still buffered was saved with the data.
+            self.truncate_wal(wal, path, checkpoint)?;
+        }
+        Ok(())
+    }
+
+    /// Starts the WALs over at `checkpoint` and deletes the kept ones, for
+    /// data that replaced everything they logged.
+    pub(crate) fn restart_wals(
+        &self,
+        wals: &mut [BufWriter<File>],
+        checkpoint: &[u8],
+    ) -> io::Result<()> {
+        for (wal, path) in wals.iter_mut().zip(&self.wal_paths) {
+            for (_, old) in retained_wals(path) {
+                fs::remove_file(old)?;
+            }
+            self.truncate_wal(wal, path, checkpoint)?;
+        }
+        Ok(())
+    }
+
+    /// Empties a WAL down to `checkpoint`. What is still buffered is
+    /// dropped rather than flushed into the new log.
+    fn truncate_wal(
+        &self,
+        wal: &mut BufWriter<File>,
+        path: &Path,
+        checkpoint: &[u8],
+    ) -> io::Result<()> {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
+        let (_, _dropped) = std::mem::replace(wal, BufWriter::new(file)).into_parts();
+        wal.write_all(&self.codec.header())?;
+        wal.write_all(checkpoint)?;
+        wal.flush()
+    }
+
+    /// Records a write to `path`, which touches the parts in `route`.
+    pub(crate) fn mark_dirty(&self, route: &Route, path: &str) {
+        if let Some(segments) = &self.segments {
+            segments.lock().touch(path);
+        }
+        match route {
+            Route::Part(i) => self.dirty[*i].store(true, Ordering::SeqCst),
+            Route::All => self.mark_all_dirty(),
+        }
+    }
+
+    pub(crate) fn mark_all_dirty(&self) {
+        if let Some(segments) = &self.segments {
+            segments.lock().touch_all();
+        }
+        for flag in &self.dirty {
+            flag.store(true, Ordering::SeqCst);
+        }
+    }
+
+    pub(crate) fn mark_part_dirty(&self, part: usize) {
+        self.dirty[part].store(true, Ordering::SeqCst);
+    }
+
+    /// The parts `save` has to write. A single file is always rewritten.
+    pub(crate) fn dirty_parts(&self) -> Vec<usize> {
+        (0..self.layout.parts())
+            .filter(|&i| !self.is_sharded() || self.dirty[i].load(Ordering::SeqCst))
+            .collect()
+    }
+
+    /// Moves data files left behind by an interrupted save into place.
+    pub(crate) fn recover(&self) {
+        for path in &self.data_paths {
+            let tmp_path = path.with_extension("tmp");
+            if tmp_path.exists() {
+                let _ = fs::rename(&tmp_path, path);
+            }
+        }
+    }
+
+    /// Reads the data file of every part; `None` for parts with no file.
+    /// Shards from an earlier shard count come last.
+    pub(crate) fn read_parts(&self) -> io::Result<Vec<Option<Vec<u8>>>> {
+        let mut parts = Vec::with_capacity(self.data_paths.len());
+        for path in self.data_paths.iter().chain(&self.stale_files("json")) {
+            // A lazy segment file is streamed by `restore_segments` instead.
+            if self.is_lazy() {
+                parts.push(path.exists().then(Vec::new));
+                continue;
+            }
+            parts.push(match fs::read(path) {
+                Ok(content) => Some(content),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+                Err(e) => return Err(e),
+            });
+        }
+        Ok(parts)
+    }
+
+    /// Reads every WAL, including those of an earlier shard count, in the
+    /// order of their paths. A missing WAL reads as empty.
+    pub(crate) fn read_wals(&self) -> Vec<Vec<u8>> {
+        self.wal_paths
+            .iter()
+            .chain(&self.stale_files("wal"))
+            .map(|path| fs::read(path).unwrap_or_default())
+            .collect()
+    }
+
+    pub(crate) fn wal_paths(&self) -> &[PathBuf] {
+        &self.wal_paths
+    }
+
+    /// The WALs kept from earlier saves next to each WAL, oldest first,
+    /// with the LSN each ends at.
+    pub(crate) fn retained_wals(&self) -> Vec<Vec<(u64, PathBuf)>> {
+        self.wal_paths
+            .iter()
+            .map(|path| retained_wals(path))
+            .collect()
+    }
+
+    /// Stamps of every data file and WAL, to tell when another process
+    /// saved or logged writes.
+    pub(crate) fn stamps(&self) -> Vec<FileStamp> {
+        self.data_paths
+            .iter()
+            .chain(&self.wal_paths)
+            .chain(&self.stale_files("json"))
+            .chain(&self.stale_files("wal"))
+            .map(|path| {
+                let metadata = fs::metadata(path).ok()?;
+                Some((metadata.len(), metadata.modified().ok()?, inode(&metadata)))
+            })
+            .collect()
+    }
+
+    /// Shard files with an index beyond the current shard count.
+    pub(crate) fn stale_files(&self, extension: &str) -> Vec<PathBuf> {
+        let dir = match &self.shard_dir {
+            Some(dir) => dir,
+            None => return Vec::new(),
+        };
+        let mut stale: Vec<PathBuf> = fs::read_dir(dir)
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| {
+                path.extension().and_then(|e| e.to_str()) == Some(extension)
+                    && path
+                        .file_stem()
+                        .and_then(|s| s.to_str())
+                        .and_then(|s| s.strip_prefix("shard_"))
+                        .and_then(|n| n.parse::<usize>().ok())
+                        .is_some_and(|n| n >= self.layout.parts())
+            })
+            .collect();
+        stale.sort();
+        stale
+    }
+
+    /// Atomically replaces the data file of `part`.
+    pub(crate) fn write_part(&self, part: usize, content: &[u8]) -> io::Result<()> {
+        let path = &self.data_paths[part];
+        let tmp_path = path.with_extension("tmp");
+        {
+            let mut file = File::create(&tmp_path)?;
+            file.write_all(content)?;
+            // Removed sync_all() for performance, rename handles atomicity
+        }
+        fs::rename(&tmp_path, path)?;
+        self.checksums.lock()[part] = crc32fast::hash(content);
+        self.dirty[part].store(false, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Records the checksum of a part read from disk, which is clean until
+    /// the next write to it.
+    pub(crate) fn loaded_part(&self, part: usize, checksum: u32) {
+        self.checksums.lock()[part] = checksum;
+        self.dirty[part].store(false, Ordering::SeqCst);
+        if let Some(segments) = &self.segments {
+            segments.lock().clear_dirty();
+        }
+    }
+
+    /// Rebuilds the data from the segment file, given its content or, when
+    /// lazy, by streaming it from disk. Returns it with the checksum of the
+    /// part of the file that holds complete saves and the codec the file was
+    /// written with. A lazy restore keeps only what sits directly at the
+    /// root; every collection stays on disk. A file written with another
+    /// codec is restored whole and replaced by the next save.
+    pub(crate) fn restore_segments(
+        &self,
+        content: &[u8],
+        decode: impl Fn(Codec, &[u8]) -> Option<SegmentRecord>,
+    ) -> io::Result<(Value, u32, Codec)> {
+        let mut tracker = match &self.segments {
+            Some(segments) => segments.lock(),
+            None => return Ok((Value::Null, 0, self.codec)),
+        };
+        let mut reader: Box<dyn BufRead> = if self.is_lazy() {
+            Box::new(BufReader::new(File::open(&self.data_paths[0])?))
+        } else {
+            Box::new(content)
+        };
+        let head = reader.fill_buf()?;
+        let (codec, header_len) =
+            Codec::detect(head).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&head[..header_len]);
+        reader.consume(header_len);
+        let converting = !codec.same_format(self.codec);
+        let lazy = self.is_lazy() && !converting;
+
+        // The header is counted like a record that is never read back.
+        let mut records = vec![(None, header_len as u64)];
+        let mut checksum = 0;
+        let mut record = Vec::new();
+        loop {
+            let len = codec.read_frame(&mut reader, &mut record)?;
+            if len == 0 {
+                break;
+            }
+            hasher.update(&record);
+            // A record cut off by a crash does not decode.
+            let decoded = match codec.unframe(&record).and_then(|r| decode(codec, r)) {
+                Some(SegmentRecord::Put { seg, .. }) if lazy && !seg.is_empty() => {
+                    Some(SegmentRecord::Put {
+                        seg,
+                        value: Value::Null,
+                    })
+                }
+                Some(SegmentRecord::Commit {}) => {
+                    checksum = hasher.clone().finalize();
+                    Some(SegmentRecord::Commit {})
+                }
+                decoded => decoded,
+            };
+            records.push((decoded, len as u64));
+        }
+        let value = tracker.restore(records, |seg| !lazy || seg.is_empty());
+        if converting {
+            tracker.file_len = 0;
+        }
+        if let Some(residency) = &self.residency {
+            let evicted = if lazy {
+                tracker.top_keys()
+            } else {
+                BTreeMap::new()
+            };
+            residency.lock().reset(evicted.into_keys());
+        }
+        Ok((value, checksum, codec))
+    }
+
+    /// Appends the segments written since the last save to the segment
+    /// file, or rewrites the file when most of it would be out of date.
+    pub(crate) fn save_segments<E>(
+        &self,
+        data: &Value,
+        lsn: u64,
+        encode: impl Fn(&SegmentRecordRef) -> Result<Vec<u8>, E> + Sync,
+    ) -> Result<(), E>
+    where
+        E: From<io::Error> + Send,
+    {
+        let mut tracker = match &self.segments {
+            Some(segments) => segments.lock(),
+            None => return Ok(()),
+        };
+        let encode_all = |segments: Vec<(SegmentPath, Option<&Value>)>| {
+            segments
+                .into_par_iter()
+                .map(|(seg, value)| {
+                    let line = match value {
+                        Some(value) => encode(&SegmentRecordRef::Put { seg: &seg, value })?,
+                        None => encode(&SegmentRecordRef::Remove { seg: &seg })?,
+                    };
+                    Ok((seg, value.is_some(), line))
+                })
+                .collect::<Result<Vec<_>, E>>()
+        };
+        let written_of = |records: &[(SegmentPath, bool, Vec<u8>)]| -> Vec<WrittenRecord> {
+            records
+                .iter()
+                .map(|(seg, put, line)| WrittenRecord {
+                    seg: seg.clone(),
+                    len: line.len() as u64,
+                    put: *put,
+                })
+                .collect()
+        };
+
+        let records = encode_all(tracker.changes(data))?;
+        let commit = encode(&SegmentRecordRef::Commit { lsn })?;
+        let written = written_of(&records);
+        let appended: u64 = written.iter().map(|w| w.len).sum::<u64>() + commit.len() as u64;
+        let total = tracker.file_len + appended;
+        // A new file is written whole, starting with its header.
+        if tracker.file_len == 0
+            || total > COMPACTION_MIN_BYTES && total > 2 * tracker.live_bytes_after(&written)
+        {
+            let records = encode_all(tracker.all_segments(data))?;
+            return self.rewrite_segments(&mut tracker, records, &commit);
+        }
+
+        let mut content: Vec<u8> = records.iter().flat_map(|r| r.2.iter().copied()).collect();
+        content.extend_from_slice(&commit);
+        let path = &self.data_paths[0];
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(path)?;
+        // Drops what an interrupted save left after the last commit.
+        file.set_len(tracker.file_len)?;
+        file.seek(SeekFrom::End(0))?;
+        file.write_all(&content)?;
+        let mut checksums = self.checksums.lock();
+        let mut hasher = crc32fast::Hasher::new_with_initial_len(checksums[0], tracker.file_len);
+        hasher.update(&content);
+        checksums[0] = hasher.finalize();
+        self.dirty[0].store(false, Ordering::SeqCst);
+        let (start, file_len) = (tracker.file_len, tracker.file_len + content.len() as u64);
+        tracker.saved(written, start, file_len, false);
+        Ok(())
+    }
+
+    /// Replaces the segment file with `records`, the segments in memory,
+    /// followed by the records of the collections that are only on disk,
+    /// copied over from the old file.
+    fn rewrite_segments<E: From<io::Error>>(
+        &self,
+        tracker: &mut SegmentTracker,
+        records: Vec<(SegmentPath, bool, Vec<u8>)>,
+        commit: &[u8],
+    ) -> Result<(), E> {
+        let evicted: Vec<(SegmentPath, Location)> = match &self.residency {
+            Some(residency) => residency
+                .lock()
+                .evicted()
+                .iter()
+                .flat_map(|key| tracker.segments_of(key))
+                .collect(),
+            None => Vec::new(),
+        };
+        let path = &self.data_paths[0];
+        let tmp_path = path.with_extension("tmp");
+        let header = self.codec.header();
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&header);
+        let mut written = Vec::with_capacity(records.len() + evicted.len());
+        let mut file_len = header.len() as u64;
+        {
+            let mut out = BufWriter::new(File::create(&tmp_path)?);
+            out.write_all(&header)?;
+            let mut put = |seg: SegmentPath, line: &[u8]| -> io::Result<()> {
+                out.write_all(line)?;
+                hasher.update(line);
+                file_len += line.len() as u64;
+                written.push(WrittenRecord {
+                    seg,
+                    len: line.len() as u64,
+                    put: true,
+                });
+                Ok(())
+            };
+            for (seg, _, line) in records {
+                put(seg, &line)?;
+            }
+            if !evicted.is_empty() {
+                let mut old = File::open(path)?;
+                for (seg, location) in evicted {
+                    put(seg, &read_record(&mut old, location)?)?;
+                }
+            }
+            out.write_all(commit)?;
+            out.flush()?;
+        }
+        fs::rename(&tmp_path, path)?;
+        hasher.update(commit);
+        self.checksums.lock()[0] = hasher.finalize();
+        self.dirty[0].store(false, Ordering::SeqCst);
+        let start = header.len() as u64;
+        tracker.saved(written, start, file_len + commit.len() as u64, true);
+        Ok(())
+    }
+
+    /// The collections a read or write of `path` needs that are not in
+    /// memory.
+    pub(crate) fn missing_collections(&self, path: &str) -> Vec<String> {
+        match &self.residency {
+            Some(residency) => residency.lock().missing(path),
+            None => Vec::new(),
+        }
+    }
+
+    /// Reads the segments of the collection `key` back from the segment
+    /// file.
+    pub(crate) fn read_collection(
+        &self,
+        key: &str,
+        decode: impl Fn(Codec, &[u8]) -> Option<SegmentRecord>,
+    ) -> io::Result<Vec<(SegmentPath, Value)>> {
+        let locations = match &self.segments {
+            Some(segments) => segments.lock().segments_of(key),
+            None => return Ok(Vec::new()),
+        };
+        let mut file = File::open(&self.data_paths[0])?;
+        locations
+            .into_iter()
+            .map(|(seg, location)| {
+                let line = read_record(&mut file, location)?;
+                let payload = self.codec.unframe(&line);
+                match payload.and_then(|payload| decode(self.codec, payload)) {
+                    Some(SegmentRecord::Put { value, .. }) => Ok((seg, value)),
+                    _ => Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Unreadable segment record for {}", seg.join(".")),
+                    )),
+                }
+            })
+            .collect()
+    }
+
+    pub(crate) fn loaded_collection(&self, key: &str) {
+        if let Some(residency) = &self.residency {
+            residency.lock().loaded(key);
+        }
+    }
+
+    /// Records a use of `path` for the eviction order.
+    pub(crate) fn touch(&self, path: &str) {
+        if let Some(residency) = &self.residency {
+            residency.lock().touch(path);
+        }
+    }
+
+    /// Picks the collections to drop from memory to fit the budget. The
+    /// collection of `keep`, collections with unsaved writes and those
+    /// under one of the `pinned` paths stay.
+    pub(crate) fn evict(&self, keep: Option<&str>, pinned: &[String]) -> Vec<String> {
+        let (Some(residency), Some(segments)) = (&self.residency, &self.segments) else {
+            return Vec::new();
+        };
+        let keep = keep.and_then(top_key);
+        let tracker = segments.lock();
+        let sizes = tracker.top_keys();
+        residency.lock().evict(&sizes, |key| {
+            Some(key) == keep
+                || tracker.is_dirty_key(key)
+                || pinned
+                    .iter()
+                    .any(|path| top_key(path).is_none_or(|p| p == key))
+        })
+    }
+
+    pub(crate) fn remove_stale_files(&self) -> io::Result<()> {
+        for path in self
+            .stale_files("json")
+            .into_iter()
+            .chain(self.stale_files("wal"))
+        {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    /// Checksum of the data files as last read or written: the file's own
+    /// CRC for a single file, a CRC of the shard CRCs otherwise.
+    pub(crate) fn checksum(&self) -> u32 {
+        let checksums = self.checksums.lock();
+        if !self.is_sharded() {
+            return checksums[0];
+        }
+        let bytes: Vec<u8> = checksums.iter().flat_map(|c| c.to_le_bytes()).collect();
+        crc32fast::hash(&bytes)
+    }
+}
+
+/// Reads the record at `location` of the segment file.
+fn read_record(file: &mut File, location: Location) -> io::Result<Vec<u8>> {
+    let mut line = vec![0; location.len as usize];
+    file.seek(SeekFrom::Start(location.offset))?;
+    file.read_exact(&mut line)?;
+    Ok(line)
+}
+
+/// Where the WAL at `path` is kept once a save at `lsn` ends it. The LSN is
+/// zero-padded so the names sort in order.
+fn retained_path(path: &Path, lsn: u64) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{:020}", lsn));
+    PathBuf::from(name)
+}
+
+/// The kept WALs of the WAL at `path`, oldest first.
+fn retained_wals(path: &Path) -> Vec<(u64, PathBuf)> {
+    let (dir, name) = match (path.parent(), path.file_name().and_then(|n| n.to_str())) {
+        (Some(dir), Some(name)) => (dir, format!("{}.", name)),
+        _ => return Vec::new(),
+    };
+    let dir = if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
+    };
+    let mut kept: Vec<(u64, PathBuf)> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            let lsn = path
+                .file_name()?
+                .to_str()?
+                .strip_prefix(&name)?
+                .parse()
+                .ok()?;
+            Some((lsn, path))
+        })
+        .collect();
+    kept.sort();
+    kept
+}
+
+#[cfg(unix)]
+fn inode(metadata: &fs::Metadata) -> u64 {
+    std::os::unix::fs::MetadataExt::ino(metadata)
+}
+
+#[cfg(not(unix))]
+fn inode(_metadata: &fs::Metadata) -> u64 {
+    0
+}
@@ -0,0 +1,85 @@
+//! Background tasks behind the `*_async` methods. Each task owns a handle to
+//! the same database state and runs the synchronous operation on the libuv
+//! threadpool, resolving the returned promise with its result.
+
+use napi::{Env, Result, Task};
+use serde_json::Value;
+
+use crate::{AggregateSpec, DatabaseCore, QueryOptions};
+
+pub struct LoadTask {
+    pub(crate) core: DatabaseCore,
+}
+
+impl Task for LoadTask {
+    type Output = ();
+    type JsValue = ();
+
+    fn compute(&mut self) -> Result<()> {
+        self.core.load()
+    }
+
+    fn resolve(&mut self, _env: Env, _output: ()) -> Result<()> {
+        Ok(())
+    }
+}
+
+pub struct SaveTask {
+    pub(crate) core: DatabaseCore,
+}
+
+impl Task for SaveTask {
+    type Output = ();
+    type JsValue = ();
+
+    fn compute(&mut self) -> Result<()> {
+        self.core.save()
+    }
+
+    fn resolve(&mut self, _env: Env, _output: ()) -> Result<()> {
+        Ok(())
+    }
+}
+
+pub struct FindTask {
+    pub(crate) core: DatabaseCore,
+    pub(crate) path: String,
+    pub(crate) query: Value,
+    pub(crate) options: Option<QueryOptions>,
+}
+
+impl Task for FindTask {
+    type Output = Vec<Value>;
+    type JsValue = Vec<Value>;
+
+    fn compute(&mut self) -> Result<Vec<Value>> {
+        let (results, _) = self
+            .core
+            .run_find(&self.path, &self.query, self.options.as_ref())?;
+        Ok(results)
+    }
+
+    fn resolve(&mut self, _env: Env, output: Vec<Value>) -> Result<Vec<Value>> {
+        Ok(output)
+    }
+}
+
+pub struct AggregateTask {
+    pub(crate) core: DatabaseCore,
+    pub(crate) path: String,
+    pub(crate) query: Value,
+    pub(crate) spec: AggregateSpec,
+}
+
+impl Task for AggregateTask {
+    type Output = Vec<Value>;
+    type JsValue = Vec<Value>;
+
+    fn compute(&mut self) -> Result<Vec<Value>> {
+        self.core.run_aggregate(&self.path, &self.query, &self.spec)
+    }
+
+    fn resolve(&mut self, _env: Env, output: Vec<Value>) -> Result<Vec<Value>> {
+        Ok(output)
+    }
+}
@@ -0,0 +1,276 @@
+use rust_stemmers::{Algorithm, Stemmer};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+
+use crate::get_value_by_path;
+use crate::indexes::{CollectionIndex, DocId, SyncState};
+
+/// BM25 term-frequency saturation.
+const BM25_K1: f64 = 1.2;
+/// BM25 document-length normalization.
+const BM25_B: f64 = 0.75;
+
+const DEFAULT_STOP_WORDS: &[&str] = &[
+    "a", "an", "and", "are", "as", "at", "be", "but", "by", "for", "if", "in", "into", "is", "it",
+    "no", "not", "of", "on", "or", "such", "that", "the", "their", "then", "there", "these",
+    "they", "this", "to", "was", "will", "with",
+];
+
+/// How a text index turns field values into terms.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct TextConfig {
+    pub(crate) fields: Vec<String>,
+    /// `word` splits on anything that is not a letter or digit, `whitespace`
+    /// only on whitespace.
+    pub(crate) tokenizer: String,
+    pub(crate) lowercase: bool,
+    /// Snowball stemming language, or `None` to index words as they are.
+    pub(crate) stem_language: Option<String>,
+    /// `None` uses the built-in English list.
+    pub(crate) stop_words: Option<Vec<String>>,
+}
+
+struct Analyzer {
+    whitespace_only: bool,
+    lowercase: bool,
+    stemmer: Option<Stemmer>,
+    stop_words: HashSet<String>,
+}
+
+impl Analyzer {
+    fn new(config: &TextConfig) -> std::result::Result<Self, String> {
+        let whitespace_only = match config.tokenizer.as_str() {
+            "word" => false,
+            "whitespace" => true,
+            other => return Err(format!("Unknown tokenizer '{}'", other)),
+        };
+        let stemmer = config
+            .stem_language
+            .as_deref()
+            .map(|lang| stem_algorithm(lang).map(Stemmer::create))
+            .transpose()?;
+        let stop_words = match &config.stop_words {
+            Some(words) => words
+                .iter()
+                .map(|w| normalize_case(w, config.lowercase))
+                .collect(),
+            None => DEFAULT_STOP_WORDS.iter().map(|w| w.to_string()).collect(),
+        };
+        Ok(Analyzer {
+            whitespace_only,
+            lowercase: config.lowercase,
+            stemmer,
+            stop_words,
+        })
+    }
+
+    fn terms(&self, text: &str, out: &mut Vec<String>) {
+        let tokens: Box<dyn Iterator<Item = &str>> = if self.whitespace_only {
+            Box::new(text.split_whitespace())
+        } else {
+            Box::new(text.split(|c: char| !c.is_alphanumeric()))
+        };
+        for token in tokens.filter(|t| !t.is_empty()) {
+            let token = normalize_case(token, self.lowercase);
+            if self.stop_words.contains(&token) {
+                continue;
+            }
+            match &self.stemmer {
+                Some(stemmer) => out.push(stemmer.stem(&token).into_owned()),
+                None => out.push(token),
+            }
+        }
+    }
+}
+
+fn normalize_case(word: &str, lowercase: bool) -> String {
+    if lowercase {
+        word.to_lowercase()
+    } else {
+        word.to_string()
+    }
+}
+
+fn stem_algorithm(language: &str) -> std::result::Result<Algorithm, String> {
+    Ok(match language.to_lowercase().as_str() {
+        "arabic" => Algorithm::Arabic,
+        "danish" => Algorithm::Danish,
+        "dutch" => Algorithm::Dutch,
+        "english" => Algorithm::English,
+        "finnish" => Algorithm::Finnish,
+        "french" => Algorithm::French,
+        "german" => Algorithm::German,
+        "greek" => Algorithm::Greek,
+        "hungarian" => Algorithm::Hungarian,
+        "italian" => Algorithm::Italian,
+        "norwegian" => Algorithm::Norwegian,
+        "portuguese" => Algorithm::Portuguese,
+        "romanian" => Algorithm::Romanian,
+        "russian" => Algorithm::Russian,
+        "spanish" => Algorithm::Spanish,
+        "swedish" => Algorithm::Swedish,
+        "tamil" => Algorithm::Tamil,
+        "turkish" => Algorithm::Turkish,
+        other => return Err(format!("Unsupported stemming language '{}'", other)),
+    })
+}
+
+/// An inverted index over the text fields of a collection, ranked with BM25.
+pub(crate) struct TextIndex {
+    pub(crate) name: String,
+    pub(crate) collection: String,
+    pub(crate) config: TextConfig,
+    analyzer: Analyzer,
+    /// term -> document -> term frequency
+    postings: HashMap<String, HashMap<DocId, u32>>,
+    doc_lens: HashMap<DocId, u32>,
+    total_len: u64,
+    sync: SyncState,
+}
+
+impl TextIndex {
+    pub(crate) fn new(
+        name: String,
+        collection: String,
+        config: TextConfig,
+    ) -> std::result::Result<Self, String> {
+        Ok(TextIndex {
+            name,
+            collection,
+            analyzer: Analyzer::new(&config)?,
+            config,
+            postings: HashMap::new(),
+            doc_lens: HashMap::new(),
+            total_len: 0,
+            sync: SyncState::default(),
+        })
+    }
+
+    fn doc_terms(&self, doc: &Value) -> Vec<String> {
+        let mut terms = Vec::new();
+        for field in &self.config.fields {
+            match get_value_by_path(doc, field) {
+                Some(Value::String(text)) => self.analyzer.terms(text, &mut terms),
+                Some(Value::Array(elems)) => {
+                    for text in elems.iter().filter_map(|e| e.as_str()) {
+                        self.analyzer.terms(text, &mut terms);
+                    }
+                }
+                _ => {}
+            }
+        }
+        terms
+    }
+
+    /// Scores the documents containing any term of `text` with BM25, best
+    /// first. Ties keep collection order.
+    pub(crate) fn search(&self, text: &str) -> Vec<(DocId, f64)> {
+        let mut terms = Vec::new();
+        self.analyzer.terms(text, &mut terms);
+        terms.sort();
+        terms.dedup();
+
+        let doc_count = self.doc_lens.len() as f64;
+        if doc_count == 0.0 {
+            return Vec::new();
+        }
+        let avg_len = self.total_len as f64 / doc_count;
+
+        let mut scores: HashMap<&DocId, f64> = HashMap::new();
+        for term in &terms {
+            let postings = match self.postings.get(term) {
+                Some(p) => p,
+                None => continue,
+            };
+            let df = postings.len() as f64;
+            let idf = (1.0 + (doc_count - df + 0.5) / (df + 0.5)).ln();
+            for (id, tf) in postings {
+                let tf = *tf as f64;
+                let len = self.doc_lens.get(id).copied().unwrap_or(0) as f64;
+                let norm = tf + BM25_K1 * (1.0 - BM25_B + BM25_B * len / avg_len.max(1.0));
+                *scores.entry(id).or_default() += idf * tf * (BM25_K1 + 1.0) / norm;
+            }
+        }
+
+        let mut ranked: Vec<(DocId, f64)> = scores
+            .into_iter()
+            .map(|(id, score)| (id.clone(), score))
+            .collect();
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        ranked
+    }
+
+    pub(crate) fn to_snapshot(&self) -> TextIndexSnapshot {
+        TextIndexSnapshot {
+            name: self.name.clone(),
+            collection: self.collection.clone(),
+            config: self.config.clone(),
+        }
+    }
+
+    /// Text indexes are persisted by definition only and rebuilt on first use.
+    pub(crate) fn from_snapshot(snapshot: TextIndexSnapshot) -> Option<Self> {
+        TextIndex::new(snapshot.name, snapshot.collection, snapshot.config).ok()
+    }
+}
+
+impl CollectionIndex for TextIndex {
+    fn collection(&self) -> &str {
+        &self.collection
+    }
+
+    fn sync(&self) -> &SyncState {
+        &self.sync
+    }
+
+    fn sync_mut(&mut self) -> &mut SyncState {
+        &mut self.sync
+    }
+
+    fn clear(&mut self) {
+        self.postings.clear();
+        self.doc_lens.clear();
+        self.total_len = 0;
+    }
+
+    fn insert_doc(&mut self, id: DocId, doc: &Value) {
+        let terms = self.doc_terms(doc);
+        if terms.is_empty() {
+            return;
+        }
+        self.total_len += terms.len() as u64;
+        self.doc_lens.insert(id.clone(), terms.len() as u32);
+        for term in terms {
+            *self
+                .postings
+                .entry(term)
+                .or_default()
+                .entry(id.clone())
+                .or_default() += 1;
+        }
+    }
+
+    fn remove_doc(&mut self, id: &DocId, doc: &Value) {
+        let len = match self.doc_lens.remove(id) {
+            Some(len) => len,
+            None => return,
+        };
+        self.total_len -= len as u64;
+        for term in self.doc_terms(doc) {
+            if let Some(docs) = self.postings.get_mut(&term) {
+                docs.remove(id);
+                if docs.is_empty() {
+                    self.postings.remove(&term);
+                }
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct TextIndexSnapshot {
+    name: String,
+    collection: String,
+    config: TextConfig,
+}
@@ -0,0 +1,144 @@
+use napi::threadsafe_function::{ErrorStrategy, ThreadsafeFunction, ThreadsafeFunctionCallMode};
+use napi_derive::napi;
+use parking_lot::RwLock;
+use serde_json::Value;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use crate::{get_value_by_path, Operation};
+
+/// A committed write, as `watch` callbacks receive it.
+#[napi(object)]
+#[derive(Clone)]
+pub struct ChangeEvent {
+    /// `set` or `delete`.
+    pub op: String,
+    pub path: String,
+    /// The value at `path` before the write, if there was one.
+    pub old_value: Option<Value>,
+    /// The value at `path` after the write; absent after a delete.
+    pub new_value: Option<Value>,
+    pub lsn: i64,
+}
+
+impl ChangeEvent {
+    /// A write as the WAL logged it, without the value it replaced.
+    pub(crate) fn logged(lsn: u64, op: Operation) -> Self {
+        let (op, path, new_value) = match op {
+            Operation::Set { path, value } => ("set", path, Some(value)),
+            Operation::Delete { path } => ("delete", path, None),
+        };
+        ChangeEvent {
+            op: op.to_string(),
+            path,
+            old_value: None,
+            new_value,
+            lsn: lsn as i64,
+        }
+    }
+}
+
+pub(crate) type ChangeCallback = ThreadsafeFunction<ChangeEvent, ErrorStrategy::Fatal>;
+
+struct Watcher {
+    id: u32,
+    pattern: Vec<String>,
+    callback: ChangeCallback,
+}
+
+/// The callbacks registered with `watch`.
+#[derive(Default)]
+pub(crate) struct Watchers {
+    list: RwLock<Vec<Watcher>>,
+    next_id: AtomicU32,
+}
+
+impl Watchers {
+    pub(crate) fn add(&self, pattern: &str, callback: ChangeCallback) -> u32 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        self.list.write().push(Watcher {
+            id,
+            pattern: split(pattern).map(String::from).collect(),
+            callback,
+        });
+        id
+    }
+
+    pub(crate) fn remove(&self, id: u32) -> bool {
+        let mut list = self.list.write();
+        let len = list.len();
+        list.retain(|w| w.id != id);
+        list.len() != len
+    }
+
+    /// Applies `op` with `apply`, then queues a change event for every
+    /// watcher of its path. The values are only copied when someone
+    /// watches.
+    pub(crate) fn observe(
+        &self,
+        data: &mut Value,
+        op: Operation,
+        lsn: u64,
+        apply: impl FnOnce(&mut Value, Operation),
+    ) {
+        let list = self.list.read();
+        let path = op.path().to_string();
+        let parts = split(&path).collect::<Vec<_>>();
+        let callbacks = list
+            .iter()
+            .filter(|w| overlaps(&w.pattern, &parts))
+            .map(|w| &w.callback)
+            .collect::<Vec<_>>();
+        if callbacks.is_empty() {
+            apply(data, op);
+            return;
+        }
+
+        let deleted = matches!(op, Operation::Delete { .. });
+        let old_value = get_value_by_path(data, &path).cloned();
+        apply(data, op);
+        let event = ChangeEvent {
+            op: if deleted { "delete" } else { "set" }.to_string(),
+            new_value: if deleted {
+                None
+            } else {
+                get_value_by_path(data, &path).cloned()
+            },
+            path,
+            old_value,
+            lsn: lsn as i64,
+        };
+        // Called with the writer's locks held, so events are queued in LSN
+        // order; they reach JavaScript once it is idle.
+        for callback in callbacks {
+            callback.call(event.clone(), ThreadsafeFunctionCallMode::NonBlocking);
+        }
+    }
+}
+
+fn split(path: &str) -> impl Iterator<Item = &str> {
+    path.split('.').filter(|part| !part.is_empty())
+}
+
+/// Whether a write to `path` can change a path `pattern` matches: the path
+/// matches, lies below a match, or holds one. `*` in a pattern segment
+/// stands for any characters within the segment, and a `**` segment for
+/// any number of segments.
+fn overlaps(pattern: &[String], path: &[&str]) -> bool {
+    match (pattern.split_first(), path.split_first()) {
+        (None, _) | (_, None) => true,
+        (Some((first, rest)), _) if first == "**" => {
+            (0..=path.len()).any(|skip| overlaps(rest, &path[skip..]))
+        }
+        (Some((first, rest)), Some((part, path_rest))) => {
+            segment_matches(first.as_bytes(), part.as_bytes()) && overlaps(rest, path_rest)
+        }
+    }
+}
+
+fn segment_matches(pattern: &[u8], part: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => part.is_empty(),
+        Some((b'*', rest)) => (0..=part.len()).any(|skip| segment_matches(rest, &part[skip..])),
+        Some((c, rest)) => part.first() == Some(c) && segment_matches(rest, &part[1..]),
+    }
+}